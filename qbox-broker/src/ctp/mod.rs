@@ -3,9 +3,11 @@ mod trade;
 
 use anyhow::Result;
 use ctp_rs::{ffi::*, QuoteApi, TradeApi};
+use qbox_core::broker::ids::{IdGen, IdMap};
 use qbox_core::broker::{Counter, Driver};
 #[cfg(target_os = "windows")]
 use std::os::windows::ffi::OsStrExt;
+use std::sync::atomic::AtomicI32;
 use std::sync::Arc;
 use url::Url;
 
@@ -21,6 +23,13 @@ pub struct CTP {
     quote: Option<QuoteApi>,
     trade: Option<TradeApi>,
     login: Option<CThostFtdcRspUserLoginField>,
+    investor_id: String,
+    //本地订单号与FrontID/SessionID/OrderRef、OrderSysID的映射
+    ids: Option<Arc<IdMap>>,
+    //本地订单号生成，报单时替换调用方传入的订单号
+    idgen: Option<IdGen>,
+    //报单引用，登录后从MaxOrderRef开始递增
+    order_ref: AtomicI32,
 }
 
 impl Counter for CTP {
@@ -4,6 +4,7 @@ use chrono::prelude::*;
 use crossbeam::channel::{self, Sender};
 use ctp_rs::{ffi::*, Configuration, FromCBuf, QuoteApi, QuoteSpi, Response};
 use qbox_core::broker::*;
+use qbox_core::calendar;
 use qbox_core::core;
 use qbox_core::core::events::QuoteEvent;
 use std::collections::HashMap;
@@ -97,6 +98,7 @@ impl CTP {
                         quote: Some(qapi),
                         login: Some(info),
                         trade: None,
+                        ..Default::default()
                     })
                 }
                 Event::Error(code, msg) => return Err(anyhow!("{} {}", code, msg)),
@@ -155,21 +157,17 @@ impl QuoteSpi for QuoteClient {
     }
     fn on_depth_market_data(&self, q: &CThostFtdcDepthMarketDataField) {
         log::trace!("on_depth_market_data {:?}", q,);
-        let exchange = Exchange::UNKNOWN;
+        let exchange = Exchange::from(String::from_c_buf(&q.ExchangeID));
         let trading_date = String::from_c_buf(&q.TradingDay);
         let action_date = String::from_c_buf(&q.ActionDay);
         let security_id = String::from_c_buf(&q.InstrumentID);
         let updatetime = String::from_c_buf(&q.UpdateTime);
-        let time = NaiveTime::parse_from_str("%H:%M:%S", updatetime.as_str())
-            .unwrap_or(Local::now().naive_local().time());
-        let time = Local::now()
-            .with_hour(time.hour())
-            .unwrap()
-            .with_minute(time.minute())
-            .unwrap()
-            .with_second(time.second())
-            .unwrap()
-            .timestamp();
+        let now = Utc::now().timestamp();
+        //夜盘跨越午夜，ActionDay不可靠，取离当前时间最近的日期
+        let time = match NaiveTime::parse_from_str(updatetime.as_str(), "%H:%M:%S") {
+            Ok(time) => calendar::timestamp_near(exchange, time, now),
+            Err(_) => now,
+        };
 
         let ev = QuoteEvent::Level1(
             Level1::new()
@@ -309,7 +307,10 @@ impl QuoteSpi for QuoteClient {
                     ),
                 ])
                 .with_item("trading_date", Value::String(trading_date))
-                .with_item("action_date", Value::String(action_date)),
+                .with_item("action_date", Value::String(action_date))
+                .with_item("open_interest", Value::F64(q.OpenInterest))
+                .with_item("upper_limit", Value::F64(q.UpperLimitPrice))
+                .with_item("lower_limit", Value::F64(q.LowerLimitPrice)),
         );
         let _ = core::quotes_event(ev);
     }
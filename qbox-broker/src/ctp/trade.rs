@@ -3,12 +3,16 @@ use anyhow::anyhow;
 use anyhow::Result;
 use crossbeam::channel::{self, Sender};
 use ctp_rs::{ffi::*, Configuration, FromCBuf, Response, ResumeType, ToArray, TradeApi, TradeSpi};
+use qbox_core::broker::ids::{IdGen, IdMap, VenueId};
 use qbox_core::broker::*;
+use qbox_core::calendar;
 use qbox_core::core;
 use qbox_core::core::events::TradeEvent;
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
 use url::Url;
 use urlencoding::decode;
 
@@ -21,10 +25,23 @@ impl CTP {
             .to_owned();
         let user_id = decode(uri.username())?.to_string();
         let passwd = decode(uri.password().unwrap_or(""))?.to_string();
-        let _investor_id = params
+        let investor_id = params
             .get("investor_id")
             .unwrap_or(&String::from(""))
             .to_owned();
+        //订单号映射按单元持久化，重连后仍可由回报找到本地订单
+        let unit = params
+            .get("unit")
+            .unwrap_or(&String::from("ctp"))
+            .to_owned();
+        let ids = Arc::new(IdMap::open(&unit)?);
+        //多个进程共用一个单元时用node区分
+        let node = params
+            .get("node")
+            .map(|v| v.parse::<u16>())
+            .transpose()?
+            .unwrap_or(0);
+        let idgen = IdGen::open(&unit, node)?;
         let appid = params.get("appid").unwrap_or(&String::from("")).to_owned();
         let auth_code = params
             .get("auth_code")
@@ -58,7 +75,10 @@ impl CTP {
                 passwd,
                 ..Default::default()
             })
-            .with_spi(TradeClient(tx));
+            .with_spi(TradeClient {
+                tx,
+                ids: ids.clone(),
+            });
         tapi.subscribe_public_topic(ResumeType::THOST_TERT_RESTART)?;
         tapi.subscribe_private_topic(ResumeType::THOST_TERT_RESTART)?;
         tapi.register_front()?;
@@ -71,11 +91,19 @@ impl CTP {
                     tapi.login()?;
                 }
                 Event::Login(info) => {
+                    let order_ref = String::from_c_buf(&info.MaxOrderRef)
+                        .trim()
+                        .parse::<i32>()
+                        .unwrap_or(0);
                     return Ok(CTP {
                         quote: None,
                         login: Some(info),
                         trade: Some(tapi),
-                    })
+                        investor_id,
+                        ids: Some(ids),
+                        idgen: Some(idgen),
+                        order_ref: AtomicI32::new(order_ref),
+                    });
                 }
                 Event::Error(code, msg) => return Err(anyhow!("{} {}", code, msg)),
             }
@@ -83,12 +111,15 @@ impl CTP {
     }
 }
 
-struct TradeClient(Sender<Event>);
+struct TradeClient {
+    tx: Sender<Event>,
+    ids: Arc<IdMap>,
+}
 
 impl Deref for TradeClient {
     type Target = Sender<Event>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.tx
     }
 }
 
@@ -185,10 +216,57 @@ impl TradeSpi for TradeClient {
                 } else {
                     InstState::Unknown
                 });
-            let ev = TradeEvent::InstrumentsResponse(instrument);
+            let ev = TradeEvent::Instrument(instrument);
             let _ = core::query_event(ev);
         }
     }
+
+    ///报单回报，交易所受理后绑定OrderSysID
+    fn on_order(&self, order: &CThostFtdcOrderField) {
+        log::trace!("on_order {:?}", order);
+        let sys_id = String::from_c_buf(&order.OrderSysID);
+        if sys_id.trim().is_empty() {
+            return;
+        }
+        let venue = VenueId::Ctp {
+            front_id: order.FrontID,
+            session_id: order.SessionID,
+            order_ref: String::from_c_buf(&order.OrderRef).trim().to_string(),
+        };
+        match self.ids.order_id(&venue) {
+            Some(order_id) => {
+                let sys = VenueId::OrderSys {
+                    exchange: Exchange::from(String::from_c_buf(&order.ExchangeID)),
+                    sys_id,
+                };
+                if let Err(err) = self.ids.bind(order_id, sys) {
+                    log::warn!("on_order bind {} {:?}", order_id, err);
+                }
+            }
+            //其它会话的报单
+            None => log::trace!("on_order unknown {:?}", venue),
+        }
+    }
+}
+
+//开平标志
+fn offset_flag(offset: Side) -> Result<i8> {
+    match offset {
+        Side::Open => Ok('0' as i8),
+        Side::Close => Ok('1' as i8),
+        Side::CloseToday => Ok('3' as i8),
+        Side::CloseYesterday => Ok('4' as i8),
+        _ => Err(anyhow!("unsupported offset {:?}", offset)),
+    }
+}
+
+//买卖方向
+fn direction(side: Side) -> Result<i8> {
+    match side {
+        Side::Buy => Ok('0' as i8),
+        Side::Sell => Ok('1' as i8),
+        _ => Err(anyhow!("unsupported side {:?}", side)),
+    }
 }
 
 impl Trades for CTP {
@@ -219,11 +297,102 @@ impl Trades for CTP {
     fn accounts(&self, filter: &[&str]) {}
     //查时区
     fn timezone(&mut self, zone: &'static str) -> String {
-        "".to_string()
+        calendar::timezone(Exchange::from(zone)).to_string()
     }
     //报单
     fn offer(&self, order: Order) -> Result<Order> {
-        unimplemented!()
+        let (tapi, login, ids, idgen) = match (&self.trade, &self.login, &self.ids, &self.idgen) {
+            (Some(tapi), Some(login), Some(ids), Some(idgen)) => (tapi, login, ids, idgen),
+            _ => return Err(anyhow!("ctp trade not login")),
+        };
+        //订单号由本地生成，调用方以返回的订单为准
+        let order = order.with_id(idgen.next()?);
+        let mut req = CThostFtdcInputOrderField::default();
+        req.BrokerID = login.BrokerID;
+        req.UserID = login.UserID;
+        req.InvestorID = self.investor_id.as_str().into_array::<13>();
+        req.InstrumentID = order.security_id().into_array::<81>();
+        let exchange: &str = order.exchange().into();
+        req.ExchangeID = exchange.into_array::<9>();
+        //投机
+        req.CombHedgeFlag[0] = '1' as i8;
+        req.MinVolume = 1;
+        //立即触发
+        req.ContingentCondition = '1' as i8;
+        //非强平
+        req.ForceCloseReason = '0' as i8;
+        match &order {
+            Order::Limit {
+                side,
+                offset,
+                price,
+                quantity,
+                pov,
+                ..
+            } => {
+                req.Direction = direction(*side)?;
+                req.CombOffsetFlag[0] = offset_flag(*offset)?;
+                //限价
+                req.OrderPriceType = '2' as i8;
+                req.LimitPrice = *price;
+                req.VolumeTotalOriginal = *quantity as i32;
+                match pov {
+                    //立即完成否则撤销，全部成交
+                    OrderLife::FOK | OrderLife::AON => {
+                        req.TimeCondition = '1' as i8;
+                        req.VolumeCondition = '3' as i8;
+                    }
+                    //立即完成否则撤销，任意数量
+                    OrderLife::IOC | OrderLife::FAK => {
+                        req.TimeCondition = '1' as i8;
+                        req.VolumeCondition = '1' as i8;
+                    }
+                    //当日有效
+                    _ => {
+                        req.TimeCondition = '3' as i8;
+                        req.VolumeCondition = '1' as i8;
+                    }
+                }
+            }
+            Order::Market {
+                side,
+                offset,
+                quantity,
+                ..
+            } => {
+                req.Direction = direction(*side)?;
+                req.CombOffsetFlag[0] = offset_flag(*offset)?;
+                //任意价
+                req.OrderPriceType = '1' as i8;
+                req.VolumeTotalOriginal = *quantity as i32;
+                req.TimeCondition = '1' as i8;
+                req.VolumeCondition = '1' as i8;
+            }
+            _ => return Err(anyhow!("unsupported order {:?}", order)),
+        }
+        let order_ref = (self.order_ref.fetch_add(1, Ordering::SeqCst) + 1).to_string();
+        req.OrderRef = order_ref.as_str().into_array::<13>();
+        //先登记报单引用，回报可能早于请求返回
+        ids.bind(
+            order.id(),
+            VenueId::Ctp {
+                front_id: login.FrontID,
+                session_id: login.SessionID,
+                order_ref,
+            },
+        )?;
+        if let Err(err) = tapi.insert_order(&mut req) {
+            ids.unbind(order.id())?;
+            return Err(err.into());
+        }
+        let mut order = order;
+        match &mut order {
+            Order::Limit { state, .. } | Order::Market { state, .. } => {
+                state.state = State::Submitted
+            }
+            _ => {}
+        }
+        Ok(order)
     }
     //撤单
     fn cancel(&self, order: Order) -> Result<()> {
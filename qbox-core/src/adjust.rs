@@ -0,0 +1,289 @@
+use crate::broker::{Bar, Period};
+use crate::calendar;
+use crate::db;
+use anyhow::Result;
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+//除权除息数据文件
+const ACTIONS_FILE: &str = "corporate_actions.csv";
+
+lazy_static! {
+    //除权除息表
+    static ref ACTIONS: RwLock<ActionTable> = RwLock::new(ActionTable::load());
+}
+
+#[doc = "复权方式"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AdjustMode {
+    //不复权
+    None,
+    //前复权，最新价格不变，调整除权日之前的价格
+    Forward,
+    //后复权，最早价格不变，调整除权日及之后的价格
+    Backward,
+}
+
+impl Default for AdjustMode {
+    fn default() -> Self {
+        AdjustMode::None
+    }
+}
+
+#[doc = "除权除息，数量按每股计"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorporateAction {
+    pub security_id: String,
+    pub ex_date: NaiveDate, //除权除息日
+    pub cash: f64,          //现金分红
+    pub bonus: f64,         //送转股
+    pub rights: f64,        //配股
+    pub rights_price: f64,  //配股价
+}
+
+impl CorporateAction {
+    //除权参考价
+    pub fn ex_price(&self, prev_close: f64) -> f64 {
+        (prev_close - self.cash + self.rights * self.rights_price)
+            / (1.0 + self.bonus + self.rights)
+    }
+
+    //复权因子，除权前收盘价与除权参考价之比
+    pub fn factor(&self, prev_close: f64) -> f64 {
+        let ex_price = self.ex_price(prev_close);
+        if prev_close.is_nan() || ex_price <= 0.0 {
+            1.0
+        } else {
+            prev_close / ex_price
+        }
+    }
+}
+
+//数据文件的一行，数量按每10股计
+#[derive(Debug, Deserialize)]
+struct Row {
+    security_id: String,
+    ex_date: String,
+    #[serde(default)]
+    cash: f64,
+    #[serde(default)]
+    bonus: f64,
+    #[serde(default)]
+    rights: f64,
+    #[serde(default)]
+    rights_price: f64,
+}
+
+#[doc = "除权除息表"]
+#[derive(Debug, Clone, Default)]
+pub struct ActionTable {
+    //证券代码 => 按除权日排序的除权除息
+    actions: HashMap<String, Vec<CorporateAction>>,
+}
+
+impl ActionTable {
+    //加载数据目录下的除权除息文件
+    pub fn load() -> Self {
+        let mut table = Self::default();
+        let path = Path::new(&crate::data_path()).join(ACTIONS_FILE);
+        if path.exists() {
+            if let Err(err) = table.load_file(&path) {
+                log::error!("load corporate actions {:?} error {:?}", path, err);
+            }
+        }
+        table
+    }
+
+    //csv文件，列为security_id,ex_date,cash,bonus,rights,rights_price，
+    //除权日为YYYYMMDD或YYYY-MM-DD，分红、送转、配股按每10股计
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        let mut count = 0;
+        for row in reader.deserialize::<Row>() {
+            let row = row?;
+            let ex_date = NaiveDate::parse_from_str(&row.ex_date, "%Y%m%d")
+                .or_else(|_| NaiveDate::parse_from_str(&row.ex_date, "%Y-%m-%d"))?;
+            self.add(CorporateAction {
+                security_id: row.security_id,
+                ex_date,
+                cash: row.cash / 10.0,
+                bonus: row.bonus / 10.0,
+                rights: row.rights / 10.0,
+                rights_price: row.rights_price,
+            });
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    //同一证券同一除权日的记录覆盖
+    pub fn add(&mut self, action: CorporateAction) {
+        let actions = self.actions.entry(action.security_id.clone()).or_default();
+        actions.retain(|a| a.ex_date != action.ex_date);
+        actions.push(action);
+        actions.sort_by_key(|a| a.ex_date);
+    }
+
+    pub fn get(&self, security_id: &str) -> &[CorporateAction] {
+        self.actions
+            .get(security_id)
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+    }
+
+    //按k线序列计算各除权日的复权因子，除权前收盘价取除权日之前最后一根k线
+    pub fn factors(&self, security_id: &str, bars: &[Bar]) -> Vec<(NaiveDate, f64)> {
+        self.get(security_id)
+            .iter()
+            .map(|action| {
+                let prev_close = bars
+                    .iter()
+                    .filter(|bar| calendar::trading_day(bar.exchange, bar.time) < action.ex_date)
+                    .max_by_key(|bar| bar.time)
+                    .map(|bar| bar.close)
+                    .unwrap_or(f64::NAN);
+                (action.ex_date, action.factor(prev_close))
+            })
+            .collect()
+    }
+}
+
+//按复权因子调整价格，成交量不变
+pub fn apply(bars: &[Bar], factors: &[(NaiveDate, f64)], mode: AdjustMode) -> Vec<Bar> {
+    if mode == AdjustMode::None || factors.is_empty() {
+        return bars.to_vec();
+    }
+    bars.iter()
+        .map(|bar| {
+            let day = calendar::trading_day(bar.exchange, bar.time);
+            let ratio = match mode {
+                AdjustMode::Forward => {
+                    1.0 / factors
+                        .iter()
+                        .filter(|(date, _)| *date > day)
+                        .map(|(_, f)| f)
+                        .product::<f64>()
+                }
+                AdjustMode::Backward => factors
+                    .iter()
+                    .filter(|(date, _)| *date <= day)
+                    .map(|(_, f)| f)
+                    .product(),
+                AdjustMode::None => 1.0,
+            };
+            let mut bar = bar.clone();
+            bar.open *= ratio;
+            bar.high *= ratio;
+            bar.low *= ratio;
+            bar.close *= ratio;
+            bar
+        })
+        .collect()
+}
+
+//按除权除息表复权，除权前收盘价取自同一序列
+pub fn adjust(bars: &[Bar], mode: AdjustMode) -> Vec<Bar> {
+    let security_id = match bars.first() {
+        Some(bar) => bar.security_id.as_str(),
+        None => return vec![],
+    };
+    let factors = ACTIONS.read().factors(security_id, bars);
+    apply(bars, &factors, mode)
+}
+
+//从单元存储查询[begin,end)内的复权k线，复权因子按日k线计算，没有日k线时按本周期全部k线计算
+pub fn query_bars<S: AsRef<str>>(
+    unit: S,
+    security_id: &str,
+    period: Period,
+    begin: i64,
+    end: i64,
+    mode: AdjustMode,
+) -> Result<Vec<Bar>> {
+    let store = db::open(unit)?;
+    let bars = store
+        .query_bar_with_time(security_id, period, begin, end)?
+        .unwrap_or_default();
+    if mode == AdjustMode::None || ACTIONS.read().get(security_id).is_empty() {
+        return Ok(bars);
+    }
+    let history = match store.query_bar(security_id, Period::Day(1))? {
+        Some(daily) if !daily.is_empty() => daily,
+        _ => store.query_bar(security_id, period)?.unwrap_or_default(),
+    };
+    let factors = ACTIONS.read().factors(security_id, &history);
+    Ok(apply(&bars, &factors, mode))
+}
+
+pub fn load_actions<P: AsRef<Path>>(path: P) -> Result<usize> {
+    ACTIONS.write().load_file(path)
+}
+
+pub fn add_action(action: CorporateAction) {
+    ACTIONS.write().add(action)
+}
+
+pub fn actions(security_id: &str) -> Vec<CorporateAction> {
+    ACTIONS.read().get(security_id).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Exchange;
+    use chrono::TimeZone;
+
+    fn bar(day: &str, close: f64) -> Bar {
+        let date = NaiveDate::parse_from_str(day, "%Y-%m-%d").unwrap();
+        Bar {
+            security_id: "600000".into(),
+            exchange: Exchange::SSE,
+            period: Period::Day(1),
+            time: calendar::timezone(Exchange::SSE)
+                .from_local_datetime(&date.and_hms(0, 0, 0))
+                .unwrap()
+                .timestamp(),
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 100.0,
+            turnover: None,
+        }
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut table = ActionTable::default();
+        //10派5元送10股
+        table.add(CorporateAction {
+            security_id: "600000".into(),
+            ex_date: NaiveDate::from_ymd(2022, 3, 15),
+            cash: 0.5,
+            bonus: 1.0,
+            rights: 0.0,
+            rights_price: 0.0,
+        });
+        let bars = vec![bar("2022-03-14", 20.0), bar("2022-03-15", 10.0)];
+        let factors = table.factors("600000", &bars);
+        assert_eq!(factors.len(), 1);
+        assert!((factors[0].1 - 20.0 / 9.75).abs() < 1e-9);
+
+        let forward = apply(&bars, &factors, AdjustMode::Forward);
+        assert!((forward[0].close - 9.75).abs() < 1e-9);
+        assert_eq!(forward[1].close, 10.0);
+        assert_eq!(forward[0].volume, 100.0);
+
+        let backward = apply(&bars, &factors, AdjustMode::Backward);
+        assert_eq!(backward[0].close, 20.0);
+        assert!((backward[1].close - 10.0 * 20.0 / 9.75).abs() < 1e-9);
+
+        assert_eq!(apply(&bars, &factors, AdjustMode::None)[0].close, 20.0);
+    }
+}
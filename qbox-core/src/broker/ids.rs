@@ -0,0 +1,268 @@
+use crate::broker::Exchange;
+use crate::db::{self, Store};
+use ahash::RandomState;
+use anyhow::Result;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//2021-01-01 00:00:00 UTC
+const EPOCH: u64 = 1_609_459_200_000;
+const NODE_BITS: u64 = 10;
+const SEQ_BITS: u64 = 12;
+const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+const SEQ_MASK: u64 = (1 << SEQ_BITS) - 1;
+//时间租约，避免每次生成都写库
+const LEASE_MS: u64 = 10_000;
+const IDGEN_PREFIX: &str = "idgen/";
+const IDMAP_PREFIX: &str = "idmap/";
+
+#[doc = "订单号生成器，时间戳(41位)+节点(10位)+序号(12位)"]
+pub struct IdGen {
+    node: u64,
+    key: String,
+    store: Arc<dyn Store>,
+    state: Mutex<IdState>,
+}
+
+struct IdState {
+    last: u64,
+    seq: u64,
+    lease: u64,
+}
+
+impl IdGen {
+    //已分配的订单号均小于持久化的租约时间，重启后从租约时间继续，保证单调递增
+    pub fn open<S: AsRef<str>>(unit: S, node: u16) -> Result<Self> {
+        let node = node as u64;
+        if node > MAX_NODE {
+            return Err(anyhow::anyhow!(
+                "node {} out of range 0..={}",
+                node,
+                MAX_NODE
+            ));
+        }
+        let store = db::open(unit)?;
+        let key = format!("{}{}", IDGEN_PREFIX, node);
+        let lease = match store.get(&key)? {
+            Some(val) => val.parse::<u64>()?,
+            None => 0,
+        };
+        Ok(Self {
+            node,
+            key,
+            store,
+            state: Mutex::new(IdState {
+                last: lease,
+                seq: 0,
+                lease,
+            }),
+        })
+    }
+
+    pub fn next(&self) -> Result<u64> {
+        let mut state = self.state.lock();
+        let mut now = current_millis().max(state.last);
+        if now == state.last {
+            state.seq = (state.seq + 1) & SEQ_MASK;
+            if state.seq == 0 {
+                //本毫秒序号用尽，借用下一毫秒
+                now += 1;
+            }
+        } else {
+            state.seq = 0;
+        }
+        state.last = now;
+        if now >= state.lease {
+            let lease = now + LEASE_MS;
+            self.store.set(&self.key, &lease.to_string())?;
+            state.lease = lease;
+        }
+        Ok(((now - EPOCH) << (NODE_BITS + SEQ_BITS)) | (self.node << SEQ_BITS) | state.seq)
+    }
+
+    //订单号中的毫秒时间戳
+    pub fn timestamp(id: u64) -> i64 {
+        ((id >> (NODE_BITS + SEQ_BITS)) + EPOCH) as i64
+    }
+}
+
+fn current_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(EPOCH)
+        .max(EPOCH)
+}
+
+#[doc = "柜台/交易所订单标识"]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub enum VenueId {
+    //CTP报单引用
+    Ctp {
+        front_id: i32,
+        session_id: i32,
+        order_ref: String,
+    },
+    //交易所报单编号
+    OrderSys {
+        exchange: Exchange,
+        sys_id: String,
+    },
+    //客户端订单号
+    Client(String),
+}
+
+#[doc = "本地订单号与柜台订单标识的双向映射"]
+pub struct IdMap {
+    store: Arc<dyn Store>,
+    locals: DashMap<VenueId, u64, RandomState>,
+    venues: DashMap<u64, Vec<VenueId>, RandomState>,
+}
+
+impl IdMap {
+    pub fn open<S: AsRef<str>>(unit: S) -> Result<Self> {
+        let store = db::open(unit)?;
+        let map = Self {
+            store,
+            locals: DashMap::with_hasher(RandomState::new()),
+            venues: DashMap::with_hasher(RandomState::new()),
+        };
+        if let Some(list) = map.store.get_prefix(IDMAP_PREFIX)? {
+            for (key, val) in list {
+                match (
+                    ron::from_str::<VenueId>(&key[IDMAP_PREFIX.len()..]),
+                    val.parse::<u64>(),
+                ) {
+                    (Ok(venue), Ok(order_id)) => map.cache(order_id, venue),
+                    _ => log::warn!("invalid id mapping {} {}", key, val),
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    fn cache(&self, order_id: u64, venue: VenueId) {
+        self.locals.insert(venue.clone(), order_id);
+        let mut venues = self.venues.entry(order_id).or_insert_with(Vec::new);
+        if !venues.contains(&venue) {
+            venues.push(venue);
+        }
+    }
+
+    //绑定本地订单号与柜台订单标识，一个订单可以有多个柜台标识
+    pub fn bind(&self, order_id: u64, venue: VenueId) -> Result<()> {
+        if let Some(exist) = self.locals.get(&venue) {
+            if *exist.value() != order_id {
+                return Err(anyhow::anyhow!(
+                    "{:?} already bound to order {}",
+                    venue,
+                    exist.value()
+                ));
+            }
+        }
+        let key = format!("{}{}", IDMAP_PREFIX, ron::to_string(&venue)?);
+        self.store.set(&key, &order_id.to_string())?;
+        self.cache(order_id, venue);
+        Ok(())
+    }
+
+    pub fn unbind(&self, order_id: u64) -> Result<()> {
+        if let Some((_, venues)) = self.venues.remove(&order_id) {
+            for venue in venues {
+                let key = format!("{}{}", IDMAP_PREFIX, ron::to_string(&venue)?);
+                self.store.remove(&key)?;
+                self.locals.remove(&venue);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn order_id(&self, venue: &VenueId) -> Option<u64> {
+        self.locals.get(venue).map(|id| *id.value())
+    }
+
+    pub fn venue_ids(&self, order_id: u64) -> Option<Vec<VenueId>> {
+        self.venues.get(&order_id).map(|v| v.value().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_idgen() {
+        let unit = unit("test_idgen");
+        let gen = IdGen::open(&unit, 7).unwrap();
+        let mut last = 0;
+        for _ in 0..10000 {
+            let id = gen.next().unwrap();
+            assert!(id > last);
+            assert_eq!((id >> SEQ_BITS) & MAX_NODE, 7);
+            last = id;
+        }
+        assert!(IdGen::timestamp(last) as u64 >= EPOCH);
+        assert!(IdGen::open(&unit, 1024).is_err());
+        drop(gen);
+
+        //重启后从租约时间继续，新订单号大于重启前的订单号
+        let gen = IdGen::open(&unit, 7).unwrap();
+        let id = gen.next().unwrap();
+        assert!(id > last);
+        assert!(IdGen::timestamp(id) > IdGen::timestamp(last));
+        drop(gen);
+        cleanup(&unit);
+    }
+
+    #[test]
+    fn test_idmap() {
+        let unit = unit("test_idmap");
+        let ctp = VenueId::Ctp {
+            front_id: 1,
+            session_id: -12345,
+            order_ref: "000000000001".into(),
+        };
+        let sys = VenueId::OrderSys {
+            exchange: Exchange::SHFE,
+            sys_id: "      123456".into(),
+        };
+        let map = IdMap::open(&unit).unwrap();
+        map.bind(100, ctp.clone()).unwrap();
+        map.bind(100, sys.clone()).unwrap();
+        //重复绑定同一订单无副作用
+        map.bind(100, sys.clone()).unwrap();
+        //同一柜台标识不能绑定到其它订单
+        assert!(map.bind(200, ctp.clone()).is_err());
+        map.bind(200, VenueId::Client("c-200".into())).unwrap();
+        assert_eq!(map.order_id(&ctp), Some(100));
+        assert_eq!(map.order_id(&sys), Some(100));
+        assert_eq!(map.venue_ids(100), Some(vec![ctp.clone(), sys.clone()]));
+        drop(map);
+
+        //重新打开后映射仍然有效
+        let map = IdMap::open(&unit).unwrap();
+        assert_eq!(map.order_id(&ctp), Some(100));
+        assert_eq!(map.order_id(&sys), Some(100));
+        assert_eq!(map.venue_ids(100), Some(vec![ctp.clone(), sys.clone()]));
+        assert_eq!(map.order_id(&VenueId::Client("c-200".into())), Some(200));
+
+        map.unbind(100).unwrap();
+        assert_eq!(map.order_id(&ctp), None);
+        assert_eq!(map.order_id(&sys), None);
+        assert_eq!(map.venue_ids(100), None);
+        //解绑后可以重新绑定
+        map.bind(300, ctp.clone()).unwrap();
+        drop(map);
+
+        let map = IdMap::open(&unit).unwrap();
+        assert_eq!(map.order_id(&sys), None);
+        assert_eq!(map.order_id(&ctp), Some(300));
+        assert_eq!(map.venue_ids(200).map(|v| v.len()), Some(1));
+        drop(map);
+        cleanup(&unit);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod ids;
 pub mod quoter;
 pub mod trader;
 pub mod types;
@@ -44,7 +44,7 @@ impl<S: AsRef<str>> From<S> for Exchange {
             "SZE" => Exchange::SZE,
             "SHFE" => Exchange::SHFE,
             "DCE" => Exchange::DCE,
-            "DZCE" => Exchange::DZCE,
+            "DZCE" | "CZCE" => Exchange::DZCE,
             "CFFEX" => Exchange::CFFEX,
             "INE" => Exchange::INE,
             "OKEX" => Exchange::OKEX,
@@ -288,6 +288,7 @@ pub struct Position {
     pub realized_pnl: f64,   //已实现盈亏
     pub unrealized_pnl: f64, //未实现盈亏
     pub position_pnl: f64, // 持仓浮动盈亏(数据货币单位：BTC/LTC,传统期货单位:RMB,股票不支持此字段,注:OKEX合约全仓情况下指实现盈余,并非持仓盈亏,逐仓下指持仓盈亏)
+    pub time: i64,         //更新时间
                            // //现货
                            // Spot {
                            //     exchange: Exchange,
@@ -600,6 +601,28 @@ pub enum Order {
 }
 
 impl Order {
+    pub fn id(&self) -> u64 {
+        match self {
+            &Order::Limit { id, .. } => id,
+            &Order::Market { id, .. } => id,
+            &Order::TakeStop { id, .. } => id,
+            &Order::Tracking { id, .. } => id,
+            &Order::Iceberg { id, .. } => id,
+            &Order::TimeWeights { id, .. } => id,
+        }
+    }
+    //替换为本地生成的订单号
+    pub fn with_id(mut self, id: u64) -> Self {
+        match &mut self {
+            Order::Limit { id: v, .. }
+            | Order::Market { id: v, .. }
+            | Order::TakeStop { id: v, .. }
+            | Order::Tracking { id: v, .. }
+            | Order::Iceberg { id: v, .. }
+            | Order::TimeWeights { id: v, .. } => *v = id,
+        }
+        self
+    }
     pub fn security_id(&self) -> &str {
         match self {
             Order::Limit { security_id, .. } => security_id.as_ref(),
@@ -610,6 +633,26 @@ impl Order {
             Order::TimeWeights { security_id, .. } => security_id.as_ref(),
         }
     }
+    pub fn time(&self) -> i64 {
+        match self {
+            &Order::Limit { time, .. } => time,
+            &Order::Market { time, .. } => time,
+            &Order::TakeStop { time, .. } => time,
+            &Order::Tracking { time, .. } => time,
+            &Order::Iceberg { time, .. } => time,
+            &Order::TimeWeights { time, .. } => time,
+        }
+    }
+    pub fn state(&self) -> &OrderState {
+        match self {
+            Order::Limit { state, .. } => state,
+            Order::Market { state, .. } => state,
+            Order::TakeStop { state, .. } => state,
+            Order::Tracking { state, .. } => state,
+            Order::Iceberg { state, .. } => state,
+            Order::TimeWeights { state, .. } => state,
+        }
+    }
     pub fn exchange(&self) -> Exchange {
         match self {
             &Order::Limit { exchange, .. } => exchange,
@@ -638,6 +681,21 @@ pub enum State {
 #[doc = "行情深度，价、量、委托笔数、委托额"]
 pub type Depth = (f64, f64, f64, f64); //[价,量,委托数,委托额]
 
+#[doc = "逐笔委托类型"]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, Eq, PartialEq)]
+pub enum OfferKind {
+    Limit,   //限价
+    Market,  //市价
+    BestOwn, //本方最优
+    Cancel,  //撤单，id为被撤委托的编号，quantity为撤单数量
+}
+
+impl Default for OfferKind {
+    fn default() -> Self {
+        OfferKind::Limit
+    }
+}
+
 #[doc = "逐笔委托"]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TickToOffer {
@@ -649,6 +707,8 @@ pub struct TickToOffer {
     pub quantity: f64,
     pub bids: Option<Vec<Depth>>,
     pub asks: Option<Vec<Depth>>,
+    pub id: String,      //委托编号
+    pub kind: OfferKind, //委托类型
 }
 
 #[doc = "逐笔成交"]
@@ -788,6 +848,7 @@ impl Level1 {
         Bar {
             security_id: self.security_id.clone(),
             exchange: self.exchange,
+            period: Period::Timeline,
             time: self.time,
             open: self.open,
             high: self.high,
@@ -807,6 +868,7 @@ impl Level1 {
 pub struct Bar {
     pub security_id: String, //证券代码
     pub exchange: Exchange,
+    pub period: Period, //周期
     pub time: i64,
     pub open: f64,             //开盘价
     pub high: f64,             //最高价
@@ -866,8 +928,41 @@ pub struct Level2 {
 pub struct TopOfOrderBook {
     pub security_id: String, //证券代码
     pub exchange: Exchange,
+    pub time: i64, //时间
+    pub bids: Vec<Depth>,
+    pub asks: Vec<Depth>,
+}
+
+#[doc = "增量深度，数量为0表示删除该价位"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DepthUpdate {
+    pub security_id: String, //证券代码
+    pub exchange: Exchange,
+    pub time: i64,             //时间
+    pub snapshot: bool,        //全量快照
+    pub first_seq: u64,        //本次更新的首个序号
+    pub seq: u64,              //本次更新的最后序号
+    pub prev_seq: Option<u64>, //上一次更新的序号，交易所提供时按此校验连续性
+    pub checksum: Option<i64>, //校验和
     pub bids: Vec<Depth>,
     pub asks: Vec<Depth>,
+    //交易所推送的原始(价,量)文本，与bids/asks逐档对应，用于计算校验和
+    pub raw_bids: Vec<(String, String)>,
+    pub raw_asks: Vec<(String, String)>,
+}
+
+#[doc = "微观结构指标"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Analytics {
+    pub security_id: String, //证券代码
+    pub exchange: Exchange,
+    pub time: i64,            //时间
+    pub vwap: f64,            //当日成交量加权均价
+    pub spread: f64,          //买卖价差
+    pub mid: f64,             //中间价
+    pub micro_price: f64,     //按对手方挂单量加权的中间价
+    pub ofi: f64,             //窗口内订单流不平衡
+    pub trade_imbalance: f64, //窗口内主动买卖量差占比，[-1,1]
 }
 
 pub type Item = String;
@@ -923,6 +1018,7 @@ impl Parameter {
 }
 
 #[doc = "周期"]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, Eq, PartialEq, Hash)]
 pub enum Period {
     Timeline,
     Second(u8),
@@ -932,3 +1028,61 @@ pub enum Period {
     Month(u8),
     Year(u8),
 }
+
+impl Default for Period {
+    fn default() -> Self {
+        Period::Timeline
+    }
+}
+
+impl Period {
+    //周期秒数，月、年周期长度不固定返回None
+    pub fn seconds(&self) -> Option<i64> {
+        match *self {
+            Period::Timeline => None,
+            Period::Second(n) => Some(n as i64),
+            Period::Minute(n) => Some(n as i64 * 60),
+            Period::Hour(n) => Some(n as i64 * 3600),
+            Period::Day(n) => Some(n as i64 * 86400),
+            Period::Month(_) | Period::Year(_) => None,
+        }
+    }
+}
+
+//1s/5m/1h/1d/1M/1y，分时为timeline，无法识别时为分时
+impl<S: AsRef<str>> From<S> for Period {
+    fn from(s: S) -> Self {
+        let s = s.as_ref().trim();
+        if s.len() < 2 || !s.is_char_boundary(s.len() - 1) {
+            return Period::Timeline;
+        }
+        let (n, unit) = s.split_at(s.len() - 1);
+        let n = match n.parse::<u8>() {
+            Ok(n) if n > 0 => n,
+            _ => return Period::Timeline,
+        };
+        match unit {
+            "s" => Period::Second(n),
+            "m" => Period::Minute(n),
+            "h" => Period::Hour(n),
+            "d" => Period::Day(n),
+            "M" => Period::Month(n),
+            "y" => Period::Year(n),
+            _ => Period::Timeline,
+        }
+    }
+}
+
+impl Into<String> for Period {
+    fn into(self) -> String {
+        match self {
+            Period::Timeline => "timeline".into(),
+            Period::Second(n) => format!("{}s", n),
+            Period::Minute(n) => format!("{}m", n),
+            Period::Hour(n) => format!("{}h", n),
+            Period::Day(n) => format!("{}d", n),
+            Period::Month(n) => format!("{}M", n),
+            Period::Year(n) => format!("{}y", n),
+        }
+    }
+}
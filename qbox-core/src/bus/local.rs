@@ -3,10 +3,12 @@ use ahash::RandomState;
 use anyhow::Result;
 use dashmap::DashMap;
 use rayon::prelude::*;
-use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
 pub struct LocalBus<T> {
     subscriber: DashMap<String, Vec<(Token, Box<dyn Fn(&str, T) + Send + Sync>)>, RandomState>,
     call_fn: DashMap<String, Box<dyn Fn(&str, T) -> Result<T> + Send + Sync>, RandomState>,
+    //订阅序号，同一闭包多次订阅也各自独立退订
+    next_id: AtomicU64,
 }
 
 impl<T> LocalBus<T> {
@@ -16,6 +18,7 @@ impl<T> LocalBus<T> {
         Self {
             subscriber,
             call_fn,
+            next_id: AtomicU64::new(1),
         }
     }
 }
@@ -40,10 +43,9 @@ impl<T: Send + Sync + Clone> EventBus for LocalBus<T> {
         f: impl Fn(&str, T) + Send + Sync + 'static,
     ) -> Result<Token> {
         let topic = topic.as_ref();
-        let tid = f.type_id();
         let token = Token {
             topic: topic.into(),
-            id: format!("{:?}", tid),
+            id: self.next_id.fetch_add(1, Ordering::SeqCst).to_string(),
         };
         if let Some(mut list) = self.subscriber.get_mut(topic) {
             list.push((token.clone(), Box::new(f)));
@@ -98,6 +100,8 @@ impl<T: Send + Sync + Clone> EventBus for LocalBus<T> {
 mod tests {
     use super::super::EventBus;
     use super::LocalBus;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
     use url::Url;
 
@@ -140,4 +144,32 @@ mod tests {
         .join()
         .ok();
     }
+
+    #[test]
+    fn test_unsubscribe() {
+        let bus = LocalBus::<Message>::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        //同一闭包类型订阅两次
+        let tokens: Vec<_> = (0..2)
+            .map(|_| {
+                let count = count.clone();
+                bus.subscribe("/ctp/abc", move |_, _| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap()
+            })
+            .collect();
+        assert_ne!(tokens[0].id, tokens[1].id);
+        bus.publish("/ctp/abc", Message::Quote("".into())).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        bus.unsubscribe(&tokens[1]);
+        bus.publish("/ctp/abc", Message::Quote("".into())).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        bus.unsubscribe(&tokens[1]);
+        bus.publish("/ctp/abc", Message::Quote("".into())).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        bus.unsubscribe(&tokens[0]);
+        bus.publish("/ctp/abc", Message::Quote("".into())).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
 }
@@ -0,0 +1,562 @@
+use crate::broker::Exchange;
+use anyhow::Result;
+use chrono::prelude::*;
+use chrono::Duration;
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+//内置休市日
+const HOLIDAYS: &str = include_str!("../data/holidays.txt");
+//休市日数据文件
+const HOLIDAYS_FILE: &str = "holidays.txt";
+//夜盘起始时间，此后的行情归属下一交易日
+const NIGHT_HOUR: u32 = 18;
+//向后查找交易日的最大天数
+const MAX_LOOKUP_DAYS: i64 = 60;
+
+lazy_static! {
+    //交易日历
+    static ref CALENDAR: RwLock<Calendar> = RwLock::new(Calendar::load());
+    //已提示休市日数据未覆盖的年份
+    static ref UNCOVERED: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+#[doc = "交易时段，end小于begin表示跨越午夜"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub begin: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Session {
+    pub fn new(begin: (u32, u32), end: (u32, u32)) -> Self {
+        Self {
+            begin: NaiveTime::from_hms(begin.0, begin.1, 0),
+            end: NaiveTime::from_hms(end.0, end.1, 0),
+        }
+    }
+
+    //夜盘
+    pub fn is_night(&self) -> bool {
+        self.begin.hour() >= NIGHT_HOUR
+    }
+
+    //跨越午夜
+    pub fn cross_midnight(&self) -> bool {
+        self.end <= self.begin
+    }
+}
+
+#[doc = "交易所作息"]
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub offset: FixedOffset,
+    //全天候交易（数字货币）
+    pub continuous: bool,
+    //休市日是否生效
+    pub holidays: bool,
+    //默认交易时段
+    pub sessions: Vec<Session>,
+    //品种交易时段，按品种代码（小写）覆盖默认时段
+    pub products: HashMap<String, Vec<Session>>,
+}
+
+impl Schedule {
+    pub fn new(offset: FixedOffset, sessions: Vec<Session>) -> Self {
+        Self {
+            offset,
+            continuous: false,
+            holidays: true,
+            sessions,
+            products: HashMap::new(),
+        }
+    }
+
+    pub fn continuous(offset: FixedOffset) -> Self {
+        Self {
+            offset,
+            continuous: true,
+            holidays: false,
+            sessions: vec![],
+            products: HashMap::new(),
+        }
+    }
+
+    pub fn with_products(mut self, products: &[&str], sessions: Vec<Session>) -> Self {
+        for product in products {
+            self.products
+                .insert(product.to_lowercase(), sessions.clone());
+        }
+        self
+    }
+
+    //品种交易时段，夜盘在前
+    pub fn sessions(&self, security_id: &str) -> &[Session] {
+        if let Some(sessions) = self.products.get(&product(security_id)) {
+            sessions
+        } else {
+            &self.sessions
+        }
+    }
+
+    pub fn has_night(&self) -> bool {
+        self.sessions
+            .iter()
+            .chain(self.products.values().flatten())
+            .any(|s| s.is_night())
+    }
+}
+
+#[doc = "交易日历"]
+#[derive(Debug, Clone)]
+pub struct Calendar {
+    holidays: BTreeSet<NaiveDate>,
+    //休市日数据覆盖到的年份，此后的日期只按周末判断
+    covered: Option<i32>,
+    schedules: HashMap<Exchange, Schedule>,
+    fallback: Schedule,
+}
+
+impl Calendar {
+    //内置作息及休市日，并合并数据目录下的休市日文件
+    pub fn load() -> Self {
+        let mut calendar = Self::new();
+        calendar.add_holidays(HOLIDAYS);
+        let path = Path::new(&crate::data_path()).join(HOLIDAYS_FILE);
+        if path.exists() {
+            if let Err(err) = calendar.load_holidays(&path) {
+                log::error!("load holidays {:?} error {:?}", path, err);
+            }
+        }
+        calendar
+    }
+
+    pub fn new() -> Self {
+        let cst = FixedOffset::east(8 * 3600);
+        let utc = FixedOffset::east(0);
+        let day = vec![
+            Session::new((9, 0), (10, 15)),
+            Session::new((10, 30), (11, 30)),
+            Session::new((13, 30), (15, 0)),
+        ];
+        let night = |end: (u32, u32)| {
+            let mut sessions = vec![Session::new((21, 0), end)];
+            sessions.extend(day.iter().copied());
+            sessions
+        };
+        let mut schedules = HashMap::new();
+        let stock = vec![
+            Session::new((9, 30), (11, 30)),
+            Session::new((13, 0), (15, 0)),
+        ];
+        schedules.insert(Exchange::SSE, Schedule::new(cst, stock.clone()));
+        schedules.insert(Exchange::SZE, Schedule::new(cst, stock.clone()));
+        schedules.insert(
+            Exchange::SHFE,
+            Schedule::new(cst, day.clone())
+                .with_products(&["cu", "al", "zn", "pb", "ni", "sn", "ss"], night((1, 0)))
+                .with_products(&["au", "ag"], night((2, 30)))
+                .with_products(&["rb", "hc", "fu", "bu", "ru", "sp"], night((23, 0))),
+        );
+        schedules.insert(
+            Exchange::INE,
+            Schedule::new(cst, day.clone())
+                .with_products(&["sc"], night((2, 30)))
+                .with_products(&["bc"], night((1, 0)))
+                .with_products(&["nr", "lu"], night((23, 0))),
+        );
+        schedules.insert(
+            Exchange::DCE,
+            Schedule::new(cst, day.clone()).with_products(
+                &[
+                    "a", "b", "m", "y", "p", "c", "cs", "i", "j", "jm", "l", "v", "pp", "eg", "eb",
+                    "rr", "pg",
+                ],
+                night((23, 0)),
+            ),
+        );
+        schedules.insert(
+            Exchange::DZCE,
+            Schedule::new(cst, day.clone()).with_products(
+                &[
+                    "sr", "cf", "cy", "ta", "ma", "fg", "rm", "oi", "zc", "sa", "pf",
+                ],
+                night((23, 0)),
+            ),
+        );
+        schedules.insert(
+            Exchange::CFFEX,
+            Schedule::new(cst, stock).with_products(
+                &["t", "tf", "ts"],
+                vec![
+                    Session::new((9, 15), (11, 30)),
+                    Session::new((13, 0), (15, 15)),
+                ],
+            ),
+        );
+        for ex in [Exchange::OKEX, Exchange::BINANCE, Exchange::HUOBI] {
+            schedules.insert(ex, Schedule::continuous(utc));
+        }
+        Self {
+            holidays: BTreeSet::new(),
+            covered: None,
+            schedules,
+            fallback: Schedule::continuous(Local::now().offset().fix()),
+        }
+    }
+
+    //休市日文件，每行一个YYYYMMDD，#开头为注释
+    pub fn load_holidays<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        self.add_holidays(&text);
+        Ok(())
+    }
+
+    fn add_holidays(&mut self, text: &str) {
+        text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .for_each(|line| match NaiveDate::parse_from_str(line, "%Y%m%d") {
+                Ok(date) => self.add_holiday(date),
+                Err(err) => log::warn!("invalid holiday {} {}", line, err),
+            });
+    }
+
+    pub fn add_holiday(&mut self, date: NaiveDate) {
+        self.holidays.insert(date);
+        self.covered = self.covered.max(Some(date.year()));
+    }
+
+    //休市日数据覆盖的最后一天
+    pub fn covered_until(&self) -> Option<NaiveDate> {
+        self.covered.map(|year| NaiveDate::from_ymd(year, 12, 31))
+    }
+
+    pub fn set_schedule(&mut self, exchange: Exchange, schedule: Schedule) {
+        self.schedules.insert(exchange, schedule);
+    }
+
+    pub fn schedule(&self, exchange: Exchange) -> &Schedule {
+        self.schedules.get(&exchange).unwrap_or(&self.fallback)
+    }
+
+    pub fn timezone(&self, exchange: Exchange) -> FixedOffset {
+        self.schedule(exchange).offset
+    }
+
+    pub fn is_trading_date(&self, exchange: Exchange, date: NaiveDate) -> bool {
+        let schedule = self.schedule(exchange);
+        if schedule.continuous {
+            return true;
+        }
+        if schedule.holidays && self.covered.map_or(true, |year| date.year() > year) {
+            warn_uncovered(date.year());
+        }
+        let weekend = matches!(date.weekday(), Weekday::Sat | Weekday::Sun);
+        !weekend && !(schedule.holidays && self.holidays.contains(&date))
+    }
+
+    //date之后（不含）的下一交易日
+    pub fn next_trading_date(&self, exchange: Exchange, date: NaiveDate) -> Option<NaiveDate> {
+        (1..=MAX_LOOKUP_DAYS)
+            .map(|n| date + Duration::days(n))
+            .find(|d| self.is_trading_date(exchange, *d))
+    }
+
+    //date之前（不含）的上一交易日
+    pub fn prev_trading_date(&self, exchange: Exchange, date: NaiveDate) -> Option<NaiveDate> {
+        (1..=MAX_LOOKUP_DAYS)
+            .map(|n| date - Duration::days(n))
+            .find(|d| self.is_trading_date(exchange, *d))
+    }
+
+    //时间戳（秒）所属交易日，夜盘归属下一交易日
+    pub fn trading_day(&self, exchange: Exchange, ts: i64) -> NaiveDate {
+        let schedule = self.schedule(exchange);
+        let local = schedule.offset.timestamp(ts, 0).naive_local();
+        let date = local.date();
+        if schedule.continuous {
+            return date;
+        }
+        let hour = local.time().hour();
+        let evening = if !schedule.has_night() {
+            None
+        } else if hour >= NIGHT_HOUR {
+            Some(date)
+        } else if hour < 6 {
+            Some(date.pred())
+        } else {
+            None
+        };
+        match evening {
+            Some(evening) => self.next_trading_date(exchange, evening),
+            None if self.is_trading_date(exchange, date) => Some(date),
+            None => self.next_trading_date(exchange, date),
+        }
+        .unwrap_or(date)
+    }
+
+    //交易日内的全部交易时段，返回[开始,结束)时间戳（秒）
+    pub fn sessions(
+        &self,
+        exchange: Exchange,
+        security_id: &str,
+        day: NaiveDate,
+    ) -> Vec<(i64, i64)> {
+        let schedule = self.schedule(exchange);
+        if schedule.continuous {
+            let begin = day.and_hms(0, 0, 0);
+            return vec![(
+                to_timestamp(schedule.offset, begin),
+                to_timestamp(schedule.offset, begin + Duration::days(1)),
+            )];
+        }
+        if !self.is_trading_date(exchange, day) {
+            return vec![];
+        }
+        //上一交易日与本交易日之间只隔周末时才有夜盘
+        let evening = self.prev_trading_date(exchange, day).filter(|prev| {
+            let mut d = prev.succ();
+            while d < day {
+                if self.holidays.contains(&d) {
+                    return false;
+                }
+                d = d.succ();
+            }
+            true
+        });
+        schedule
+            .sessions(security_id)
+            .iter()
+            .filter_map(|s| {
+                let date = if s.is_night() { evening? } else { day };
+                let begin = date.and_time(s.begin);
+                let end = if s.cross_midnight() {
+                    date.succ().and_time(s.end)
+                } else {
+                    date.and_time(s.end)
+                };
+                Some((
+                    to_timestamp(schedule.offset, begin),
+                    to_timestamp(schedule.offset, end),
+                ))
+            })
+            .collect()
+    }
+
+    pub fn is_trading_at(&self, exchange: Exchange, security_id: &str, ts: i64) -> bool {
+        let day = self.trading_day(exchange, ts);
+        self.sessions(exchange, security_id, day)
+            .iter()
+            .any(|(begin, end)| *begin <= ts && ts < *end)
+    }
+
+    //ts之后（含）最近一次开盘的时间戳（秒）
+    pub fn next_open(&self, exchange: Exchange, security_id: &str, ts: i64) -> Option<i64> {
+        let mut day = self.trading_day(exchange, ts);
+        for _ in 0..MAX_LOOKUP_DAYS {
+            if let Some((begin, _)) = self
+                .sessions(exchange, security_id, day)
+                .into_iter()
+                .find(|(begin, _)| *begin >= ts)
+            {
+                return Some(begin);
+            }
+            day = self.next_trading_date(exchange, day)?;
+        }
+        None
+    }
+
+    //将不带日期的行情时间还原为离now最近的时间戳（秒），处理夜盘跨越午夜
+    pub fn timestamp_near(&self, exchange: Exchange, time: NaiveTime, now: i64) -> i64 {
+        let offset = self.timezone(exchange);
+        let now = offset.timestamp(now, 0).naive_local();
+        let mut dt = now.date().and_time(time);
+        if dt - now > Duration::hours(12) {
+            dt = dt - Duration::days(1);
+        } else if now - dt > Duration::hours(12) {
+            dt = dt + Duration::days(1);
+        }
+        to_timestamp(offset, dt)
+    }
+}
+
+impl Default for Calendar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//品种代码，如rb2205 => rb
+pub fn product(security_id: &str) -> String {
+    security_id
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+//每个年份只提示一次
+fn warn_uncovered(year: i32) {
+    if UNCOVERED.lock().insert(year) {
+        log::warn!(
+            "holidays of {} not loaded, add them to {} under the data path",
+            year,
+            HOLIDAYS_FILE
+        );
+    }
+}
+
+fn to_timestamp(offset: FixedOffset, dt: NaiveDateTime) -> i64 {
+    dt.timestamp() - offset.local_minus_utc() as i64
+}
+
+//重新加载交易日历
+pub fn reload() {
+    *CALENDAR.write() = Calendar::load();
+}
+
+pub fn load_holidays<P: AsRef<Path>>(path: P) -> Result<()> {
+    CALENDAR.write().load_holidays(path)
+}
+
+pub fn set_schedule(exchange: Exchange, schedule: Schedule) {
+    CALENDAR.write().set_schedule(exchange, schedule)
+}
+
+pub fn covered_until() -> Option<NaiveDate> {
+    CALENDAR.read().covered_until()
+}
+
+pub fn timezone(exchange: Exchange) -> FixedOffset {
+    CALENDAR.read().timezone(exchange)
+}
+
+pub fn is_trading_date(exchange: Exchange, date: NaiveDate) -> bool {
+    CALENDAR.read().is_trading_date(exchange, date)
+}
+
+pub fn next_trading_date(exchange: Exchange, date: NaiveDate) -> Option<NaiveDate> {
+    CALENDAR.read().next_trading_date(exchange, date)
+}
+
+pub fn prev_trading_date(exchange: Exchange, date: NaiveDate) -> Option<NaiveDate> {
+    CALENDAR.read().prev_trading_date(exchange, date)
+}
+
+pub fn trading_day(exchange: Exchange, ts: i64) -> NaiveDate {
+    CALENDAR.read().trading_day(exchange, ts)
+}
+
+pub fn sessions(exchange: Exchange, security_id: &str, day: NaiveDate) -> Vec<(i64, i64)> {
+    CALENDAR.read().sessions(exchange, security_id, day)
+}
+
+pub fn is_trading(exchange: Exchange, security_id: &str) -> bool {
+    is_trading_at(exchange, security_id, Utc::now().timestamp())
+}
+
+pub fn is_trading_at(exchange: Exchange, security_id: &str, ts: i64) -> bool {
+    CALENDAR.read().is_trading_at(exchange, security_id, ts)
+}
+
+pub fn next_open(exchange: Exchange, security_id: &str, ts: i64) -> Option<i64> {
+    CALENDAR.read().next_open(exchange, security_id, ts)
+}
+
+pub fn timestamp_near(exchange: Exchange, time: NaiveTime, now: i64) -> i64 {
+    CALENDAR.read().timestamp_near(exchange, time, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> i64 {
+        DateTime::parse_from_str(&format!("{} +0800", s), "%Y-%m-%d %H:%M:%S %z")
+            .unwrap()
+            .timestamp()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_trading_day() {
+        let cal = Calendar::load();
+        //周五夜盘归属下周一
+        assert_eq!(
+            cal.trading_day(Exchange::SHFE, ts("2022-03-11 21:30:00")),
+            date("2022-03-14")
+        );
+        assert_eq!(
+            cal.trading_day(Exchange::SHFE, ts("2022-03-12 01:30:00")),
+            date("2022-03-14")
+        );
+        assert_eq!(
+            cal.trading_day(Exchange::DCE, ts("2022-03-14 10:00:00")),
+            date("2022-03-14")
+        );
+        //股票没有夜盘
+        assert_eq!(
+            cal.trading_day(Exchange::SSE, ts("2022-03-14 21:30:00")),
+            date("2022-03-14")
+        );
+    }
+
+    #[test]
+    fn test_sessions() {
+        let cal = Calendar::load();
+        assert!(cal.is_trading_at(Exchange::SHFE, "au2206", ts("2022-03-12 02:00:00")));
+        assert!(!cal.is_trading_at(Exchange::SHFE, "rb2205", ts("2022-03-12 00:30:00")));
+        assert!(!cal.is_trading_at(Exchange::SHFE, "rb2205", ts("2022-03-14 10:20:00")));
+        assert!(!cal.is_trading_at(Exchange::SSE, "600000", ts("2022-03-14 12:00:00")));
+        assert!(cal.is_trading_at(Exchange::BINANCE, "btcusdt", ts("2022-03-13 12:00:00")));
+        //节前最后一个交易日没有夜盘
+        assert!(!cal.is_trading_at(Exchange::DCE, "m2205", ts("2022-04-01 21:30:00")));
+        assert_eq!(
+            cal.next_open(Exchange::DCE, "m2205", ts("2022-04-01 15:30:00")),
+            Some(ts("2022-04-06 09:00:00"))
+        );
+        assert_eq!(
+            cal.next_open(Exchange::DCE, "m2205", ts("2022-03-14 15:30:00")),
+            Some(ts("2022-03-14 21:00:00"))
+        );
+    }
+
+    #[test]
+    fn test_holidays() {
+        let cal = Calendar::load();
+        assert_eq!(cal.covered_until(), Some(date("2026-12-31")));
+        assert!(!cal.is_trading_date(Exchange::SSE, date("2024-02-09")));
+        assert!(!cal.is_trading_date(Exchange::SHFE, date("2025-10-08")));
+        assert!(cal.is_trading_date(Exchange::SHFE, date("2025-10-09")));
+        //春节前最后一个交易日没有夜盘
+        assert_eq!(
+            cal.trading_day(Exchange::SHFE, ts("2026-02-13 21:30:00")),
+            date("2026-02-24")
+        );
+        assert!(!cal.is_trading_at(Exchange::SHFE, "rb2605", ts("2026-02-13 21:30:00")));
+        assert_eq!(
+            cal.next_open(Exchange::SHFE, "rb2605", ts("2026-02-13 15:30:00")),
+            Some(ts("2026-02-24 09:00:00"))
+        );
+        //未覆盖的年份只按周末判断
+        assert!(cal.is_trading_date(Exchange::SSE, date("2027-01-01")));
+        assert_eq!(Calendar::new().covered_until(), None);
+    }
+
+    #[test]
+    fn test_timestamp_near() {
+        let cal = Calendar::load();
+        let now = ts("2022-03-12 00:00:05");
+        let time = NaiveTime::from_hms(23, 59, 58);
+        assert_eq!(
+            cal.timestamp_near(Exchange::SHFE, time, now),
+            ts("2022-03-11 23:59:58")
+        );
+    }
+}
@@ -0,0 +1,887 @@
+use crate::broker::*;
+use crate::core::events::{Event, QuoteEvent, TradeEvent};
+use crate::core::instruments::ListingChange;
+use crate::core::settings::SettingChange;
+use crate::filter::quality::StaleAlert;
+use crate::recorder::BlockIndex;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+//二进制信封头：MAGIC + 版本号(u16 LE)
+const MAGIC: &[u8; 4] = b"QBOX";
+//文本信封头：//qbox:v<版本号>\n，对旧的ron解析器来说是注释
+const TEXT_MAGIC: &str = "//qbox:v";
+
+#[doc = "序列化格式"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Bincode,
+    Ron,
+}
+
+#[doc = "带版本的数据结构"]
+pub trait Versioned: Serialize + DeserializeOwned {
+    //当前版本，结构变化时递增并实现upgrade
+    const VERSION: u16;
+
+    //将旧版本数据升级为当前版本，版本0为未加信封的旧数据
+    //默认实现认为版本0与版本1结构相同
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        if version == 0 && Self::VERSION == 1 {
+            deserialize(format, body)
+        } else {
+            Err(anyhow!(
+                "{} can't upgrade from version {} to {}",
+                std::any::type_name::<Self>(),
+                version,
+                Self::VERSION
+            ))
+        }
+    }
+}
+
+macro_rules! versioned {
+    ($($ty:ty => $ver:expr),* $(,)?) => {
+        $(impl Versioned for $ty {
+            const VERSION: u16 = $ver;
+        })*
+    };
+}
+
+versioned! {
+    Order => 1,
+    Transaction => 1,
+    Instrument => 1,
+    Parameter => 1,
+    Level1 => 1,
+    Level2 => 1,
+    TickToTrade => 1,
+    Analytics => 1,
+    BlockIndex => 1,
+}
+
+//版本2：逐笔委托增加委托编号和类型，旧数据按限价处理
+impl Versioned for TickToOffer {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(deserialize::<TickToOfferV1>(format, body)?.into()),
+            _ => Err(anyhow!(
+                "TickToOffer can't upgrade from version {}",
+                version
+            )),
+        }
+    }
+}
+
+//版本2：持仓增加更新时间，旧数据为0
+impl Versioned for Position {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(deserialize::<PositionV1>(format, body)?.into()),
+            _ => Err(anyhow!("Position can't upgrade from version {}", version)),
+        }
+    }
+}
+
+impl Versioned for TradeEvent {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            //038加入证券上市变化时未升级版本号，版本1的数据可能是两种结构
+            0 | 1 => match deserialize::<TradeEventV1>(format, body) {
+                Ok(v) => Ok(v.into()),
+                Err(_) => Ok(deserialize::<TradeEventListingV1>(format, body)?.into()),
+            },
+            _ => Err(anyhow!("TradeEvent can't upgrade from version {}", version)),
+        }
+    }
+}
+
+//版本2：Bar增加周期字段，旧数据按分时处理
+impl Versioned for Bar {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(deserialize::<BarV1>(format, body)?.into()),
+            _ => Err(anyhow!("Bar can't upgrade from version {}", version)),
+        }
+    }
+}
+
+//版本4：增量深度增加交易所原始文本
+impl Versioned for QuoteEvent {
+    const VERSION: u16 = 4;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(deserialize::<QuoteEventV1>(format, body)?.into()),
+            2 => Ok(deserialize::<QuoteEventV2>(format, body)?.into()),
+            3 => Ok(deserialize::<QuoteEventV3>(format, body)?.into()),
+            _ => Err(anyhow!("QuoteEvent can't upgrade from version {}", version)),
+        }
+    }
+}
+
+impl Versioned for Event {
+    const VERSION: u16 = 5;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => match deserialize::<EventV1<TradeEventV1>>(format, body) {
+                Ok(v) => Ok(v.into()),
+                Err(_) => Ok(deserialize::<EventV1<TradeEventListingV1>>(format, body)?.into()),
+            },
+            2 => Ok(deserialize::<EventV2>(format, body)?.into()),
+            3 => Ok(deserialize::<EventV3>(format, body)?.into()),
+            4 => Ok(deserialize::<EventV4>(format, body)?.into()),
+            _ => Err(anyhow!("Event can't upgrade from version {}", version)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BarV1 {
+    security_id: String,
+    exchange: Exchange,
+    time: i64,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    volume: f64,
+    turnover: Option<f64>,
+}
+
+impl From<BarV1> for Bar {
+    fn from(v1: BarV1) -> Self {
+        Bar {
+            security_id: v1.security_id,
+            exchange: v1.exchange,
+            period: Period::Timeline,
+            time: v1.time,
+            open: v1.open,
+            high: v1.high,
+            low: v1.low,
+            close: v1.close,
+            volume: v1.volume,
+            turnover: v1.turnover,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TickToOfferV1 {
+    security_id: String,
+    exchange: Exchange,
+    time: i64,
+    side: Side,
+    price: f64,
+    quantity: f64,
+    bids: Option<Vec<Depth>>,
+    asks: Option<Vec<Depth>>,
+}
+
+impl From<TickToOfferV1> for TickToOffer {
+    fn from(v1: TickToOfferV1) -> Self {
+        TickToOffer {
+            security_id: v1.security_id,
+            exchange: v1.exchange,
+            time: v1.time,
+            side: v1.side,
+            price: v1.price,
+            quantity: v1.quantity,
+            bids: v1.bids,
+            asks: v1.asks,
+            id: Default::default(),
+            kind: OfferKind::Limit,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PositionV1 {
+    exchange: Exchange,
+    security_id: String,
+    side: Side,
+    offset: Side,
+    margin_level: u16,
+    quantity: i64,
+    frozen: f64,
+    last: f64,
+    average: f64,
+    settlement: f64,
+    cost: f64,
+    margin: f64,
+    realized_pnl: f64,
+    unrealized_pnl: f64,
+    position_pnl: f64,
+}
+
+impl From<PositionV1> for Position {
+    fn from(v1: PositionV1) -> Self {
+        Position {
+            exchange: v1.exchange,
+            security_id: v1.security_id,
+            side: v1.side,
+            offset: v1.offset,
+            margin_level: v1.margin_level,
+            quantity: v1.quantity,
+            frozen: v1.frozen,
+            last: v1.last,
+            average: v1.average,
+            settlement: v1.settlement,
+            cost: v1.cost,
+            margin: v1.margin,
+            realized_pnl: v1.realized_pnl,
+            unrealized_pnl: v1.unrealized_pnl,
+            position_pnl: v1.position_pnl,
+            time: 0,
+        }
+    }
+}
+
+//变体顺序必须与版本1一致
+#[derive(Serialize, Deserialize)]
+enum TradeEventV1 {
+    Offer(Order),
+    Cancel(Order),
+    QueryPosition(String),
+    QueryInstrument(Vec<String>),
+    OrderChanged(Order),
+    PositionChanged(PositionV1),
+    Instrument(Instrument),
+    Transaction(Transaction),
+}
+
+impl From<TradeEventV1> for TradeEvent {
+    fn from(v1: TradeEventV1) -> Self {
+        match v1 {
+            TradeEventV1::Offer(v) => TradeEvent::Offer(v),
+            TradeEventV1::Cancel(v) => TradeEvent::Cancel(v),
+            TradeEventV1::QueryPosition(v) => TradeEvent::QueryPosition(v),
+            TradeEventV1::QueryInstrument(v) => TradeEvent::QueryInstrument(v),
+            TradeEventV1::OrderChanged(v) => TradeEvent::OrderChanged(v),
+            TradeEventV1::PositionChanged(v) => TradeEvent::PositionChanged(v.into()),
+            TradeEventV1::Instrument(v) => TradeEvent::Instrument(v),
+            TradeEventV1::Transaction(v) => TradeEvent::Transaction(v),
+        }
+    }
+}
+
+//版本1后期：增加证券上市变化，持仓仍为版本1
+#[derive(Serialize, Deserialize)]
+enum TradeEventListingV1 {
+    Offer(Order),
+    Cancel(Order),
+    QueryPosition(String),
+    QueryInstrument(Vec<String>),
+    OrderChanged(Order),
+    PositionChanged(PositionV1),
+    Instrument(Instrument),
+    Transaction(Transaction),
+    ListingChanged(ListingChange),
+}
+
+impl From<TradeEventListingV1> for TradeEvent {
+    fn from(v1: TradeEventListingV1) -> Self {
+        match v1 {
+            TradeEventListingV1::Offer(v) => TradeEvent::Offer(v),
+            TradeEventListingV1::Cancel(v) => TradeEvent::Cancel(v),
+            TradeEventListingV1::QueryPosition(v) => TradeEvent::QueryPosition(v),
+            TradeEventListingV1::QueryInstrument(v) => TradeEvent::QueryInstrument(v),
+            TradeEventListingV1::OrderChanged(v) => TradeEvent::OrderChanged(v),
+            TradeEventListingV1::PositionChanged(v) => TradeEvent::PositionChanged(v.into()),
+            TradeEventListingV1::Instrument(v) => TradeEvent::Instrument(v),
+            TradeEventListingV1::Transaction(v) => TradeEvent::Transaction(v),
+            TradeEventListingV1::ListingChanged(v) => TradeEvent::ListingChanged(v),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DepthUpdateV1 {
+    security_id: String,
+    exchange: Exchange,
+    time: i64,
+    snapshot: bool,
+    first_seq: u64,
+    seq: u64,
+    prev_seq: Option<u64>,
+    checksum: Option<i64>,
+    bids: Vec<Depth>,
+    asks: Vec<Depth>,
+}
+
+impl From<DepthUpdateV1> for DepthUpdate {
+    fn from(v1: DepthUpdateV1) -> Self {
+        DepthUpdate {
+            security_id: v1.security_id,
+            exchange: v1.exchange,
+            time: v1.time,
+            snapshot: v1.snapshot,
+            first_seq: v1.first_seq,
+            seq: v1.seq,
+            prev_seq: v1.prev_seq,
+            checksum: v1.checksum,
+            bids: v1.bids,
+            asks: v1.asks,
+            raw_bids: vec![],
+            raw_asks: vec![],
+        }
+    }
+}
+
+//变体顺序必须与版本1一致
+#[derive(Serialize, Deserialize)]
+enum QuoteEventV1 {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    TickToOffer(TickToOfferV1),
+    TickToTrade(TickToTrade),
+    Level1(Level1),
+    Level2(Level2),
+    Bar(BarV1),
+}
+
+impl From<QuoteEventV1> for QuoteEvent {
+    fn from(v1: QuoteEventV1) -> Self {
+        match v1 {
+            QuoteEventV1::Subscribe(v) => QuoteEvent::Subscribe(v),
+            QuoteEventV1::Unsubscribe(v) => QuoteEvent::Unsubscribe(v),
+            QuoteEventV1::TickToOffer(v) => QuoteEvent::TickToOffer(v.into()),
+            QuoteEventV1::TickToTrade(v) => QuoteEvent::TickToTrade(v),
+            QuoteEventV1::Level1(v) => QuoteEvent::Level1(v),
+            QuoteEventV1::Level2(v) => QuoteEvent::Level2(v),
+            QuoteEventV1::Bar(v) => QuoteEvent::Bar(v.into()),
+        }
+    }
+}
+
+//变体顺序必须与版本2一致
+#[derive(Serialize, Deserialize)]
+enum QuoteEventV2 {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    TickToOffer(TickToOfferV1),
+    TickToTrade(TickToTrade),
+    Level1(Level1),
+    Level2(Level2),
+    Bar(Bar),
+    BarUpdated(Bar),
+    DepthUpdate(DepthUpdateV1),
+    TopOfOrderBook(TopOfOrderBook),
+    ResyncDepth(Vec<String>),
+}
+
+impl From<QuoteEventV2> for QuoteEvent {
+    fn from(v2: QuoteEventV2) -> Self {
+        match v2 {
+            QuoteEventV2::Subscribe(v) => QuoteEvent::Subscribe(v),
+            QuoteEventV2::Unsubscribe(v) => QuoteEvent::Unsubscribe(v),
+            QuoteEventV2::TickToOffer(v) => QuoteEvent::TickToOffer(v.into()),
+            QuoteEventV2::TickToTrade(v) => QuoteEvent::TickToTrade(v),
+            QuoteEventV2::Level1(v) => QuoteEvent::Level1(v),
+            QuoteEventV2::Level2(v) => QuoteEvent::Level2(v),
+            QuoteEventV2::Bar(v) => QuoteEvent::Bar(v),
+            QuoteEventV2::BarUpdated(v) => QuoteEvent::BarUpdated(v),
+            QuoteEventV2::DepthUpdate(v) => QuoteEvent::DepthUpdate(v.into()),
+            QuoteEventV2::TopOfOrderBook(v) => QuoteEvent::TopOfOrderBook(v),
+            QuoteEventV2::ResyncDepth(v) => QuoteEvent::ResyncDepth(v),
+        }
+    }
+}
+
+//变体顺序必须与版本3一致
+#[derive(Serialize, Deserialize)]
+enum QuoteEventV3 {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    TickToOffer(TickToOffer),
+    TickToTrade(TickToTrade),
+    Level1(Level1),
+    Level2(Level2),
+    Bar(Bar),
+    BarUpdated(Bar),
+    DepthUpdate(DepthUpdateV1),
+    TopOfOrderBook(TopOfOrderBook),
+    ResyncDepth(Vec<String>),
+    Stale(StaleAlert),
+    Analytics(Analytics),
+}
+
+impl From<QuoteEventV3> for QuoteEvent {
+    fn from(v3: QuoteEventV3) -> Self {
+        match v3 {
+            QuoteEventV3::Subscribe(v) => QuoteEvent::Subscribe(v),
+            QuoteEventV3::Unsubscribe(v) => QuoteEvent::Unsubscribe(v),
+            QuoteEventV3::TickToOffer(v) => QuoteEvent::TickToOffer(v),
+            QuoteEventV3::TickToTrade(v) => QuoteEvent::TickToTrade(v),
+            QuoteEventV3::Level1(v) => QuoteEvent::Level1(v),
+            QuoteEventV3::Level2(v) => QuoteEvent::Level2(v),
+            QuoteEventV3::Bar(v) => QuoteEvent::Bar(v),
+            QuoteEventV3::BarUpdated(v) => QuoteEvent::BarUpdated(v),
+            QuoteEventV3::DepthUpdate(v) => QuoteEvent::DepthUpdate(v.into()),
+            QuoteEventV3::TopOfOrderBook(v) => QuoteEvent::TopOfOrderBook(v),
+            QuoteEventV3::ResyncDepth(v) => QuoteEvent::ResyncDepth(v),
+            QuoteEventV3::Stale(v) => QuoteEvent::Stale(v),
+            QuoteEventV3::Analytics(v) => QuoteEvent::Analytics(v),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum EventV1<T> {
+    Startup,
+    Shutdown,
+    Log(String),
+    StartQuoter(String),
+    StopQuoter(String),
+    StartTrader(String),
+    StopTrader(String),
+    TradeEvent(T),
+    QuoteEvent(QuoteEventV1),
+}
+
+impl<T: Into<TradeEvent>> From<EventV1<T>> for Event {
+    fn from(v1: EventV1<T>) -> Self {
+        match v1 {
+            EventV1::Startup => Event::Startup,
+            EventV1::Shutdown => Event::Shutdown,
+            EventV1::Log(v) => Event::Log(v),
+            EventV1::StartQuoter(v) => Event::StartQuoter(v),
+            EventV1::StopQuoter(v) => Event::StopQuoter(v),
+            EventV1::StartTrader(v) => Event::StartTrader(v),
+            EventV1::StopTrader(v) => Event::StopTrader(v),
+            EventV1::TradeEvent(v) => Event::TradeEvent(v.into()),
+            EventV1::QuoteEvent(v) => Event::QuoteEvent(v.into()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum EventV2 {
+    Startup,
+    Shutdown,
+    Log(String),
+    StartQuoter(String),
+    StopQuoter(String),
+    StartTrader(String),
+    StopTrader(String),
+    TradeEvent(TradeEventListingV1),
+    QuoteEvent(QuoteEventV2),
+    SettingChanged(SettingChange),
+}
+
+impl From<EventV2> for Event {
+    fn from(v2: EventV2) -> Self {
+        match v2 {
+            EventV2::Startup => Event::Startup,
+            EventV2::Shutdown => Event::Shutdown,
+            EventV2::Log(v) => Event::Log(v),
+            EventV2::StartQuoter(v) => Event::StartQuoter(v),
+            EventV2::StopQuoter(v) => Event::StopQuoter(v),
+            EventV2::StartTrader(v) => Event::StartTrader(v),
+            EventV2::StopTrader(v) => Event::StopTrader(v),
+            EventV2::TradeEvent(v) => Event::TradeEvent(v.into()),
+            EventV2::QuoteEvent(v) => Event::QuoteEvent(v.into()),
+            EventV2::SettingChanged(v) => Event::SettingChanged(v),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum EventV3 {
+    Startup,
+    Shutdown,
+    Log(String),
+    StartQuoter(String),
+    StopQuoter(String),
+    StartTrader(String),
+    StopTrader(String),
+    TradeEvent(TradeEventListingV1),
+    QuoteEvent(QuoteEventV3),
+    SettingChanged(SettingChange),
+}
+
+impl From<EventV3> for Event {
+    fn from(v3: EventV3) -> Self {
+        match v3 {
+            EventV3::Startup => Event::Startup,
+            EventV3::Shutdown => Event::Shutdown,
+            EventV3::Log(v) => Event::Log(v),
+            EventV3::StartQuoter(v) => Event::StartQuoter(v),
+            EventV3::StopQuoter(v) => Event::StopQuoter(v),
+            EventV3::StartTrader(v) => Event::StartTrader(v),
+            EventV3::StopTrader(v) => Event::StopTrader(v),
+            EventV3::TradeEvent(v) => Event::TradeEvent(v.into()),
+            EventV3::QuoteEvent(v) => Event::QuoteEvent(v.into()),
+            EventV3::SettingChanged(v) => Event::SettingChanged(v),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum EventV4 {
+    Startup,
+    Shutdown,
+    Log(String),
+    StartQuoter(String),
+    StopQuoter(String),
+    StartTrader(String),
+    StopTrader(String),
+    TradeEvent(TradeEventListingV1),
+    QuoteEvent(QuoteEvent),
+    SettingChanged(SettingChange),
+}
+
+impl From<EventV4> for Event {
+    fn from(v4: EventV4) -> Self {
+        match v4 {
+            EventV4::Startup => Event::Startup,
+            EventV4::Shutdown => Event::Shutdown,
+            EventV4::Log(v) => Event::Log(v),
+            EventV4::StartQuoter(v) => Event::StartQuoter(v),
+            EventV4::StopQuoter(v) => Event::StopQuoter(v),
+            EventV4::StartTrader(v) => Event::StartTrader(v),
+            EventV4::StopTrader(v) => Event::StopTrader(v),
+            EventV4::TradeEvent(v) => Event::TradeEvent(v.into()),
+            EventV4::QuoteEvent(v) => Event::QuoteEvent(v),
+            EventV4::SettingChanged(v) => Event::SettingChanged(v),
+        }
+    }
+}
+
+//不带信封的原始序列化
+pub fn serialize<T: Serialize>(format: Format, val: &T) -> Result<Vec<u8>> {
+    match format {
+        Format::Bincode => Ok(bincode::serialize(val)?),
+        Format::Ron => Ok(ron::to_string(val)?.into_bytes()),
+    }
+}
+
+//不带信封的原始反序列化，供upgrade解析旧结构
+pub fn deserialize<T: DeserializeOwned>(format: Format, body: &[u8]) -> Result<T> {
+    match format {
+        Format::Bincode => Ok(bincode::deserialize(body)?),
+        Format::Ron => Ok(ron::de::from_bytes(body)?),
+    }
+}
+
+pub fn encode<T: Versioned>(format: Format, val: &T) -> Result<Vec<u8>> {
+    let body = serialize(format, val)?;
+    let mut buf = match format {
+        Format::Bincode => {
+            let mut buf = Vec::with_capacity(body.len() + MAGIC.len() + 2);
+            buf.extend_from_slice(MAGIC);
+            buf.extend_from_slice(&T::VERSION.to_le_bytes());
+            buf
+        }
+        Format::Ron => format!("{}{}\n", TEXT_MAGIC, T::VERSION).into_bytes(),
+    };
+    buf.extend_from_slice(&body);
+    Ok(buf)
+}
+
+pub fn decode<T: Versioned>(format: Format, bytes: &[u8]) -> Result<T> {
+    let (version, body) = split(format, bytes)?;
+    if version == T::VERSION {
+        deserialize(format, body)
+    } else if version > T::VERSION {
+        Err(anyhow!(
+            "{} version {} is newer than {}",
+            std::any::type_name::<T>(),
+            version,
+            T::VERSION
+        ))
+    } else {
+        T::upgrade(version, format, body)
+    }
+}
+
+//数据版本，未加信封的旧数据为0
+pub fn version(format: Format, bytes: &[u8]) -> Result<u16> {
+    split(format, bytes).map(|(version, _)| version)
+}
+
+fn split(format: Format, bytes: &[u8]) -> Result<(u16, &[u8])> {
+    match format {
+        Format::Bincode => {
+            if bytes.len() >= MAGIC.len() + 2 && bytes.starts_with(MAGIC) {
+                let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+                Ok((version, &bytes[MAGIC.len() + 2..]))
+            } else {
+                Ok((0, bytes))
+            }
+        }
+        Format::Ron => {
+            if !bytes.starts_with(TEXT_MAGIC.as_bytes()) {
+                return Ok((0, bytes));
+            }
+            let end = bytes
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| anyhow!("invalid envelope header"))?;
+            let version = std::str::from_utf8(&bytes[TEXT_MAGIC.len()..end])?.parse::<u16>()?;
+            Ok((version, &bytes[end + 1..]))
+        }
+    }
+}
+
+//文本格式，用于数据库中的文本字段
+pub fn to_string<T: Versioned>(val: &T) -> Result<String> {
+    Ok(String::from_utf8(encode(Format::Ron, val)?)?)
+}
+
+pub fn from_str<T: Versioned>(s: &str) -> Result<T> {
+    decode(Format::Ron, s.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order() -> Order {
+        Order::Limit {
+            id: 1,
+            security_id: "rb2205".into(),
+            exchange: Exchange::SHFE,
+            time: 1647241200,
+            side: Side::Buy,
+            offset: Side::Open,
+            price: 4800.0,
+            quantity: 2.0,
+            lever: 1,
+            pov: OrderLife::GTC,
+            remark: "".into(),
+            state: OrderState {
+                filled_quantity: 0.0,
+                filled_amount: 0.0,
+                avg_price: 0.0,
+                last_time: 0,
+                state: State::Created,
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [Format::Bincode, Format::Ron] {
+            let bytes = encode(format, &order()).unwrap();
+            assert_eq!(version(format, &bytes).unwrap(), 1);
+            assert_eq!(decode::<Order>(format, &bytes).unwrap(), order());
+
+            let ev = Event::TradeEvent(TradeEvent::Offer(order()));
+            let bytes = encode(format, &ev).unwrap();
+            assert_eq!(version(format, &bytes).unwrap(), Event::VERSION);
+            match decode::<Event>(format, &bytes).unwrap() {
+                Event::TradeEvent(TradeEvent::Offer(o)) => assert_eq!(o, order()),
+                _ => panic!("unexpected event"),
+            }
+        }
+        let items = Parameter::new().with("PriceTick", Value::F64(1.0));
+        assert_eq!(
+            from_str::<Parameter>(&to_string(&items).unwrap()).unwrap(),
+            items
+        );
+    }
+
+    #[test]
+    fn test_legacy() {
+        //未加信封的旧数据按版本0解析
+        let raw = bincode::serialize(&order()).unwrap();
+        assert_eq!(version(Format::Bincode, &raw).unwrap(), 0);
+        assert_eq!(decode::<Order>(Format::Bincode, &raw).unwrap(), order());
+        let raw = ron::to_string(&order()).unwrap();
+        assert_eq!(from_str::<Order>(&raw).unwrap(), order());
+        //带信封的ron仍可被旧的解析器读取
+        let text = to_string(&order()).unwrap();
+        assert_eq!(ron::from_str::<Order>(&text).unwrap(), order());
+    }
+
+    #[test]
+    fn test_bar_upgrade() {
+        let bar = QuoteEventV1::Bar(BarV1 {
+            security_id: "rb2205".into(),
+            exchange: Exchange::SHFE,
+            time: 1647241200,
+            open: 4800.0,
+            high: 4810.0,
+            low: 4790.0,
+            close: 4805.0,
+            volume: 10.0,
+            turnover: None,
+        });
+        for format in [Format::Bincode, Format::Ron] {
+            let raw = serialize(format, &bar).unwrap();
+            match decode::<QuoteEvent>(format, &raw).unwrap() {
+                QuoteEvent::Bar(bar) => {
+                    assert_eq!(bar.period, Period::Timeline);
+                    assert_eq!(bar.close, 4805.0);
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_offer_upgrade() {
+        let offer = QuoteEventV2::TickToOffer(TickToOfferV1 {
+            security_id: "000001".into(),
+            exchange: Exchange::SZE,
+            time: 1647241200,
+            side: Side::Buy,
+            price: 15.5,
+            quantity: 100.0,
+            bids: None,
+            asks: None,
+        });
+        for format in [Format::Bincode, Format::Ron] {
+            let mut raw = match format {
+                Format::Bincode => [&MAGIC[..], &2u16.to_le_bytes()].concat(),
+                Format::Ron => format!("{}2\n", TEXT_MAGIC).into_bytes(),
+            };
+            raw.extend(serialize(format, &offer).unwrap());
+            match decode::<QuoteEvent>(format, &raw).unwrap() {
+                QuoteEvent::TickToOffer(offer) => {
+                    assert_eq!(offer.kind, OfferKind::Limit);
+                    assert_eq!(offer.quantity, 100.0);
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_position_upgrade() {
+        let position = TradeEventV1::PositionChanged(PositionV1 {
+            exchange: Exchange::SHFE,
+            security_id: "rb2205".into(),
+            side: Side::Long,
+            offset: Side::Open,
+            margin_level: 1,
+            quantity: 2,
+            frozen: 0.0,
+            last: 4800.0,
+            average: 4800.0,
+            settlement: 4800.0,
+            cost: 9600.0,
+            margin: 0.0,
+            realized_pnl: 0.0,
+            unrealized_pnl: 0.0,
+            position_pnl: 0.0,
+        });
+        for format in [Format::Bincode, Format::Ron] {
+            let raw = serialize(format, &position).unwrap();
+            match decode::<TradeEvent>(format, &raw).unwrap() {
+                TradeEvent::PositionChanged(position) => {
+                    assert_eq!(position.quantity, 2);
+                    assert_eq!(position.time, 0);
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_listing_upgrade() {
+        //版本1后期的证券上市变化仍可解析
+        let listing = TradeEventListingV1::ListingChanged(ListingChange::MarginRatio {
+            security_id: "rb2205".into(),
+            prev: (0.1, 0.1),
+            current: (0.12, 0.12),
+        });
+        for format in [Format::Bincode, Format::Ron] {
+            let raw = serialize(format, &listing).unwrap();
+            assert!(deserialize::<TradeEventV1>(format, &raw).is_err());
+            match decode::<TradeEvent>(format, &raw).unwrap() {
+                TradeEvent::ListingChanged(ListingChange::MarginRatio { current, .. }) => {
+                    assert_eq!(current, (0.12, 0.12));
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_depth_upgrade() {
+        let depth = QuoteEventV3::DepthUpdate(DepthUpdateV1 {
+            security_id: "btc-usdt".into(),
+            exchange: Exchange::OKEX,
+            time: 1647241200,
+            snapshot: true,
+            first_seq: 1,
+            seq: 1,
+            prev_seq: None,
+            checksum: Some(-1),
+            bids: vec![(100.0, 1.0, f64::NAN, f64::NAN)],
+            asks: vec![],
+        });
+        for format in [Format::Bincode, Format::Ron] {
+            let mut raw = match format {
+                Format::Bincode => [&MAGIC[..], &3u16.to_le_bytes()].concat(),
+                Format::Ron => format!("{}3\n", TEXT_MAGIC).into_bytes(),
+            };
+            raw.extend(serialize(format, &depth).unwrap());
+            match decode::<QuoteEvent>(format, &raw).unwrap() {
+                QuoteEvent::DepthUpdate(update) => {
+                    assert_eq!(update.checksum, Some(-1));
+                    assert_eq!(update.bids[0].0, 100.0);
+                    assert!(update.raw_bids.is_empty());
+                }
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TickV1 {
+        price: f64,
+    }
+
+    impl Versioned for TickV1 {
+        const VERSION: u16 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Tick {
+        price: f64,
+        quantity: f64,
+    }
+
+    impl Versioned for Tick {
+        const VERSION: u16 = 2;
+        fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+            match version {
+                0 | 1 => {
+                    let v1: TickV1 = deserialize(format, body)?;
+                    Ok(Tick {
+                        price: v1.price,
+                        quantity: f64::NAN,
+                    })
+                }
+                _ => Err(anyhow!("unsupported version {}", version)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_upgrade() {
+        for format in [Format::Bincode, Format::Ron] {
+            let old = encode(format, &TickV1 { price: 1.5 }).unwrap();
+            let tick = decode::<Tick>(format, &old).unwrap();
+            assert_eq!(tick.price, 1.5);
+            assert!(tick.quantity.is_nan());
+
+            let new = encode(
+                format,
+                &Tick {
+                    price: 2.0,
+                    quantity: 3.0,
+                },
+            )
+            .unwrap();
+            assert!(decode::<TickV1>(format, &new).is_err());
+        }
+    }
+}
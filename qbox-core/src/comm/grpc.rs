@@ -1,3 +1,4 @@
+use crate::codec::{self, Format};
 use crate::core::events;
 use crossbeam::channel::{self, Receiver, TryRecvError, TrySendError};
 use futures::Stream;
@@ -24,12 +25,27 @@ lazy_static! {
 }
 pub struct QboxServer;
 
+//客户端在metadata中带上envelope时，返回带版本信封的数据；否则保持原始bincode，兼容旧客户端
+const ENVELOPE: &str = "envelope";
+
+fn enveloped<T>(request: &Request<T>) -> bool {
+    request.metadata().get(ENVELOPE).is_some()
+}
+
+fn to_bytes(envelope: bool, ev: &Event) -> anyhow::Result<Vec<u8>> {
+    if envelope {
+        codec::encode(Format::Bincode, ev)
+    } else {
+        codec::serialize(Format::Bincode, ev)
+    }
+}
+
 #[tonic::async_trait]
 impl Qbox for QboxServer {
     async fn call(&self, request: Request<QboxRequest>) -> Result<Response<QboxResponse>, Status> {
-        match ron::de::from_bytes::<Event>(&request.get_ref().body[..]) {
+        match codec::decode::<Event>(Format::Ron, &request.get_ref().body[..]) {
             Ok(ev) => match events::call(&request.get_ref().path, ev) {
-                Ok(ret) => match bincode::serialize(ret.as_ref()) {
+                Ok(ret) => match to_bytes(enveloped(&request), ret.as_ref()) {
                     Ok(b) => Ok(Response::new(QboxResponse {
                         path: request.get_ref().path.clone(),
                         body: b,
@@ -47,7 +63,7 @@ impl Qbox for QboxServer {
     }
 
     async fn send(&self, request: Request<QboxStreamEvent>) -> Result<Response<Void>, Status> {
-        match ron::de::from_bytes::<Event>(&request.get_ref().body[..]) {
+        match codec::decode::<Event>(Format::Ron, &request.get_ref().body[..]) {
             Ok(ev) => {
                 if let Err(err) = crate::core::events::publish(&request.get_ref().topic, ev) {
                     return Err(Status::invalid_argument(format!("send error: {}", err)));
@@ -66,6 +82,7 @@ impl Qbox for QboxServer {
     ) -> Result<Response<Self::SubscribeStream>, Status> {
         if let Some(client_id) = request.metadata().get("client_id") {
             let client_id = client_id.to_str().unwrap().to_owned();
+            let envelope = enveloped(&request);
             if !TOKENS.contains_key(&client_id) {
                 TOKENS.insert(client_id.clone(), DashMap::with_hasher(RandomState::new()));
             }
@@ -75,7 +92,7 @@ impl Qbox for QboxServer {
                     let tx = tx.clone();
                     let cid = client_id.clone();
                     match events::subscribe(topic, move |topic, ev| {
-                        match bincode::serialize(ev.as_ref()) {
+                        match to_bytes(envelope, ev.as_ref()) {
                             Ok(b) => {
                                 match tx.try_send(Ok(QboxStreamEvent {
                                     topic: topic.into(),
@@ -95,7 +112,7 @@ impl Qbox for QboxServer {
                                 }
                             }
                             Err(err) => {
-                                log::error!("bincode::serialize error {}", err);
+                                log::error!("to_bytes error {}", err);
                             }
                         }
                     }) {
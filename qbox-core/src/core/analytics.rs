@@ -0,0 +1,345 @@
+use crate::broker::{Analytics, Depth, Exchange, Level1, Level2, Side, TickToTrade};
+use crate::bus::Token;
+use crate::calendar;
+use crate::core::{self, *};
+use crate::db::{self, Store};
+use ahash::RandomState;
+use anyhow::Result;
+use chrono::NaiveDate;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+//最优价位，(价,量)
+type Best = (f64, f64);
+
+fn best(levels: &[Depth]) -> Option<Best> {
+    let level = levels.first()?;
+    if level.0.is_nan() || level.1.is_nan() || level.1 <= 0.0 {
+        None
+    } else {
+        Some((level.0, level.1))
+    }
+}
+
+//主动成交方向，买为true
+fn is_buy(side: Option<Side>) -> Option<bool> {
+    match side? {
+        Side::Buy | Side::Bid | Side::Long => Some(true),
+        Side::Sell | Side::Ask | Side::Short => Some(false),
+        _ => None,
+    }
+}
+
+//最优价变化带来的订单流不平衡：买方增加、卖方减少为正
+fn order_flow(prev: (Best, Best), cur: (Best, Best)) -> f64 {
+    let ((prev_bid, prev_bid_qty), (prev_ask, prev_ask_qty)) = prev;
+    let ((bid, bid_qty), (ask, ask_qty)) = cur;
+    let mut e = 0.0;
+    if bid >= prev_bid {
+        e += bid_qty;
+    }
+    if bid <= prev_bid {
+        e -= prev_bid_qty;
+    }
+    if ask <= prev_ask {
+        e -= ask_qty;
+    }
+    if ask >= prev_ask {
+        e += prev_ask_qty;
+    }
+    e
+}
+
+fn push<T>(window: &mut VecDeque<T>, size: usize, v: T) {
+    if window.len() >= size {
+        window.pop_front();
+    }
+    window.push_back(v);
+}
+
+#[derive(Default)]
+struct State {
+    exchange: Exchange,
+    time: i64,
+    day: Option<NaiveDate>,
+    bid: Option<Best>,
+    ask: Option<Best>,
+    //窗口内每次最优价变化的订单流不平衡
+    flows: VecDeque<f64>,
+    //窗口内每笔成交的(主动买量,主动卖量)
+    trades: VecDeque<(f64, f64)>,
+    //有逐笔成交时按成交计算均价
+    has_trades: bool,
+    volume: f64,
+    amount: f64,
+    //上一次快照的累计成交量
+    last_volume: f64,
+    //最后保存的时间
+    saved: i64,
+}
+
+impl State {
+    //交易日切换时重新累计均价
+    fn roll_day(&mut self, exchange: Exchange, time: i64) {
+        self.exchange = exchange;
+        self.time = self.time.max(time);
+        let day = calendar::trading_day(exchange, time);
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.volume = 0.0;
+            self.amount = 0.0;
+            self.last_volume = 0.0;
+        }
+    }
+
+    fn on_quote(&mut self, bid: Option<Best>, ask: Option<Best>, window: usize) {
+        if let (Some(prev_bid), Some(prev_ask), Some(bid), Some(ask)) =
+            (self.bid, self.ask, bid, ask)
+        {
+            let e = order_flow((prev_bid, prev_ask), (bid, ask));
+            push(&mut self.flows, window, e);
+        }
+        self.bid = bid;
+        self.ask = ask;
+    }
+
+    fn snapshot(&self, security_id: &str) -> Analytics {
+        let (spread, mid, micro_price) = match (self.bid, self.ask) {
+            (Some((bid, bid_qty)), Some((ask, ask_qty))) => (
+                ask - bid,
+                (bid + ask) / 2.0,
+                (bid * ask_qty + ask * bid_qty) / (bid_qty + ask_qty),
+            ),
+            _ => (f64::NAN, f64::NAN, f64::NAN),
+        };
+        let (buy, sell) = self
+            .trades
+            .iter()
+            .fold((0.0, 0.0), |(b, s), (buy, sell)| (b + buy, s + sell));
+        Analytics {
+            security_id: security_id.into(),
+            exchange: self.exchange,
+            time: self.time,
+            vwap: if self.volume > 0.0 {
+                self.amount / self.volume
+            } else {
+                f64::NAN
+            },
+            spread,
+            mid,
+            micro_price,
+            ofi: self.flows.iter().sum(),
+            trade_imbalance: if buy + sell > 0.0 {
+                (buy - sell) / (buy + sell)
+            } else {
+                f64::NAN
+            },
+        }
+    }
+}
+
+#[doc = "微观结构指标：成交均价、价差、中间价、微观价格、订单流及主动成交不平衡"]
+pub struct Microstructure {
+    //订单流及主动成交统计的窗口笔数
+    window: usize,
+    //保存快照的间隔秒数
+    interval: i64,
+    store: Option<Arc<dyn Store>>,
+    states: DashMap<String, State, RandomState>,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl Microstructure {
+    pub fn new(window: usize) -> Arc<Self> {
+        Arc::new(Self {
+            window: window.max(1),
+            interval: 0,
+            store: None,
+            states: DashMap::with_hasher(RandomState::new()),
+            tokens: Mutex::new(vec![]),
+        })
+    }
+
+    //按间隔将指标快照与k线一起保存到单元存储
+    pub fn with_store<S: AsRef<str>>(unit: S, window: usize, interval: i64) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            window: window.max(1),
+            interval: interval.max(1),
+            store: Some(db::open(unit)?),
+            states: DashMap::with_hasher(RandomState::new()),
+            tokens: Mutex::new(vec![]),
+        }))
+    }
+
+    pub fn get(&self, security_id: &str) -> Option<Analytics> {
+        self.states
+            .get(security_id)
+            .map(|state| state.snapshot(security_id))
+    }
+
+    pub fn on_level1(&self, level1: &Level1) -> Option<Analytics> {
+        self.update(&level1.security_id, |state, window| {
+            state.roll_day(level1.exchange, level1.time);
+            state.on_quote(best(&level1.bids), best(&level1.asks), window);
+            if !state.has_trades && !level1.volume.is_nan() && !level1.last.is_nan() {
+                let traded = level1.volume - state.last_volume;
+                if traded > 0.0 {
+                    state.volume += traded;
+                    state.amount += traded * level1.last;
+                }
+                state.last_volume = level1.volume;
+            }
+        })
+    }
+
+    pub fn on_level2(&self, level2: &Level2) -> Option<Analytics> {
+        self.update(&level2.security_id, |state, window| {
+            state.roll_day(level2.exchange, level2.time);
+            state.on_quote(best(&level2.bids), best(&level2.asks), window);
+        })
+    }
+
+    pub fn on_trade(&self, trade: &TickToTrade) -> Option<Analytics> {
+        if trade.price.is_nan() || trade.quantity.is_nan() || trade.quantity <= 0.0 {
+            return None;
+        }
+        self.update(&trade.security_id, |state, window| {
+            state.roll_day(trade.exchange, trade.time);
+            if !state.has_trades {
+                //切换为按逐笔成交计算
+                state.has_trades = true;
+                state.volume = 0.0;
+                state.amount = 0.0;
+            }
+            state.volume += trade.quantity;
+            state.amount += trade.quantity * trade.price;
+            match is_buy(trade.into_side) {
+                Some(true) => push(&mut state.trades, window, (trade.quantity, 0.0)),
+                Some(false) => push(&mut state.trades, window, (0.0, trade.quantity)),
+                None => {}
+            }
+        })
+    }
+
+    fn update(&self, security_id: &str, f: impl FnOnce(&mut State, usize)) -> Option<Analytics> {
+        let (analytics, save) = {
+            let mut state = self.states.entry(security_id.to_string()).or_default();
+            f(&mut state, self.window);
+            let analytics = state.snapshot(security_id);
+            let save = self.store.is_some() && analytics.time - state.saved >= self.interval;
+            if save {
+                state.saved = analytics.time;
+            }
+            (analytics, save)
+        };
+        if save {
+            if let Some(store) = &self.store {
+                if let Err(err) = store.insert_analytics(analytics.clone()) {
+                    log::error!("save analytics {} error {}", security_id, err);
+                }
+            }
+        }
+        Some(analytics)
+    }
+
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let analytics = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            let ret = match ev.as_ref() {
+                Event::QuoteEvent(QuoteEvent::Level1(level1)) => analytics.on_level1(level1),
+                Event::QuoteEvent(QuoteEvent::Level2(level2)) => analytics.on_level2(level2),
+                Event::QuoteEvent(QuoteEvent::TickToTrade(trade)) => analytics.on_trade(trade),
+                _ => None,
+            };
+            if let Some(ret) = ret {
+                if let Err(err) = core::quotes_event(QuoteEvent::Analytics(ret)) {
+                    log::error!("publish analytics error {}", err);
+                }
+            }
+        })?;
+        self.tokens.lock().push(token);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: i64 = 1647234000;
+
+    fn level1(time: i64, bid: Depth, ask: Depth, last: f64, volume: f64) -> Level1 {
+        let mut level1 = Level1::new();
+        level1.security_id = "rb2205".into();
+        level1.exchange = Exchange::SHFE;
+        level1.time = time;
+        level1.bids = vec![bid];
+        level1.asks = vec![ask];
+        level1.last = last;
+        level1.volume = volume;
+        level1
+    }
+
+    fn trade(price: f64, quantity: f64, side: Side) -> TickToTrade {
+        TickToTrade {
+            security_id: "rb2205".into(),
+            exchange: Exchange::SHFE,
+            id: "".into(),
+            time: TIME + 10,
+            price,
+            quantity,
+            order_side: None,
+            into_side: Some(side),
+            take_order_id: None,
+            make_order_id: None,
+        }
+    }
+
+    #[test]
+    fn test_microstructure() {
+        let analytics = Microstructure::new(10);
+        let ret = analytics
+            .on_level1(&level1(
+                TIME,
+                (4800.0, 10.0, 0.0, 0.0),
+                (4802.0, 30.0, 0.0, 0.0),
+                4801.0,
+                100.0,
+            ))
+            .unwrap();
+        assert_eq!(ret.spread, 2.0);
+        assert_eq!(ret.mid, 4801.0);
+        assert_eq!(ret.micro_price, 4800.5);
+        assert_eq!(ret.vwap, 4801.0);
+        assert_eq!(ret.ofi, 0.0);
+        assert!(ret.trade_imbalance.is_nan());
+
+        //买价上移，卖量减少
+        let ret = analytics
+            .on_level1(&level1(
+                TIME + 1,
+                (4801.0, 5.0, 0.0, 0.0),
+                (4802.0, 20.0, 0.0, 0.0),
+                4802.0,
+                200.0,
+            ))
+            .unwrap();
+        assert_eq!(ret.ofi, 5.0 - 20.0 + 30.0);
+        assert_eq!(ret.vwap, 4801.5);
+
+        //有逐笔成交后按成交计算均价
+        analytics.on_trade(&trade(4802.0, 3.0, Side::Buy));
+        let ret = analytics.on_trade(&trade(4801.0, 1.0, Side::Sell)).unwrap();
+        assert_eq!(ret.vwap, (4802.0 * 3.0 + 4801.0) / 4.0);
+        assert_eq!(ret.trade_imbalance, 0.5);
+        assert_eq!(analytics.get("rb2205").unwrap().time, TIME + 10);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::broker::{Bar, Exchange, Level1, Period, TickToTrade, Value};
+use crate::bus::Token;
+use crate::calendar;
+use crate::core::{self, *};
+use anyhow::Result;
+use chrono::{Datelike, FixedOffset, NaiveDate, TimeZone, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+//检查超时未收到行情的k线的间隔
+const CLOSE_INTERVAL: Duration = Duration::from_millis(500);
+//行情中交易日的字段，格式YYYYMMDD
+const TRADING_DATE: &str = "trading_date";
+
+#[doc = "k线对齐选项"]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct BarOptions {
+    //按交易时段对齐，k线不跨越休市，夜盘归属下一交易日；否则按本地时间整数倍对齐
+    pub sessions: bool,
+    //开盘前若干秒内（集合竞价）的行情并入时段首根k线
+    pub pre_open: i64,
+    //收盘后若干秒内的行情并入时段末根k线
+    pub post_close: i64,
+}
+
+impl Default for BarOptions {
+    fn default() -> Self {
+        Self {
+            sessions: true,
+            pre_open: 60,
+            post_close: 60,
+        }
+    }
+}
+
+impl BarOptions {
+    pub fn with_sessions(mut self, sessions: bool) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    pub fn with_pre_open(mut self, secs: i64) -> Self {
+        self.pre_open = secs.max(0);
+        self
+    }
+
+    pub fn with_post_close(mut self, secs: i64) -> Self {
+        self.post_close = secs.max(0);
+        self
+    }
+}
+
+//自月份序号（年*12+月-1）起n个月，返回[开始,结束)
+fn month_bounds(offset: FixedOffset, date: NaiveDate, n: u8) -> (i64, i64) {
+    let month_start = |months: i32| {
+        let date = NaiveDate::from_ymd(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1);
+        date.and_hms(0, 0, 0).timestamp() - offset.local_minus_utc() as i64
+    };
+    let n = n.max(1) as i32;
+    let months = date.year() * 12 + date.month0() as i32;
+    let months = months - months.rem_euclid(n);
+    (month_start(months), month_start(months + n))
+}
+
+//本地时间的整数倍对齐，返回k线[开始,结束)
+pub fn bounds(exchange: Exchange, period: Period, ts: i64) -> Option<(i64, i64)> {
+    let offset = calendar::timezone(exchange);
+    let local_offset = offset.local_minus_utc() as i64;
+    match period {
+        Period::Timeline => None,
+        Period::Second(_) | Period::Minute(_) | Period::Hour(_) | Period::Day(_) => {
+            let secs = period.seconds()?;
+            let local = ts + local_offset;
+            let begin = local - local.rem_euclid(secs) - local_offset;
+            Some((begin, begin + secs))
+        }
+        Period::Month(n) => {
+            let date = offset.timestamp(ts, 0).naive_local().date();
+            Some(month_bounds(offset, date, n))
+        }
+        Period::Year(n) => {
+            let date = offset.timestamp(ts, 0).naive_local().date();
+            Some(month_bounds(offset, date, n.saturating_mul(12)))
+        }
+    }
+}
+
+//按交易时段对齐，返回k线[开始,收盘)，时段末根k线的收盘时间包含post_close，时段外的行情返回None
+//day为行情所属交易日，未提供时按交易日历推算；日k线开始时间为交易日零点
+pub fn session_bounds(
+    exchange: Exchange,
+    security_id: &str,
+    period: Period,
+    ts: i64,
+    day: Option<NaiveDate>,
+    opts: &BarOptions,
+) -> Option<(i64, i64)> {
+    if period == Period::Timeline {
+        return None;
+    }
+    let day = day.unwrap_or_else(|| calendar::trading_day(exchange, ts));
+    let sessions = calendar::sessions(exchange, security_id, day);
+    let (begin, end) = sessions
+        .iter()
+        .copied()
+        .find(|(begin, end)| begin - opts.pre_open <= ts && ts < end + opts.post_close)?;
+    let offset = calendar::timezone(exchange);
+    match period {
+        Period::Second(_) | Period::Minute(_) | Period::Hour(_) => {
+            let secs = period.seconds()?;
+            let t = ts.max(begin).min(end - 1);
+            let bar_begin = begin + (t - begin) / secs * secs;
+            let bar_end = (bar_begin + secs).min(end);
+            if bar_end == end {
+                Some((bar_begin, end + opts.post_close))
+            } else {
+                Some((bar_begin, bar_end))
+            }
+        }
+        Period::Day(1) => {
+            let (_, last) = sessions.last()?;
+            let midnight = day.and_hms(0, 0, 0).timestamp() - offset.local_minus_utc() as i64;
+            Some((midnight, last + opts.post_close))
+        }
+        Period::Day(_) => bounds(exchange, period, ts),
+        Period::Month(n) => Some(month_bounds(offset, day, n)),
+        Period::Year(n) => Some(month_bounds(offset, day, n.saturating_mul(12))),
+        Period::Timeline => None,
+    }
+}
+
+//一笔成交，成交量、成交额为增量
+struct Tick {
+    time: i64,
+    //所属交易日
+    day: Option<NaiveDate>,
+    price: f64,
+    volume: f64,
+    turnover: f64,
+}
+
+#[derive(Default)]
+struct State {
+    //(证券代码,周期) => (当前k线,结束时间)
+    bars: HashMap<(String, Period), (Bar, i64)>,
+    //证券代码 => (累计成交量,累计成交额)
+    totals: HashMap<String, (f64, f64)>,
+    //有逐笔成交的证券，成交量只按逐笔成交计，基本行情只更新价格
+    has_trades: HashSet<String>,
+}
+
+#[doc = "多周期k线合成"]
+pub struct BarAggregator {
+    periods: Vec<Period>,
+    options: BarOptions,
+    state: Mutex<State>,
+    running: AtomicBool,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl BarAggregator {
+    pub fn new(periods: &[Period]) -> Arc<Self> {
+        Self::with_options(periods, BarOptions::default())
+    }
+
+    pub fn with_options(periods: &[Period], options: BarOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            periods: periods
+                .iter()
+                .filter(|p| **p != Period::Timeline)
+                .copied()
+                .collect(),
+            state: Mutex::new(State::default()),
+            running: AtomicBool::new(false),
+            tokens: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn periods(&self) -> &[Period] {
+        &self.periods
+    }
+
+    pub fn options(&self) -> &BarOptions {
+        &self.options
+    }
+
+    //基本行情的成交量、成交额为累计值，取与上一笔的差值，累计值变小时视为重新开始
+    pub fn on_level1(&self, level1: &Level1) -> Vec<QuoteEvent> {
+        if level1.last.is_nan() {
+            return vec![];
+        }
+        let mut state = self.state.lock();
+        let (volume, turnover) = if level1.volume.is_nan() {
+            let volume = if level1.last_volume.is_nan() {
+                0.0
+            } else {
+                level1.last_volume
+            };
+            (volume, volume * level1.last)
+        } else {
+            let turnover = if level1.turnover.is_nan() {
+                0.0
+            } else {
+                level1.turnover
+            };
+            match state
+                .totals
+                .insert(level1.security_id.clone(), (level1.volume, turnover))
+            {
+                Some((v, t)) if level1.volume >= v => (level1.volume - v, (turnover - t).max(0.0)),
+                Some(_) => (level1.volume, turnover),
+                //首笔只作为基准
+                None => (0.0, 0.0),
+            }
+        };
+        //累计成交量已包含逐笔成交，避免重复计算
+        let (volume, turnover) = if state.has_trades.contains(&level1.security_id) {
+            (0.0, 0.0)
+        } else {
+            (volume, turnover)
+        };
+        let day = match level1.items.get(TRADING_DATE) {
+            Some(Value::String(day)) => NaiveDate::parse_from_str(day, "%Y%m%d").ok(),
+            _ => None,
+        };
+        let tick = Tick {
+            time: level1.time,
+            day,
+            price: level1.last,
+            volume,
+            turnover,
+        };
+        self.update(&mut state, &level1.security_id, level1.exchange, tick)
+    }
+
+    pub fn on_trade(&self, trade: &TickToTrade) -> Vec<QuoteEvent> {
+        if trade.price.is_nan() {
+            return vec![];
+        }
+        let mut state = self.state.lock();
+        if !state.has_trades.contains(&trade.security_id) {
+            state.has_trades.insert(trade.security_id.clone());
+        }
+        let tick = Tick {
+            time: trade.time,
+            day: None,
+            price: trade.price,
+            volume: trade.quantity,
+            turnover: trade.price * trade.quantity,
+        };
+        self.update(&mut state, &trade.security_id, trade.exchange, tick)
+    }
+
+    fn update(
+        &self,
+        state: &mut State,
+        security_id: &str,
+        exchange: Exchange,
+        tick: Tick,
+    ) -> Vec<QuoteEvent> {
+        let Tick {
+            time,
+            day,
+            price,
+            volume,
+            turnover,
+        } = tick;
+        let mut events = vec![];
+        for period in self.periods.iter() {
+            let bounds = if self.options.sessions {
+                session_bounds(exchange, security_id, *period, time, day, &self.options)
+            } else {
+                bounds(exchange, *period, time)
+            };
+            let (begin, end) = match bounds {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            let key = (security_id.to_string(), *period);
+            match state.bars.get_mut(&key) {
+                Some((bar, _)) if bar.time == begin => {
+                    bar.high = bar.high.max(price);
+                    bar.low = bar.low.min(price);
+                    bar.close = price;
+                    bar.volume += volume;
+                    bar.turnover = Some(bar.turnover.unwrap_or_default() + turnover);
+                    events.push(QuoteEvent::BarUpdated(bar.clone()));
+                    continue;
+                }
+                //迟到的行情
+                Some((bar, _)) if bar.time > begin => continue,
+                _ => {}
+            }
+            if let Some((bar, _)) = state.bars.remove(&key) {
+                events.push(QuoteEvent::Bar(bar));
+            }
+            let bar = Bar {
+                security_id: security_id.into(),
+                exchange,
+                period: *period,
+                time: begin,
+                open: price,
+                high: price,
+                low: price,
+                close: price,
+                volume,
+                turnover: Some(turnover),
+            };
+            events.push(QuoteEvent::BarUpdated(bar.clone()));
+            state.bars.insert(key, (bar, end));
+        }
+        events
+    }
+
+    //结束时间早于now的k线收盘
+    pub fn close_expired(&self, now: i64) -> Vec<QuoteEvent> {
+        let mut state = self.state.lock();
+        let keys: Vec<(String, Period)> = state
+            .bars
+            .iter()
+            .filter(|(_, (_, end))| *end <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut bars: Vec<(Bar, i64)> = keys.iter().filter_map(|k| state.bars.remove(k)).collect();
+        bars.sort_by_key(|(_, end)| *end);
+        bars.into_iter()
+            .map(|(bar, _)| QuoteEvent::Bar(bar))
+            .collect()
+    }
+
+    //订阅行情合成k线，未完成的k线发布BarUpdated，收盘的k线发布Bar
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let aggregator = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            let events = match ev.as_ref() {
+                Event::QuoteEvent(QuoteEvent::Level1(level1)) => aggregator.on_level1(level1),
+                Event::QuoteEvent(QuoteEvent::TickToTrade(trade)) => aggregator.on_trade(trade),
+                _ => return,
+            };
+            publish_all(events);
+        })?;
+        self.tokens.lock().push(token);
+        let aggregator = self.clone();
+        std::thread::Builder::new()
+            .name("qbox-bar-aggregator".into())
+            .spawn(move || {
+                while aggregator.running.load(Ordering::SeqCst) {
+                    std::thread::sleep(CLOSE_INTERVAL);
+                    publish_all(aggregator.close_expired(Utc::now().timestamp()));
+                }
+            })?;
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+fn publish_all(events: Vec<QuoteEvent>) {
+    for ev in events {
+        if let Err(err) = core::quotes_event(ev) {
+            log::error!("publish bar error {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level1(time: i64, last: f64, volume: f64) -> Level1 {
+        let mut level1 = Level1::new();
+        level1.security_id = "rb2205".into();
+        level1.exchange = Exchange::SHFE;
+        level1.time = time;
+        level1.last = last;
+        level1.volume = volume;
+        level1.turnover = volume * 10.0;
+        level1
+    }
+
+    fn closed(events: &[QuoteEvent]) -> Vec<Bar> {
+        events
+            .iter()
+            .filter_map(|ev| match ev {
+                QuoteEvent::Bar(bar) => Some(bar.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bounds() {
+        //2022-03-14 10:00:30 +08:00
+        let ts = 1647223230;
+        assert_eq!(
+            bounds(Exchange::SHFE, Period::Minute(1), ts),
+            Some((ts - 30, ts + 30))
+        );
+        let day = 1647187200;
+        assert_eq!(
+            bounds(Exchange::SHFE, Period::Day(1), ts),
+            Some((day, day + 86400))
+        );
+        //2022-03-01 00:00:00 +08:00 ~ 2022-04-01
+        assert_eq!(
+            bounds(Exchange::SHFE, Period::Month(1), ts),
+            Some((1646064000, 1648742400))
+        );
+        assert_eq!(bounds(Exchange::SHFE, Period::Timeline, ts), None);
+    }
+
+    fn ts(s: &str) -> i64 {
+        chrono::DateTime::parse_from_str(&format!("{} +0800", s), "%Y-%m-%d %H:%M:%S %z")
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn test_session_bounds() {
+        let opts = BarOptions::default();
+        let bounds =
+            |period, s| session_bounds(Exchange::SHFE, "rb2205", period, ts(s), None, &opts);
+        let m1 = Period::Minute(1);
+        //小节休息前的末根k线
+        assert_eq!(
+            bounds(m1, "2022-03-14 10:14:30"),
+            Some((ts("2022-03-14 10:14:00"), ts("2022-03-14 10:16:00")))
+        );
+        assert_eq!(
+            bounds(m1, "2022-03-14 10:15:00"),
+            Some((ts("2022-03-14 10:14:00"), ts("2022-03-14 10:16:00")))
+        );
+        assert_eq!(bounds(m1, "2022-03-14 10:20:00"), None);
+        //小时k线不跨越休息
+        assert_eq!(
+            bounds(Period::Hour(1), "2022-03-14 10:05:00"),
+            Some((ts("2022-03-14 10:00:00"), ts("2022-03-14 10:16:00")))
+        );
+        //集合竞价并入首根k线
+        assert_eq!(
+            bounds(m1, "2022-03-11 20:59:00"),
+            Some((ts("2022-03-11 21:00:00"), ts("2022-03-11 21:01:00")))
+        );
+        //周五夜盘归属下周一
+        assert_eq!(
+            bounds(Period::Day(1), "2022-03-11 21:30:00"),
+            Some((ts("2022-03-14 00:00:00"), ts("2022-03-14 15:01:00")))
+        );
+        //行情带交易日时以行情为准
+        let day = NaiveDate::from_ymd(2022, 3, 15);
+        assert_eq!(
+            session_bounds(
+                Exchange::SHFE,
+                "rb2205",
+                Period::Day(1),
+                ts("2022-03-14 21:30:00"),
+                Some(day),
+                &opts
+            ),
+            Some((ts("2022-03-15 00:00:00"), ts("2022-03-15 15:01:00")))
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let t0 = 1647223200;
+        let agg = BarAggregator::new(&[Period::Minute(1), Period::Minute(5)]);
+        assert!(closed(&agg.on_level1(&level1(t0, 100.0, 1000.0))).is_empty());
+        agg.on_level1(&level1(t0 + 10, 102.0, 1010.0));
+        agg.on_level1(&level1(t0 + 20, 99.0, 1015.0));
+        let events = agg.on_level1(&level1(t0 + 60, 101.0, 1020.0));
+        let bars = closed(&events);
+        assert_eq!(bars.len(), 1);
+        let bar = &bars[0];
+        assert_eq!(bar.period, Period::Minute(1));
+        assert_eq!(bar.time, t0);
+        assert_eq!(
+            (bar.open, bar.high, bar.low, bar.close),
+            (100.0, 102.0, 99.0, 99.0)
+        );
+        assert_eq!(bar.volume, 15.0);
+        assert_eq!(bar.turnover, Some(150.0));
+
+        agg.on_trade(&TickToTrade {
+            security_id: "rb2205".into(),
+            exchange: Exchange::SHFE,
+            id: "1".into(),
+            time: t0 + 70,
+            price: 103.0,
+            quantity: 2.0,
+            order_side: None,
+            into_side: None,
+            take_order_id: None,
+            make_order_id: None,
+        });
+        //基本行情的累计成交量已包含上面的逐笔成交，只取价格
+        agg.on_level1(&level1(t0 + 80, 104.0, 1022.0));
+        let bars = closed(&agg.close_expired(t0 + 300));
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].volume, 7.0);
+        assert_eq!(bars[0].turnover, Some(50.0 + 206.0));
+        assert_eq!(bars[0].close, 104.0);
+        assert_eq!(bars[1].period, Period::Minute(5));
+        assert_eq!(bars[1].volume, 22.0);
+        assert_eq!(bars[1].high, 104.0);
+    }
+}
@@ -0,0 +1,461 @@
+use crate::broker::{Depth, DepthUpdate, Exchange, Level2, TopOfOrderBook};
+use crate::bus::Token;
+use crate::core::{self, *};
+use ahash::RandomState;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use flate2::Crc;
+use parking_lot::Mutex;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+//等待快照期间缓存的增量上限
+const MAX_PENDING: usize = 1000;
+//crc32校验的档位数
+const CHECKSUM_LEVELS: usize = 25;
+
+//根据订单簿计算校验和，与交易所推送的校验和比较
+pub type Checksum = fn(&OrderBook) -> i64;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Price(f64);
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+//价位及交易所推送的原始(价,量)文本
+#[derive(Debug, Clone)]
+struct Level {
+    depth: Depth,
+    raw: Option<(String, String)>,
+}
+
+impl Level {
+    //校验和使用的"价:量"，优先使用原始文本，避免浮点格式化与交易所不一致
+    fn text(&self) -> String {
+        match &self.raw {
+            Some((price, size)) => format!("{}:{}", price, size),
+            None => format!("{}:{}", self.depth.0, self.depth.1),
+        }
+    }
+}
+
+#[doc = "单个证券的订单簿"]
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+    security_id: String,
+    exchange: Exchange,
+    time: i64,
+    seq: u64,
+    synced: bool,
+    bids: BTreeMap<Price, Level>,
+    asks: BTreeMap<Price, Level>,
+    //等待快照期间收到的增量
+    pending: VecDeque<DepthUpdate>,
+}
+
+impl OrderBook {
+    pub fn new<S: Into<String>>(security_id: S, exchange: Exchange) -> Self {
+        Self {
+            security_id: security_id.into(),
+            exchange,
+            time: 0,
+            seq: 0,
+            synced: false,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn security_id(&self) -> &str {
+        &self.security_id
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    //买盘，价格从高到低
+    pub fn bids(&self, depth: usize) -> Vec<Depth> {
+        self.bids
+            .values()
+            .rev()
+            .take(depth)
+            .map(|v| v.depth)
+            .collect()
+    }
+
+    //卖盘，价格从低到高
+    pub fn asks(&self, depth: usize) -> Vec<Depth> {
+        self.asks.values().take(depth).map(|v| v.depth).collect()
+    }
+
+    pub fn best_bid(&self) -> Option<Depth> {
+        self.bids.values().next_back().map(|v| v.depth)
+    }
+
+    pub fn best_ask(&self) -> Option<Depth> {
+        self.asks.values().next().map(|v| v.depth)
+    }
+
+    pub fn level2(&self, depth: usize) -> Level2 {
+        Level2 {
+            security_id: self.security_id.clone(),
+            exchange: self.exchange,
+            time: self.time,
+            bids: self.bids(depth),
+            asks: self.asks(depth),
+        }
+    }
+
+    pub fn top(&self) -> TopOfOrderBook {
+        TopOfOrderBook {
+            security_id: self.security_id.clone(),
+            exchange: self.exchange,
+            time: self.time,
+            bids: self.best_bid().into_iter().collect(),
+            asks: self.best_ask().into_iter().collect(),
+        }
+    }
+
+    //丢弃订单簿，等待新的快照
+    pub fn reset(&mut self) {
+        self.synced = false;
+        self.seq = 0;
+        self.bids.clear();
+        self.asks.clear();
+        self.pending.clear();
+    }
+
+    //应用快照或增量，返回订单簿是否变化；序号不连续或校验失败时返回错误，需重新同步
+    pub fn apply(&mut self, update: &DepthUpdate, checksum: Option<Checksum>) -> Result<bool> {
+        if update.snapshot {
+            self.bids.clear();
+            self.asks.clear();
+            self.merge(update);
+            self.seq = update.seq;
+            self.synced = true;
+            self.verify(update, checksum)?;
+            //重放快照之前缓存的增量，过期的增量被忽略
+            let pending: Vec<DepthUpdate> = self.pending.drain(..).collect();
+            for update in pending.iter() {
+                self.apply(update, checksum)?;
+            }
+            return Ok(true);
+        }
+        if !self.synced {
+            if self.pending.len() >= MAX_PENDING {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(update.clone());
+            return Ok(false);
+        }
+        if update.seq <= self.seq {
+            return Ok(false);
+        }
+        let continuous = match update.prev_seq {
+            Some(prev) => prev == self.seq,
+            None => update.first_seq <= self.seq + 1,
+        };
+        if !continuous {
+            return Err(anyhow!(
+                "{} depth gap, expect {} got {}",
+                self.security_id,
+                self.seq,
+                update.prev_seq.unwrap_or(update.first_seq)
+            ));
+        }
+        self.merge(update);
+        self.seq = update.seq;
+        self.verify(update, checksum)?;
+        Ok(true)
+    }
+
+    fn merge(&mut self, update: &DepthUpdate) {
+        self.time = update.time;
+        for (levels, raws, side) in [
+            (&update.bids, &update.raw_bids, &mut self.bids),
+            (&update.asks, &update.raw_asks, &mut self.asks),
+        ] {
+            for (i, depth) in levels.iter().enumerate().filter(|(_, d)| !d.0.is_nan()) {
+                if depth.1 > 0.0 {
+                    let level = Level {
+                        depth: *depth,
+                        raw: raws.get(i).cloned(),
+                    };
+                    side.insert(Price(depth.0), level);
+                } else {
+                    side.remove(&Price(depth.0));
+                }
+            }
+        }
+    }
+
+    fn verify(&self, update: &DepthUpdate, checksum: Option<Checksum>) -> Result<()> {
+        match (update.checksum, checksum) {
+            (Some(expect), Some(f)) if f(self) != expect => Err(anyhow!(
+                "{} depth checksum mismatch at {}",
+                self.security_id,
+                self.seq
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+//前25档按买、卖交替拼接为"价:量"，取crc32的有符号值
+pub fn crc32_checksum(book: &OrderBook) -> i64 {
+    let bids: Vec<&Level> = book.bids.values().rev().take(CHECKSUM_LEVELS).collect();
+    let asks: Vec<&Level> = book.asks.values().take(CHECKSUM_LEVELS).collect();
+    let mut fields = vec![];
+    for i in 0..bids.len().max(asks.len()) {
+        if let Some(bid) = bids.get(i) {
+            fields.push(bid.text());
+        }
+        if let Some(ask) = asks.get(i) {
+            fields.push(ask.text());
+        }
+    }
+    let mut crc = Crc::new();
+    crc.update(fields.join(":").as_bytes());
+    crc.sum() as i32 as i64
+}
+
+#[doc = "按增量深度维护订单簿，发布N档深度及最优买卖价"]
+pub struct OrderBooks {
+    depth: usize,
+    books: DashMap<String, OrderBook, RandomState>,
+    checksums: DashMap<Exchange, Checksum, RandomState>,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl OrderBooks {
+    pub fn new(depth: usize) -> Arc<Self> {
+        let checksums = DashMap::with_hasher(RandomState::new());
+        checksums.insert(Exchange::OKEX, crc32_checksum as Checksum);
+        Arc::new(Self {
+            depth: depth.max(1),
+            books: DashMap::with_hasher(RandomState::new()),
+            checksums,
+            tokens: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    //设置交易所的校验和算法
+    pub fn set_checksum(&self, exchange: Exchange, checksum: Checksum) {
+        self.checksums.insert(exchange, checksum);
+    }
+
+    pub fn level2(&self, security_id: &str) -> Option<Level2> {
+        self.books
+            .get(security_id)
+            .filter(|book| book.is_synced())
+            .map(|book| book.level2(self.depth))
+    }
+
+    pub fn top(&self, security_id: &str) -> Option<TopOfOrderBook> {
+        self.books
+            .get(security_id)
+            .filter(|book| book.is_synced())
+            .map(|book| book.top())
+    }
+
+    //返回需要发布的事件：深度、变化的最优买卖价，或重新同步请求
+    pub fn on_update(&self, update: &DepthUpdate) -> Vec<QuoteEvent> {
+        let checksum = self.checksums.get(&update.exchange).map(|v| *v.value());
+        let mut book = self
+            .books
+            .entry(update.security_id.clone())
+            .or_insert_with(|| OrderBook::new(update.security_id.clone(), update.exchange));
+        //只比较价和量，笔数、金额可能为NaN
+        let best = |book: &OrderBook| {
+            let top = |d: Depth| (d.0, d.1);
+            (book.best_bid().map(top), book.best_ask().map(top))
+        };
+        let prev = best(&book);
+        match book.apply(update, checksum) {
+            Ok(true) => {
+                let mut events = vec![QuoteEvent::Level2(book.level2(self.depth))];
+                if prev != best(&book) {
+                    events.push(QuoteEvent::TopOfOrderBook(book.top()));
+                }
+                events
+            }
+            Ok(false) => vec![],
+            Err(err) => {
+                log::warn!("resync order book: {}", err);
+                book.reset();
+                vec![QuoteEvent::ResyncDepth(vec![update.security_id.clone()])]
+            }
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let books = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            if let Event::QuoteEvent(QuoteEvent::DepthUpdate(update)) = ev.as_ref() {
+                for ev in books.on_update(update) {
+                    if let Err(err) = core::quotes_event(ev) {
+                        log::error!("publish order book error {}", err);
+                    }
+                }
+            }
+        })?;
+        self.tokens.lock().push(token);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //zlib.crc32计算的参考值
+    const CHECKSUM: i64 = -1881014294;
+    const TRAILING_ZERO_CHECKSUM: i64 = 186644765;
+
+    fn update(
+        snapshot: bool,
+        first_seq: u64,
+        seq: u64,
+        bids: Vec<(f64, f64)>,
+        asks: Vec<(f64, f64)>,
+    ) -> DepthUpdate {
+        let depth = |v: Vec<(f64, f64)>| {
+            v.into_iter()
+                .map(|(p, q)| (p, q, f64::NAN, f64::NAN))
+                .collect()
+        };
+        DepthUpdate {
+            security_id: "btc-usdt".into(),
+            exchange: Exchange::BINANCE,
+            time: seq as i64,
+            snapshot,
+            first_seq,
+            seq,
+            prev_seq: None,
+            checksum: None,
+            bids: depth(bids),
+            asks: depth(asks),
+            raw_bids: vec![],
+            raw_asks: vec![],
+        }
+    }
+
+    //按交易所推送的文本构造增量
+    fn raw_update(bids: &[(&str, &str)], asks: &[(&str, &str)]) -> DepthUpdate {
+        let raw = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+            v.iter()
+                .map(|(p, q)| (p.to_string(), q.to_string()))
+                .collect()
+        };
+        let depth = |v: &[(&str, &str)]| {
+            v.iter()
+                .map(|(p, q)| (p.parse().unwrap(), q.parse().unwrap()))
+                .collect()
+        };
+        let mut update = update(true, 1, 1, depth(bids), depth(asks));
+        update.exchange = Exchange::OKEX;
+        update.raw_bids = raw(bids);
+        update.raw_asks = raw(asks);
+        update
+    }
+
+    #[test]
+    fn test_order_book() {
+        let books = OrderBooks::new(5);
+        //快照之前的增量先缓存
+        assert!(books
+            .on_update(&update(false, 9, 11, vec![(99.0, 0.0)], vec![]))
+            .is_empty());
+        let events = books.on_update(&update(
+            true,
+            10,
+            10,
+            vec![(100.0, 1.0), (99.0, 2.0)],
+            vec![(101.0, 1.0), (102.0, 3.0)],
+        ));
+        assert!(matches!(
+            events.as_slice(),
+            [QuoteEvent::Level2(_), QuoteEvent::TopOfOrderBook(_)]
+        ));
+        let level2 = books.level2("btc-usdt").unwrap();
+        assert_eq!(level2.bids.len(), 1);
+        assert_eq!(level2.asks[0].0, 101.0);
+
+        //过期的增量被忽略
+        assert!(books
+            .on_update(&update(false, 5, 8, vec![(98.0, 1.0)], vec![]))
+            .is_empty());
+        let events = books.on_update(&update(false, 12, 12, vec![], vec![(102.0, 5.0)]));
+        assert!(matches!(events.as_slice(), [QuoteEvent::Level2(_)]));
+        let events = books.on_update(&update(false, 13, 13, vec![(100.5, 1.0)], vec![]));
+        assert_eq!(events.len(), 2);
+        assert_eq!(books.top("btc-usdt").unwrap().bids[0].0, 100.5);
+
+        //序号中断请求重新同步
+        let events = books.on_update(&update(false, 15, 15, vec![], vec![]));
+        assert!(matches!(events.as_slice(), [QuoteEvent::ResyncDepth(_)]));
+        assert!(books.level2("btc-usdt").is_none());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut book = OrderBook::new("btc-usdt", Exchange::OKEX);
+        let mut snapshot = update(true, 1, 1, vec![(100.0, 1.0)], vec![(101.0, 2.0)]);
+        let mut expect = OrderBook::new("btc-usdt", Exchange::OKEX);
+        expect.apply(&snapshot, None).unwrap();
+        snapshot.checksum = Some(crc32_checksum(&expect));
+        assert!(book.apply(&snapshot, Some(crc32_checksum)).is_ok());
+
+        let mut diff = update(false, 2, 2, vec![(100.0, 3.0)], vec![]);
+        diff.checksum = Some(0);
+        assert!(book.apply(&diff, Some(crc32_checksum)).is_err());
+    }
+
+    #[test]
+    fn test_okex_checksum() {
+        //交易所文档示例："3366.1:7:3366.8:9:3366:6:3368:8"
+        let mut book = OrderBook::new("btc-usdt", Exchange::OKEX);
+        let mut snapshot = raw_update(
+            &[("3366.1", "7"), ("3366", "6")],
+            &[("3366.8", "9"), ("3368", "8")],
+        );
+        snapshot.checksum = Some(CHECKSUM);
+        assert!(book.apply(&snapshot, Some(crc32_checksum)).is_ok());
+        assert_eq!(crc32_checksum(&book), CHECKSUM);
+
+        //使用原始文本，末尾的0不因浮点格式化丢失
+        let mut book = OrderBook::new("btc-usdt", Exchange::OKEX);
+        let snapshot = raw_update(&[("0.10", "1.50")], &[("0.20", "2")]);
+        book.apply(&snapshot, None).unwrap();
+        assert_eq!(crc32_checksum(&book), TRAILING_ZERO_CHECKSUM);
+    }
+}
@@ -0,0 +1,669 @@
+use crate::broker::{Bar, Exchange, InstState, Instrument, Level1, Value};
+use crate::bus::Token;
+use crate::calendar;
+use crate::core::instruments::{self, ListingChange};
+use crate::core::settings::Settings;
+use crate::core::{self, *};
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+//换月记录的设置命名空间，键为品种代码
+const ROLLS_NAMESPACE: &str = "continuous";
+//连续合约代码后缀
+const SYMBOL_SUFFIX: &str = "888";
+const TRADING_DATE: &str = "trading_date";
+const OPEN_INTEREST: &str = "open_interest";
+//连续合约对应的主力合约
+const DOMINANT: &str = "dominant";
+
+#[doc = "主力合约的判断依据"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RollBy {
+    //持仓量，没有持仓量时按成交量
+    OpenInterest,
+    Volume,
+}
+
+#[doc = "换月规则"]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct RollRule {
+    pub by: RollBy,
+    //新合约超过当前主力的倍数
+    pub threshold: f64,
+    //连续满足条件的交易日数
+    pub days: u32,
+    //只向更远月份的合约切换
+    pub forward_only: bool,
+}
+
+impl Default for RollRule {
+    fn default() -> Self {
+        Self {
+            by: RollBy::OpenInterest,
+            threshold: 1.1,
+            days: 1,
+            forward_only: true,
+        }
+    }
+}
+
+impl RollRule {
+    pub fn with_by(mut self, by: RollBy) -> Self {
+        self.by = by;
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_days(mut self, days: u32) -> Self {
+        self.days = days.max(1);
+        self
+    }
+
+    pub fn with_forward_only(mut self, forward_only: bool) -> Self {
+        self.forward_only = forward_only;
+        self
+    }
+}
+
+#[doc = "价格复权方式"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Adjust {
+    //不复权
+    None,
+    //等比例后复权，换月前价格乘以新旧合约价格比
+    Ratio,
+    //差值后复权，换月前价格加上新旧合约价差
+    Difference,
+}
+
+#[doc = "合约交易日统计"]
+#[derive(Debug, Clone)]
+pub struct DailyStat {
+    pub security_id: String,
+    pub volume: f64,
+    pub open_interest: f64,
+    pub close: f64,
+}
+
+#[doc = "换月记录，date为新主力生效的交易日"]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Roll {
+    pub product: String,
+    pub date: NaiveDate,
+    pub from: Option<String>,
+    pub to: String,
+    //换月当日新旧合约的收盘价
+    pub from_close: f64,
+    pub to_close: f64,
+}
+
+#[doc = "按规则选择品种的主力合约"]
+#[derive(Debug, Clone)]
+pub struct Dominant {
+    product: String,
+    rule: RollRule,
+    current: Option<String>,
+    //(候选合约,连续满足条件的交易日数)
+    candidate: Option<(String, u32)>,
+    rolls: Vec<Roll>,
+}
+
+impl Dominant {
+    pub fn new<S: Into<String>>(product: S, rule: RollRule) -> Self {
+        Self {
+            product: product.into(),
+            rule,
+            current: None,
+            candidate: None,
+            rolls: vec![],
+        }
+    }
+
+    //从换月记录恢复
+    pub fn with_rolls(mut self, rolls: Vec<Roll>) -> Self {
+        self.current = rolls.last().map(|r| r.to.clone());
+        self.rolls = rolls;
+        self
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn rolls(&self) -> &[Roll] {
+        &self.rolls
+    }
+
+    fn metric(&self, stat: &DailyStat) -> f64 {
+        match self.rule.by {
+            RollBy::OpenInterest if !stat.open_interest.is_nan() => stat.open_interest,
+            _ => stat.volume,
+        }
+    }
+
+    //交易日收盘后更新，需要换月时返回换月记录，下一交易日生效
+    pub fn update(
+        &mut self,
+        exchange: Exchange,
+        day: NaiveDate,
+        stats: &[DailyStat],
+    ) -> Option<Roll> {
+        let current = self
+            .current
+            .as_ref()
+            .and_then(|c| stats.iter().find(|s| &s.security_id == c));
+        let best = stats
+            .iter()
+            .filter(|s| !self.metric(s).is_nan())
+            .filter(|s| match &self.current {
+                Some(c) if self.rule.forward_only => {
+                    match (delivery(&s.security_id, day), delivery(c, day)) {
+                        (Some(a), Some(b)) => a > b,
+                        _ => s.security_id > *c,
+                    }
+                }
+                Some(c) => s.security_id != *c,
+                None => true,
+            })
+            .max_by(|a, b| {
+                self.metric(a)
+                    .partial_cmp(&self.metric(b))
+                    .unwrap_or(Ordering::Equal)
+            })?;
+        if let Some(current) = current {
+            if self.metric(best) < self.metric(current) * self.rule.threshold {
+                self.candidate = None;
+                return None;
+            }
+            let days = match &self.candidate {
+                Some((id, n)) if *id == best.security_id => n + 1,
+                _ => 1,
+            };
+            if days < self.rule.days {
+                self.candidate = Some((best.security_id.clone(), days));
+                return None;
+            }
+        }
+        //当前主力没有行情（已到期）时立即切换
+        self.candidate = None;
+        let roll = Roll {
+            product: self.product.clone(),
+            date: calendar::next_trading_date(exchange, day)?,
+            from: self.current.clone(),
+            to: best.security_id.clone(),
+            from_close: current.map(|s| s.close).unwrap_or(f64::NAN),
+            to_close: best.close,
+        };
+        self.current = Some(roll.to.clone());
+        self.rolls.push(roll.clone());
+        Some(roll)
+    }
+}
+
+//按日k线的成交量计算历史换月
+pub fn select_rolls(product: &str, daily: &[Bar], rule: RollRule) -> Vec<Roll> {
+    let mut days: BTreeMap<NaiveDate, (Exchange, Vec<DailyStat>)> = BTreeMap::new();
+    for bar in daily {
+        let day = calendar::trading_day(bar.exchange, bar.time);
+        days.entry(day)
+            .or_insert_with(|| (bar.exchange, vec![]))
+            .1
+            .push(DailyStat {
+                security_id: bar.security_id.clone(),
+                volume: bar.volume,
+                open_interest: f64::NAN,
+                close: bar.close,
+            });
+    }
+    let mut dominant = Dominant::new(product, rule);
+    for (day, (exchange, stats)) in days {
+        dominant.update(exchange, day, &stats);
+    }
+    dominant.rolls
+}
+
+//按换月记录拼接连续合约k线，复权以最新主力合约价格为准
+pub fn build(symbol: &str, bars: &[Bar], rolls: &[Roll], adjust: Adjust) -> Vec<Bar> {
+    let active = |day: NaiveDate| {
+        rolls
+            .iter()
+            .take_while(|r| r.date <= day)
+            .last()
+            .map(|r| r.to.as_str())
+    };
+    let mut series: Vec<(NaiveDate, Bar)> = bars
+        .iter()
+        .filter_map(|bar| {
+            let day = calendar::trading_day(bar.exchange, bar.time);
+            if active(day) == Some(bar.security_id.as_str()) {
+                Some((day, bar.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    series.sort_by_key(|(_, bar)| bar.time);
+    series
+        .into_iter()
+        .map(|(day, mut bar)| {
+            let later = rolls
+                .iter()
+                .filter(|r| r.date > day && r.from.is_some())
+                .filter(|r| !r.from_close.is_nan() && !r.to_close.is_nan());
+            match adjust {
+                Adjust::None => {}
+                Adjust::Ratio => {
+                    let ratio: f64 = later
+                        .filter(|r| r.from_close != 0.0)
+                        .map(|r| r.to_close / r.from_close)
+                        .product();
+                    bar.open *= ratio;
+                    bar.high *= ratio;
+                    bar.low *= ratio;
+                    bar.close *= ratio;
+                }
+                Adjust::Difference => {
+                    let diff: f64 = later.map(|r| r.to_close - r.from_close).sum();
+                    bar.open += diff;
+                    bar.high += diff;
+                    bar.low += diff;
+                    bar.close += diff;
+                }
+            }
+            bar.security_id = symbol.into();
+            bar
+        })
+        .collect()
+}
+
+//连续合约代码，如rb2205 => rb888
+pub fn symbol(security_id: &str) -> String {
+    let prefix: String = security_id
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    format!("{}{}", prefix, SYMBOL_SUFFIX)
+}
+
+//交割年月，如rb2205 => 202205；郑商所三位年月如SR001取离交易日最近的年份
+fn delivery(security_id: &str, day: NaiveDate) -> Option<i32> {
+    let digits: String = security_id
+        .chars()
+        .skip_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (year, month) = match digits.len() {
+        4 => (
+            2000 + digits[..2].parse::<i32>().ok()?,
+            digits[2..].parse::<i32>().ok()?,
+        ),
+        3 => {
+            let year = day.year() / 10 * 10 + digits[..1].parse::<i32>().ok()?;
+            let year = [year - 10, year, year + 10]
+                .iter()
+                .copied()
+                .min_by_key(|y| (y - day.year()).abs())?;
+            (year, digits[1..].parse::<i32>().ok()?)
+        }
+        _ => return None,
+    };
+    Some(year * 100 + month)
+}
+
+struct Tracker {
+    dominant: Dominant,
+    day: Option<NaiveDate>,
+    stats: HashMap<String, DailyStat>,
+}
+
+#[doc = "实时跟踪主力合约，以连续合约代码发布主力合约行情"]
+pub struct ContinuousContracts {
+    rule: RollRule,
+    settings: Settings,
+    trackers: Mutex<HashMap<String, Tracker>>,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl ContinuousContracts {
+    //换月记录保存在单元设置中
+    pub fn new<S: AsRef<str>>(unit: S, products: &[&str], rule: RollRule) -> Result<Arc<Self>> {
+        let settings = Settings::open(unit, ROLLS_NAMESPACE)?;
+        let mut trackers = HashMap::new();
+        for product in products {
+            let product = product.to_lowercase();
+            let rolls: Vec<Roll> = settings.get_or_default(&product)?;
+            trackers.insert(
+                product.clone(),
+                Tracker {
+                    dominant: Dominant::new(product, rule).with_rolls(rolls),
+                    day: None,
+                    stats: HashMap::new(),
+                },
+            );
+        }
+        Ok(Arc::new(Self {
+            rule,
+            settings,
+            trackers: Mutex::new(trackers),
+            tokens: Mutex::new(vec![]),
+        }))
+    }
+
+    pub fn rule(&self) -> &RollRule {
+        &self.rule
+    }
+
+    pub fn dominant(&self, product: &str) -> Option<String> {
+        self.trackers
+            .lock()
+            .get(&product.to_lowercase())
+            .and_then(|t| t.dominant.current().map(String::from))
+    }
+
+    pub fn rolls(&self, product: &str) -> Vec<Roll> {
+        self.trackers
+            .lock()
+            .get(&product.to_lowercase())
+            .map(|t| t.dominant.rolls().to_vec())
+            .unwrap_or_default()
+    }
+
+    //连续合约沿用主力合约的交易参数，主力合约未知时只登记代码
+    pub fn instrument(&self, product: &str) -> Instrument {
+        let symbol = format!("{}{}", product.to_lowercase(), SYMBOL_SUFFIX);
+        let dominant = self.dominant(product);
+        let instrument = match dominant.as_deref().and_then(instruments::get_instrument) {
+            Some(instr) => instr.with_secrity_id(symbol.clone()),
+            None => Instrument::new()
+                .with_secrity_id(symbol.clone())
+                .with_state(InstState::Trading),
+        }
+        .with_symbol(symbol);
+        match dominant {
+            Some(dominant) => instrument.with_item(DOMINANT, Value::String(dominant)),
+            None => instrument,
+        }
+    }
+
+    //登记连续合约，使其出现在证券列表中
+    fn list_instruments(&self) {
+        let products: Vec<String> = self.trackers.lock().keys().cloned().collect();
+        for product in products {
+            if let Err(err) = core::publish(
+                QUERY_EVENT,
+                Event::TradeEvent(TradeEvent::Instrument(self.instrument(&product))),
+            ) {
+                log::error!("list continuous {} error {}", product, err);
+            }
+        }
+    }
+
+    //交易日切换时按上一交易日统计判断换月，返回主力合约行情改为连续合约代码后的副本
+    pub fn on_level1(&self, level1: &Level1) -> Option<Level1> {
+        if level1.security_id.ends_with(SYMBOL_SUFFIX) {
+            return None;
+        }
+        let product = calendar::product(&level1.security_id);
+        let mut rolled = None;
+        let mut trackers = self.trackers.lock();
+        let tracker = trackers.get_mut(&product)?;
+        let day = match level1.items.get(TRADING_DATE) {
+            Some(Value::String(day)) => NaiveDate::parse_from_str(day, "%Y%m%d").ok(),
+            _ => None,
+        }
+        .unwrap_or_else(|| calendar::trading_day(level1.exchange, level1.time));
+        match tracker.day {
+            Some(prev) if day > prev => {
+                let stats: Vec<DailyStat> = tracker.stats.drain().map(|(_, v)| v).collect();
+                if let Some(roll) = tracker.dominant.update(level1.exchange, prev, &stats) {
+                    rolled = Some((tracker.dominant.rolls().to_vec(), roll));
+                }
+                tracker.day = Some(day);
+            }
+            Some(prev) if day < prev => return None,
+            Some(_) => {}
+            None => tracker.day = Some(day),
+        }
+        let open_interest = match level1.items.get(OPEN_INTEREST) {
+            Some(Value::F64(v)) => *v,
+            _ => f64::NAN,
+        };
+        tracker.stats.insert(
+            level1.security_id.clone(),
+            DailyStat {
+                security_id: level1.security_id.clone(),
+                volume: level1.volume,
+                open_interest,
+                close: level1.last,
+            },
+        );
+        let dominant = tracker.dominant.current() == Some(level1.security_id.as_str());
+        drop(trackers);
+        if let Some((rolls, roll)) = rolled {
+            self.rolled(&rolls, roll);
+        }
+        if !dominant {
+            return None;
+        }
+        let mut synthetic = level1.clone();
+        synthetic.security_id = symbol(&level1.security_id);
+        Some(synthetic)
+    }
+
+    fn rolled(&self, rolls: &[Roll], roll: Roll) {
+        log::info!(
+            "{} roll {:?} => {} at {}",
+            roll.product,
+            roll.from,
+            roll.to,
+            roll.date
+        );
+        if let Err(err) = self.settings.set(&roll.product, &rolls) {
+            log::error!("save {} rolls error {}", roll.product, err);
+        }
+        if let Err(err) = core::publish(
+            INSTRUMENTS_EVENT,
+            Event::TradeEvent(TradeEvent::ListingChanged(ListingChange::Rolled(roll))),
+        ) {
+            log::error!("publish roll error {}", err);
+        }
+        self.list_instruments();
+    }
+
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let contracts = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            if let Event::QuoteEvent(QuoteEvent::Level1(level1)) = ev.as_ref() {
+                if let Some(synthetic) = contracts.on_level1(level1) {
+                    if let Err(err) = core::quotes_event(QuoteEvent::Level1(synthetic)) {
+                        log::error!("publish continuous quote error {}", err);
+                    }
+                }
+            }
+        })?;
+        self.tokens.lock().push(token);
+        self.list_instruments();
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Period;
+    use crate::testing::{cleanup, unit};
+
+    fn stat(security_id: &str, open_interest: f64, close: f64) -> DailyStat {
+        DailyStat {
+            security_id: security_id.into(),
+            volume: f64::NAN,
+            open_interest,
+            close,
+        }
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn bar(security_id: &str, day: &str, close: f64) -> Bar {
+        Bar {
+            security_id: security_id.into(),
+            exchange: Exchange::SHFE,
+            period: Period::Day(1),
+            //交易日10:00
+            time: date(day).and_hms(2, 0, 0).timestamp(),
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 1.0,
+            turnover: None,
+        }
+    }
+
+    #[test]
+    fn test_dominant() {
+        let mut dominant = Dominant::new("rb", RollRule::default().with_days(2));
+        let roll = dominant
+            .update(
+                Exchange::SHFE,
+                date("2022-03-14"),
+                &[stat("rb2205", 100.0, 4800.0), stat("rb2210", 50.0, 4700.0)],
+            )
+            .unwrap();
+        assert_eq!(roll.from, None);
+        assert_eq!(roll.to, "rb2205");
+        assert_eq!(roll.date, date("2022-03-15"));
+        //未超过阈值
+        let stats = [stat("rb2205", 100.0, 4800.0), stat("rb2210", 105.0, 4700.0)];
+        assert!(dominant
+            .update(Exchange::SHFE, date("2022-03-15"), &stats)
+            .is_none());
+        //需要连续两个交易日
+        let stats = [stat("rb2205", 100.0, 4800.0), stat("rb2210", 120.0, 4700.0)];
+        assert!(dominant
+            .update(Exchange::SHFE, date("2022-03-16"), &stats)
+            .is_none());
+        let roll = dominant
+            .update(Exchange::SHFE, date("2022-03-17"), &stats)
+            .unwrap();
+        assert_eq!(roll.from.as_deref(), Some("rb2205"));
+        assert_eq!(roll.to, "rb2210");
+        assert_eq!(roll.date, date("2022-03-18"));
+        //不回到近月合约
+        let stats = [stat("rb2205", 500.0, 4800.0), stat("rb2210", 120.0, 4700.0)];
+        assert!(dominant
+            .update(Exchange::SHFE, date("2022-03-18"), &stats)
+            .is_none());
+        assert_eq!(dominant.current(), Some("rb2210"));
+    }
+
+    #[test]
+    fn test_delivery_decade() {
+        assert_eq!(delivery("rb2205", date("2022-03-14")), Some(202205));
+        assert_eq!(delivery("SR001", date("2019-09-02")), Some(202001));
+        assert_eq!(delivery("SR912", date("2019-09-02")), Some(201912));
+        assert_eq!(delivery("SR912", date("2020-01-02")), Some(201912));
+        assert_eq!(delivery("rb888x", date("2022-03-14")), None);
+        //跨十年时SR001是比SR912更远的合约
+        let mut dominant = Dominant::new("sr", RollRule::default());
+        dominant.update(
+            Exchange::DZCE,
+            date("2019-09-02"),
+            &[stat("SR912", 100.0, 5500.0)],
+        );
+        let roll = dominant
+            .update(
+                Exchange::DZCE,
+                date("2019-09-03"),
+                &[stat("SR912", 100.0, 5500.0), stat("SR001", 200.0, 5600.0)],
+            )
+            .unwrap();
+        assert_eq!(roll.to, "SR001");
+    }
+
+    #[test]
+    fn test_instrument() {
+        let unit = unit("continuous-instrument");
+        let contracts = ContinuousContracts::new(&unit, &["rb"], RollRule::default()).unwrap();
+        let instrument = contracts.instrument("rb");
+        assert_eq!(instrument.security_id, "rb888");
+        assert_eq!(instrument.symbol, "rb888");
+        assert!(instrument.items.get(DOMINANT).is_none());
+        let stats = [stat("rb2205", 100.0, 4800.0)];
+        contracts
+            .trackers
+            .lock()
+            .get_mut("rb")
+            .unwrap()
+            .dominant
+            .update(Exchange::SHFE, date("2022-03-14"), &stats);
+        let instrument = contracts.instrument("rb");
+        assert_eq!(
+            instrument.items.get(DOMINANT),
+            Some(&Value::String("rb2205".into()))
+        );
+        cleanup(&unit);
+    }
+
+    #[test]
+    fn test_build() {
+        let rolls = vec![
+            Roll {
+                product: "rb".into(),
+                date: date("2022-03-14"),
+                from: None,
+                to: "rb2205".into(),
+                from_close: f64::NAN,
+                to_close: 4800.0,
+            },
+            Roll {
+                product: "rb".into(),
+                date: date("2022-03-16"),
+                from: Some("rb2205".into()),
+                to: "rb2210".into(),
+                from_close: 4800.0,
+                to_close: 4600.0,
+            },
+        ];
+        let bars = vec![
+            bar("rb2205", "2022-03-14", 4900.0),
+            bar("rb2210", "2022-03-14", 4700.0),
+            bar("rb2205", "2022-03-15", 4800.0),
+            bar("rb2210", "2022-03-15", 4600.0),
+            bar("rb2205", "2022-03-16", 4850.0),
+            bar("rb2210", "2022-03-16", 4650.0),
+        ];
+        let series = build("rb888", &bars, &rolls, Adjust::None);
+        assert_eq!(series.len(), 3);
+        assert_eq!(series[0].security_id, "rb888");
+        assert_eq!(series[0].close, 4900.0);
+        assert_eq!(series[2].close, 4650.0);
+
+        let series = build("rb888", &bars, &rolls, Adjust::Difference);
+        assert_eq!(series[0].close, 4700.0);
+        assert_eq!(series[2].close, 4650.0);
+
+        let series = build("rb888", &bars, &rolls, Adjust::Ratio);
+        assert!((series[1].close - 4600.0).abs() < 1e-9);
+        assert_eq!(series[2].close, 4650.0);
+    }
+}
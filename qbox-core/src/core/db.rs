@@ -1,17 +1,48 @@
+use crate::broker::Period;
+use crate::db;
 use crate::db::memory::MemQuoteStore;
-use crate::db::sqlite::SqliteStore;
-use crate::db::{OrderStore, QuoteStore};
+pub use crate::db::{OrderStore, QboxStore, QuoteStore, Retention, Store};
 use anyhow::Result;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref QUOTES: Option<MemQuoteStore> = None;
-}
+use std::sync::Arc;
 
 pub fn quotes<S: Into<String>>(unit: S) -> impl QuoteStore {
     MemQuoteStore::open(unit)
 }
 
-pub fn trades<S: AsRef<str>>(unit: S) -> Result<impl OrderStore> {
-    SqliteStore::open(unit)
+pub fn trades<S: AsRef<str>>(unit: S) -> Result<Arc<dyn Store>> {
+    db::open(unit)
+}
+
+//关闭单元，返回单元是否已打开
+pub fn close<S: AsRef<str>>(unit: S) -> bool {
+    db::close(unit)
+}
+
+//设置单元的k线保留策略，策略随单元保存，重新打开时生效
+pub fn set_retention<S: AsRef<str>>(unit: S, period: Period, retention: Retention) -> Result<()> {
+    db::open(unit)?.set_retention(period, retention)
+}
+
+pub fn retention<S: AsRef<str>>(unit: S, period: Period) -> Result<Retention> {
+    Ok(db::open(unit)?.retention(period))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_retention() {
+        let unit = unit("core-retention");
+        let period = Period::Minute(1);
+        assert_eq!(retention(&unit, period).unwrap(), Retention::Forever);
+        set_retention(&unit, period, Retention::Days(30)).unwrap();
+        //重新打开后仍然生效
+        assert!(close(&unit));
+        assert_eq!(retention(&unit, period).unwrap(), Retention::Days(30));
+        assert!(close(&unit));
+        assert!(!close(&unit));
+        cleanup(&unit);
+    }
 }
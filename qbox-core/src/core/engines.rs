@@ -0,0 +1,182 @@
+use crate::broker::Period;
+use crate::core::analytics::Microstructure;
+use crate::core::bars::{BarAggregator, BarOptions};
+use crate::core::book::OrderBooks;
+use crate::core::continuous::{ContinuousContracts, RollRule};
+use crate::core::l3book::L3Books;
+use crate::core::settings::Settings;
+use crate::core::synthetic::SyntheticInstruments;
+use crate::filter::quality::{QualityOptions, QualityStage};
+use crate::recorder::Recorder;
+use anyhow::Result;
+use std::sync::Arc;
+
+//引擎配置保存在单元设置的engines命名空间，未配置的引擎不启动
+const ENGINES_NAMESPACE: &str = "engines";
+//行情质量检查选项
+const QUALITY: &str = "quality";
+//k线周期及对齐选项
+const BARS: &str = "bars";
+const BAR_OPTIONS: &str = "bar_options";
+//是否录制行情
+const RECORDER: &str = "recorder";
+//订单簿深度
+const ORDER_BOOKS: &str = "order_books";
+const L3_BOOKS: &str = "l3_books";
+//连续合约品种及换月规则
+const CONTINUOUS: &str = "continuous";
+const ROLL_RULE: &str = "roll_rule";
+//微观结构指标窗口笔数及快照保存间隔秒数，间隔为0时不保存
+const MICROSTRUCTURE: &str = "microstructure";
+const ANALYTICS_INTERVAL: &str = "analytics_interval";
+//是否计算合成证券
+const SYNTHETIC: &str = "synthetic";
+
+#[doc = "按单元设置启动的行情引擎"]
+#[derive(Default)]
+pub struct Engines {
+    pub quality: Option<Arc<QualityStage>>,
+    pub bars: Option<Arc<BarAggregator>>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub order_books: Option<Arc<OrderBooks>>,
+    pub l3_books: Option<Arc<L3Books>>,
+    pub continuous: Option<Arc<ContinuousContracts>>,
+    pub microstructure: Option<Arc<Microstructure>>,
+    pub synthetic: Option<Arc<SyntheticInstruments>>,
+}
+
+impl Engines {
+    //启动失败时停止已启动的引擎
+    pub fn start<S: AsRef<str>>(unit: S) -> Result<Self> {
+        let mut engines = Engines::default();
+        if let Err(err) = engines.start_all(unit.as_ref()) {
+            engines.stop();
+            return Err(err);
+        }
+        Ok(engines)
+    }
+
+    fn start_all(&mut self, unit: &str) -> Result<()> {
+        let settings = Settings::open(unit, ENGINES_NAMESPACE)?;
+        //质量检查最先接入，其余引擎只收到检查后的行情
+        if let Some(options) = settings.get::<QualityOptions>(QUALITY)? {
+            let stage = QualityStage::new(options);
+            stage.start();
+            self.quality = Some(stage);
+        }
+        let periods = settings.get_or_default::<Vec<Period>>(BARS)?;
+        if !periods.is_empty() {
+            let options = settings.get_or_default::<BarOptions>(BAR_OPTIONS)?;
+            let aggregator = BarAggregator::with_options(&periods, options);
+            aggregator.start()?;
+            self.bars = Some(aggregator);
+        }
+        if settings.get_or(RECORDER, false)? {
+            let recorder = Recorder::open()?;
+            recorder.start()?;
+            self.recorder = Some(recorder);
+        }
+        let depth = settings.get_or(ORDER_BOOKS, 0usize)?;
+        if depth > 0 {
+            let books = OrderBooks::new(depth);
+            books.start()?;
+            self.order_books = Some(books);
+        }
+        let depth = settings.get_or(L3_BOOKS, 0usize)?;
+        if depth > 0 {
+            let books = L3Books::new(depth);
+            books.start()?;
+            self.l3_books = Some(books);
+        }
+        let products = settings.get_or_default::<Vec<String>>(CONTINUOUS)?;
+        if !products.is_empty() {
+            let products: Vec<&str> = products.iter().map(|p| p.as_str()).collect();
+            let rule = settings.get_or_default::<RollRule>(ROLL_RULE)?;
+            let continuous = ContinuousContracts::new(unit, &products, rule)?;
+            continuous.start()?;
+            self.continuous = Some(continuous);
+        }
+        let window = settings.get_or(MICROSTRUCTURE, 0usize)?;
+        if window > 0 {
+            let interval = settings.get_or(ANALYTICS_INTERVAL, 0i64)?;
+            let analytics = if interval > 0 {
+                Microstructure::with_store(unit, window, interval)?
+            } else {
+                Microstructure::new(window)
+            };
+            analytics.start()?;
+            self.microstructure = Some(analytics);
+        }
+        if settings.get_or(SYNTHETIC, false)? {
+            let synthetics = SyntheticInstruments::new(unit)?;
+            synthetics.start()?;
+            self.synthetic = Some(synthetics);
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(synthetics) = self.synthetic.take() {
+            synthetics.stop();
+        }
+        if let Some(analytics) = self.microstructure.take() {
+            analytics.stop();
+        }
+        if let Some(continuous) = self.continuous.take() {
+            continuous.stop();
+        }
+        if let Some(books) = self.l3_books.take() {
+            books.stop();
+        }
+        if let Some(books) = self.order_books.take() {
+            books.stop();
+        }
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(err) = recorder.stop() {
+                log::error!("stop recorder error {}", err);
+            }
+        }
+        if let Some(aggregator) = self.bars.take() {
+            aggregator.stop();
+        }
+        if let Some(stage) = self.quality.take() {
+            stage.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_engines() {
+        let unit = unit("test_engines");
+        let settings = Settings::open(&unit, ENGINES_NAMESPACE).unwrap();
+        settings
+            .set(BARS, &vec![Period::Minute(1), Period::Minute(5)])
+            .unwrap();
+        settings.set(ORDER_BOOKS, &5usize).unwrap();
+        settings.set(MICROSTRUCTURE, &20usize).unwrap();
+        settings.set(SYNTHETIC, &true).unwrap();
+        let mut engines = Engines::start(&unit).unwrap();
+        assert!(engines.bars.is_some());
+        assert!(engines.order_books.is_some());
+        assert!(engines.microstructure.is_some());
+        assert!(engines.synthetic.is_some());
+        assert!(engines.quality.is_none());
+        assert!(engines.recorder.is_none());
+        assert!(engines.l3_books.is_none());
+        assert!(engines.continuous.is_none());
+        engines.stop();
+        assert!(engines.bars.is_none());
+        assert!(engines.synthetic.is_none());
+
+        //配置错误时启动失败
+        settings.set(BARS, &"1m").unwrap();
+        assert!(Engines::start(&unit).is_err());
+
+        cleanup(&unit);
+    }
+}
@@ -1,16 +1,22 @@
+use super::instruments::ListingChange;
+use super::settings::SettingChange;
 use super::topics::*;
 use crate::broker::*;
 use crate::bus::local::LocalBus;
 use crate::bus::{EventBus, Token};
+use crate::filter::quality::StaleAlert;
+use crate::filter::Stage;
 use anyhow::Result;
 use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 lazy_static! {
     //消息总线
     static ref BUS:LocalBus<Arc<Event>>=LocalBus::new();
-
+    //行情发布前的处理阶段
+    static ref QUOTE_STAGE: RwLock<Option<Arc<dyn Stage<QuoteEvent>>>> = RwLock::new(None);
 }
 
 //广播消息
@@ -66,7 +72,21 @@ pub fn trade_event(msg: TradeEvent) -> Result<()> {
 
 #[inline]
 pub fn quotes_event(msg: QuoteEvent) -> Result<()> {
-    publish(QUOTES_EVENT, Event::QuoteEvent(msg))
+    let stage = QUOTE_STAGE.read().clone();
+    match stage {
+        Some(stage) => {
+            for ev in stage.process(msg) {
+                publish(QUOTES_EVENT, Event::QuoteEvent(ev))?;
+            }
+            Ok(())
+        }
+        None => publish(QUOTES_EVENT, Event::QuoteEvent(msg)),
+    }
+}
+
+//设置行情处理阶段，None为直接发布
+pub fn set_quote_stage(stage: Option<Arc<dyn Stage<QuoteEvent>>>) {
+    *QUOTE_STAGE.write() = stage;
 }
 
 #[inline]
@@ -85,6 +105,9 @@ pub enum TradeEvent {
     PositionChanged(Position),
     Instrument(Instrument),
     Transaction(Transaction),
+    ListingChanged(ListingChange),
+    //完整的证券列表查询结果，不在列表中的已保存证券视为到期
+    Instruments(Vec<Instrument>),
 }
 
 #[doc = "行情"]
@@ -102,8 +125,20 @@ pub enum QuoteEvent {
     Level1(Level1),
     //深度行情
     Level2(Level2),
-    //k线
+    //k线，已收盘
     Bar(Bar),
+    //未收盘k线的更新
+    BarUpdated(Bar),
+    //增量深度
+    DepthUpdate(DepthUpdate),
+    //最优买卖价
+    TopOfOrderBook(TopOfOrderBook),
+    //订单簿不连续，请求重新推送深度快照
+    ResyncDepth(Vec<String>),
+    //交易时段内行情停更
+    Stale(StaleAlert),
+    //微观结构指标
+    Analytics(Analytics),
 }
 
 impl ToString for QuoteEvent {
@@ -130,6 +165,8 @@ pub enum Event {
 
     TradeEvent(TradeEvent),
     QuoteEvent(QuoteEvent),
+    //设置变化
+    SettingChanged(SettingChange),
 }
 
 impl Event {
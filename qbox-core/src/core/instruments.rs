@@ -0,0 +1,370 @@
+use crate::broker::{Exchange, InstState, Instrument, TradeKind, Value};
+use crate::bus::Token;
+use crate::calendar;
+use crate::core::continuous::Roll;
+use crate::core::{self, *};
+use crate::db::{self, Store};
+use ahash::RandomState;
+use anyhow::Result;
+use chrono::{NaiveDate, TimeZone, Utc};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+const LONG_MARGIN_RATIO: &str = "LongMarginRatio";
+const SHORT_MARGIN_RATIO: &str = "ShortMarginRatio";
+const PRICE_TICK: &str = "PriceTick";
+//到期日，YYYYMMDD
+const EXPIRE_DATE: &str = "ExpireDate";
+
+lazy_static! {
+    //证券列表
+    static ref INSTRUMENTS: DashMap<String, Instrument, RandomState> = DashMap::with_hasher(RandomState::new());
+}
+
+#[doc = "证券上市信息变化"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum ListingChange {
+    //新上市
+    Listed(Instrument),
+    //到期
+    Expired(Instrument),
+    //保证金率变化，(多头,空头)
+    MarginRatio {
+        security_id: String,
+        prev: (f64, f64),
+        current: (f64, f64),
+    },
+    //最小变动价位变化
+    PriceTick {
+        security_id: String,
+        prev: f64,
+        current: f64,
+    },
+    //主力合约切换
+    Rolled(Roll),
+}
+
+pub fn get_instrument(security_id: &str) -> Option<Instrument> {
+    INSTRUMENTS.get(security_id).map(|v| v.value().clone())
+}
+
+pub fn get_all_instrument() -> Option<Vec<Instrument>> {
+    let data: Vec<Instrument> = INSTRUMENTS.iter().map(|v| v.value().clone()).collect();
+    if data.len() > 0 {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+pub fn find_instrument_with_prefix(prefix: &str) -> Option<Vec<Instrument>> {
+    let data: Vec<Instrument> = INSTRUMENTS
+        .iter()
+        .filter(|v| v.key().starts_with(prefix))
+        .map(|v| v.value().clone())
+        .collect();
+    if data.len() > 0 {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+pub fn find_instrument_with_prefixs(prefixs: &[&str]) -> Option<Vec<Instrument>> {
+    let data: Vec<Vec<Instrument>> = prefixs
+        .iter()
+        .filter_map(|prefix| find_instrument_with_prefix(prefix))
+        .collect();
+    if data.len() > 0 {
+        Some(data.concat())
+    } else {
+        None
+    }
+}
+
+//从单元存储加载证券列表，并持久化查询返回的证券
+pub fn init<S: AsRef<str>>(unit: S) -> Result<Token> {
+    let store = db::open(unit)?;
+    if let Some(list) = store.query_all_symbol()? {
+        for instr in list {
+            INSTRUMENTS.insert(instr.security_id.clone(), instr);
+        }
+    }
+    core::subscribe(QUERY_EVENT, move |_, ev| match ev.as_ref() {
+        Event::TradeEvent(TradeEvent::Instrument(instr)) => {
+            if let Err(err) = update(store.as_ref(), instr) {
+                log::error!("update instrument {} error {}", instr.security_id, err);
+            }
+        }
+        Event::TradeEvent(TradeEvent::Instruments(list)) => sync(store.as_ref(), list),
+        _ => {}
+    })
+}
+
+//完整的证券列表查询结果，已保存的同交易所同类证券不在列表中时视为到期
+fn sync(store: &dyn Store, list: &[Instrument]) {
+    for instr in list {
+        if let Err(err) = update(store, instr) {
+            log::error!("update instrument {} error {}", instr.security_id, err);
+        }
+    }
+    let scope: HashSet<(Exchange, TradeKind)> = list.iter().map(|i| (i.exchange, i.kind)).collect();
+    let ids: HashSet<&str> = list.iter().map(|i| i.security_id.as_str()).collect();
+    let missing: Vec<Instrument> = INSTRUMENTS
+        .iter()
+        .filter(|v| {
+            !ids.contains(v.key().as_str())
+                && scope.contains(&(v.exchange, v.kind))
+                && v.state != InstState::Expired
+        })
+        .map(|v| v.value().clone())
+        .collect();
+    for instr in missing {
+        if let Err(err) = update(store, &instr.with_state(InstState::Expired)) {
+            log::error!("expire instrument error {}", err);
+        }
+    }
+}
+
+//与已保存的证券比较，保存并发布变化，到期日已过的证券按到期处理
+fn update(store: &dyn Store, instr: &Instrument) -> Result<()> {
+    let mut instr = instr.clone();
+    if expire_date(&instr).map_or(false, |date| date < today(instr.exchange)) {
+        instr.state = InstState::Expired;
+    }
+    let prev = match INSTRUMENTS.get(&instr.security_id) {
+        Some(prev) => Some(prev.value().clone()),
+        None => store.query_one_symbol(&instr.security_id)?,
+    };
+    if prev
+        .as_ref()
+        .map_or(false, |prev| same_listing(prev, &instr))
+    {
+        return Ok(());
+    }
+    store.update_symbol(instr.clone())?;
+    INSTRUMENTS.insert(instr.security_id.clone(), instr.clone());
+    for change in diff(prev.as_ref(), &instr) {
+        log::info!("listing change {:?}", change);
+        core::publish(
+            INSTRUMENTS_EVENT,
+            Event::TradeEvent(TradeEvent::ListingChanged(change)),
+        )?;
+    }
+    Ok(())
+}
+
+fn today(exchange: Exchange) -> NaiveDate {
+    calendar::timezone(exchange)
+        .timestamp(Utc::now().timestamp(), 0)
+        .naive_local()
+        .date()
+}
+
+fn expire_date(instr: &Instrument) -> Option<NaiveDate> {
+    match instr.items.get(EXPIRE_DATE)? {
+        Value::String(date) => NaiveDate::parse_from_str(date.trim(), "%Y%m%d").ok(),
+        _ => None,
+    }
+}
+
+//只比较上市信息，浮点参数NaN视为相等，避免每次查询都报告变化
+pub fn same_listing(a: &Instrument, b: &Instrument) -> bool {
+    let same_value = |a: &Value, b: &Value| match (a, b) {
+        (Value::F64(a), Value::F64(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (Value::F32(a), Value::F32(b)) => a == b || (a.is_nan() && b.is_nan()),
+        _ => a == b,
+    };
+    a.security_id == b.security_id
+        && a.exchange == b.exchange
+        && a.symbol == b.symbol
+        && a.kind == b.kind
+        && a.base_currency == b.base_currency
+        && a.quote_currency == b.quote_currency
+        && a.multiplier == b.multiplier
+        && a.state == b.state
+        && a.items.len() == b.items.len()
+        && a.items
+            .iter()
+            .all(|(k, v)| b.items.get(k).map_or(false, |w| same_value(v, w)))
+}
+
+fn item_f64(instr: &Instrument, key: &str) -> Option<f64> {
+    match instr.items.get(key)? {
+        Value::F64(v) => Some(*v),
+        Value::F32(v) => Some(*v as f64),
+        _ => None,
+    }
+}
+
+pub fn diff(prev: Option<&Instrument>, current: &Instrument) -> Vec<ListingChange> {
+    let prev = match prev {
+        Some(prev) => prev,
+        None => {
+            return if current.state == InstState::Expired {
+                vec![]
+            } else {
+                vec![ListingChange::Listed(current.clone())]
+            }
+        }
+    };
+    let mut changes = vec![];
+    if current.state == InstState::Expired && prev.state != InstState::Expired {
+        changes.push(ListingChange::Expired(current.clone()));
+    }
+    let margin = |instr: &Instrument| {
+        (
+            item_f64(instr, LONG_MARGIN_RATIO).unwrap_or_default(),
+            item_f64(instr, SHORT_MARGIN_RATIO).unwrap_or_default(),
+        )
+    };
+    if margin(prev) != margin(current) {
+        changes.push(ListingChange::MarginRatio {
+            security_id: current.security_id.clone(),
+            prev: margin(prev),
+            current: margin(current),
+        });
+    }
+    let tick = |instr: &Instrument| item_f64(instr, PRICE_TICK).unwrap_or_default();
+    if tick(prev) != tick(current) {
+        changes.push(ListingChange::PriceTick {
+            security_id: current.security_id.clone(),
+            prev: tick(prev),
+            current: tick(current),
+        });
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_diff() {
+        let rb = Instrument::new()
+            .with_secrity_id("rb2205")
+            .with_state(InstState::Trading)
+            .with_item(LONG_MARGIN_RATIO, Value::F64(0.1))
+            .with_item(SHORT_MARGIN_RATIO, Value::F64(0.1))
+            .with_item(PRICE_TICK, Value::F64(1.0));
+        assert!(matches!(
+            diff(None, &rb).as_slice(),
+            [ListingChange::Listed(_)]
+        ));
+        assert!(diff(Some(&rb), &rb).is_empty());
+
+        let changed = rb
+            .clone()
+            .with_state(InstState::Expired)
+            .with_item(LONG_MARGIN_RATIO, Value::F64(0.15));
+        let changes = diff(Some(&rb), &changed);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(changes[0], ListingChange::Expired(_)));
+        match &changes[1] {
+            ListingChange::MarginRatio { prev, current, .. } => {
+                assert_eq!(*prev, (0.1, 0.1));
+                assert_eq!(*current, (0.15, 0.1));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_same_listing() {
+        let rb = Instrument::new()
+            .with_secrity_id("rb2205")
+            .with_item("StrikePrice", Value::F64(f64::NAN));
+        assert!(same_listing(&rb, &rb.clone()));
+        assert!(!same_listing(
+            &rb,
+            &rb.clone().with_item("StrikePrice", Value::F64(0.0))
+        ));
+        assert!(!same_listing(
+            &rb,
+            &rb.clone().with_state(InstState::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_expire() {
+        let unit = unit("instruments-expire");
+        let store = db::open(&unit).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let token = core::subscribe(INSTRUMENTS_EVENT, move |_, ev| {
+            if let Event::TradeEvent(TradeEvent::ListingChanged(change)) = ev.as_ref() {
+                tx.send(change.clone()).ok();
+            }
+        })
+        .unwrap();
+        let future = |security_id: &str, expire: &str| {
+            Instrument::new()
+                .with_exchange(Exchange::DCE)
+                .with_secrity_id(security_id)
+                .with_state(InstState::Trading)
+                .with_item(EXPIRE_DATE, Value::String(expire.into()))
+        };
+        let listed = vec![
+            future("expire-m2201", "20220114"),
+            future("expire-m2205", "20990513"),
+            future("expire-m2209", "20990915"),
+        ];
+        sync(store.as_ref(), &listed);
+        //再次查询返回相同信息时不重复报告
+        sync(store.as_ref(), &listed[1..]);
+        core::unsubscribe(&token);
+
+        let changes: Vec<(String, bool)> = rx
+            .try_iter()
+            .filter_map(|change| match change {
+                ListingChange::Listed(instr) => Some((instr.security_id, true)),
+                ListingChange::Expired(instr) => Some((instr.security_id, false)),
+                _ => None,
+            })
+            .filter(|(id, _)| id.starts_with("expire-"))
+            .collect();
+        //到期日已过的直接按到期保存，不报告上市
+        assert_eq!(
+            changes,
+            vec![
+                ("expire-m2205".to_string(), true),
+                ("expire-m2209".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            get_instrument("expire-m2201").unwrap().state,
+            InstState::Expired
+        );
+
+        //不在查询结果中的证券视为到期
+        let (tx, rx) = std::sync::mpsc::channel();
+        let token = core::subscribe(INSTRUMENTS_EVENT, move |_, ev| {
+            if let Event::TradeEvent(TradeEvent::ListingChanged(ListingChange::Expired(instr))) =
+                ev.as_ref()
+            {
+                tx.send(instr.security_id.clone()).ok();
+            }
+        })
+        .unwrap();
+        sync(store.as_ref(), &listed[2..]);
+        core::unsubscribe(&token);
+        let expired: Vec<String> = rx
+            .try_iter()
+            .filter(|id| id.starts_with("expire-"))
+            .collect();
+        assert_eq!(expired, vec!["expire-m2205"]);
+        assert_eq!(
+            store
+                .query_one_symbol("expire-m2205")
+                .unwrap()
+                .unwrap()
+                .state,
+            InstState::Expired
+        );
+
+        cleanup(&unit);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::broker::{Depth, Exchange, Level2, OfferKind, Side, TickToOffer, TickToTrade};
+use crate::bus::Token;
+use crate::core::{self, *};
+use ahash::RandomState;
+use anyhow::Result;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+//价格精度，价格按此放大后取整作为价位
+const PRICE_SCALE: f64 = 10000.0;
+
+fn level(price: f64) -> i64 {
+    (price * PRICE_SCALE).round() as i64
+}
+
+//委托方向，买为true
+fn is_bid(side: Side) -> Option<bool> {
+    match side {
+        Side::Buy | Side::Bid | Side::Long => Some(true),
+        Side::Sell | Side::Ask | Side::Short => Some(false),
+        _ => None,
+    }
+}
+
+#[doc = "排队中的委托"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestingOffer {
+    pub id: String,
+    pub time: i64,
+    pub price: f64,
+    pub quantity: f64,
+}
+
+#[doc = "逐笔委托重建的订单簿"]
+#[derive(Debug, Clone)]
+pub struct L3Book {
+    security_id: String,
+    exchange: Exchange,
+    time: i64,
+    //价位 => 按时间优先排队的委托
+    bids: BTreeMap<i64, VecDeque<RestingOffer>>,
+    asks: BTreeMap<i64, VecDeque<RestingOffer>>,
+    //委托编号 => (买方,价位)
+    index: HashMap<String, (bool, i64)>,
+}
+
+impl L3Book {
+    pub fn new<S: Into<String>>(security_id: S, exchange: Exchange) -> Self {
+        Self {
+            security_id: security_id.into(),
+            exchange,
+            time: 0,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn security_id(&self) -> &str {
+        &self.security_id
+    }
+
+    //委托笔数
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    fn side_mut(&mut self, bid: bool) -> &mut BTreeMap<i64, VecDeque<RestingOffer>> {
+        if bid {
+            &mut self.bids
+        } else {
+            &mut self.asks
+        }
+    }
+
+    fn side(&self, bid: bool) -> &BTreeMap<i64, VecDeque<RestingOffer>> {
+        if bid {
+            &self.bids
+        } else {
+            &self.asks
+        }
+    }
+
+    //本方最优价位
+    fn best(&self, bid: bool) -> Option<i64> {
+        if bid {
+            self.bids.keys().next_back().copied()
+        } else {
+            self.asks.keys().next().copied()
+        }
+    }
+
+    //逐笔委托：限价及本方最优挂单，撤单减少或删除，市价委托不进入订单簿；返回订单簿是否变化
+    pub fn on_offer(&mut self, offer: &TickToOffer) -> bool {
+        self.time = offer.time;
+        if offer.kind == OfferKind::Cancel {
+            return self.reduce(&offer.id, offer.quantity);
+        }
+        let bid = match is_bid(offer.side) {
+            Some(bid) => bid,
+            None => return false,
+        };
+        let price = match offer.kind {
+            OfferKind::Limit if !offer.price.is_nan() => level(offer.price),
+            OfferKind::BestOwn => match self.best(bid) {
+                Some(price) => price,
+                None => return false,
+            },
+            _ => return false,
+        };
+        if offer.quantity <= 0.0 || self.index.contains_key(&offer.id) {
+            return false;
+        }
+        self.index.insert(offer.id.clone(), (bid, price));
+        self.side_mut(bid)
+            .entry(price)
+            .or_default()
+            .push_back(RestingOffer {
+                id: offer.id.clone(),
+                time: offer.time,
+                price: price as f64 / PRICE_SCALE,
+                quantity: offer.quantity,
+            });
+        true
+    }
+
+    //逐笔成交：减少买卖双方委托的剩余数量
+    pub fn on_trade(&mut self, trade: &TickToTrade) -> bool {
+        self.time = trade.time;
+        let mut changed = false;
+        for id in [&trade.take_order_id, &trade.make_order_id]
+            .iter()
+            .filter_map(|id| id.as_ref())
+        {
+            changed |= self.reduce(id, trade.quantity);
+        }
+        changed
+    }
+
+    //减少委托数量，数量无效或不小于剩余数量时删除
+    fn reduce(&mut self, id: &str, quantity: f64) -> bool {
+        let (bid, price) = match self.index.get(id) {
+            Some(v) => *v,
+            None => return false,
+        };
+        let side = self.side_mut(bid);
+        let queue = match side.get_mut(&price) {
+            Some(queue) => queue,
+            None => return false,
+        };
+        let pos = match queue.iter().position(|o| o.id == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let remove = quantity.is_nan() || quantity >= queue[pos].quantity;
+        if remove {
+            queue.remove(pos);
+            if queue.is_empty() {
+                side.remove(&price);
+            }
+            self.index.remove(id);
+        } else {
+            queue[pos].quantity -= quantity;
+        }
+        true
+    }
+
+    pub fn get(&self, id: &str) -> Option<&RestingOffer> {
+        let (bid, price) = self.index.get(id)?;
+        self.side(*bid).get(price)?.iter().find(|o| o.id == id)
+    }
+
+    //价位上按时间优先排队的委托
+    pub fn queue(&self, side: Side, price: f64) -> Vec<RestingOffer> {
+        is_bid(side)
+            .and_then(|bid| self.side(bid).get(&level(price)))
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    //委托的排队位置，返回(前面的委托笔数,前面的委托数量)
+    pub fn position(&self, id: &str) -> Option<(usize, f64)> {
+        let (bid, price) = self.index.get(id)?;
+        let queue = self.side(*bid).get(price)?;
+        let pos = queue.iter().position(|o| o.id == id)?;
+        Some((pos, queue.iter().take(pos).map(|o| o.quantity).sum()))
+    }
+
+    //新委托在价位上需要等待的(委托笔数,委托数量)
+    pub fn ahead(&self, side: Side, price: f64) -> (usize, f64) {
+        let queue = self.queue(side, price);
+        (queue.len(), queue.iter().map(|o| o.quantity).sum())
+    }
+
+    fn depth(queue: &VecDeque<RestingOffer>, price: i64) -> Depth {
+        let price = price as f64 / PRICE_SCALE;
+        let quantity: f64 = queue.iter().map(|o| o.quantity).sum();
+        (price, quantity, queue.len() as f64, price * quantity)
+    }
+
+    //按价位汇总的深度，价、量、委托笔数、委托额
+    pub fn level2(&self, depth: usize) -> Level2 {
+        Level2 {
+            security_id: self.security_id.clone(),
+            exchange: self.exchange,
+            time: self.time,
+            bids: self
+                .bids
+                .iter()
+                .rev()
+                .take(depth)
+                .map(|(p, q)| Self::depth(q, *p))
+                .collect(),
+            asks: self
+                .asks
+                .iter()
+                .take(depth)
+                .map(|(p, q)| Self::depth(q, *p))
+                .collect(),
+        }
+    }
+}
+
+#[doc = "按逐笔委托和逐笔成交重建订单簿，发布N档深度"]
+pub struct L3Books {
+    depth: usize,
+    books: DashMap<String, L3Book, RandomState>,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl L3Books {
+    pub fn new(depth: usize) -> Arc<Self> {
+        Arc::new(Self {
+            depth: depth.max(1),
+            books: DashMap::with_hasher(RandomState::new()),
+            tokens: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn level2(&self, security_id: &str) -> Option<Level2> {
+        self.books
+            .get(security_id)
+            .map(|book| book.level2(self.depth))
+    }
+
+    pub fn queue(&self, security_id: &str, side: Side, price: f64) -> Vec<RestingOffer> {
+        self.books
+            .get(security_id)
+            .map(|book| book.queue(side, price))
+            .unwrap_or_default()
+    }
+
+    pub fn position(&self, security_id: &str, id: &str) -> Option<(usize, f64)> {
+        self.books.get(security_id)?.position(id)
+    }
+
+    pub fn ahead(&self, security_id: &str, side: Side, price: f64) -> (usize, f64) {
+        self.books
+            .get(security_id)
+            .map(|book| book.ahead(side, price))
+            .unwrap_or_default()
+    }
+
+    //清空证券的订单簿，如交易日切换或行情重连
+    pub fn clear(&self, security_id: &str) {
+        self.books.remove(security_id);
+    }
+
+    pub fn on_offer(&self, offer: &TickToOffer) -> Option<Level2> {
+        let mut book = self
+            .books
+            .entry(offer.security_id.clone())
+            .or_insert_with(|| L3Book::new(offer.security_id.clone(), offer.exchange));
+        if book.on_offer(offer) {
+            Some(book.level2(self.depth))
+        } else {
+            None
+        }
+    }
+
+    pub fn on_trade(&self, trade: &TickToTrade) -> Option<Level2> {
+        let mut book = self.books.get_mut(&trade.security_id)?;
+        if book.on_trade(trade) {
+            Some(book.level2(self.depth))
+        } else {
+            None
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let books = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            let level2 = match ev.as_ref() {
+                Event::QuoteEvent(QuoteEvent::TickToOffer(offer)) => books.on_offer(offer),
+                Event::QuoteEvent(QuoteEvent::TickToTrade(trade)) => books.on_trade(trade),
+                _ => None,
+            };
+            if let Some(level2) = level2 {
+                if let Err(err) = core::quotes_event(QuoteEvent::Level2(level2)) {
+                    log::error!("publish order book error {}", err);
+                }
+            }
+        })?;
+        self.tokens.lock().push(token);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(id: &str, side: Side, price: f64, quantity: f64, kind: OfferKind) -> TickToOffer {
+        TickToOffer {
+            security_id: "000001".into(),
+            exchange: Exchange::SZE,
+            time: 1647241200,
+            side,
+            price,
+            quantity,
+            bids: None,
+            asks: None,
+            id: id.into(),
+            kind,
+        }
+    }
+
+    fn trade(buy: &str, sell: &str, price: f64, quantity: f64) -> TickToTrade {
+        TickToTrade {
+            security_id: "000001".into(),
+            exchange: Exchange::SZE,
+            id: format!("{}-{}", buy, sell),
+            time: 1647241201,
+            price,
+            quantity,
+            order_side: None,
+            into_side: None,
+            take_order_id: Some(buy.into()),
+            make_order_id: Some(sell.into()),
+        }
+    }
+
+    #[test]
+    fn test_l3_book() {
+        let mut book = L3Book::new("000001", Exchange::SZE);
+        assert!(book.on_offer(&offer("1", Side::Buy, 15.5, 100.0, OfferKind::Limit)));
+        assert!(book.on_offer(&offer("2", Side::Buy, 15.5, 200.0, OfferKind::Limit)));
+        assert!(book.on_offer(&offer("3", Side::Buy, 15.4, 300.0, OfferKind::Limit)));
+        assert!(book.on_offer(&offer("4", Side::Sell, 15.6, 500.0, OfferKind::Limit)));
+        //本方最优价挂在买一
+        assert!(book.on_offer(&offer("5", Side::Buy, 0.0, 50.0, OfferKind::BestOwn)));
+        assert!(!book.on_offer(&offer("6", Side::Sell, 0.0, 50.0, OfferKind::Market)));
+        assert_eq!(book.position("5"), Some((2, 300.0)));
+        assert_eq!(book.ahead(Side::Buy, 15.5), (3, 350.0));
+
+        //卖单主动成交，吃掉买一的第一笔及第二笔的一部分
+        assert!(book.on_offer(&offer("7", Side::Sell, 15.5, 150.0, OfferKind::Limit)));
+        book.on_trade(&trade("1", "7", 15.5, 100.0));
+        book.on_trade(&trade("2", "7", 15.5, 50.0));
+        assert!(book.get("1").is_none());
+        assert!(book.get("7").is_none());
+        assert_eq!(book.get("2").unwrap().quantity, 150.0);
+        assert_eq!(book.position("5"), Some((1, 150.0)));
+
+        //撤单
+        assert!(book.on_offer(&offer("2", Side::Buy, 0.0, 150.0, OfferKind::Cancel)));
+        assert_eq!(book.position("5"), Some((0, 0.0)));
+
+        let level2 = book.level2(5);
+        assert_eq!(level2.bids[0], (15.5, 50.0, 1.0, 15.5 * 50.0));
+        assert_eq!(level2.bids[1].0, 15.4);
+        assert_eq!(level2.asks[0].1, 500.0);
+        assert_eq!(book.len(), 3);
+    }
+}
@@ -1,16 +1,61 @@
+pub mod analytics;
+pub mod bars;
+pub mod book;
+pub mod continuous;
+pub mod db;
+pub mod engines;
 pub mod events;
+pub mod instruments;
+pub mod l3book;
 pub mod qbox;
+pub mod quotes;
+pub mod settings;
+pub mod synthetic;
 pub mod topics;
 
 pub use events::*;
 pub use topics::*;
 
+use crate::bus::Token;
 use anyhow::Result;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+//启动单元配置项，默认qbox
+const UNIT_KEY: &str = "QBOX_UNIT";
+
+//启动时订阅的证券列表事件及按单元设置启动的引擎，关闭时停止
+static STARTED: Lazy<Mutex<Option<(Token, engines::Engines)>>> = Lazy::new(|| Mutex::new(None));
+
+//启动单元
+pub fn unit() -> Result<String> {
+    Ok(crate::setting::get_with_default::<String>(
+        UNIT_KEY, "qbox",
+    )?)
+}
 
 pub fn startup() -> Result<()> {
     //启动总线
     log::debug!("qbox events startup");
     broadcast(Event::Startup)?;
+    //启动行情缓存
+    quotes::init()?;
+    let unit = unit()?;
+    let mut started = STARTED.lock();
+    if started.is_none() {
+        //加载证券列表，保存查询返回的证券
+        log::debug!("qbox instruments startup {}", unit);
+        let token = instruments::init(&unit)?;
+        //启动单元配置的行情引擎
+        log::debug!("qbox engines startup {}", unit);
+        match engines::Engines::start(&unit) {
+            Ok(engines) => *started = Some((token, engines)),
+            Err(err) => {
+                unsubscribe(&token);
+                return Err(err);
+            }
+        }
+    }
     // log::debug!("qbox database startup");
     // //启动数据库
     // crate::db::startup()?;
@@ -18,5 +63,9 @@ pub fn startup() -> Result<()> {
 }
 
 pub fn shutdown() -> Result<()> {
+    if let Some((token, mut engines)) = STARTED.lock().take() {
+        engines.stop();
+        unsubscribe(&token);
+    }
     broadcast(Event::Shutdown)
 }
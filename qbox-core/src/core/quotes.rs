@@ -1,56 +1,114 @@
-use crate::broker::{
-    Bar, Instrument, Level1, Level2, QuoteEvent, TickToOffer, TickToTrade, TradeEvent,
-};
+use crate::broker::{Bar, Level1, Level2, Period, TickToOffer, TickToTrade};
+use crate::bus::Token;
 use crate::core::{self, *};
-use crate::db::sqlite;
+use crate::db::memory::Capacity;
+use crate::ring::Ring;
 use ahash::RandomState;
 use anyhow::Result;
 use crossbeam::channel::{self, Receiver};
 use dashmap::DashMap;
 use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
-const MAX_BAR_SIZE: usize = 1000;
-const MAX_TTO_SIZE: usize = 100;
-const MAX_TTT_SIZE: usize = 100;
+type Callback = Box<dyn Fn(&str, Arc<Event>) + Send + Sync>;
+
+//先快照后增量的订阅者
+struct Subscriber {
+    id: String,
+    //空为全部证券
+    securities: HashSet<String>,
+    f: Callback,
+    //待投递事件，快照先于增量入队
+    queue: Mutex<VecDeque<(String, Arc<Event>)>>,
+    //正在投递，同一时刻只有一个线程按入队顺序调用回调
+    delivering: AtomicBool,
+}
+
+impl Subscriber {
+    fn matches(&self, security_id: &str) -> bool {
+        self.securities.is_empty() || self.securities.contains(security_id)
+    }
+
+    fn push(&self, topic: &str, ev: Arc<Event>) {
+        self.queue.lock().push_back((topic.into(), ev));
+    }
+
+    //调用回调时不持锁，回调中发布行情只入队，由当前投递线程随后送达
+    fn deliver(&self) {
+        loop {
+            if self
+                .delivering
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                return;
+            }
+            loop {
+                let next = self.queue.lock().pop_front();
+                match next {
+                    Some((topic, ev)) => (self.f)(&topic, ev),
+                    None => break,
+                }
+            }
+            self.delivering.store(false, Ordering::SeqCst);
+            //释放投递权前入队的事件
+            if self.queue.lock().is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
 lazy_static! {
-    //图表
-    static ref BARS: DashMap<String, Vec<Bar>,RandomState> = DashMap::with_hasher(RandomState::new());
+    //快照订阅者，更新缓存时持读锁，订阅时持写锁，保证快照与增量之间不缺不重
+    static ref SUBSCRIBERS: RwLock<Vec<Arc<Subscriber>>> = RwLock::new(vec![]);
+    //缓存条数
+    static ref CAPACITY: Capacity = Capacity::from_env();
+    //图表，按(证券,周期)分别缓存
+    static ref BARS: DashMap<(String, Period), Ring<Bar>,RandomState> = DashMap::with_hasher(RandomState::new());
     //LEVEL1行情
     static ref LEVEL1S: DashMap<String, Level1,RandomState> = DashMap::with_hasher(RandomState::new());
     //深度行情
     static ref DEPTHS: DashMap<String, Level2,RandomState> = DashMap::with_hasher(RandomState::new());
     //逐笔委托
-    static ref TTOS: DashMap<String, Vec<TickToOffer>,RandomState> = DashMap::with_hasher(RandomState::new());
+    static ref TTOS: DashMap<String, Ring<TickToOffer>,RandomState> = DashMap::with_hasher(RandomState::new());
     //逐笔成交
-    static ref TTTS: DashMap<String, Vec<TickToTrade>,RandomState> = DashMap::with_hasher(RandomState::new());
-    //证券列表
-    static ref INSTRUMENTS: DashMap<String, Instrument,RandomState> = DashMap::with_hasher(RandomState::new());
+    static ref TTTS: DashMap<String, Ring<TickToTrade>,RandomState> = DashMap::with_hasher(RandomState::new());
 }
 
-pub fn get_bar(security_id: &String) -> Option<Vec<Bar>> {
-    if let Some(bars) = BARS.get(security_id) {
-        Some(bars.value().clone())
-    } else {
-        None
-    }
+//k线快照，只复制引用
+pub fn get_bar(security_id: &str, period: Period) -> Option<Vec<Arc<Bar>>> {
+    with_bar(security_id, period, |bars| bars.snapshot())
 }
 
-pub fn get_tick2offer(security_id: &String) -> Option<Vec<TickToOffer>> {
-    if let Some(ttos) = TTOS.get(security_id) {
-        Some(ttos.value().clone())
-    } else {
-        None
-    }
+//在缓存上直接读取k线，不复制数据
+pub fn with_bar<R>(
+    security_id: &str,
+    period: Period,
+    f: impl FnOnce(&Ring<Bar>) -> R,
+) -> Option<R> {
+    BARS.get(&(security_id.to_string(), period))
+        .map(|bars| f(bars.value()))
 }
 
-pub fn get_tick2trade(security_id: &String) -> Option<Vec<TickToTrade>> {
-    if let Some(ttts) = TTTS.get(security_id) {
-        Some(ttts.value().clone())
-    } else {
-        None
-    }
+fn push_bar(bar: Bar) {
+    BARS.entry((bar.security_id.clone(), bar.period))
+        .or_insert_with(|| Ring::new(CAPACITY.bars))
+        .push(bar);
+}
+
+pub fn get_tick2offer(security_id: &str) -> Option<Vec<Arc<TickToOffer>>> {
+    TTOS.get(security_id).map(|ttos| ttos.snapshot())
+}
+
+pub fn get_tick2trade(security_id: &str) -> Option<Vec<Arc<TickToTrade>>> {
+    TTTS.get(security_id).map(|ttts| ttts.snapshot())
 }
 
 pub fn get_level1(security_id: &String) -> Option<Level1> {
@@ -98,148 +156,164 @@ pub fn find_level1_with_prefixs(prefixs: &[&str]) -> Option<Vec<Level1>> {
     }
 }
 
-pub fn get_instrument(security_id: &String) -> Option<Instrument> {
-    if let Some(level1) = INSTRUMENTS.get(security_id) {
-        Some(level1.value().clone())
-    } else {
-        None
+//更新缓存，返回事件所属的证券及由事件生成的行情
+fn update(
+    quote: &QuoteEvent,
+    tx: &channel::Sender<Arc<Event>>,
+) -> Option<(String, Option<Arc<Event>>)> {
+    match quote {
+        QuoteEvent::Level1(level1) => {
+            LEVEL1S.insert(level1.security_id.clone(), level1.clone());
+            let bar = level1.to_bar();
+            push_bar(bar.clone());
+            let bar = Event::QuoteEvent(QuoteEvent::Bar(bar)).arced();
+            tx.send(bar.clone()).ok();
+            Some((level1.security_id.clone(), Some(bar)))
+        }
+        QuoteEvent::Bar(bar) => {
+            push_bar(bar.clone());
+            Some((bar.security_id.clone(), None))
+        }
+        QuoteEvent::Level2(level2) => {
+            DEPTHS.insert(level2.security_id.clone(), level2.clone());
+            Some((level2.security_id.clone(), None))
+        }
+        QuoteEvent::TickToOffer(tto) => {
+            TTOS.entry(tto.security_id.clone())
+                .or_insert_with(|| Ring::new(CAPACITY.offers))
+                .push(tto.clone());
+            Some((tto.security_id.clone(), None))
+        }
+        QuoteEvent::TickToTrade(ttt) => {
+            TTTS.entry(ttt.security_id.clone())
+                .or_insert_with(|| Ring::new(CAPACITY.trades))
+                .push(ttt.clone());
+            Some((ttt.security_id.clone(), None))
+        }
+        QuoteEvent::BarUpdated(bar) => Some((bar.security_id.clone(), None)),
+        QuoteEvent::DepthUpdate(v) => Some((v.security_id.clone(), None)),
+        QuoteEvent::TopOfOrderBook(v) => Some((v.security_id.clone(), None)),
+        QuoteEvent::Stale(v) => Some((v.security_id.clone(), None)),
+        QuoteEvent::Analytics(v) => Some((v.security_id.clone(), None)),
+        _ => None,
     }
 }
 
-pub fn get_all_instrument() -> Option<Vec<Instrument>> {
-    let data: Vec<Instrument> = INSTRUMENTS.iter().map(|v| v.value().clone()).collect();
-    if data.len() > 0 {
-        Some(data)
-    } else {
-        None
+//启动行情缓存，重复调用无副作用
+pub(crate) fn init() -> Result<()> {
+    if INITIALIZED.swap(true, Ordering::SeqCst) {
+        return Ok(());
     }
+    let (tx, rx) = channel::bounded(8192);
+    quote_worker(rx)?;
+    core::subscribe(topics::QUOTES_EVENT, move |topic, ev| {
+        if let Event::QuoteEvent(quote) = ev.as_ref() {
+            //持读锁入队，保证与订阅时的快照不缺不重
+            let targets: Vec<Arc<Subscriber>> = {
+                let subscribers = SUBSCRIBERS.read();
+                match update(quote, &tx) {
+                    Some((security_id, derived)) => subscribers
+                        .iter()
+                        .filter(|s| s.matches(&security_id))
+                        .map(|s| {
+                            s.push(topic, ev.clone());
+                            if let Some(derived) = &derived {
+                                s.push(topic, derived.clone());
+                            }
+                            s.clone()
+                        })
+                        .collect(),
+                    None => vec![],
+                }
+            };
+            for target in targets {
+                target.deliver();
+            }
+        }
+    })?;
+    Ok(())
 }
 
-pub fn find_instrument_with_prefix(prefix: &str) -> Option<Vec<Instrument>> {
-    let data: Vec<Instrument> = INSTRUMENTS
-        .iter()
-        .filter(|v| v.key().starts_with(prefix))
-        .map(|v| v.value().clone())
-        .collect();
-    if data.len() > 0 {
-        Some(data)
+//证券的当前缓存：基本行情、深度行情及各周期最新k线；securities为空时为全部证券
+fn snapshot(securities: &[String]) -> Vec<QuoteEvent> {
+    let wanted: HashSet<&str> = securities.iter().map(|s| s.as_str()).collect();
+    let mut bars: HashMap<String, Vec<Bar>> = HashMap::new();
+    for item in BARS.iter() {
+        let (security_id, _) = item.key();
+        if wanted.is_empty() || wanted.contains(security_id.as_str()) {
+            if let Some(bar) = item.value().last() {
+                bars.entry(security_id.clone())
+                    .or_insert_with(Vec::new)
+                    .push(bar.clone());
+            }
+        }
+    }
+    let securities: Vec<String> = if securities.is_empty() {
+        LEVEL1S
+            .iter()
+            .map(|item| item.key().clone())
+            .chain(DEPTHS.iter().map(|item| item.key().clone()))
+            .chain(bars.keys().cloned())
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect()
     } else {
-        None
+        securities.to_vec()
+    };
+    let mut events = vec![];
+    for security_id in securities {
+        if let Some(level1) = LEVEL1S.get(&security_id) {
+            events.push(QuoteEvent::Level1(level1.value().clone()));
+        }
+        if let Some(level2) = DEPTHS.get(&security_id) {
+            events.push(QuoteEvent::Level2(level2.value().clone()));
+        }
+        if let Some(mut latest) = bars.remove(&security_id) {
+            latest.sort_by_key(|bar| bar.time);
+            events.extend(latest.into_iter().map(QuoteEvent::Bar));
+        }
     }
+    events
 }
 
-pub fn find_instrument_with_prefixs(prefixs: &[&str]) -> Option<Vec<Instrument>> {
-    let data: Vec<Vec<Instrument>> = prefixs
-        .iter()
-        .filter_map(|prefix| find_instrument_with_prefix(prefix))
-        .collect();
-    if data.len() > 0 {
-        Some(data.concat())
-    } else {
-        None
+//订阅行情，先送达请求证券的当前缓存，再转为实时增量；securities为空时订阅全部证券
+pub fn subscribe(
+    securities: &[String],
+    f: impl Fn(&str, Arc<Event>) + Send + Sync + 'static,
+) -> Result<Token> {
+    init()?;
+    let subscriber = Arc::new(Subscriber {
+        id: format!("snapshot-{}", NEXT_ID.fetch_add(1, Ordering::SeqCst)),
+        securities: securities.iter().cloned().collect(),
+        f: Box::new(f),
+        queue: Mutex::new(VecDeque::new()),
+        delivering: AtomicBool::new(false),
+    });
+    {
+        let mut subscribers = SUBSCRIBERS.write();
+        for ev in snapshot(securities) {
+            subscriber.push(topics::QUOTES_EVENT, Event::QuoteEvent(ev).arced());
+        }
+        subscribers.push(subscriber.clone());
     }
+    subscriber.deliver();
+    Ok(Token {
+        topic: topics::QUOTES_EVENT.into(),
+        id: subscriber.id.clone(),
+    })
 }
 
-pub(crate) fn init() -> Result<()> {
-    let (tx, rx) = channel::bounded(8192);
-    let tx1 = tx.clone();
-    let tx2 = tx.clone();
-    quote_worker(rx)?;
-    let _ = core::subscribe(topics::QUOTES_EVENT, move |_, ev| {
-        match ev.as_ref() {
-            Event::Quote(quote) => match quote {
-                QuoteEvent::Level1(level1) => {
-                    {
-                        LEVEL1S.insert(level1.security_id.clone(), level1.clone());
-                    }
-                    let bar = level1.to_bar();
-                    if let Some(mut bars) = BARS.get_mut(&bar.security_id) {
-                        bars.value_mut().push(bar.clone());
-                        if bars.len() > MAX_BAR_SIZE {
-                            bars.remove(0);
-                        }
-                    } else {
-                        let mut bars = Vec::with_capacity(MAX_BAR_SIZE);
-                        bars.push(bar.clone());
-                        BARS.insert(bar.security_id.clone(), bars);
-                    }
-                    tx1.send(Event::Quote(QuoteEvent::Bar(bar)).arced()).ok();
-                }
-                QuoteEvent::Bar(bar) => {
-                    if let Some(mut bars) = BARS.get_mut(&bar.security_id) {
-                        bars.value_mut().push(bar.clone());
-                        if bars.len() > MAX_BAR_SIZE {
-                            bars.remove(0);
-                        }
-                    } else {
-                        let mut bars = Vec::with_capacity(MAX_BAR_SIZE);
-                        bars.push(bar.clone());
-                        BARS.insert(bar.security_id.clone(), bars);
-                    }
-                }
-                QuoteEvent::Level2(level2) => {
-                    DEPTHS.insert(level2.security_id.clone(), level2.clone());
-                }
-                QuoteEvent::TickToOffer(tto) => {
-                    if let Some(mut ttos) = TTOS.get_mut(&tto.security_id) {
-                        ttos.value_mut().push(tto.clone());
-                        if ttos.len() > MAX_TTO_SIZE {
-                            ttos.remove(0);
-                        }
-                    } else {
-                        let mut ttos = Vec::with_capacity(MAX_TTO_SIZE);
-                        ttos.push(tto.clone());
-                        TTOS.insert(tto.security_id.clone(), ttos);
-                    }
-                }
-                QuoteEvent::TickToTrade(ttt) => {
-                    if let Some(mut ttts) = TTTS.get_mut(&ttt.security_id) {
-                        ttts.value_mut().push(ttt.clone());
-                        if ttts.len() > MAX_TTT_SIZE {
-                            ttts.remove(0);
-                        }
-                    } else {
-                        let mut ttts = Vec::with_capacity(MAX_TTT_SIZE);
-                        ttts.push(ttt.clone());
-                        TTTS.insert(ttt.security_id.clone(), ttts);
-                    }
-                }
-            },
-            _ => {}
-        }
-        // tx1.send(ev).ok();
-    })?;
-    let _ = core::subscribe(topics::QUERY_EVENT, move |_, ev| {
-        match ev.as_ref() {
-            Event::Trade(TradeEvent::InstrumentsResponse(instr)) => {
-                INSTRUMENTS.insert(instr.security_id.clone(), instr.clone());
-            }
-            _ => {}
-        }
-        tx2.send(ev).ok();
-    })?;
-    Ok(())
+pub fn unsubscribe(token: &Token) {
+    SUBSCRIBERS.write().retain(|s| s.id != token.id);
 }
 
 fn quote_worker(rx: Receiver<Arc<Event>>) -> Result<()> {
-    // let db = sqlite::opendb()?;
-    // sqlite::find_all_instruments(&db)?.iter().for_each(|instr| {
-    //     INSTRUMENTS.insert(instr.security_id.clone(), instr.clone());
-    // });
     std::thread::Builder::new()
         .name("qbox-quote-worker".into())
         .spawn(move || loop {
             match rx.recv() {
                 Ok(ev) => {
                     log::trace!("process {:?}", ev);
-                    match ev.as_ref() {
-                        Event::Trade(TradeEvent::InstrumentsResponse(instr)) => {
-                            // if let Err(err) = sqlite::insert_instrument(&db, instr) {
-                            //     log::error!("sqlite error {:?}", err);
-                            // }
-                        }
-                        _ => {}
-                    }
                 }
                 Err(err) => {
                     log::error!("!!!!!!!!!! {:?}", err);
@@ -249,3 +323,122 @@ fn quote_worker(rx: Receiver<Arc<Event>>) -> Result<()> {
         .ok();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe() {
+        init().unwrap();
+        let security_id = "snapshot-600000".to_string();
+        let mut level1 = Level1::new();
+        level1.security_id = security_id.clone();
+        level1.time = 1;
+        level1.last = 10.0;
+        core::quotes_event(QuoteEvent::Level1(level1.clone())).unwrap();
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let list = received.clone();
+        let token = subscribe(&[security_id], move |_, ev| {
+            if let Event::QuoteEvent(quote) = ev.as_ref() {
+                let kind = match quote {
+                    QuoteEvent::Level1(v) => format!("level1 {}", v.time),
+                    QuoteEvent::Bar(v) => format!("bar {}", v.time),
+                    _ => "other".into(),
+                };
+                list.lock().push(kind);
+            }
+        })
+        .unwrap();
+        //先送达缓存
+        assert_eq!(*received.lock(), vec!["level1 1", "bar 1"]);
+
+        //基本行情生成的k线随增量送达
+        level1.time = 2;
+        core::quotes_event(QuoteEvent::Level1(level1.clone())).unwrap();
+        assert_eq!(
+            *received.lock(),
+            vec!["level1 1", "bar 1", "level1 2", "bar 2"]
+        );
+
+        unsubscribe(&token);
+        level1.time = 3;
+        core::quotes_event(QuoteEvent::Level1(level1)).unwrap();
+        assert_eq!(received.lock().len(), 4);
+    }
+
+    #[test]
+    fn test_publish_in_callback() {
+        init().unwrap();
+        let security_id = "snapshot-600001".to_string();
+        let mut level1 = Level1::new();
+        level1.security_id = security_id.clone();
+        level1.time = 1;
+        level1.last = 10.0;
+        core::quotes_event(QuoteEvent::Level1(level1.clone())).unwrap();
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let list = received.clone();
+        //回调中发布同一证券的行情，不死锁且按顺序送达
+        let token = subscribe(&[security_id], move |_, ev| {
+            if let Event::QuoteEvent(QuoteEvent::Level1(v)) = ev.as_ref() {
+                list.lock().push(v.time);
+                if v.time < 3 {
+                    let mut next = v.clone();
+                    next.time += 1;
+                    core::quotes_event(QuoteEvent::Level1(next)).unwrap();
+                }
+            }
+        })
+        .unwrap();
+        assert_eq!(*received.lock(), vec![1, 2, 3]);
+        unsubscribe(&token);
+    }
+
+    #[test]
+    fn test_bar_periods() {
+        init().unwrap();
+        let security_id = "snapshot-600002".to_string();
+        for (time, period) in [
+            (60, Period::Minute(1)),
+            (120, Period::Minute(1)),
+            (300, Period::Minute(5)),
+            (180, Period::Minute(1)),
+        ]
+        .iter()
+        {
+            let mut bar = Level1::new().to_bar();
+            bar.security_id = security_id.clone();
+            bar.period = *period;
+            bar.time = *time;
+            core::quotes_event(QuoteEvent::Bar(bar)).unwrap();
+        }
+        let times = |period| {
+            get_bar(&security_id, period)
+                .unwrap()
+                .iter()
+                .map(|bar| bar.time)
+                .collect::<Vec<_>>()
+        };
+        //各周期分别缓存，互不挤占
+        assert_eq!(times(Period::Minute(1)), vec![60, 120, 180]);
+        assert_eq!(times(Period::Minute(5)), vec![300]);
+        assert!(get_bar(&security_id, Period::Day(1)).is_none());
+
+        //快照为各周期最新k线
+        let received = Arc::new(Mutex::new(vec![]));
+        let list = received.clone();
+        let token = subscribe(&[security_id], move |_, ev| {
+            if let Event::QuoteEvent(QuoteEvent::Bar(v)) = ev.as_ref() {
+                list.lock().push((v.period, v.time));
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            *received.lock(),
+            vec![(Period::Minute(1), 180), (Period::Minute(5), 300)]
+        );
+        unsubscribe(&token);
+    }
+}
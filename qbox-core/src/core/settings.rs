@@ -0,0 +1,270 @@
+use crate::bus::Token;
+use crate::core::{self, *};
+use crate::db::{self, Store};
+use ahash::RandomState;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+//键为settings/<命名空间>/<名称>，值为ron文本
+const SETTINGS_PREFIX: &str = "settings/";
+
+type Validator = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+lazy_static! {
+    //(命名空间,名称) => 校验
+    static ref VALIDATORS: DashMap<(String, String), Validator, RandomState> = DashMap::with_hasher(RandomState::new());
+}
+
+#[doc = "设置变化，value为ron文本，删除时为None"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettingChange {
+    pub unit: String,
+    pub namespace: String,
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl SettingChange {
+    pub fn value<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        match &self.value {
+            Some(v) => Ok(Some(ron::from_str(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+//注册设置校验，写入前按目标类型解析后校验
+pub fn validate<T, F>(namespace: &str, key: &str, f: F)
+where
+    T: DeserializeOwned,
+    F: Fn(&T) -> Result<()> + Send + Sync + 'static,
+{
+    let validator: Validator = Box::new(move |v| f(&ron::from_str::<T>(v)?));
+    VALIDATORS.insert((namespace.into(), key.into()), validator);
+}
+
+fn check(namespace: &str, key: &str, value: &str) -> Result<()> {
+    match VALIDATORS.get(&(namespace.to_string(), key.to_string())) {
+        Some(validator) => validator.value()(value)
+            .map_err(|err| anyhow!("invalid setting {}/{}: {}", namespace, key, err)),
+        None => Ok(()),
+    }
+}
+
+#[doc = "单元内一个命名空间的类型化设置"]
+#[derive(Clone)]
+pub struct Settings {
+    unit: String,
+    namespace: String,
+    store: Arc<dyn Store>,
+}
+
+impl Settings {
+    pub fn open<S: AsRef<str>>(unit: S, namespace: &str) -> Result<Self> {
+        if namespace.is_empty() || namespace.contains('/') {
+            return Err(anyhow!("invalid settings namespace {:?}", namespace));
+        }
+        Ok(Self {
+            unit: unit.as_ref().into(),
+            namespace: namespace.into(),
+            store: db::open(unit)?,
+        })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn prefix(&self) -> String {
+        format!("{}{}/", SETTINGS_PREFIX, self.namespace)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.store.get(&format!("{}{}", self.prefix(), key))? {
+            Some(v) => Ok(Some(ron::from_str(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> Result<T> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    pub fn get_or_default<T: DeserializeOwned + Default>(&self, key: &str) -> Result<T> {
+        Ok(self.get(key)?.unwrap_or_default())
+    }
+
+    //校验后写入，值变化时发布通知
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let value = ron::to_string(value)?;
+        check(&self.namespace, key, &value)?;
+        let k = format!("{}{}", self.prefix(), key);
+        if self.store.get(&k)?.as_deref() == Some(value.as_str()) {
+            return Ok(());
+        }
+        self.store.set(&k, &value)?;
+        self.notify(key, Some(value))
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let k = format!("{}{}", self.prefix(), key);
+        if self.store.get(&k)?.is_none() {
+            return Ok(());
+        }
+        self.store.remove(&k)?;
+        self.notify(key, None)
+    }
+
+    //命名空间内的全部设置，值为ron文本
+    pub fn all(&self) -> Result<Vec<(String, String)>> {
+        let prefix = self.prefix();
+        Ok(self
+            .store
+            .get_prefix(&prefix)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, v)| (k[prefix.len()..].to_string(), v))
+            .collect())
+    }
+
+    fn notify(&self, key: &str, value: Option<String>) -> Result<()> {
+        core::publish(
+            SETTINGS_EVENT,
+            Event::SettingChanged(SettingChange {
+                unit: self.unit.clone(),
+                namespace: self.namespace.clone(),
+                key: key.into(),
+                value,
+            }),
+        )
+    }
+
+    //订阅本单元本命名空间的设置变化
+    pub fn subscribe(&self, f: impl Fn(&SettingChange) + Send + Sync + 'static) -> Result<Token> {
+        let unit = self.unit.clone();
+        let namespace = self.namespace.clone();
+        core::subscribe(SETTINGS_EVENT, move |_, ev| {
+            if let Event::SettingChanged(change) = ev.as_ref() {
+                if change.unit == unit && change.namespace == namespace {
+                    f(change)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_validate() {
+        validate("risk", "max_lots", |v: &u32| {
+            if *v > 0 && *v <= 100 {
+                Ok(())
+            } else {
+                Err(anyhow!("out of range"))
+            }
+        });
+        assert!(check("risk", "max_lots", &ron::to_string(&10u32).unwrap()).is_ok());
+        assert!(check("risk", "max_lots", &ron::to_string(&0u32).unwrap()).is_err());
+        assert!(check("risk", "max_lots", &ron::to_string(&"ten").unwrap()).is_err());
+        assert!(check("risk", "other", "anything").is_ok());
+
+        let change = SettingChange {
+            unit: "unit".into(),
+            namespace: "risk".into(),
+            key: "max_lots".into(),
+            value: Some(ron::to_string(&10u32).unwrap()),
+        };
+        assert_eq!(change.value::<u32>().unwrap(), Some(10));
+    }
+
+    #[test]
+    fn test_settings() {
+        let unit = unit("settings");
+        let grid = Settings::open(&unit, "grid").unwrap();
+        let risk = Settings::open(&unit, "risk").unwrap();
+        assert!(Settings::open(&unit, "a/b").is_err());
+
+        grid.set("levels", &vec![1.0, 2.0]).unwrap();
+        grid.set("enabled", &true).unwrap();
+        risk.set("levels", &5u32).unwrap();
+        assert_eq!(
+            grid.get::<Vec<f64>>("levels").unwrap(),
+            Some(vec![1.0, 2.0])
+        );
+        assert_eq!(risk.get::<u32>("levels").unwrap(), Some(5));
+        assert_eq!(grid.get::<u32>("missing").unwrap(), None);
+        assert_eq!(grid.get_or("missing", 3u32).unwrap(), 3);
+        //类型不符时报错
+        assert!(grid.get::<u32>("enabled").is_err());
+
+        //命名空间相互隔离
+        let mut keys: Vec<String> = grid.all().unwrap().into_iter().map(|(k, _)| k).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["enabled", "levels"]);
+        assert_eq!(risk.all().unwrap().len(), 1);
+        //同一单元重新打开读取相同设置
+        let reopened = Settings::open(&unit, "grid").unwrap();
+        assert_eq!(reopened.get::<bool>("enabled").unwrap(), Some(true));
+
+        grid.remove("levels").unwrap();
+        assert_eq!(grid.get::<Vec<f64>>("levels").unwrap(), None);
+        assert_eq!(risk.get::<u32>("levels").unwrap(), Some(5));
+        cleanup(&unit);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let unit = unit("settings-events");
+        let grid = Settings::open(&unit, "grid").unwrap();
+        let risk = Settings::open(&unit, "risk").unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let token = grid
+            .subscribe(move |change| {
+                tx.send((change.key.clone(), change.value::<u32>().unwrap()))
+                    .ok();
+            })
+            .unwrap();
+        //全局事件同样发出
+        let (all_tx, all_rx) = std::sync::mpsc::channel();
+        let unit_name = unit.clone();
+        let all = core::subscribe(SETTINGS_EVENT, move |_, ev| {
+            if let Event::SettingChanged(change) = ev.as_ref() {
+                if change.unit == unit_name {
+                    all_tx.send(change.namespace.clone()).ok();
+                }
+            }
+        })
+        .unwrap();
+
+        grid.set("levels", &3u32).unwrap();
+        //值未变化时不通知
+        grid.set("levels", &3u32).unwrap();
+        risk.set("levels", &5u32).unwrap();
+        grid.remove("levels").unwrap();
+        grid.remove("levels").unwrap();
+        core::unsubscribe(&token);
+        core::unsubscribe(&all);
+        grid.set("levels", &4u32).unwrap();
+
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                ("levels".to_string(), Some(3)),
+                ("levels".to_string(), None)
+            ]
+        );
+        assert_eq!(
+            all_rx.try_iter().collect::<Vec<_>>(),
+            vec!["grid", "risk", "grid"]
+        );
+        cleanup(&unit);
+    }
+}
@@ -0,0 +1,588 @@
+use crate::broker::{
+    Bar, Depth, Exchange, InstState, Instrument, Level1, Period, TradeKind, Value,
+};
+use crate::bus::Token;
+use crate::calendar;
+use crate::core::settings::Settings;
+use crate::core::{self, *};
+use crate::core::{continuous, instruments};
+use crate::db::{self, Store};
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+//合成证券定义的设置命名空间，键为合成证券代码，值为表达式
+const SYNTHETIC_NAMESPACE: &str = "synthetic";
+const TRADING_DATE: &str = "trading_date";
+//合成证券的表达式
+const EXPRESSION: &str = "expression";
+
+#[doc = "合成证券的一条腿，比例为负表示卖出"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg {
+    pub security_id: String,
+    pub ratio: f64,
+}
+
+#[doc = "合成证券，如跨期价差rb2205 - rb2210或组合3*rb - 1*i - 0.5*j，只写品种时取连续合约"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Synthetic {
+    pub security_id: String,
+    pub expression: String,
+    pub legs: Vec<Leg>,
+}
+
+impl Synthetic {
+    //表达式为若干项的加减，每项为[比例*]证券代码；加减号须以空格分隔，证券代码中的-不作为运算符，
+    //代码也可加双引号，如"btc-usdt" - 0.5*"eth-usdt"
+    pub fn parse<S: Into<String>>(security_id: S, expression: &str) -> Result<Self> {
+        let security_id = security_id.into();
+        let invalid = || anyhow!("invalid synthetic expression {:?}", expression);
+        //(符号,项)，首项前可带符号
+        let mut terms: Vec<(f64, String)> = vec![];
+        let mut sign: Option<f64> = None;
+        for token in tokenize(expression)? {
+            let op = match token.as_str() {
+                "+" => 1.0,
+                "-" => -1.0,
+                _ => {
+                    //两项之间缺少运算符
+                    if sign.is_none() && !terms.is_empty() {
+                        return Err(invalid());
+                    }
+                    terms.push((sign.take().unwrap_or(1.0), token));
+                    continue;
+                }
+            };
+            //连续的运算符
+            if sign.is_some() {
+                return Err(invalid());
+            }
+            sign = Some(op);
+        }
+        if sign.is_some() {
+            return Err(invalid());
+        }
+        let mut legs: Vec<Leg> = vec![];
+        for (sign, term) in terms {
+            let leg = parse_term(&term, sign)?;
+            match legs.iter_mut().find(|l| l.security_id == leg.security_id) {
+                Some(l) => l.ratio += leg.ratio,
+                None => legs.push(leg),
+            }
+        }
+        legs.retain(|l| l.ratio != 0.0);
+        if legs.is_empty() {
+            return Err(invalid());
+        }
+        if legs.iter().any(|l| l.security_id == security_id) {
+            return Err(anyhow!("synthetic {} refers to itself", security_id));
+        }
+        Ok(Self {
+            security_id,
+            expression: expression.trim().into(),
+            legs,
+        })
+    }
+
+    pub fn contains(&self, security_id: &str) -> bool {
+        self.legs.iter().any(|l| l.security_id == security_id)
+    }
+
+    pub fn instrument(&self, exchange: Exchange) -> Instrument {
+        Instrument::new()
+            .with_secrity_id(self.security_id.clone())
+            .with_symbol(self.expression.clone())
+            .with_exchange(exchange)
+            .with_kind(TradeKind::Unknown)
+            .with_state(InstState::Trading)
+            .with_item(EXPRESSION, Value::String(self.expression.clone()))
+    }
+
+    //按各腿最新行情计算，卖价为买入组合的可成交价：正比例腿取卖价，负比例腿取买价
+    pub fn level1(&self, quotes: &HashMap<String, Level1>) -> Option<Level1> {
+        let legs: Vec<(&Leg, &Level1)> = self
+            .legs
+            .iter()
+            .map(|leg| quotes.get(&leg.security_id).map(|q| (leg, q)))
+            .collect::<Option<_>>()?;
+        let sum = |f: &dyn Fn(&Level1) -> f64| -> f64 {
+            legs.iter().map(|(leg, q)| leg.ratio * f(q)).sum()
+        };
+        let last = sum(&|q| q.last);
+        if last.is_nan() {
+            return None;
+        }
+        let first = legs[0].1;
+        let mut level1 = Level1::new();
+        level1.security_id = self.security_id.clone();
+        level1.exchange = first.exchange;
+        level1.time = legs.iter().map(|(_, q)| q.time).max().unwrap_or_default();
+        level1.open = sum(&|q| q.open);
+        level1.close = sum(&|q| q.close);
+        level1.last = last;
+        level1.bids = implied(&legs, true).into_iter().collect();
+        level1.asks = implied(&legs, false).into_iter().collect();
+        //组合的成交量按各腿可配对的数量计
+        level1.volume = legs
+            .iter()
+            .map(|(leg, q)| q.volume / leg.ratio.abs())
+            .fold(f64::INFINITY, f64::min);
+        if level1.volume.is_infinite() {
+            level1.volume = f64::NAN;
+        }
+        if let Some(day) = first.items.get(TRADING_DATE) {
+            level1.items.insert(TRADING_DATE.into(), day.clone());
+        }
+        Some(level1)
+    }
+
+    //按时间对齐各腿k线，缺腿的时间不合成；最高最低价无法由各腿k线还原，置为NAN
+    pub fn bars(&self, legs: &[Vec<Bar>]) -> Vec<Bar> {
+        if legs.len() != self.legs.len() || legs.is_empty() {
+            return vec![];
+        }
+        let mut times: BTreeMap<i64, Vec<&Bar>> = BTreeMap::new();
+        for bars in legs {
+            for bar in bars {
+                times.entry(bar.time).or_default().push(bar);
+            }
+        }
+        times
+            .into_iter()
+            .filter_map(|(time, bars)| {
+                let bars: Vec<&Bar> = self
+                    .legs
+                    .iter()
+                    .map(|leg| {
+                        bars.iter()
+                            .find(|b| b.security_id == leg.security_id)
+                            .copied()
+                    })
+                    .collect::<Option<_>>()?;
+                let sum = |f: &dyn Fn(&Bar) -> f64| -> f64 {
+                    self.legs
+                        .iter()
+                        .zip(bars.iter())
+                        .map(|(leg, bar)| leg.ratio * f(bar))
+                        .sum()
+                };
+                let open = sum(&|b| b.open);
+                let close = sum(&|b| b.close);
+                Some(Bar {
+                    security_id: self.security_id.clone(),
+                    exchange: bars[0].exchange,
+                    period: bars[0].period,
+                    time,
+                    open,
+                    high: f64::NAN,
+                    low: f64::NAN,
+                    close,
+                    volume: self
+                        .legs
+                        .iter()
+                        .zip(bars.iter())
+                        .map(|(leg, bar)| bar.volume / leg.ratio.abs())
+                        .fold(f64::INFINITY, f64::min),
+                    turnover: None,
+                })
+            })
+            .collect()
+    }
+}
+
+//按空白分词，双引号内的空白不分隔
+fn tokenize(expression: &str) -> Result<Vec<String>> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in expression.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c.is_whitespace() && !quoted {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+            continue;
+        }
+        token.push(c);
+    }
+    if quoted {
+        return Err(anyhow!(
+            "unclosed quote in synthetic expression {:?}",
+            expression
+        ));
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+//项前可直接带符号，如-rb2210、-0.5*j
+fn parse_term(term: &str, sign: f64) -> Result<Leg> {
+    let (sign, term) = match term.strip_prefix('-') {
+        Some(term) => (-sign, term),
+        None => (sign, term.strip_prefix('+').unwrap_or(term)),
+    };
+    let (ratio, security_id) = match term.split_once('*') {
+        Some((ratio, security_id)) => (ratio.trim().parse::<f64>()?, security_id.trim()),
+        None => (1.0, term.trim()),
+    };
+    let quoted =
+        security_id.len() >= 2 && security_id.starts_with('"') && security_id.ends_with('"');
+    let security_id = if quoted {
+        &security_id[1..security_id.len() - 1]
+    } else {
+        security_id
+    };
+    if security_id.is_empty()
+        || !security_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        || security_id.starts_with('-')
+        || !ratio.is_finite()
+    {
+        return Err(anyhow!("invalid synthetic leg {:?}", term));
+    }
+    //只写品种时取连续合约
+    let security_id = if security_id.chars().all(|c| c.is_ascii_alphabetic()) {
+        continuous::symbol(security_id)
+    } else {
+        security_id.to_string()
+    };
+    Ok(Leg {
+        security_id,
+        ratio: sign * ratio,
+    })
+}
+
+//合成买价(bid为true)或卖价，数量为各腿可配对的最小组合数
+fn implied(legs: &[(&Leg, &Level1)], bid: bool) -> Option<Depth> {
+    let mut price = 0.0;
+    let mut quantity = f64::INFINITY;
+    for (leg, q) in legs {
+        //买入组合时正比例腿按卖价买入，卖出组合时按买价卖出
+        let side = if (leg.ratio > 0.0) == bid {
+            &q.bids
+        } else {
+            &q.asks
+        };
+        let (p, v, _, _) = side.first()?;
+        if p.is_nan() || v.is_nan() || *v <= 0.0 {
+            return None;
+        }
+        price += leg.ratio * p;
+        quantity = quantity.min(v / leg.ratio.abs());
+    }
+    Some((price, quantity, f64::NAN, f64::NAN))
+}
+
+#[derive(Default)]
+struct State {
+    synthetics: HashMap<String, Synthetic>,
+    //各腿最新行情
+    quotes: HashMap<String, Level1>,
+    //合成证券的(交易日,最高价,最低价)
+    extremes: HashMap<String, (NaiveDate, f64, f64)>,
+}
+
+#[doc = "按配置的表达式实时计算合成证券行情，如同真实证券发布到行情缓存、k线及策略"]
+pub struct SyntheticInstruments {
+    settings: Settings,
+    store: Arc<dyn Store>,
+    state: Mutex<State>,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl SyntheticInstruments {
+    //定义保存在单元设置中
+    pub fn new<S: AsRef<str>>(unit: S) -> Result<Arc<Self>> {
+        let settings = Settings::open(unit.as_ref(), SYNTHETIC_NAMESPACE)?;
+        let mut state = State::default();
+        for (security_id, expression) in settings.all()? {
+            let expression: String = ron::from_str(&expression)?;
+            match Synthetic::parse(security_id.clone(), &expression) {
+                Ok(synthetic) => {
+                    state.synthetics.insert(security_id, synthetic);
+                }
+                Err(err) => log::error!("load synthetic {} error {}", security_id, err),
+            }
+        }
+        Ok(Arc::new(Self {
+            settings,
+            store: db::open(unit)?,
+            state: Mutex::new(state),
+            tokens: Mutex::new(vec![]),
+        }))
+    }
+
+    //新增或修改合成证券并保存
+    pub fn define(&self, security_id: &str, expression: &str) -> Result<Synthetic> {
+        let synthetic = Synthetic::parse(security_id, expression)?;
+        self.settings.set(security_id, &synthetic.expression)?;
+        self.state
+            .lock()
+            .synthetics
+            .insert(security_id.into(), synthetic.clone());
+        Ok(synthetic)
+    }
+
+    pub fn remove(&self, security_id: &str) -> Result<()> {
+        self.settings.remove(security_id)?;
+        let mut state = self.state.lock();
+        state.synthetics.remove(security_id);
+        state.extremes.remove(security_id);
+        Ok(())
+    }
+
+    pub fn get(&self, security_id: &str) -> Option<Synthetic> {
+        self.state.lock().synthetics.get(security_id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Synthetic> {
+        self.state.lock().synthetics.values().cloned().collect()
+    }
+
+    //设置变化时更新定义，如其他进程或界面修改了配置
+    fn on_setting(&self, security_id: &str, expression: Option<String>) {
+        let mut state = self.state.lock();
+        match expression {
+            Some(expression) => match Synthetic::parse(security_id, &expression) {
+                Ok(synthetic) => {
+                    state.synthetics.insert(security_id.into(), synthetic);
+                }
+                Err(err) => log::error!("update synthetic {} error {}", security_id, err),
+            },
+            None => {
+                state.synthetics.remove(security_id);
+            }
+        }
+    }
+
+    //返回以此证券为腿的合成证券行情
+    pub fn on_level1(&self, level1: &Level1) -> Vec<Level1> {
+        let mut state = self.state.lock();
+        if state.synthetics.contains_key(&level1.security_id)
+            || !state
+                .synthetics
+                .values()
+                .any(|s| s.contains(&level1.security_id))
+        {
+            return vec![];
+        }
+        state
+            .quotes
+            .insert(level1.security_id.clone(), level1.clone());
+        let State {
+            synthetics,
+            quotes,
+            extremes,
+        } = &mut *state;
+        synthetics
+            .values()
+            .filter(|s| s.contains(&level1.security_id))
+            .filter_map(|s| s.level1(quotes))
+            .map(|mut synthetic| {
+                let day = match synthetic.items.get(TRADING_DATE) {
+                    Some(Value::String(day)) => NaiveDate::parse_from_str(day, "%Y%m%d").ok(),
+                    _ => None,
+                }
+                .unwrap_or_else(|| calendar::trading_day(synthetic.exchange, synthetic.time));
+                let last = synthetic.last;
+                let extreme = extremes
+                    .entry(synthetic.security_id.clone())
+                    .or_insert((day, last, last));
+                if extreme.0 != day {
+                    *extreme = (day, last, last);
+                }
+                extreme.1 = extreme.1.max(last);
+                extreme.2 = extreme.2.min(last);
+                synthetic.high = extreme.1;
+                synthetic.low = extreme.2;
+                synthetic
+            })
+            .collect()
+    }
+
+    //由各腿k线合成[begin,end)内的k线
+    pub fn query_bars(
+        &self,
+        security_id: &str,
+        period: Period,
+        begin: i64,
+        end: i64,
+    ) -> Result<Vec<Bar>> {
+        let synthetic = self
+            .get(security_id)
+            .ok_or_else(|| anyhow!("synthetic {} not found", security_id))?;
+        let mut legs = vec![];
+        for leg in &synthetic.legs {
+            legs.push(
+                self.store
+                    .query_bar_with_time(&leg.security_id, period, begin, end)?
+                    .unwrap_or_default(),
+            );
+        }
+        Ok(synthetic.bars(&legs))
+    }
+
+    //登记合成证券，使其出现在证券列表中
+    fn list_instruments(&self) {
+        for synthetic in self.list() {
+            let exchange = synthetic
+                .legs
+                .first()
+                .and_then(|leg| instruments::get_instrument(&leg.security_id))
+                .map(|instr| instr.exchange)
+                .unwrap_or_default();
+            if let Err(err) = core::publish(
+                QUERY_EVENT,
+                Event::TradeEvent(TradeEvent::Instrument(synthetic.instrument(exchange))),
+            ) {
+                log::error!("list synthetic {} error {}", synthetic.security_id, err);
+            }
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let synthetics = self.clone();
+        let token = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            if let Event::QuoteEvent(QuoteEvent::Level1(level1)) = ev.as_ref() {
+                for synthetic in synthetics.on_level1(level1) {
+                    if let Err(err) = synthetics.store.update_level1(synthetic.clone()) {
+                        log::error!("cache synthetic quote error {}", err);
+                    }
+                    if let Err(err) = core::quotes_event(QuoteEvent::Level1(synthetic)) {
+                        log::error!("publish synthetic quote error {}", err);
+                    }
+                }
+            }
+        })?;
+        self.tokens.lock().push(token);
+        let synthetics = self.clone();
+        let token = self.settings.subscribe(move |change| {
+            match change.value::<String>() {
+                Ok(expression) => synthetics.on_setting(&change.key, expression),
+                Err(err) => log::error!("synthetic setting {} error {}", change.key, err),
+            }
+            synthetics.list_instruments();
+        })?;
+        self.tokens.lock().push(token);
+        self.list_instruments();
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(security_id: &str, bid: f64, ask: f64, qty: f64, last: f64) -> Level1 {
+        let mut level1 = Level1::new();
+        level1.security_id = security_id.into();
+        level1.exchange = Exchange::SHFE;
+        level1.time = 1647234000;
+        level1.bids = vec![(bid, qty, 1.0, bid * qty)];
+        level1.asks = vec![(ask, qty, 1.0, ask * qty)];
+        level1.last = last;
+        level1.volume = 100.0;
+        level1
+    }
+
+    #[test]
+    fn test_parse() {
+        let spread = Synthetic::parse("rb05-10", "rb2205 - rb2210").unwrap();
+        assert_eq!(
+            spread.legs,
+            vec![
+                Leg {
+                    security_id: "rb2205".into(),
+                    ratio: 1.0
+                },
+                Leg {
+                    security_id: "rb2210".into(),
+                    ratio: -1.0
+                },
+            ]
+        );
+        let basket = Synthetic::parse("steel", "3*rb - 1*i - 0.5*j").unwrap();
+        let ratios: Vec<(&str, f64)> = basket
+            .legs
+            .iter()
+            .map(|l| (l.security_id.as_str(), l.ratio))
+            .collect();
+        assert_eq!(ratios, vec![("rb888", 3.0), ("i888", -1.0), ("j888", -0.5)]);
+        //证券代码中带-
+        let pair = Synthetic::parse("btc-eth", "btc-usdt - 0.5*\"eth-usdt\"").unwrap();
+        let ratios: Vec<(&str, f64)> = pair
+            .legs
+            .iter()
+            .map(|l| (l.security_id.as_str(), l.ratio))
+            .collect();
+        assert_eq!(ratios, vec![("btc-usdt", 1.0), ("eth-usdt", -0.5)]);
+        let spread = Synthetic::parse("rb10-05", "-rb2205 + rb2210").unwrap();
+        assert_eq!(spread.legs[0].ratio, -1.0);
+        assert!(Synthetic::parse("bad", "rb2205 rb2210").is_err());
+        assert!(Synthetic::parse("bad", "rb2205 - - rb2210").is_err());
+        assert!(Synthetic::parse("bad", "\"btc-usdt").is_err());
+        assert!(Synthetic::parse("bad", "rb2205 -").is_err());
+        assert!(Synthetic::parse("bad", "x*rb2205").is_err());
+        assert!(Synthetic::parse("bad", "rb2205 - rb2205").is_err());
+    }
+
+    #[test]
+    fn test_level1() {
+        let spread = Synthetic::parse("rb05-10", "2*rb2205 - rb2210").unwrap();
+        let mut quotes = HashMap::new();
+        quotes.insert(
+            "rb2205".into(),
+            quote("rb2205", 4800.0, 4801.0, 10.0, 4800.0),
+        );
+        assert!(spread.level1(&quotes).is_none());
+        quotes.insert(
+            "rb2210".into(),
+            quote("rb2210", 4700.0, 4702.0, 3.0, 4701.0),
+        );
+        let level1 = spread.level1(&quotes).unwrap();
+        assert_eq!(level1.last, 2.0 * 4800.0 - 4701.0);
+        //买入组合：买rb2205卖价，卖rb2210买价
+        assert_eq!(level1.asks[0].0, 2.0 * 4801.0 - 4700.0);
+        assert_eq!(level1.bids[0].0, 2.0 * 4800.0 - 4702.0);
+        assert_eq!(level1.bids[0].1, 3.0);
+        assert_eq!(level1.volume, 50.0);
+    }
+
+    #[test]
+    fn test_bars() {
+        let spread = Synthetic::parse("rb05-10", "rb2205 - rb2210").unwrap();
+        let bar = |security_id: &str, time: i64, close: f64| Bar {
+            security_id: security_id.into(),
+            exchange: Exchange::SHFE,
+            period: Period::Minute(1),
+            time,
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 10.0,
+            turnover: None,
+        };
+        let bars = spread.bars(&[
+            vec![bar("rb2205", 60, 4800.0), bar("rb2205", 120, 4810.0)],
+            vec![bar("rb2210", 120, 4700.0)],
+        ]);
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].time, 120);
+        assert_eq!(bars[0].close, 110.0);
+        assert!(bars[0].high.is_nan() && bars[0].low.is_nan());
+    }
+}
@@ -6,6 +6,10 @@ pub const LOG: &str = "__/log";
 pub const TRADES_EVENT: &str = "__/trades/event";
 //行情主题
 pub const QUOTES_EVENT: &str = "__/quotes/event";
+//证券上市信息变化主题
+pub const INSTRUMENTS_EVENT: &str = "__/instruments/event";
+//设置变化主题
+pub const SETTINGS_EVENT: &str = "__/settings/event";
 //查询返回主题
 pub const QUERY_EVENT: &str = "__/query/event";
 //查询返回主题
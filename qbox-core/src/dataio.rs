@@ -0,0 +1,976 @@
+use crate::broker::{Bar, Exchange, Order, Period, Position, Side, TickToTrade, Transaction};
+use crate::calendar;
+use crate::core::QuoteEvent;
+use crate::db;
+use crate::recorder::{Recorder, TickReader};
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+use encoding_rs::GBK;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[doc = "导出文件格式"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FileFormat {
+    //按扩展名识别，.jsonl/.json为JSON Lines，其余为CSV
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("json") => FileFormat::Jsonl,
+            _ => FileFormat::Csv,
+        }
+    }
+}
+
+#[doc = "时间列格式"]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeFormat {
+    Seconds,
+    Millis,
+    //chrono格式串，按交易所时区解析，如"%Y-%m-%d %H:%M:%S"、"%Y%m%d"
+    Pattern(String),
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        TimeFormat::Seconds
+    }
+}
+
+impl TimeFormat {
+    pub fn parse(&self, exchange: Exchange, s: &str) -> Result<i64> {
+        let s = s.trim();
+        match self {
+            TimeFormat::Seconds => Ok(s.parse::<f64>()? as i64),
+            TimeFormat::Millis => Ok(s.parse::<i64>()? / 1000),
+            TimeFormat::Pattern(fmt) => {
+                let local = match NaiveDateTime::parse_from_str(s, fmt) {
+                    Ok(dt) => dt,
+                    //只有日期的格式，取当日零点
+                    Err(_) => NaiveDate::parse_from_str(s, fmt)?.and_hms(0, 0, 0),
+                };
+                calendar::timezone(exchange)
+                    .from_local_datetime(&local)
+                    .single()
+                    .map(|dt| dt.timestamp())
+                    .ok_or_else(|| anyhow!("invalid local time {}", s))
+            }
+        }
+    }
+}
+
+#[doc = "CSV导入选项，列映射为字段名到表头列名，未映射的字段按同名列读取"]
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub columns: HashMap<String, String>,
+    pub time_format: TimeFormat,
+    //文件中没有代码/交易所列时使用
+    pub security_id: Option<String>,
+    pub exchange: Exchange,
+    //k线周期，文件中没有周期列时使用
+    pub period: Period,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            columns: HashMap::new(),
+            time_format: TimeFormat::default(),
+            security_id: None,
+            exchange: Exchange::default(),
+            period: Period::default(),
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+    pub fn with_column<S: Into<String>>(mut self, field: S, column: S) -> Self {
+        self.columns.insert(field.into(), column.into());
+        self
+    }
+    pub fn with_time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+    pub fn with_security_id<S: Into<String>>(mut self, security_id: S) -> Self {
+        self.security_id = Some(security_id.into());
+        self
+    }
+    pub fn with_exchange(mut self, exchange: Exchange) -> Self {
+        self.exchange = exchange;
+        self
+    }
+    pub fn with_period(mut self, period: Period) -> Self {
+        self.period = period;
+        self
+    }
+}
+
+//按表头定位字段所在列
+struct Row<'a> {
+    opts: &'a CsvOptions,
+    index: HashMap<String, usize>,
+    record: csv::StringRecord,
+}
+
+impl<'a> Row<'a> {
+    fn new(opts: &'a CsvOptions, headers: &csv::StringRecord) -> Self {
+        let index = headers
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (h.trim().to_string(), i))
+            .collect();
+        Self {
+            opts,
+            index,
+            record: csv::StringRecord::new(),
+        }
+    }
+
+    fn get(&self, field: &str) -> Option<&str> {
+        let column = self
+            .opts
+            .columns
+            .get(field)
+            .map(|c| c.as_str())
+            .unwrap_or(field);
+        self.index
+            .get(column)
+            .and_then(|i| self.record.get(*i))
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn required(&self, field: &str) -> Result<&str> {
+        self.get(field)
+            .ok_or_else(|| anyhow!("missing column {}", field))
+    }
+
+    fn f64(&self, field: &str) -> Result<f64> {
+        Ok(self.required(field)?.parse::<f64>()?)
+    }
+
+    fn security_id(&self) -> Result<String> {
+        match self.get("security_id") {
+            Some(v) => Ok(v.into()),
+            None => self
+                .opts
+                .security_id
+                .clone()
+                .ok_or_else(|| anyhow!("missing column security_id")),
+        }
+    }
+
+    fn exchange(&self) -> Exchange {
+        self.get("exchange")
+            .map(Exchange::from)
+            .unwrap_or(self.opts.exchange)
+    }
+
+    //日期与时间分列时合并后解析
+    fn time(&self, exchange: Exchange) -> Result<i64> {
+        let format = &self.opts.time_format;
+        match (self.get("date"), self.get("time")) {
+            (Some(date), Some(time)) => format.parse(exchange, &format!("{} {}", date, time)),
+            (Some(date), None) => format.parse(exchange, date),
+            _ => format.parse(exchange, self.required("time")?),
+        }
+    }
+
+    fn side(&self) -> Option<Side> {
+        match self.get("side")?.to_lowercase().as_str() {
+            "b" | "buy" | "bid" | "1" => Some(Side::Buy),
+            "s" | "sell" | "ask" | "2" | "-1" => Some(Side::Sell),
+            _ => None,
+        }
+    }
+}
+
+//逐行读取CSV，返回处理的行数
+fn read_csv<P, F>(path: P, opts: &CsvOptions, mut f: F) -> Result<usize>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Row) -> Result<()>,
+{
+    //国内行情软件导出的文件多为GBK编码
+    let raw = fs::read(path.as_ref())?;
+    let text = match String::from_utf8(raw) {
+        Ok(text) => text,
+        Err(err) => GBK.decode(err.as_bytes()).0.into_owned(),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(opts.delimiter)
+        .flexible(true)
+        .from_reader(text.trim_start_matches('\u{feff}').as_bytes());
+    let mut row = Row::new(opts, reader.headers()?);
+    let mut count = 0;
+    while reader.read_record(&mut row.record)? {
+        f(&row).map_err(|err| anyhow!("line {}: {}", count + 2, err))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+//读取k线
+//字段：security_id exchange period date time open high low close volume turnover
+pub fn read_bars<P: AsRef<Path>>(path: P, opts: &CsvOptions) -> Result<Vec<Bar>> {
+    let mut bars = vec![];
+    read_csv(path, opts, |row| {
+        let exchange = row.exchange();
+        bars.push(Bar {
+            security_id: row.security_id()?,
+            exchange,
+            period: row.get("period").map(Period::from).unwrap_or(opts.period),
+            time: row.time(exchange)?,
+            open: row.f64("open")?,
+            high: row.f64("high")?,
+            low: row.f64("low")?,
+            close: row.f64("close")?,
+            volume: row.f64("volume")?,
+            turnover: row.get("turnover").and_then(|v| v.parse::<f64>().ok()),
+        });
+        Ok(())
+    })?;
+    Ok(bars)
+}
+
+//导入k线到单元的k线存储
+pub fn import_bars<S, P>(unit: S, path: P, opts: &CsvOptions) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    load_bars(unit, read_bars(path, opts)?)
+}
+
+//写入单元的k线存储，返回写入条数
+pub fn load_bars<S: AsRef<str>>(unit: S, bars: Vec<Bar>) -> Result<usize> {
+    let store = db::open(unit)?;
+    let count = bars.len();
+    for bar in bars {
+        store.insert_bar(bar)?;
+    }
+    Ok(count)
+}
+
+//导入逐笔成交到行情录制
+//字段：security_id exchange id time price quantity side
+pub fn import_ticks<P: AsRef<Path>>(
+    recorder: &Recorder,
+    path: P,
+    opts: &CsvOptions,
+) -> Result<usize> {
+    let count = read_csv(path, opts, |row| {
+        let exchange = row.exchange();
+        let time = row.time(exchange)?;
+        recorder.record(&QuoteEvent::TickToTrade(TickToTrade {
+            security_id: row.security_id()?,
+            exchange,
+            id: row.get("id").map(|v| v.into()).unwrap_or_default(),
+            time,
+            price: row.f64("price")?,
+            quantity: row.f64("quantity")?,
+            order_side: row.side(),
+            into_side: None,
+            take_order_id: None,
+            make_order_id: None,
+        }))
+    })?;
+    recorder.flush()?;
+    Ok(count)
+}
+
+//逐条写入，CSV带表头，JSONL每行一个对象
+fn write_rows<P, T, I>(path: P, format: FileFormat, rows: I) -> Result<usize>
+where
+    P: AsRef<Path>,
+    T: Serialize,
+    I: IntoIterator<Item = Result<T>>,
+{
+    let mut count = 0;
+    match format {
+        FileFormat::Csv => {
+            let mut writer = csv::Writer::from_path(path)?;
+            for row in rows {
+                writer.serialize(row?)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        FileFormat::Jsonl => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            for row in rows {
+                serde_json::to_writer(&mut writer, &row?)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Serialize)]
+struct BarRow {
+    security_id: String,
+    exchange: String,
+    period: String,
+    time: i64,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    volume: f64,
+    turnover: Option<f64>,
+}
+
+impl From<Bar> for BarRow {
+    fn from(bar: Bar) -> Self {
+        let exchange: &str = bar.exchange.into();
+        Self {
+            security_id: bar.security_id,
+            exchange: exchange.into(),
+            period: bar.period.into(),
+            time: bar.time,
+            open: bar.open,
+            high: bar.high,
+            low: bar.low,
+            close: bar.close,
+            volume: bar.volume,
+            turnover: bar.turnover,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TickRow {
+    kind: &'static str,
+    security_id: String,
+    exchange: String,
+    time: i64,
+    price: f64,
+    quantity: f64,
+    side: Option<String>,
+}
+
+impl TickRow {
+    //深度行情没有单一价格，不导出
+    fn from_event(ev: QuoteEvent) -> Option<Self> {
+        let (kind, security_id, exchange, time, price, quantity, side) = match ev {
+            QuoteEvent::Level1(v) => (
+                "level1",
+                v.security_id,
+                v.exchange,
+                v.time,
+                v.last,
+                v.last_volume,
+                None,
+            ),
+            QuoteEvent::TickToTrade(v) => (
+                "trade",
+                v.security_id,
+                v.exchange,
+                v.time,
+                v.price,
+                v.quantity,
+                v.order_side,
+            ),
+            QuoteEvent::TickToOffer(v) => (
+                "offer",
+                v.security_id,
+                v.exchange,
+                v.time,
+                v.price,
+                v.quantity,
+                Some(v.side),
+            ),
+            _ => return None,
+        };
+        let exchange: &str = exchange.into();
+        Some(Self {
+            kind,
+            security_id,
+            exchange: exchange.into(),
+            time,
+            price,
+            quantity,
+            side: side.map(|s| format!("{:?}", s)),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OrderRow {
+    id: u64,
+    kind: &'static str,
+    security_id: String,
+    exchange: String,
+    time: i64,
+    side: String,
+    offset: String,
+    price: Option<f64>,
+    quantity: f64,
+    state: String,
+    filled_quantity: f64,
+    filled_amount: f64,
+    avg_price: f64,
+    last_time: i64,
+}
+
+impl From<Order> for OrderRow {
+    fn from(order: Order) -> Self {
+        let (kind, side, offset, price, quantity) = match &order {
+            Order::Limit {
+                side,
+                offset,
+                price,
+                quantity,
+                ..
+            } => ("limit", side, offset, Some(*price), *quantity),
+            Order::Market {
+                side,
+                offset,
+                quantity,
+                ..
+            } => ("market", side, offset, None, *quantity),
+            Order::TakeStop {
+                side,
+                offset,
+                price,
+                quantity,
+                ..
+            } => ("take_stop", side, offset, Some(*price), *quantity),
+            Order::Tracking {
+                side,
+                offset,
+                price,
+                quantity,
+                ..
+            } => ("tracking", side, offset, Some(*price), *quantity),
+            Order::Iceberg {
+                side,
+                offset,
+                price,
+                quantity,
+                ..
+            } => ("iceberg", side, offset, Some(*price), *quantity),
+            Order::TimeWeights {
+                side,
+                offset,
+                price,
+                quantity,
+                ..
+            } => ("time_weights", side, offset, Some(*price), *quantity),
+        };
+        let exchange: &str = order.exchange().into();
+        let state = order.state();
+        Self {
+            id: order.id(),
+            kind,
+            security_id: order.security_id().into(),
+            exchange: exchange.into(),
+            time: order.time(),
+            side: format!("{:?}", side),
+            offset: format!("{:?}", offset),
+            price,
+            quantity,
+            state: format!("{:?}", state.state),
+            filled_quantity: state.filled_quantity,
+            filled_amount: state.filled_amount,
+            avg_price: state.avg_price,
+            last_time: state.last_time,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TransactionRow {
+    id: u64,
+    order_id: u64,
+    out_id: String,
+    security_id: String,
+    exchange: String,
+    time: i64,
+    side: String,
+    into_side: String,
+    price: f64,
+    quantity: f64,
+}
+
+impl From<Transaction> for TransactionRow {
+    fn from(tx: Transaction) -> Self {
+        let exchange: &str = tx.exchange.into();
+        Self {
+            id: tx.id,
+            order_id: tx.order_id,
+            out_id: tx.out_id,
+            security_id: tx.security_id,
+            exchange: exchange.into(),
+            time: tx.time,
+            side: format!("{:?}", tx.side),
+            into_side: format!("{:?}", tx.into_side),
+            price: tx.price,
+            quantity: tx.quantity,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PositionRow {
+    time: Option<i64>,
+    security_id: String,
+    exchange: String,
+    side: String,
+    quantity: i64,
+    frozen: f64,
+    average: f64,
+    cost: f64,
+    margin: f64,
+    realized_pnl: f64,
+    unrealized_pnl: f64,
+}
+
+impl PositionRow {
+    fn new(time: Option<i64>, position: Position) -> Self {
+        let exchange: &str = position.exchange.into();
+        Self {
+            time,
+            security_id: position.security_id,
+            exchange: exchange.into(),
+            side: format!("{:?}", position.side),
+            quantity: position.quantity,
+            frozen: position.frozen,
+            average: position.average,
+            cost: position.cost,
+            margin: position.margin,
+            realized_pnl: position.realized_pnl,
+            unrealized_pnl: position.unrealized_pnl,
+        }
+    }
+}
+
+//导出[begin,end)内的k线
+pub fn export_bars<S, P>(
+    unit: S,
+    security_id: &str,
+    period: Period,
+    begin: i64,
+    end: i64,
+    path: P,
+    format: FileFormat,
+) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let bars = db::open(unit)?
+        .query_bar_with_time(security_id, period, begin, end)?
+        .unwrap_or_default();
+    write_rows(path, format, bars.into_iter().map(|b| Ok(BarRow::from(b))))
+}
+
+//导出[begin,end)内录制的行情，security_ids为空时导出全部证券
+pub fn export_ticks<P: AsRef<Path>>(
+    reader: &TickReader,
+    exchanges: &[Exchange],
+    security_ids: &[&str],
+    begin: i64,
+    end: i64,
+    path: P,
+    format: FileFormat,
+) -> Result<usize> {
+    let rows = reader
+        .read(exchanges, security_ids, begin, end)?
+        .filter_map(|ev| match ev {
+            Ok(ev) => TickRow::from_event(ev).map(Ok),
+            Err(err) => Some(Err(err)),
+        });
+    write_rows(path, format, rows)
+}
+
+//导出[begin,end)内的订单
+pub fn export_orders<S, P>(
+    unit: S,
+    begin: i64,
+    end: i64,
+    path: P,
+    format: FileFormat,
+) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let orders = db::open(unit)?
+        .query_order_with_time(begin, end)?
+        .unwrap_or_default();
+    write_rows(
+        path,
+        format,
+        orders.into_iter().map(|o| Ok(OrderRow::from(o))),
+    )
+}
+
+//导出[begin,end)内的成交
+pub fn export_transactions<S, P>(
+    unit: S,
+    begin: i64,
+    end: i64,
+    path: P,
+    format: FileFormat,
+) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let txs = db::open(unit)?
+        .query_tx_with_time(begin, end)?
+        .unwrap_or_default();
+    write_rows(
+        path,
+        format,
+        txs.into_iter().map(|t| Ok(TransactionRow::from(t))),
+    )
+}
+
+//导出持仓，security_ids为空时导出当前持仓，否则导出这些证券[begin,end)内的持仓快照
+pub fn export_positions<S, P>(
+    unit: S,
+    security_ids: &[&str],
+    begin: i64,
+    end: i64,
+    path: P,
+    format: FileFormat,
+) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let store = db::open(unit)?;
+    let mut rows = vec![];
+    if security_ids.is_empty() {
+        for position in store.query_all_position()?.unwrap_or_default() {
+            rows.push(Ok(PositionRow::new(None, position)));
+        }
+    } else {
+        for security_id in security_ids {
+            for (time, position) in store
+                .query_position_history(security_id, begin, end)?
+                .unwrap_or_default()
+            {
+                rows.push(Ok(PositionRow::new(Some(time), position)));
+            }
+        }
+    }
+    write_rows(path, format, rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cleanup, unit};
+
+    #[test]
+    fn test_time_format() {
+        let shanghai = Exchange::SHFE;
+        //2022-03-14 10:00:00 +08:00
+        let ts = 1647223200;
+        assert_eq!(
+            TimeFormat::Seconds.parse(shanghai, "1647223200").unwrap(),
+            ts
+        );
+        assert_eq!(
+            TimeFormat::Millis.parse(shanghai, "1647223200000").unwrap(),
+            ts
+        );
+        assert_eq!(
+            TimeFormat::Pattern("%Y-%m-%d %H:%M:%S".into())
+                .parse(shanghai, "2022-03-14 10:00:00")
+                .unwrap(),
+            ts
+        );
+        assert_eq!(
+            TimeFormat::Pattern("%Y%m%d".into())
+                .parse(shanghai, "20220314")
+                .unwrap(),
+            ts - 10 * 3600
+        );
+    }
+
+    #[test]
+    fn test_import_and_export_ticks() {
+        let dir = std::env::temp_dir().join(format!("qbox-dataio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("ticks.csv");
+        std::fs::write(
+            &csv,
+            "代码,时间,价格,成交量,方向\nrb2205,2022-03-14 10:00:00,4900,3,B\nrb2205,2022-03-14 10:00:01,4901,1,S\n",
+        )
+        .unwrap();
+        let opts = CsvOptions::new()
+            .with_exchange(Exchange::SHFE)
+            .with_column("security_id", "代码")
+            .with_column("time", "时间")
+            .with_column("price", "价格")
+            .with_column("quantity", "成交量")
+            .with_column("side", "方向")
+            .with_time_format(TimeFormat::Pattern("%Y-%m-%d %H:%M:%S".into()));
+        let recorder = Recorder::with_path(dir.join("ticks")).unwrap();
+        assert_eq!(import_ticks(&recorder, &csv, &opts).unwrap(), 2);
+
+        let reader = TickReader::with_path(dir.join("ticks"));
+        let out = dir.join("ticks.jsonl");
+        let t0 = 1647223200;
+        let n = export_ticks(
+            &reader,
+            &[Exchange::SHFE],
+            &[],
+            t0,
+            t0 + 60,
+            &out,
+            FileFormat::from_path(&out),
+        )
+        .unwrap();
+        assert_eq!(n, 2);
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[1]["price"], 4901.0);
+        assert_eq!(lines[1]["side"], "Sell");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn read_jsonl(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    //CSV按表头读取为(列名,值)
+    fn read_rows(path: &Path) -> Vec<HashMap<String, String>> {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        reader
+            .records()
+            .map(|r| {
+                headers
+                    .iter()
+                    .map(|h| h.to_string())
+                    .zip(r.unwrap().iter().map(|v| v.to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_import_and_export_bars() {
+        let unit = unit("dataio-bars");
+        let dir = std::env::temp_dir().join(&unit);
+        fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("bars.csv");
+        let (text, _, _) = GBK.encode(
+            "日期;时间;开盘;最高;最低;收盘;成交量\n\
+             2022-03-14;10:00:00;4800;4805;4798;4802;120\n\
+             2022-03-14;10:01:00;4802;4810;4801;4808;80\n",
+        );
+        fs::write(&csv, text).unwrap();
+        let opts = CsvOptions::new()
+            .with_delimiter(b';')
+            .with_security_id("rb2205")
+            .with_exchange(Exchange::SHFE)
+            .with_period(Period::Minute(1))
+            .with_column("date", "日期")
+            .with_column("time", "时间")
+            .with_column("open", "开盘")
+            .with_column("high", "最高")
+            .with_column("low", "最低")
+            .with_column("close", "收盘")
+            .with_column("volume", "成交量")
+            .with_time_format(TimeFormat::Pattern("%Y-%m-%d %H:%M:%S".into()));
+        let bars = read_bars(&csv, &opts).unwrap();
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].time, 1647223200);
+        assert_eq!(bars[1].close, 4808.0);
+        assert_eq!(import_bars(&unit, &csv, &opts).unwrap(), 2);
+
+        //导出的CSV可按默认选项重新导入
+        let out = dir.join("bars-out.csv");
+        let t0 = 1647223200;
+        assert_eq!(
+            export_bars(
+                &unit,
+                "rb2205",
+                Period::Minute(1),
+                t0,
+                t0 + 3600,
+                &out,
+                FileFormat::from_path(&out),
+            )
+            .unwrap(),
+            2
+        );
+        let reread = read_bars(&out, &CsvOptions::new()).unwrap();
+        assert_eq!(format!("{:?}", reread), format!("{:?}", bars));
+
+        let out = dir.join("bars-out.jsonl");
+        export_bars(
+            &unit,
+            "rb2205",
+            Period::Minute(1),
+            t0,
+            t0 + 3600,
+            &out,
+            FileFormat::from_path(&out),
+        )
+        .unwrap();
+        let lines = read_jsonl(&out);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["exchange"], "SHFE");
+        assert_eq!(lines[1]["high"], 4810.0);
+        assert_eq!(lines[1]["turnover"], serde_json::Value::Null);
+
+        cleanup(&unit);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_export_orders_transactions_positions() {
+        use crate::broker::{OrderLife, OrderState, State};
+        let unit = unit("dataio-trades");
+        let dir = std::env::temp_dir().join(&unit);
+        fs::create_dir_all(&dir).unwrap();
+        let t0 = 1647223200;
+        let store = db::open(&unit).unwrap();
+        store
+            .update_order(Order::Limit {
+                id: 1,
+                security_id: "rb2205".into(),
+                exchange: Exchange::SHFE,
+                time: t0,
+                side: Side::Buy,
+                offset: Side::Open,
+                price: 4800.0,
+                quantity: 2.0,
+                lever: 1,
+                pov: OrderLife::GTC,
+                remark: "".into(),
+                state: OrderState {
+                    filled_quantity: 2.0,
+                    filled_amount: 9600.0,
+                    avg_price: 4800.0,
+                    last_time: t0 + 1,
+                    state: State::Filled,
+                },
+            })
+            .unwrap();
+        store
+            .insert_tx(Transaction {
+                id: 10,
+                order_id: 1,
+                out_id: "T10".into(),
+                exchange: Exchange::SHFE,
+                security_id: "rb2205".into(),
+                time: t0 + 1,
+                side: Side::Buy,
+                into_side: Side::Taker,
+                price: 4800.0,
+                quantity: 2.0,
+                ask_order_id: None,
+                bid_order_id: None,
+            })
+            .unwrap();
+        let position = |quantity: i64, time: i64| Position {
+            exchange: Exchange::SHFE,
+            security_id: "rb2205".into(),
+            side: Side::Long,
+            offset: Side::Open,
+            margin_level: 1,
+            quantity,
+            frozen: 0.0,
+            last: 4800.0,
+            average: 4800.0,
+            settlement: 4800.0,
+            cost: 4800.0 * quantity as f64,
+            margin: 0.0,
+            realized_pnl: 0.0,
+            unrealized_pnl: 0.0,
+            position_pnl: 0.0,
+            time,
+        };
+        store.update_position(position(1, t0)).unwrap();
+        store.update_position(position(2, t0 + 60)).unwrap();
+
+        let out = dir.join("orders.csv");
+        assert_eq!(
+            export_orders(&unit, t0, t0 + 3600, &out, FileFormat::Csv).unwrap(),
+            1
+        );
+        let rows = read_rows(&out);
+        assert_eq!(rows[0]["id"], "1");
+        assert_eq!(rows[0]["kind"], "limit");
+        assert_eq!(rows[0]["side"], "Buy");
+        assert_eq!(rows[0]["price"], "4800.0");
+        assert_eq!(rows[0]["state"], "Filled");
+        assert_eq!(rows[0]["last_time"], (t0 + 1).to_string());
+        //时间范围外不导出
+        assert_eq!(
+            export_orders(&unit, t0 + 3600, t0 + 7200, &out, FileFormat::Csv).unwrap(),
+            0
+        );
+
+        let out = dir.join("transactions.jsonl");
+        assert_eq!(
+            export_transactions(&unit, t0, t0 + 3600, &out, FileFormat::Jsonl).unwrap(),
+            1
+        );
+        let lines = read_jsonl(&out);
+        assert_eq!(lines[0]["order_id"], 1);
+        assert_eq!(lines[0]["out_id"], "T10");
+        assert_eq!(lines[0]["into_side"], "Taker");
+        assert_eq!(lines[0]["quantity"], 2.0);
+
+        //当前持仓
+        let out = dir.join("positions.csv");
+        assert_eq!(
+            export_positions(&unit, &[], t0, t0 + 3600, &out, FileFormat::Csv).unwrap(),
+            1
+        );
+        let rows = read_rows(&out);
+        assert_eq!(rows[0]["time"], "");
+        assert_eq!(rows[0]["quantity"], "2");
+        assert_eq!(rows[0]["side"], "Long");
+        //持仓快照
+        let out = dir.join("positions.jsonl");
+        assert_eq!(
+            export_positions(&unit, &["rb2205"], t0, t0 + 3600, &out, FileFormat::Jsonl).unwrap(),
+            2
+        );
+        let history: Vec<(i64, i64)> = read_jsonl(&out)
+            .iter()
+            .map(|v| (v["time"].as_i64().unwrap(), v["quantity"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(history, vec![(t0, 1), (t0 + 60, 2)]);
+
+        cleanup(&unit);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,37 +1,183 @@
 use super::QuoteStore;
-use crate::broker::{Bar, Level1, Level2, Period, TickToOffer, TickToTrade};
+use crate::broker::{Analytics, Bar, Level1, Level2, Period, TickToOffer, TickToTrade};
+use crate::ring::Ring;
+use crate::setting;
 use ahash::RandomState;
 use anyhow::Result;
 use dashmap::DashMap;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+//各类数据缓存条数的配置项
+const BARS_KEY: &str = "QBOX_CACHE_BARS";
+const OFFERS_KEY: &str = "QBOX_CACHE_OFFERS";
+const TRADES_KEY: &str = "QBOX_CACHE_TRADES";
+const ANALYTICS_KEY: &str = "QBOX_CACHE_ANALYTICS";
+//按单元登记的实例，同一单元共享行情
+static STORES: Lazy<DashMap<String, MemQuoteStore, RandomState>> =
+    Lazy::new(|| DashMap::with_hasher(RandomState::new()));
+
+#[doc = "行情缓存每个证券各类数据的保留条数"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Capacity {
+    pub bars: usize,      //每个周期的k线
+    pub offers: usize,    //逐笔委托
+    pub trades: usize,    //逐笔成交
+    pub analytics: usize, //微观结构指标
+}
+
+impl Default for Capacity {
+    fn default() -> Self {
+        Self {
+            bars: 1000,
+            offers: 100,
+            trades: 100,
+            analytics: 1000,
+        }
+    }
+}
+
+impl Capacity {
+    //按启动配置，未配置的取默认值
+    pub fn from_env() -> Self {
+        let def = Self::default();
+        let get = |key: &str, v: usize| setting::get_with_default(key, &v.to_string()).unwrap_or(v);
+        Self {
+            bars: get(BARS_KEY, def.bars),
+            offers: get(OFFERS_KEY, def.offers),
+            trades: get(TRADES_KEY, def.trades),
+            analytics: get(ANALYTICS_KEY, def.analytics),
+        }
+    }
+
+    pub fn with_bars(mut self, bars: usize) -> Self {
+        self.bars = bars;
+        self
+    }
+
+    pub fn with_offers(mut self, offers: usize) -> Self {
+        self.offers = offers;
+        self
+    }
+
+    pub fn with_trades(mut self, trades: usize) -> Self {
+        self.trades = trades;
+        self
+    }
+
+    pub fn with_analytics(mut self, analytics: usize) -> Self {
+        self.analytics = analytics;
+        self
+    }
+}
+
+//定长缓存，读取时不复制数据
+type Cache<K, T> = Arc<DashMap<K, Ring<T>, RandomState>>;
 
-const MAX_BAR_SIZE: usize = 1000;
-const MAX_TTO_SIZE: usize = 100;
-const MAX_TTT_SIZE: usize = 100;
 #[derive(Clone)]
 pub struct MemQuoteStore {
     unit: String,
-    level1: DashMap<String, Level1, RandomState>,
-    bars: DashMap<String, Vec<Bar>, RandomState>,
-    depths: DashMap<String, Level2, RandomState>,
-    ttos: DashMap<String, Vec<TickToOffer>, RandomState>,
-    ttts: DashMap<String, Vec<TickToTrade>, RandomState>,
+    capacity: Arc<RwLock<Capacity>>,
+    level1: Arc<DashMap<String, Level1, RandomState>>,
+    bars: Cache<(String, Period), Bar>,
+    depths: Arc<DashMap<String, Level2, RandomState>>,
+    ttos: Cache<String, TickToOffer>,
+    ttts: Cache<String, TickToTrade>,
+    analytics: Cache<String, Analytics>,
+}
+
+fn cache<K: std::hash::Hash + Eq, T>() -> Cache<K, T> {
+    Arc::new(DashMap::with_hasher(RandomState::new()))
+}
+
+fn resize<K: std::hash::Hash + Eq, T>(cache: &Cache<K, T>, capacity: usize) {
+    for mut ring in cache.iter_mut() {
+        ring.set_capacity(capacity);
+    }
 }
 
 impl MemQuoteStore {
     pub fn open<S: Into<String>>(unit: S) -> Self {
-        static INSTANCE: OnceCell<MemQuoteStore> = OnceCell::new();
-        let ret = INSTANCE
-            .get_or_init(|| Self {
-                unit: unit.into(),
-                level1: DashMap::with_hasher(RandomState::new()),
-                bars: DashMap::with_hasher(RandomState::new()),
-                depths: DashMap::with_hasher(RandomState::new()),
-                ttos: DashMap::with_hasher(RandomState::new()),
-                ttts: DashMap::with_hasher(RandomState::new()),
+        let unit = unit.into();
+        STORES
+            .entry(unit.clone())
+            .or_insert_with(|| Self {
+                unit,
+                capacity: Arc::new(RwLock::new(Capacity::from_env())),
+                level1: Arc::new(DashMap::with_hasher(RandomState::new())),
+                bars: cache(),
+                depths: Arc::new(DashMap::with_hasher(RandomState::new())),
+                ttos: cache(),
+                ttts: cache(),
+                analytics: cache(),
             })
-            .clone();
-        ret
+            .clone()
+    }
+
+    //注销单元，已打开的实例仍可使用
+    pub fn close(unit: &str) {
+        STORES.remove(unit);
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        *self.capacity.read()
+    }
+
+    //调整缓存条数，已缓存的数据按新容量截取最近的部分
+    pub fn set_capacity(&self, capacity: Capacity) {
+        *self.capacity.write() = capacity;
+        resize(&self.bars, capacity.bars);
+        resize(&self.ttos, capacity.offers);
+        resize(&self.ttts, capacity.trades);
+        resize(&self.analytics, capacity.analytics);
+    }
+
+    //在缓存上直接读取k线，不复制数据
+    pub fn with_bars<R>(
+        &self,
+        security_id: &str,
+        period: Period,
+        f: impl FnOnce(&Ring<Bar>) -> R,
+    ) -> Option<R> {
+        self.bars
+            .get(&(security_id.to_string(), period))
+            .map(|bars| f(bars.value()))
+    }
+
+    //k线快照，只复制引用
+    pub fn bars(&self, security_id: &str, period: Period) -> Option<Vec<Arc<Bar>>> {
+        self.with_bars(security_id, period, |bars| bars.snapshot())
+    }
+
+    pub fn with_tick2offer<R>(
+        &self,
+        security_id: &str,
+        f: impl FnOnce(&Ring<TickToOffer>) -> R,
+    ) -> Option<R> {
+        self.ttos.get(security_id).map(|ttos| f(ttos.value()))
+    }
+
+    pub fn with_tick2trade<R>(
+        &self,
+        security_id: &str,
+        f: impl FnOnce(&Ring<TickToTrade>) -> R,
+    ) -> Option<R> {
+        self.ttts.get(security_id).map(|ttts| f(ttts.value()))
+    }
+}
+
+//缓存内容转为查询结果，空缓存返回None
+fn collect<'a, T: Clone + 'a>(items: impl Iterator<Item = &'a T>) -> Option<Vec<T>> {
+    let data: Vec<T> = items.cloned().collect();
+    if data.len() > 0 {
+        Some(data)
+    } else {
+        None
     }
 }
 
@@ -90,65 +236,91 @@ impl QuoteStore for MemQuoteStore {
         }
     }
     fn insert_bar(&self, bar: Bar) -> Result<()> {
-        if let Some(mut bars) = self.bars.get_mut(&bar.security_id) {
-            bars.value_mut().push(bar.clone());
-            if bars.len() > MAX_BAR_SIZE {
-                bars.remove(0);
-            }
-        } else {
-            let mut bars = Vec::with_capacity(MAX_BAR_SIZE);
-            bars.push(bar.clone());
-            self.bars.insert(bar.security_id.clone(), bars);
-        }
-
+        let capacity = self.capacity.read().bars;
+        //按时间有序，同一时间覆盖
+        self.bars
+            .entry((bar.security_id.clone(), bar.period))
+            .or_insert_with(|| Ring::new(capacity))
+            .upsert_by_key(bar, |b| b.time);
         Ok(())
     }
     fn query_bar(&self, security_id: &str, period: Period) -> Result<Option<Vec<Bar>>> {
-        if let Some(bars) = self.bars.get(security_id) {
-            Ok(Some(bars.value().clone()))
-        } else {
-            Ok(None)
-        }
+        Ok(self
+            .with_bars(security_id, period, |bars| collect(bars.iter()))
+            .flatten())
+    }
+    fn query_bar_with_time(
+        &self,
+        security_id: &str,
+        period: Period,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Bar>>> {
+        Ok(self
+            .with_bars(security_id, period, |bars| {
+                collect(
+                    bars.iter()
+                        .filter(|bar| bar.time >= begin && bar.time < end),
+                )
+            })
+            .flatten())
+    }
+    fn query_last_bar(
+        &self,
+        security_id: &str,
+        period: Period,
+        n: usize,
+    ) -> Result<Option<Vec<Bar>>> {
+        Ok(self
+            .with_bars(security_id, period, |bars| {
+                collect(bars.iter().skip(bars.len().saturating_sub(n)))
+            })
+            .flatten())
+    }
+    fn insert_analytics(&self, analytics: Analytics) -> Result<()> {
+        let capacity = self.capacity.read().analytics;
+        self.analytics
+            .entry(analytics.security_id.clone())
+            .or_insert_with(|| Ring::new(capacity))
+            .upsert_by_key(analytics, |a| a.time);
+        Ok(())
+    }
+    fn query_analytics_with_time(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Analytics>>> {
+        Ok(self
+            .analytics
+            .get(security_id)
+            .and_then(|list| collect(list.iter().filter(|a| a.time >= begin && a.time < end))))
     }
     fn insert_tick2offer(&self, tto: TickToOffer) -> Result<()> {
-        if let Some(mut ttos) = self.ttos.get_mut(&tto.security_id) {
-            ttos.value_mut().push(tto.clone());
-            if ttos.len() > MAX_TTO_SIZE {
-                ttos.remove(0);
-            }
-        } else {
-            let mut ttos = Vec::with_capacity(MAX_TTO_SIZE);
-            ttos.push(tto.clone());
-            self.ttos.insert(tto.security_id.clone(), ttos);
-        }
+        let capacity = self.capacity.read().offers;
+        self.ttos
+            .entry(tto.security_id.clone())
+            .or_insert_with(|| Ring::new(capacity))
+            .push(tto);
         Ok(())
     }
     fn query_tick2offer(&self, security_id: &str) -> Result<Option<Vec<TickToOffer>>> {
-        if let Some(ttos) = self.ttos.get(security_id) {
-            Ok(Some(ttos.value().clone()))
-        } else {
-            Ok(None)
-        }
+        Ok(self
+            .with_tick2offer(security_id, |ttos| collect(ttos.iter()))
+            .flatten())
     }
     fn insert_tick2trade(&self, ttt: TickToTrade) -> Result<()> {
-        if let Some(mut ttts) = self.ttts.get_mut(&ttt.security_id) {
-            ttts.value_mut().push(ttt.clone());
-            if ttts.len() > MAX_TTT_SIZE {
-                ttts.remove(0);
-            }
-        } else {
-            let mut ttts = Vec::with_capacity(MAX_TTT_SIZE);
-            ttts.push(ttt.clone());
-            self.ttts.insert(ttt.security_id.clone(), ttts);
-        }
+        let capacity = self.capacity.read().trades;
+        self.ttts
+            .entry(ttt.security_id.clone())
+            .or_insert_with(|| Ring::new(capacity))
+            .push(ttt);
         Ok(())
     }
     fn query_tick2trade(&self, security_id: &str) -> Result<Option<Vec<TickToTrade>>> {
-        if let Some(ttts) = self.ttts.get(security_id) {
-            Ok(Some(ttts.value().clone()))
-        } else {
-            Ok(None)
-        }
+        Ok(self
+            .with_tick2trade(security_id, |ttts| collect(ttts.iter()))
+            .flatten())
     }
     fn update_depth(&self, level2: Level2) -> Result<()> {
         self.depths.insert(level2.security_id.clone(), level2);
@@ -162,3 +334,57 @@ impl QuoteStore for MemQuoteStore {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let a = MemQuoteStore::open("memory-a");
+        let b = MemQuoteStore::open("memory-b");
+        let mut level1 = Level1::new();
+        level1.security_id = "600000".into();
+        a.update_level1(level1).unwrap();
+        //同一单元共享，不同单元隔离
+        assert!(MemQuoteStore::open("memory-a")
+            .query_one_level1("600000")
+            .unwrap()
+            .is_some());
+        assert!(b.query_one_level1("600000").unwrap().is_none());
+        MemQuoteStore::close("memory-a");
+        assert!(MemQuoteStore::open("memory-a")
+            .query_one_level1("600000")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let store = MemQuoteStore::open("memory-capacity");
+        store.set_capacity(Capacity::default().with_bars(3));
+        for time in (0..5).rev().chain(5..8) {
+            let mut bar = Level1::new().to_bar();
+            bar.security_id = "600000".into();
+            bar.time = time;
+            store.insert_bar(bar).unwrap();
+        }
+        let times = store
+            .with_bars("600000", Period::Timeline, |bars| {
+                bars.iter().map(|bar| bar.time).collect::<Vec<_>>()
+            })
+            .unwrap();
+        assert_eq!(times, vec![5, 6, 7]);
+        assert_eq!(store.bars("600000", Period::Timeline).unwrap().len(), 3);
+        store.set_capacity(Capacity::default().with_bars(2));
+        assert_eq!(
+            store
+                .query_last_bar("600000", Period::Timeline, 10)
+                .unwrap()
+                .unwrap()
+                .len(),
+            2
+        );
+        MemQuoteStore::close("memory-capacity");
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+const SCHEMA_VERSION: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TIMESTAMP
+);
+"#;
+
+#[doc = "数据库升级脚本，版本号只增不改，已发布的脚本不能修改"]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "init",
+        sql: include_str!("migrations/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "blotter",
+        sql: include_str!("migrations/0002_blotter.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "bars",
+        sql: include_str!("migrations/0003_bars.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "analytics",
+        sql: include_str!("migrations/0004_analytics.sql"),
+    },
+];
+
+//自检时必须存在的表
+const TABLES: &[&str] = &[
+    "qbox",
+    "symbols",
+    "orders",
+    "order_states",
+    "transactions",
+    "positions",
+    "position_snapshots",
+    "bars",
+    "analytics",
+];
+
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+//当前数据库版本，未初始化为0
+pub fn version(conn: &Connection) -> Result<u32> {
+    conn.execute_batch(SCHEMA_VERSION)?;
+    let version: Option<u32> =
+        conn.query_row("SELECT MAX(version) FROM schema_version;", [], |row| {
+            row.get(0)
+        })?;
+    Ok(version.unwrap_or(0))
+}
+
+//执行未应用的升级脚本，已有数据的库升级前先备份到<path>.v<版本>.bak
+pub fn migrate(conn: &Connection, path: &Path) -> Result<u32> {
+    let current = version(conn)?;
+    let latest = latest();
+    if current > latest {
+        return Err(anyhow!(
+            "database {:?} version {} is newer than {}",
+            path,
+            current,
+            latest
+        ));
+    }
+    if current < latest {
+        if has_data(conn)? {
+            backup(conn, path, current)?;
+        }
+        for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+            log::info!("migrate {:?} to version {} {}", path, m.version, m.name);
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(m.sql)
+                .map_err(|err| anyhow!("migration {} {} error {}", m.version, m.name, err))?;
+            tx.execute(
+                "INSERT INTO schema_version (version,name,applied_at) VALUES (?1,?2,CURRENT_TIMESTAMP);",
+                params![m.version, m.name],
+            )?;
+            tx.commit()?;
+        }
+    }
+    check(conn)?;
+    Ok(latest)
+}
+
+//自检：数据完整性及表结构
+pub fn check(conn: &Connection) -> Result<()> {
+    let ret: String = conn.query_row("PRAGMA quick_check;", [], |row| row.get(0))?;
+    if ret != "ok" {
+        return Err(anyhow!("database check error {}", ret));
+    }
+    let mut stat = conn.prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name=?;")?;
+    for table in TABLES {
+        if !stat.exists(params![table])? {
+            return Err(anyhow!("database check error, table {} not found", table));
+        }
+    }
+    Ok(())
+}
+
+fn has_data(conn: &Connection) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name<>'schema_version';",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn backup(conn: &Connection, path: &Path, version: u32) -> Result<()> {
+    let bak = path.with_extension(format!("v{}.bak", version));
+    if bak.exists() {
+        std::fs::remove_file(&bak)?;
+    }
+    let bak = bak
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid path {:?}", bak))?;
+    log::info!("backup {:?} to {}", path, bak);
+    conn.execute("VACUUM INTO ?;", params![bak])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let dir = std::env::temp_dir().join(format!("qbox-migrations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("unit.db");
+        //未记录版本的旧库
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(MIGRATIONS[1].sql)
+            .unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(migrate(&conn, &path).unwrap(), latest());
+        assert_eq!(version(&conn).unwrap(), latest());
+        assert!(dir.join("unit.v0.bak").exists());
+        //重复执行无副作用
+        assert_eq!(migrate(&conn, &path).unwrap(), latest());
+
+        conn.execute(
+            "INSERT INTO schema_version (version,name) VALUES (?1,'future');",
+            params![latest() + 1],
+        )
+        .unwrap();
+        assert!(migrate(&conn, &path).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
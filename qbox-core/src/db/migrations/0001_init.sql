@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS qbox (
+    unit TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (unit, key)
+);
+CREATE TABLE IF NOT EXISTS symbols (
+    security_id TEXT NOT NULL PRIMARY KEY,
+    exchange TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    base_currency TEXT NOT NULL,
+    quote_currency TEXT NOT NULL,
+    multiplier INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    items TEXT NOT NULL,
+    updated_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_symbols_exchange ON symbols (exchange);
@@ -0,0 +1,48 @@
+CREATE TABLE IF NOT EXISTS orders (
+    id INTEGER PRIMARY KEY,
+    security_id TEXT NOT NULL,
+    exchange TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    body TEXT NOT NULL,
+    updated_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_orders_security_id ON orders (security_id);
+CREATE INDEX IF NOT EXISTS idx_orders_time ON orders (time);
+CREATE TABLE IF NOT EXISTS order_states (
+    order_id INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_order_states_order_id ON order_states (order_id);
+CREATE TABLE IF NOT EXISTS transactions (
+    id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    out_id TEXT NOT NULL,
+    security_id TEXT NOT NULL,
+    exchange TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    updated_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_transactions_security_id ON transactions (security_id);
+CREATE INDEX IF NOT EXISTS idx_transactions_order_id ON transactions (order_id);
+CREATE INDEX IF NOT EXISTS idx_transactions_time ON transactions (time);
+CREATE TABLE IF NOT EXISTS positions (
+    security_id TEXT NOT NULL,
+    side TEXT NOT NULL,
+    exchange TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    PRIMARY KEY (security_id, side)
+);
+CREATE TABLE IF NOT EXISTS position_snapshots (
+    security_id TEXT NOT NULL,
+    side TEXT NOT NULL,
+    exchange TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_position_snapshots_security_id ON position_snapshots (security_id, time);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS bars (
+    security_id TEXT NOT NULL,
+    period TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+    open REAL,
+    high REAL,
+    low REAL,
+    close REAL,
+    volume REAL,
+    turnover REAL,
+    PRIMARY KEY (security_id, period, time)
+) WITHOUT ROWID;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS analytics (
+    security_id TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    PRIMARY KEY (security_id, time)
+) WITHOUT ROWID;
@@ -1,11 +1,128 @@
 pub mod memory;
+pub mod migrations;
+pub mod persy;
 // pub mod rocksdb;
 pub mod sqlite;
 
 use crate::broker::{
-    Bar, Instrument, Level1, Level2, Order, Period, Position, TickToOffer, TickToTrade, Transaction,
+    Analytics, Bar, Instrument, Level1, Level2, Order, Period, Position, TickToOffer, TickToTrade,
+    Transaction,
 };
-use anyhow::Result;
+use ahash::RandomState;
+use anyhow::{anyhow, Result};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+//存储后端配置项，默认sqlite
+const STORE_KEY: &str = "QBOX_STORE";
+//k线保留策略保存在单元的键值表中
+const RETENTION_PREFIX: &str = "retention/";
+
+#[doc = "存储后端"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Persy,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sqlite" => Ok(Backend::Sqlite),
+            "persy" => Ok(Backend::Persy),
+            _ => Err(anyhow!("unknown store backend {}", s)),
+        }
+    }
+}
+
+impl Backend {
+    //单元数据文件扩展名
+    fn extension(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "db",
+            Backend::Persy => "persy",
+        }
+    }
+}
+
+pub fn backend() -> Result<Backend> {
+    crate::setting::get_with_default::<Backend>(STORE_KEY, "sqlite")
+}
+
+#[doc = "完整存储"]
+pub trait Store: QuoteStore + OrderStore + QboxStore + Send + Sync {}
+
+impl<T: QuoteStore + OrderStore + QboxStore + Send + Sync> Store for T {}
+
+//按单元登记的存储实例，每个单元独立的数据文件
+static STORES: Lazy<DashMap<String, Arc<dyn Store>, RandomState>> =
+    Lazy::new(|| DashMap::with_hasher(RandomState::new()));
+
+//按启动配置的后端打开存储，同一单元返回同一实例
+pub fn open<S: AsRef<str>>(unit: S) -> Result<Arc<dyn Store>> {
+    let unit = unit.as_ref();
+    if let Some(store) = STORES.get(unit) {
+        return Ok(store.value().clone());
+    }
+    match STORES.entry(unit.to_string()) {
+        Entry::Occupied(entry) => Ok(entry.get().clone()),
+        Entry::Vacant(entry) => {
+            let store: Arc<dyn Store> = match backend()? {
+                Backend::Sqlite => Arc::new(sqlite::SqliteStore::open(unit)?),
+                Backend::Persy => Arc::new(persy::PersyStore::open(unit)?),
+            };
+            entry.insert(store.clone());
+            Ok(store)
+        }
+    }
+}
+
+//关闭单元，最后一个引用释放后数据文件关闭
+pub fn close<S: AsRef<str>>(unit: S) -> bool {
+    let unit = unit.as_ref();
+    memory::MemQuoteStore::close(unit);
+    STORES.remove(unit).is_some()
+}
+
+fn file_path(file: &str) -> Result<PathBuf> {
+    Ok(Path::new(&crate::data_path()).join(format!("{}.{}", file, backend()?.extension())))
+}
+
+//在另一个数据文件上打开单元，不登记到已打开的单元，用于先写入再替换
+pub fn open_file<S: AsRef<str>, F: AsRef<str>>(unit: S, file: F) -> Result<Arc<dyn Store>> {
+    let (unit, file) = (unit.as_ref(), file.as_ref());
+    Ok(match backend()? {
+        Backend::Sqlite => Arc::new(sqlite::SqliteStore::open_file(unit, file)?),
+        Backend::Persy => Arc::new(persy::PersyStore::open_file(unit, file)?),
+    })
+}
+
+pub fn remove_file<F: AsRef<str>>(file: F) -> Result<()> {
+    let path = file_path(file.as_ref())?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+//关闭单元并用数据文件替换，调用方不能再持有该单元的存储
+pub fn replace<F: AsRef<str>, S: AsRef<str>>(file: F, unit: S) -> Result<()> {
+    let unit = unit.as_ref();
+    close(unit);
+    std::fs::rename(file_path(file.as_ref())?, file_path(unit)?)?;
+    Ok(())
+}
+
+//已打开的单元
+pub fn units() -> Vec<String> {
+    STORES.iter().map(|item| item.key().clone()).collect()
+}
 
 pub trait QuoteStore {
     fn update_level1(&self, level1: Level1) -> Result<()> {
@@ -23,12 +140,52 @@ pub trait QuoteStore {
     fn query_level1_with_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<Level1>>> {
         unimplemented!()
     }
+    //同一证券、周期、时间的k线覆盖写入，用于更新未完成的k线
     fn insert_bar(&self, bar: Bar) -> Result<()> {
         unimplemented!()
     }
     fn query_bar(&self, security_id: &str, period: Period) -> Result<Option<Vec<Bar>>> {
         unimplemented!()
     }
+    //设置k线保留策略并立即清理过期k线，策略随单元保存，重新打开时生效
+    fn set_retention(&self, period: Period, retention: Retention) -> Result<()> {
+        Err(anyhow!("set_retention unsupported"))
+    }
+    fn retention(&self, period: Period) -> Retention {
+        Retention::Forever
+    }
+    //时间在[begin,end)内的k线，按时间升序
+    fn query_bar_with_time(
+        &self,
+        security_id: &str,
+        period: Period,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Bar>>> {
+        Err(anyhow!("query_bar_with_time unsupported"))
+    }
+    //最近n根k线，按时间升序
+    fn query_last_bar(
+        &self,
+        security_id: &str,
+        period: Period,
+        n: usize,
+    ) -> Result<Option<Vec<Bar>>> {
+        Err(anyhow!("query_last_bar unsupported"))
+    }
+    //微观结构指标快照，同一证券、时间覆盖写入
+    fn insert_analytics(&self, analytics: Analytics) -> Result<()> {
+        Err(anyhow!("insert_analytics unsupported"))
+    }
+    //时间在[begin,end)内的指标快照，按时间升序
+    fn query_analytics_with_time(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Analytics>>> {
+        Err(anyhow!("query_analytics_with_time unsupported"))
+    }
     fn insert_tick2offer(&self, tto: TickToOffer) -> Result<()> {
         unimplemented!()
     }
@@ -49,6 +206,46 @@ pub trait QuoteStore {
     }
 }
 
+#[doc = "k线保留策略"]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Retention {
+    //永久保留
+    Forever,
+    //保留最近n天
+    Days(u32),
+    //保留最近n根
+    Count(usize),
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention::Forever
+    }
+}
+
+fn retention_key(period: Period) -> String {
+    let period: String = period.into();
+    format!("{}{}", RETENTION_PREFIX, period)
+}
+
+//保存保留策略，永久保留时删除记录
+fn save_retention<S: QboxStore>(store: &S, period: Period, retention: Retention) -> Result<()> {
+    match retention {
+        Retention::Forever => store.remove(&retention_key(period)),
+        _ => store.set(&retention_key(period), &ron::to_string(&retention)?),
+    }
+}
+
+//已保存的保留策略
+fn load_retentions<S: QboxStore>(store: &S) -> Result<Vec<(Period, Retention)>> {
+    let mut ret = vec![];
+    for (key, value) in store.get_prefix(RETENTION_PREFIX)?.unwrap_or_default() {
+        let period = Period::from(&key[RETENTION_PREFIX.len()..]);
+        ret.push((period, ron::from_str(&value)?));
+    }
+    Ok(ret)
+}
+
 pub trait OrderStore {
     fn insert_order(&self, order: Order) -> Result<()> {
         unimplemented!()
@@ -68,6 +265,14 @@ pub trait OrderStore {
     fn query_all_order(&self) -> Result<Option<Vec<Order>>> {
         unimplemented!()
     }
+    //委托时间在[begin,end)内的订单
+    fn query_order_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Order>>> {
+        Err(anyhow!("query_order_with_time unsupported"))
+    }
+    //订单状态变化历史，按写入顺序
+    fn query_order_history(&self, order_id: u64) -> Result<Option<Vec<Order>>> {
+        Err(anyhow!("query_order_history unsupported"))
+    }
 
     fn insert_tx(&self, tx: Transaction) -> Result<()> {
         unimplemented!()
@@ -97,6 +302,10 @@ pub trait OrderStore {
     fn query_all_tx(&self) -> Result<Option<Vec<Transaction>>> {
         unimplemented!()
     }
+    //成交时间在[begin,end)内的成交记录
+    fn query_tx_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Transaction>>> {
+        Err(anyhow!("query_tx_with_time unsupported"))
+    }
 
     fn update_position(&self, position: Position) -> Result<()> {
         unimplemented!()
@@ -111,9 +320,36 @@ pub trait OrderStore {
     fn query_all_position(&self) -> Result<Option<Vec<Position>>> {
         unimplemented!()
     }
+    //快照时间在[begin,end)内的持仓快照，返回(时间,持仓)
+    fn query_position_history(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<(i64, Position)>>> {
+        Err(anyhow!("query_position_history unsupported"))
+    }
+}
+
+#[doc = "单元状态，在同一读事务中读取"]
+#[derive(Debug, Clone, Default)]
+pub struct UnitState {
+    pub settings: Vec<(String, String)>,
+    pub instruments: Vec<Instrument>,
+    //每个订单的状态历史，最后一条为当前状态
+    pub orders: Vec<Vec<Order>>,
+    pub transactions: Vec<Transaction>,
+    pub positions: Vec<Position>,
+    //持仓快照(时间,持仓)，按写入顺序
+    pub position_history: Vec<(i64, Position)>,
 }
 
 pub trait QboxStore {
+    //一致读取单元的设置、证券、订单、成交及持仓，读取期间的写入不可见
+    fn read_state(&self) -> Result<UnitState> {
+        Err(anyhow!("read_state unsupported"))
+    }
+
     fn set(&self, k: &str, v: &str) -> Result<()> {
         unimplemented!()
     }
@@ -0,0 +1,1259 @@
+use super::memory::MemQuoteStore;
+use super::{
+    load_retentions, save_retention, OrderStore, QboxStore, QuoteStore, Retention, UnitState,
+};
+use crate::broker::*;
+use crate::codec::{self, Format, Versioned};
+use ahash::RandomState;
+use anyhow::Result;
+use chrono::Utc;
+use dashmap::DashMap;
+use persy::{Config, IndexType, Persy, PersyId, Snapshot, ValueMode};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+
+//数据段
+const QBOX: &str = "qbox";
+const SYMBOLS: &str = "symbols";
+const ORDERS: &str = "orders";
+const ORDER_STATES: &str = "order_states";
+const TRANSACTIONS: &str = "transactions";
+const POSITIONS: &str = "positions";
+const POSITION_SNAPSHOTS: &str = "position_snapshots";
+const BARS: &str = "bars";
+const ANALYTICS: &str = "analytics";
+const SEGMENTS: &[&str] = &[
+    QBOX,
+    SYMBOLS,
+    ORDERS,
+    ORDER_STATES,
+    TRANSACTIONS,
+    POSITIONS,
+    POSITION_SNAPSHOTS,
+    BARS,
+    ANALYTICS,
+];
+
+//索引，字符串索引按字典序，可用于前缀查询
+const QBOX_KEY: &str = "qbox_key";
+const SYMBOLS_ID: &str = "symbols_id";
+const ORDERS_ID: &str = "orders_id";
+const ORDERS_SECURITY: &str = "orders_security";
+const ORDERS_TIME: &str = "orders_time";
+//旧版本的状态历史索引，按记录编号排列，打开时迁移
+const ORDER_STATES_ORDER: &str = "order_states_order";
+//状态历史按(订单号,写入序号)排列，与sqlite的写入顺序一致
+const ORDER_STATES_SEQ: &str = "order_states_seq";
+const TX_ID: &str = "tx_id";
+const TX_ORDER: &str = "tx_order";
+const TX_SECURITY: &str = "tx_security";
+const TX_TIME: &str = "tx_time";
+const POSITIONS_KEY: &str = "positions_key";
+const POSITION_SNAPSHOTS_SECURITY: &str = "position_snapshots_security";
+const BARS_KEY: &str = "bars_key";
+const ANALYTICS_KEY: &str = "analytics_key";
+
+#[derive(Clone)]
+pub struct PersyStore {
+    unit: String,
+    inner: Persy,
+    //实时行情只保存在内存，k线持久化
+    quotes: MemQuoteStore,
+    retentions: Arc<DashMap<Period, Retention, RandomState>>,
+    last_bars: Arc<DashMap<(String, Period), i64, RandomState>>,
+}
+
+impl PersyStore {
+    //数据文件独占打开，进程内按单元共享请使用db::open
+    pub fn open<S: AsRef<str>>(unit: S) -> Result<PersyStore> {
+        Self::open_file(unit.as_ref(), unit.as_ref())
+    }
+
+    //单元数据写入指定的数据文件
+    pub fn open_file<S: AsRef<str>, F: AsRef<str>>(unit: S, file: F) -> Result<PersyStore> {
+        let unit = unit.as_ref();
+        let path = Path::new(&crate::data_path()).join(format!("{}.persy", file.as_ref()));
+        if !path.exists() {
+            Persy::create(&path)?;
+        }
+        let inner = Persy::open(&path, Config::new())?;
+        init(&inner)?;
+        let db = Self {
+            unit: unit.into(),
+            inner,
+            quotes: MemQuoteStore::open(unit),
+            retentions: Arc::new(DashMap::with_hasher(RandomState::new())),
+            last_bars: Arc::new(DashMap::with_hasher(RandomState::new())),
+        };
+        //按保存的保留策略清理
+        for (period, retention) in load_retentions(&db)? {
+            db.retentions.insert(period, retention);
+            db.purge_period(period)?;
+        }
+        Ok(db)
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    //清理该周期所有证券的k线
+    fn purge_period(&self, period: Period) -> Result<()> {
+        if self.retention(period) == Retention::Forever {
+            return Ok(());
+        }
+        for security_id in self.bar_securities(period)? {
+            self.purge_bars(&security_id, period)?;
+        }
+        Ok(())
+    }
+
+    //有该周期k线的证券，键按证券、周期排列
+    fn bar_securities(&self, period: Period) -> Result<Vec<String>> {
+        let period: String = period.into();
+        let mut ret: Vec<String> = vec![];
+        for (key, _) in self.inner.range::<String, PersyId, _>(BARS_KEY, ..)? {
+            let mut parts = key.rsplitn(3, '/');
+            if let (Some(_), Some(p), Some(security_id)) =
+                (parts.next(), parts.next(), parts.next())
+            {
+                if p == period && ret.last().map(|s| s.as_str()) != Some(security_id) {
+                    ret.push(security_id.to_string());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn purge_bars(&self, security_id: &str, period: Period) -> Result<()> {
+        let prefix = bar_prefix(security_id, period);
+        let keys: Vec<(String, PersyId)> = match self.retention(period) {
+            Retention::Forever => return Ok(()),
+            Retention::Days(days) => {
+                let time = Utc::now().timestamp() - days as i64 * 86400;
+                self.inner
+                    .range::<String, PersyId, _>(
+                        BARS_KEY,
+                        prefix.clone()..bar_key(security_id, period, time),
+                    )?
+                    .flat_map(|(k, ids)| ids.map(move |id| (k.clone(), id)))
+                    .collect()
+            }
+            Retention::Count(n) => self
+                .inner
+                .range::<String, PersyId, _>(BARS_KEY, prefix.clone()..)?
+                .take_while(|(k, _)| k.starts_with(&prefix))
+                .flat_map(|(k, ids)| ids.map(move |id| (k.clone(), id)))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+                .skip(n)
+                .collect(),
+        };
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut tx = self.inner.begin()?;
+        for (key, id) in keys {
+            tx.delete(BARS, &id)?;
+            tx.remove::<String, PersyId>(BARS_KEY, key, None)?;
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+
+    fn read<T: Versioned>(&self, segment: &str, id: &PersyId) -> Result<Option<T>> {
+        match self.inner.read(segment, id)? {
+            Some(body) => Ok(Some(codec::decode(Format::Bincode, &body)?)),
+            None => Ok(None),
+        }
+    }
+
+    //按索引读取记录
+    fn read_by<K: IndexType, T: Versioned>(
+        &self,
+        index: &str,
+        segment: &str,
+        k: &K,
+    ) -> Result<Vec<T>> {
+        let mut ret = vec![];
+        for id in self.inner.get::<K, PersyId>(index, k)? {
+            if let Some(val) = self.read(segment, &id)? {
+                ret.push(val);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn prefix_ids(&self, index: &str, prefix: &str) -> Result<Vec<(String, PersyId)>> {
+        Ok(self
+            .inner
+            .range::<String, PersyId, _>(index, prefix.to_string()..)?
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .flat_map(|(k, ids)| ids.map(move |id| (k.clone(), id)))
+            .collect())
+    }
+
+    fn orders_by_ids<I: IntoIterator<Item = u64>>(&self, ids: I) -> Result<Vec<Order>> {
+        let mut ret = vec![];
+        for id in ids {
+            ret.extend(self.read_by::<u64, Order>(ORDERS_ID, ORDERS, &id)?);
+        }
+        Ok(ret)
+    }
+
+    fn txs_by_ids<I: IntoIterator<Item = u64>>(&self, ids: I) -> Result<Vec<Transaction>> {
+        let mut ret = vec![];
+        for id in ids {
+            ret.extend(self.read_by::<u64, Transaction>(TX_ID, TRANSACTIONS, &id)?);
+        }
+        Ok(ret)
+    }
+}
+
+//创建缺少的数据段和索引
+fn init(persy: &Persy) -> Result<()> {
+    let mut tx = persy.begin()?;
+    for segment in SEGMENTS {
+        if !persy.exists_segment(segment)? {
+            tx.create_segment(segment)?;
+        }
+    }
+    macro_rules! index {
+        ($name:expr, $k:ty, $v:ty, $mode:expr) => {
+            if !persy.exists_index($name)? {
+                tx.create_index::<$k, $v>($name, $mode)?;
+            }
+        };
+    }
+    index!(QBOX_KEY, String, PersyId, ValueMode::Replace);
+    index!(SYMBOLS_ID, String, PersyId, ValueMode::Replace);
+    index!(ORDERS_ID, u64, PersyId, ValueMode::Replace);
+    index!(ORDERS_SECURITY, String, u64, ValueMode::Cluster);
+    index!(ORDERS_TIME, i64, u64, ValueMode::Cluster);
+    index!(ORDER_STATES_SEQ, u128, PersyId, ValueMode::Replace);
+    index!(TX_ID, u64, PersyId, ValueMode::Replace);
+    index!(TX_ORDER, u64, u64, ValueMode::Cluster);
+    index!(TX_SECURITY, String, u64, ValueMode::Cluster);
+    index!(TX_TIME, i64, u64, ValueMode::Cluster);
+    index!(POSITIONS_KEY, String, PersyId, ValueMode::Replace);
+    index!(
+        POSITION_SNAPSHOTS_SECURITY,
+        String,
+        PersyId,
+        ValueMode::Cluster
+    );
+    index!(BARS_KEY, String, PersyId, ValueMode::Replace);
+    index!(ANALYTICS_KEY, String, PersyId, ValueMode::Replace);
+    tx.prepare()?.commit()?;
+    migrate_order_states(persy)
+}
+
+//旧的状态历史无法还原写入顺序，按状态时间排列后迁移
+fn migrate_order_states(persy: &Persy) -> Result<()> {
+    if !persy.exists_index(ORDER_STATES_ORDER)? {
+        return Ok(());
+    }
+    let mut tx = persy.begin()?;
+    for (order_id, ids) in persy.range::<u64, PersyId, _>(ORDER_STATES_ORDER, ..)? {
+        let mut states = vec![];
+        for id in ids {
+            if let Some(body) = persy.read(ORDER_STATES, &id)? {
+                let order: Order = codec::decode(Format::Bincode, &body)?;
+                states.push((order.state().last_time, id));
+            }
+        }
+        states.sort_by_key(|(time, _)| *time);
+        for (seq, (_, id)) in states.into_iter().enumerate() {
+            tx.put::<u128, PersyId>(ORDER_STATES_SEQ, state_key(order_id, seq as u64), id)?;
+        }
+    }
+    tx.drop_index(ORDER_STATES_ORDER)?;
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+//订单号在高64位，同一订单的状态按写入序号连续排列
+fn state_key(order_id: u64, seq: u64) -> u128 {
+    ((order_id as u128) << 64) | seq as u128
+}
+
+fn state_range(order_id: u64) -> RangeInclusive<u128> {
+    state_key(order_id, 0)..=state_key(order_id, u64::MAX)
+}
+
+fn bar_prefix(security_id: &str, period: Period) -> String {
+    let period: String = period.into();
+    format!("{}/{}/", security_id, period)
+}
+
+//时间补齐到20位，保证字典序与时间顺序一致
+fn bar_key(security_id: &str, period: Period, time: i64) -> String {
+    format!("{}{:020}", bar_prefix(security_id, period), time.max(0))
+}
+
+fn analytics_key(security_id: &str, time: i64) -> String {
+    format!("{}/{:020}", security_id, time.max(0))
+}
+
+fn position_key(security_id: &str, side: Side) -> String {
+    format!("{}/{:?}", security_id, side)
+}
+
+//按索引从快照读取记录
+fn snapshot_read_by<K: IndexType, T: Versioned>(
+    snap: &Snapshot,
+    index: &str,
+    segment: &str,
+    k: &K,
+) -> Result<Vec<T>> {
+    let mut ret = vec![];
+    for id in snap.get::<K, PersyId>(index, k)? {
+        if let Some(body) = snap.read(segment, &id)? {
+            ret.push(codec::decode(Format::Bincode, &body)?);
+        }
+    }
+    Ok(ret)
+}
+
+//持仓快照记录：时间(i64 LE)+持仓
+fn decode_position_snapshot(body: &[u8]) -> Result<Option<(i64, Position)>> {
+    if body.len() < 8 {
+        return Ok(None);
+    }
+    let mut time = [0u8; 8];
+    time.copy_from_slice(&body[..8]);
+    Ok(Some((
+        i64::from_le_bytes(time),
+        codec::decode(Format::Bincode, &body[8..])?,
+    )))
+}
+
+fn some<T>(data: Vec<T>) -> Option<Vec<T>> {
+    if data.len() > 0 {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+impl QboxStore for PersyStore {
+    //所有读取在同一快照上进行
+    fn read_state(&self) -> Result<UnitState> {
+        let snap = self.inner.snapshot()?;
+        let mut state = UnitState::default();
+        for (key, ids) in snap.range::<String, PersyId, _>(QBOX_KEY, ..)? {
+            for id in ids {
+                if let Some(val) = snap.read(QBOX, &id)? {
+                    state.settings.push((key.clone(), String::from_utf8(val)?));
+                }
+            }
+        }
+        for (_, ids) in snap.range::<String, PersyId, _>(SYMBOLS_ID, ..)? {
+            for id in ids {
+                if let Some(body) = snap.read(SYMBOLS, &id)? {
+                    state
+                        .instruments
+                        .push(codec::decode(Format::Bincode, &body)?);
+                }
+            }
+        }
+        for (_, order_ids) in snap.range::<i64, u64, _>(ORDERS_TIME, ..)? {
+            for order_id in order_ids {
+                for order in snapshot_read_by::<u64, Order>(&snap, ORDERS_ID, ORDERS, &order_id)? {
+                    let mut history = vec![];
+                    for (_, ids) in
+                        snap.range::<u128, PersyId, _>(ORDER_STATES_SEQ, state_range(order_id))?
+                    {
+                        for id in ids {
+                            if let Some(body) = snap.read(ORDER_STATES, &id)? {
+                                history.push(codec::decode(Format::Bincode, &body)?);
+                            }
+                        }
+                    }
+                    if history.last() != Some(&order) {
+                        history.push(order);
+                    }
+                    state.orders.push(history);
+                }
+            }
+        }
+        for (_, tx_ids) in snap.range::<i64, u64, _>(TX_TIME, ..)? {
+            for tx_id in tx_ids {
+                state
+                    .transactions
+                    .extend(snapshot_read_by::<u64, Transaction>(
+                        &snap,
+                        TX_ID,
+                        TRANSACTIONS,
+                        &tx_id,
+                    )?);
+            }
+        }
+        for (_, ids) in snap.range::<String, PersyId, _>(POSITIONS_KEY, ..)? {
+            for id in ids {
+                if let Some(body) = snap.read(POSITIONS, &id)? {
+                    state.positions.push(codec::decode(Format::Bincode, &body)?);
+                }
+            }
+        }
+        for (_, ids) in snap.range::<String, PersyId, _>(POSITION_SNAPSHOTS_SECURITY, ..)? {
+            for id in ids {
+                if let Some(body) = snap.read(POSITION_SNAPSHOTS, &id)? {
+                    state
+                        .position_history
+                        .extend(decode_position_snapshot(&body)?);
+                }
+            }
+        }
+        state.position_history.sort_by_key(|(time, _)| *time);
+        Ok(state)
+    }
+
+    fn set(&self, k: &str, v: &str) -> Result<()> {
+        let mut tx = self.inner.begin()?;
+        match tx.one::<String, PersyId>(QBOX_KEY, &k.to_string())? {
+            Some(id) => tx.update(QBOX, &id, v.as_bytes())?,
+            None => {
+                let id = tx.insert(QBOX, v.as_bytes())?;
+                tx.put::<String, PersyId>(QBOX_KEY, k.into(), id)?;
+            }
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn remove(&self, k: &str) -> Result<()> {
+        let mut tx = self.inner.begin()?;
+        if let Some(id) = tx.one::<String, PersyId>(QBOX_KEY, &k.to_string())? {
+            tx.delete(QBOX, &id)?;
+            tx.remove::<String, PersyId>(QBOX_KEY, k.into(), None)?;
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn get(&self, k: &str) -> Result<Option<String>> {
+        match self
+            .inner
+            .one::<String, PersyId>(QBOX_KEY, &k.to_string())?
+        {
+            Some(id) => match self.inner.read(QBOX, &id)? {
+                Some(val) => Ok(Some(String::from_utf8(val)?)),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+    fn get_all(&self) -> Result<Option<Vec<(String, String)>>> {
+        self.get_prefix("")
+    }
+    fn get_prefix(&self, prefix: &str) -> Result<Option<Vec<(String, String)>>> {
+        let mut ret = vec![];
+        for (k, id) in self.prefix_ids(QBOX_KEY, prefix)? {
+            if let Some(val) = self.inner.read(QBOX, &id)? {
+                ret.push((k, String::from_utf8(val)?));
+            }
+        }
+        Ok(some(ret))
+    }
+    fn get_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<(String, String)>>> {
+        let mut ret = vec![];
+        for prefix in prefixs {
+            if let Some(list) = self.get_prefix(prefix)? {
+                ret.extend(list);
+            }
+        }
+        Ok(some(ret))
+    }
+
+    fn update_symbol(&self, symbol: Instrument) -> Result<()> {
+        let body = codec::encode(Format::Bincode, &symbol)?;
+        let mut tx = self.inner.begin()?;
+        match tx.one::<String, PersyId>(SYMBOLS_ID, &symbol.security_id)? {
+            Some(id) => tx.update(SYMBOLS, &id, &body)?,
+            None => {
+                let id = tx.insert(SYMBOLS, &body)?;
+                tx.put::<String, PersyId>(SYMBOLS_ID, symbol.security_id.clone(), id)?;
+            }
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn query_one_symbol(&self, security_id: &str) -> Result<Option<Instrument>> {
+        Ok(self
+            .read_by::<String, Instrument>(SYMBOLS_ID, SYMBOLS, &security_id.to_string())?
+            .into_iter()
+            .next())
+    }
+    fn query_symbol_with_prefix(&self, prefix: &str) -> Result<Option<Vec<Instrument>>> {
+        let mut ret = vec![];
+        for (_, id) in self.prefix_ids(SYMBOLS_ID, prefix)? {
+            if let Some(symbol) = self.read(SYMBOLS, &id)? {
+                ret.push(symbol);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn query_symbol_with_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<Instrument>>> {
+        let mut ret = vec![];
+        for prefix in prefixs {
+            if let Some(list) = self.query_symbol_with_prefix(prefix)? {
+                ret.extend(list);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn query_all_symbol(&self) -> Result<Option<Vec<Instrument>>> {
+        self.query_symbol_with_prefix("")
+    }
+}
+
+impl QuoteStore for PersyStore {
+    fn update_level1(&self, level1: Level1) -> Result<()> {
+        self.quotes.update_level1(level1)
+    }
+    fn query_one_level1(&self, security_id: &str) -> Result<Option<Level1>> {
+        self.quotes.query_one_level1(security_id)
+    }
+    fn query_all_level1(&self) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_all_level1()
+    }
+    fn query_level1_with_prefix(&self, prefix: &str) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_level1_with_prefix(prefix)
+    }
+    fn query_level1_with_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_level1_with_prefixs(prefixs)
+    }
+    fn insert_bar(&self, bar: Bar) -> Result<()> {
+        let key = bar_key(&bar.security_id, bar.period, bar.time);
+        let body = codec::encode(Format::Bincode, &bar)?;
+        let mut tx = self.inner.begin()?;
+        match tx.one::<String, PersyId>(BARS_KEY, &key)? {
+            Some(id) => tx.update(BARS, &id, &body)?,
+            None => {
+                let id = tx.insert(BARS, &body)?;
+                tx.put::<String, PersyId>(BARS_KEY, key, id)?;
+            }
+        }
+        tx.prepare()?.commit()?;
+        //新k线开始时按保留策略清理，未完成k线的更新不触发
+        let last = (bar.security_id.clone(), bar.period);
+        let is_new = match self.last_bars.get(&last) {
+            Some(time) => bar.time > *time.value(),
+            None => true,
+        };
+        if is_new {
+            self.last_bars.insert(last, bar.time);
+            self.purge_bars(&bar.security_id, bar.period)?;
+        }
+        Ok(())
+    }
+    fn query_bar(&self, security_id: &str, period: Period) -> Result<Option<Vec<Bar>>> {
+        let mut ret = vec![];
+        for (_, id) in self.prefix_ids(BARS_KEY, &bar_prefix(security_id, period))? {
+            if let Some(bar) = self.read(BARS, &id)? {
+                ret.push(bar);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn set_retention(&self, period: Period, retention: Retention) -> Result<()> {
+        save_retention(self, period, retention)?;
+        self.retentions.insert(period, retention);
+        self.purge_period(period)
+    }
+    fn retention(&self, period: Period) -> Retention {
+        self.retentions
+            .get(&period)
+            .map(|r| *r.value())
+            .unwrap_or_default()
+    }
+    fn query_bar_with_time(
+        &self,
+        security_id: &str,
+        period: Period,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Bar>>> {
+        let range = bar_key(security_id, period, begin)..bar_key(security_id, period, end);
+        let mut ret = vec![];
+        for (_, ids) in self.inner.range::<String, PersyId, _>(BARS_KEY, range)? {
+            for id in ids {
+                if let Some(bar) = self.read(BARS, &id)? {
+                    ret.push(bar);
+                }
+            }
+        }
+        Ok(some(ret))
+    }
+    fn query_last_bar(
+        &self,
+        security_id: &str,
+        period: Period,
+        n: usize,
+    ) -> Result<Option<Vec<Bar>>> {
+        let prefix = bar_prefix(security_id, period);
+        let ids: Vec<PersyId> = self
+            .inner
+            .range::<String, PersyId, _>(BARS_KEY, prefix.clone()..)?
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        let mut ret = vec![];
+        for id in ids.iter().skip(ids.len().saturating_sub(n)) {
+            if let Some(bar) = self.read(BARS, id)? {
+                ret.push(bar);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn insert_analytics(&self, analytics: Analytics) -> Result<()> {
+        let key = analytics_key(&analytics.security_id, analytics.time);
+        let body = codec::encode(Format::Bincode, &analytics)?;
+        let mut tx = self.inner.begin()?;
+        match tx.one::<String, PersyId>(ANALYTICS_KEY, &key)? {
+            Some(id) => tx.update(ANALYTICS, &id, &body)?,
+            None => {
+                let id = tx.insert(ANALYTICS, &body)?;
+                tx.put::<String, PersyId>(ANALYTICS_KEY, key, id)?;
+            }
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn query_analytics_with_time(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Analytics>>> {
+        let range = analytics_key(security_id, begin)..analytics_key(security_id, end);
+        let mut ret = vec![];
+        for (_, ids) in self
+            .inner
+            .range::<String, PersyId, _>(ANALYTICS_KEY, range)?
+        {
+            for id in ids {
+                if let Some(analytics) = self.read(ANALYTICS, &id)? {
+                    ret.push(analytics);
+                }
+            }
+        }
+        Ok(some(ret))
+    }
+    fn insert_tick2offer(&self, tto: TickToOffer) -> Result<()> {
+        self.quotes.insert_tick2offer(tto)
+    }
+    fn query_tick2offer(&self, security_id: &str) -> Result<Option<Vec<TickToOffer>>> {
+        self.quotes.query_tick2offer(security_id)
+    }
+    fn insert_tick2trade(&self, ttt: TickToTrade) -> Result<()> {
+        self.quotes.insert_tick2trade(ttt)
+    }
+    fn query_tick2trade(&self, security_id: &str) -> Result<Option<Vec<TickToTrade>>> {
+        self.quotes.query_tick2trade(security_id)
+    }
+    fn update_depth(&self, level2: Level2) -> Result<()> {
+        self.quotes.update_depth(level2)
+    }
+    fn query_depth(&self, security_id: &str) -> Result<Option<Level2>> {
+        self.quotes.query_depth(security_id)
+    }
+}
+
+impl OrderStore for PersyStore {
+    fn insert_order(&self, order: Order) -> Result<()> {
+        self.update_order(order)
+    }
+    //订单与状态历史在同一事务中写入
+    fn update_order(&self, order: Order) -> Result<()> {
+        let order_id = order.id();
+        let body = codec::encode(Format::Bincode, &order)?;
+        let mut tx = self.inner.begin()?;
+        match tx.one::<u64, PersyId>(ORDERS_ID, &order_id)? {
+            Some(id) => tx.update(ORDERS, &id, &body)?,
+            None => {
+                let id = tx.insert(ORDERS, &body)?;
+                tx.put::<u64, PersyId>(ORDERS_ID, order_id, id)?;
+                tx.put::<String, u64>(ORDERS_SECURITY, order.security_id().into(), order_id)?;
+                tx.put::<i64, u64>(ORDERS_TIME, order.time(), order_id)?;
+            }
+        }
+        let seq = tx
+            .range::<u128, PersyId, _>(ORDER_STATES_SEQ, state_range(order_id))?
+            .next_back()
+            .map(|(key, _)| key as u64 + 1)
+            .unwrap_or(0);
+        let id = tx.insert(ORDER_STATES, &body)?;
+        tx.put::<u128, PersyId>(ORDER_STATES_SEQ, state_key(order_id, seq), id)?;
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn remove_order(&self, order_id: u64) -> Result<()> {
+        let order = match self.query_one_order(order_id)? {
+            Some(order) => order,
+            None => return Ok(()),
+        };
+        let mut tx = self.inner.begin()?;
+        if let Some(id) = tx.one::<u64, PersyId>(ORDERS_ID, &order_id)? {
+            tx.delete(ORDERS, &id)?;
+        }
+        tx.remove::<u64, PersyId>(ORDERS_ID, order_id, None)?;
+        tx.remove::<String, u64>(ORDERS_SECURITY, order.security_id().into(), Some(order_id))?;
+        tx.remove::<i64, u64>(ORDERS_TIME, order.time(), Some(order_id))?;
+        for (key, ids) in self
+            .inner
+            .range::<u128, PersyId, _>(ORDER_STATES_SEQ, state_range(order_id))?
+        {
+            for id in ids {
+                tx.delete(ORDER_STATES, &id)?;
+            }
+            tx.remove::<u128, PersyId>(ORDER_STATES_SEQ, key, None)?;
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn query_one_order(&self, order_id: u64) -> Result<Option<Order>> {
+        Ok(self
+            .read_by::<u64, Order>(ORDERS_ID, ORDERS, &order_id)?
+            .into_iter()
+            .next())
+    }
+    fn query_order(&self, security_id: &str) -> Result<Option<Vec<Order>>> {
+        let ids = self
+            .inner
+            .get::<String, u64>(ORDERS_SECURITY, &security_id.to_string())?;
+        let mut orders = self.orders_by_ids(ids)?;
+        orders.sort_by_key(|o| o.time());
+        Ok(some(orders))
+    }
+    fn query_all_order(&self) -> Result<Option<Vec<Order>>> {
+        let ids: Vec<u64> = self
+            .inner
+            .range::<i64, u64, _>(ORDERS_TIME, ..)?
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        Ok(some(self.orders_by_ids(ids)?))
+    }
+    fn query_order_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Order>>> {
+        let ids: Vec<u64> = self
+            .inner
+            .range::<i64, u64, _>(ORDERS_TIME, begin..end)?
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        Ok(some(self.orders_by_ids(ids)?))
+    }
+    fn query_order_history(&self, order_id: u64) -> Result<Option<Vec<Order>>> {
+        let mut orders = vec![];
+        for (_, ids) in self
+            .inner
+            .range::<u128, PersyId, _>(ORDER_STATES_SEQ, state_range(order_id))?
+        {
+            for id in ids {
+                if let Some(order) = self.read(ORDER_STATES, &id)? {
+                    orders.push(order);
+                }
+            }
+        }
+        Ok(some(orders))
+    }
+
+    fn insert_tx(&self, tx: Transaction) -> Result<()> {
+        self.update_tx(tx)
+    }
+    fn update_tx(&self, tx: Transaction) -> Result<()> {
+        let body = codec::encode(Format::Bincode, &tx)?;
+        let mut ptx = self.inner.begin()?;
+        match ptx.one::<u64, PersyId>(TX_ID, &tx.id)? {
+            Some(id) => ptx.update(TRANSACTIONS, &id, &body)?,
+            None => {
+                let id = ptx.insert(TRANSACTIONS, &body)?;
+                ptx.put::<u64, PersyId>(TX_ID, tx.id, id)?;
+                ptx.put::<u64, u64>(TX_ORDER, tx.order_id, tx.id)?;
+                ptx.put::<String, u64>(TX_SECURITY, tx.security_id.clone(), tx.id)?;
+                ptx.put::<i64, u64>(TX_TIME, tx.time, tx.id)?;
+            }
+        }
+        ptx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn remove_tx(&self, txid: u64) -> Result<()> {
+        let tx = match self.query_one_tx(txid)? {
+            Some(tx) => tx,
+            None => return Ok(()),
+        };
+        let mut ptx = self.inner.begin()?;
+        if let Some(id) = ptx.one::<u64, PersyId>(TX_ID, &txid)? {
+            ptx.delete(TRANSACTIONS, &id)?;
+        }
+        ptx.remove::<u64, PersyId>(TX_ID, txid, None)?;
+        ptx.remove::<u64, u64>(TX_ORDER, tx.order_id, Some(txid))?;
+        ptx.remove::<String, u64>(TX_SECURITY, tx.security_id, Some(txid))?;
+        ptx.remove::<i64, u64>(TX_TIME, tx.time, Some(txid))?;
+        ptx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn query_one_tx(&self, txid: u64) -> Result<Option<Transaction>> {
+        Ok(self
+            .read_by::<u64, Transaction>(TX_ID, TRANSACTIONS, &txid)?
+            .into_iter()
+            .next())
+    }
+    fn query_tx_with_order(&self, order_id: u64) -> Result<Option<Vec<Transaction>>> {
+        let ids = self.inner.get::<u64, u64>(TX_ORDER, &order_id)?;
+        let mut txs = self.txs_by_ids(ids)?;
+        txs.sort_by_key(|t| t.time);
+        Ok(some(txs))
+    }
+    fn query_tx_with_symbol(&self, security_id: &str) -> Result<Option<Vec<Transaction>>> {
+        let ids = self
+            .inner
+            .get::<String, u64>(TX_SECURITY, &security_id.to_string())?;
+        let mut txs = self.txs_by_ids(ids)?;
+        txs.sort_by_key(|t| t.time);
+        Ok(some(txs))
+    }
+    fn query_tx_with_symbol_and_order(
+        &self,
+        security_id: &str,
+        order_id: u64,
+    ) -> Result<Option<Vec<Transaction>>> {
+        Ok(self.query_tx_with_order(order_id)?.and_then(|txs| {
+            some(
+                txs.into_iter()
+                    .filter(|t| t.security_id == security_id)
+                    .collect(),
+            )
+        }))
+    }
+    fn query_all_tx(&self) -> Result<Option<Vec<Transaction>>> {
+        let ids: Vec<u64> = self
+            .inner
+            .range::<i64, u64, _>(TX_TIME, ..)?
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        Ok(some(self.txs_by_ids(ids)?))
+    }
+    fn query_tx_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Transaction>>> {
+        let ids: Vec<u64> = self
+            .inner
+            .range::<i64, u64, _>(TX_TIME, begin..end)?
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        Ok(some(self.txs_by_ids(ids)?))
+    }
+
+    //当前持仓与快照在同一事务中写入，快照记录为时间(i64 LE)+持仓
+    fn update_position(&self, position: Position) -> Result<()> {
+        let key = position_key(&position.security_id, position.side);
+        let body = codec::encode(Format::Bincode, &position)?;
+        let mut snapshot = position.time.to_le_bytes().to_vec();
+        snapshot.extend_from_slice(&body);
+        let mut tx = self.inner.begin()?;
+        match tx.one::<String, PersyId>(POSITIONS_KEY, &key)? {
+            Some(id) => tx.update(POSITIONS, &id, &body)?,
+            None => {
+                let id = tx.insert(POSITIONS, &body)?;
+                tx.put::<String, PersyId>(POSITIONS_KEY, key, id)?;
+            }
+        }
+        let id = tx.insert(POSITION_SNAPSHOTS, &snapshot)?;
+        tx.put::<String, PersyId>(
+            POSITION_SNAPSHOTS_SECURITY,
+            position.security_id.clone(),
+            id,
+        )?;
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+
+    fn remove_position(&self, security_id: &str) -> Result<()> {
+        let keys = self.prefix_ids(POSITIONS_KEY, &format!("{}/", security_id))?;
+        let mut tx = self.inner.begin()?;
+        for (key, id) in keys {
+            tx.delete(POSITIONS, &id)?;
+            tx.remove::<String, PersyId>(POSITIONS_KEY, key, None)?;
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    }
+    fn query_position(&self, security_id: &str) -> Result<Option<Vec<Position>>> {
+        let mut ret = vec![];
+        for (_, id) in self.prefix_ids(POSITIONS_KEY, &format!("{}/", security_id))? {
+            if let Some(position) = self.read(POSITIONS, &id)? {
+                ret.push(position);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn query_all_position(&self) -> Result<Option<Vec<Position>>> {
+        let mut ret = vec![];
+        for (_, id) in self.prefix_ids(POSITIONS_KEY, "")? {
+            if let Some(position) = self.read(POSITIONS, &id)? {
+                ret.push(position);
+            }
+        }
+        Ok(some(ret))
+    }
+    fn query_position_history(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<(i64, Position)>>> {
+        let mut ret = vec![];
+        for id in self
+            .inner
+            .get::<String, PersyId>(POSITION_SNAPSHOTS_SECURITY, &security_id.to_string())?
+        {
+            if let Some(body) = self.inner.read(POSITION_SNAPSHOTS, &id)? {
+                if let Some((time, position)) = decode_position_snapshot(&body)? {
+                    if time >= begin && time < end {
+                        ret.push((time, position));
+                    }
+                }
+            }
+        }
+        ret.sort_by_key(|(time, _)| *time);
+        Ok(some(ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::testing::*;
+
+    //每个测试独立的数据文件
+    fn open(name: &str) -> PersyStore {
+        PersyStore::open(unit(name)).unwrap()
+    }
+
+    fn close(store: PersyStore) {
+        let unit = store.unit().to_string();
+        drop(store);
+        cleanup(&unit);
+    }
+
+    #[test]
+    fn test_qbox() {
+        let store = open("persy-qbox");
+        store.set("strategy/a", "1").unwrap();
+        store.set("strategy/b", "2").unwrap();
+        store.set("strategy/a", "3").unwrap();
+        store.set("other", "4").unwrap();
+        assert_eq!(store.get("strategy/a").unwrap().as_deref(), Some("3"));
+        assert_eq!(
+            store.get_prefix("strategy/").unwrap().unwrap(),
+            vec![
+                ("strategy/a".into(), "3".into()),
+                ("strategy/b".into(), "2".into())
+            ]
+        );
+        assert_eq!(store.get_all().unwrap().unwrap().len(), 3);
+        store.remove("strategy/a").unwrap();
+        assert!(store.get("strategy/a").unwrap().is_none());
+
+        for id in ["rb2205", "rb2210", "ag2206"] {
+            store
+                .update_symbol(Instrument::new().with_secrity_id(id))
+                .unwrap();
+        }
+        let ids: Vec<String> = store
+            .query_symbol_with_prefix("rb")
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|i| i.security_id)
+            .collect();
+        assert_eq!(ids, vec!["rb2205", "rb2210"]);
+        assert!(store.query_symbol_with_prefix("cu").unwrap().is_none());
+        close(store);
+    }
+
+    #[test]
+    fn test_orders() {
+        let store = open("persy-orders");
+        store
+            .insert_order(order(1, TIME, State::Created, TIME))
+            .unwrap();
+        //回报时间可能乱序，状态历史按写入顺序
+        store
+            .update_order(order(1, TIME, State::Accepted, TIME + 5))
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Filled, TIME + 2))
+            .unwrap();
+        store
+            .insert_order(order(2, TIME + 60, State::Created, TIME + 60))
+            .unwrap();
+
+        //订单与状态历史同时写入
+        assert_eq!(
+            store.query_one_order(1).unwrap().unwrap().state().state,
+            State::Filled
+        );
+        let states: Vec<State> = store
+            .query_order_history(1)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|o| o.state().state)
+            .collect();
+        assert_eq!(states, vec![State::Created, State::Accepted, State::Filled]);
+        assert_eq!(store.query_order("rb2205").unwrap().unwrap().len(), 2);
+
+        let orders = store
+            .query_order_with_time(TIME, TIME + 60)
+            .unwrap()
+            .unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].id(), 1);
+
+        store.remove_order(1).unwrap();
+        assert!(store.query_one_order(1).unwrap().is_none());
+        assert!(store.query_order_history(1).unwrap().is_none());
+        assert!(store
+            .query_order_with_time(TIME, TIME + 1)
+            .unwrap()
+            .is_none());
+        assert_eq!(store.query_all_order().unwrap().unwrap().len(), 1);
+        close(store);
+    }
+
+    #[test]
+    fn test_migrate_order_states() {
+        let store = open("persy-migrate");
+        let unit = store.unit().to_string();
+        //旧版本的状态历史索引
+        let mut tx = store.inner.begin().unwrap();
+        tx.create_index::<u64, PersyId>(ORDER_STATES_ORDER, ValueMode::Cluster)
+            .unwrap();
+        for (state, time) in [(State::Filled, TIME + 2), (State::Created, TIME)] {
+            let body = codec::encode(Format::Bincode, &order(1, TIME, state, time)).unwrap();
+            let id = tx.insert(ORDER_STATES, &body).unwrap();
+            tx.put::<u64, PersyId>(ORDER_STATES_ORDER, 1, id).unwrap();
+        }
+        tx.prepare().unwrap().commit().unwrap();
+        drop(store);
+
+        let store = PersyStore::open(&unit).unwrap();
+        assert!(!store.inner.exists_index(ORDER_STATES_ORDER).unwrap());
+        store
+            .update_order(order(1, TIME, State::Cancelled, TIME + 1))
+            .unwrap();
+        let states: Vec<State> = store
+            .query_order_history(1)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|o| o.state().state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![State::Created, State::Filled, State::Cancelled]
+        );
+        close(store);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let store = open("persy-transactions");
+        store.insert_tx(tx(1, 1, "rb2205", TIME)).unwrap();
+        store.insert_tx(tx(2, 1, "rb2205", TIME + 1)).unwrap();
+        store.insert_tx(tx(3, 2, "ag2206", TIME + 60)).unwrap();
+        let mut updated = tx(2, 1, "rb2205", TIME + 1);
+        updated.price = 4801.0;
+        store.update_tx(updated).unwrap();
+
+        assert_eq!(store.query_one_tx(2).unwrap().unwrap().price, 4801.0);
+        assert_eq!(store.query_tx_with_order(1).unwrap().unwrap().len(), 2);
+        assert_eq!(
+            store.query_tx_with_symbol("ag2206").unwrap().unwrap().len(),
+            1
+        );
+        assert!(store
+            .query_tx_with_symbol_and_order("ag2206", 1)
+            .unwrap()
+            .is_none());
+        let ids: Vec<u64> = store
+            .query_tx_with_time(TIME, TIME + 60)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        store.remove_tx(1).unwrap();
+        assert!(store.query_one_tx(1).unwrap().is_none());
+        assert_eq!(store.query_all_tx().unwrap().unwrap().len(), 2);
+        close(store);
+    }
+
+    #[test]
+    fn test_positions() {
+        let store = open("persy-positions");
+        store
+            .update_position(position("rb2205", Side::Long, 1, TIME))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 3, TIME + 60))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 2, TIME + 120))
+            .unwrap();
+
+        let current = store.query_position("rb2205").unwrap().unwrap();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].quantity, 2);
+        let history: Vec<(i64, i64)> = store
+            .query_position_history("rb2205", TIME, TIME + 120)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|(t, p)| (*t, p.quantity))
+            .collect();
+        assert_eq!(history, vec![(TIME, 1), (TIME + 60, 3)]);
+
+        store.remove_position("rb2205").unwrap();
+        assert!(store.query_position("rb2205").unwrap().is_none());
+        close(store);
+    }
+
+    #[test]
+    fn test_read_state() {
+        let store = open("persy-state");
+        store.set("strategy/grid", "{}").unwrap();
+        store
+            .update_order(order(1, TIME, State::Submitted, TIME))
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Filled, TIME + 1))
+            .unwrap();
+        store.insert_tx(tx(10, 1, "rb2205", TIME + 1)).unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 1, TIME))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 2, TIME + 60))
+            .unwrap();
+
+        let state = store.read_state().unwrap();
+        assert_eq!(state.settings, vec![("strategy/grid".into(), "{}".into())]);
+        assert_eq!(state.orders.len(), 1);
+        let states: Vec<State> = state.orders[0].iter().map(|o| o.state().state).collect();
+        assert_eq!(states, vec![State::Submitted, State::Filled]);
+        assert_eq!(state.transactions.len(), 1);
+        assert_eq!(state.positions.len(), 1);
+        assert_eq!(state.positions[0].quantity, 2);
+        let history: Vec<(i64, i64)> = state
+            .position_history
+            .iter()
+            .map(|(t, p)| (*t, p.quantity))
+            .collect();
+        assert_eq!(history, vec![(TIME, 1), (TIME + 60, 2)]);
+        close(store);
+    }
+
+    #[test]
+    fn test_bars() {
+        let store = open("persy-bars");
+        for i in 0..5 {
+            store
+                .insert_bar(bar(Period::Minute(1), TIME + i * 60, 4800.0))
+                .unwrap();
+        }
+        //同一时间覆盖写入
+        store
+            .insert_bar(bar(Period::Minute(1), TIME + 240, 4810.0))
+            .unwrap();
+        let bars = store
+            .query_bar("rb2205", Period::Minute(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(bars.len(), 5);
+        assert_eq!(bars[4].close, 4810.0);
+        assert!(store
+            .query_bar("rb2205", Period::Minute(5))
+            .unwrap()
+            .is_none());
+
+        let times: Vec<i64> = store
+            .query_bar_with_time("rb2205", Period::Minute(1), TIME + 60, TIME + 180)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|b| b.time)
+            .collect();
+        assert_eq!(times, vec![TIME + 60, TIME + 120]);
+        let last = store
+            .query_last_bar("rb2205", Period::Minute(1), 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(last[0].time, TIME + 180);
+        assert_eq!(last[1].close, 4810.0);
+
+        store
+            .set_retention(Period::Minute(1), Retention::Count(3))
+            .unwrap();
+        assert_eq!(
+            store
+                .query_bar("rb2205", Period::Minute(1))
+                .unwrap()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        //保留策略随单元保存
+        let unit = store.unit().to_string();
+        drop(store);
+        let store = PersyStore::open(unit).unwrap();
+        assert_eq!(store.retention(Period::Minute(1)), Retention::Count(3));
+        close(store);
+    }
+
+    #[test]
+    fn test_bar_key() {
+        //负数时间归为0，保证键的字典序与时间一致
+        assert_eq!(
+            bar_key("rb2205", Period::Minute(1), -60),
+            bar_key("rb2205", Period::Minute(1), 0)
+        );
+        assert!(bar_key("rb2205", Period::Minute(1), 9) < bar_key("rb2205", Period::Minute(1), 10));
+
+        let store = open("persy-bar-key");
+        store.insert_bar(bar(Period::Minute(1), -60, 1.0)).unwrap();
+        store.insert_bar(bar(Period::Minute(1), 0, 2.0)).unwrap();
+        let bars = store
+            .query_bar("rb2205", Period::Minute(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].close, 2.0);
+        close(store);
+    }
+
+    #[test]
+    fn test_backend() {
+        std::env::set_var(db::STORE_KEY, "persy");
+        assert_eq!(db::backend().unwrap(), db::Backend::Persy);
+        let unit = unit("persy-backend");
+        let store = db::open(&unit).unwrap();
+        std::env::remove_var(db::STORE_KEY);
+        store.set("key", "value").unwrap();
+        assert!(Path::new(&crate::data_path())
+            .join(format!("{}.persy", unit))
+            .exists());
+        //同一单元返回同一实例
+        assert_eq!(
+            db::open(&unit).unwrap().get("key").unwrap().as_deref(),
+            Some("value")
+        );
+        assert!(db::close(&unit));
+        drop(store);
+        cleanup(&unit);
+    }
+}
@@ -1,80 +1,213 @@
-use super::{OrderStore, QboxStore};
+use super::memory::MemQuoteStore;
+use super::migrations;
+use super::{
+    load_retentions, save_retention, OrderStore, QboxStore, QuoteStore, Retention, UnitState,
+};
 use crate::broker::*;
+use crate::codec;
 use ahash::RandomState;
 use anyhow::Result;
+use chrono::Utc;
 use dashmap::DashMap;
-use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use rusqlite::{params, Connection, OpenFlags, Params};
 use std::path::Path;
 use std::sync::Arc;
 
+//缓存只做写穿透，数据以数据库为准
 #[derive(Clone)]
 pub struct SqliteStore {
     unit: String,
-    inner: Arc<Connection>,
-    symbols: DashMap<String, Instrument, RandomState>,
-    orders: DashMap<String, Vec<Order>, RandomState>,
-    transactions: DashMap<String, DashMap<u64, Vec<Transaction>, RandomState>, RandomState>,
-    positions: DashMap<String, Vec<Position>, RandomState>,
+    //连接不可跨线程共享，多线程访问时串行
+    inner: Arc<Mutex<Connection>>,
+    symbols: Arc<DashMap<String, Instrument, RandomState>>,
+    orders: Arc<DashMap<String, Vec<Order>, RandomState>>,
+    transactions: Arc<DashMap<String, DashMap<u64, Vec<Transaction>, RandomState>, RandomState>>,
+    positions: Arc<DashMap<String, Vec<Position>, RandomState>>,
+    //实时行情只保存在内存，k线持久化
+    quotes: MemQuoteStore,
+    retentions: Arc<DashMap<Period, Retention, RandomState>>,
+    last_bars: Arc<DashMap<(String, Period), i64, RandomState>>,
 }
 
-unsafe impl Send for SqliteStore {}
-unsafe impl Sync for SqliteStore {}
-
 impl SqliteStore {
+    //每次打开新连接，进程内按单元共享请使用db::open
     pub fn open<S: AsRef<str>>(unit: S) -> Result<SqliteStore> {
+        Self::open_file(unit.as_ref(), unit.as_ref())
+    }
+
+    //单元数据写入指定的数据文件
+    pub fn open_file<S: AsRef<str>, F: AsRef<str>>(unit: S, file: F) -> Result<SqliteStore> {
         let unit = unit.as_ref();
-        let path = Path::new(&crate::data_path()).join(format!("{}.db", unit));
-        static INSTANCE: OnceCell<SqliteStore> = OnceCell::new();
-        let ret = INSTANCE
-            .get_or_init(|| {
-                let conn = Arc::new(
-                    Connection::open_with_flags(
-                        path,
-                        OpenFlags::SQLITE_OPEN_CREATE
-                            | OpenFlags::SQLITE_OPEN_READ_WRITE
-                            | OpenFlags::SQLITE_OPEN_NO_MUTEX
-                            | OpenFlags::SQLITE_OPEN_SHARED_CACHE
-                            | OpenFlags::SQLITE_OPEN_URI,
-                    )
-                    .unwrap(),
-                );
-                let db = Self {
-                    unit: unit.into(),
-                    inner: conn,
-                    symbols: DashMap::with_hasher(RandomState::new()),
-                    orders: DashMap::with_hasher(RandomState::new()),
-                    transactions: DashMap::with_hasher(RandomState::new()),
-                    positions: DashMap::with_hasher(RandomState::new()),
-                };
-                if let Ok(Some(list)) = db.query_all_symbol() {
-                    for itr in list {
-                        db.symbols.insert(itr.security_id.clone(), itr);
-                    }
-                }
-                db
-            })
-            .clone();
-        Ok(ret)
+        let path = Path::new(&crate::data_path()).join(format!("{}.db", file.as_ref()));
+        let conn = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        migrations::migrate(&conn, &path)?;
+        let db = Self {
+            unit: unit.into(),
+            inner: Arc::new(Mutex::new(conn)),
+            symbols: Arc::new(DashMap::with_hasher(RandomState::new())),
+            orders: Arc::new(DashMap::with_hasher(RandomState::new())),
+            transactions: Arc::new(DashMap::with_hasher(RandomState::new())),
+            positions: Arc::new(DashMap::with_hasher(RandomState::new())),
+            quotes: MemQuoteStore::open(unit),
+            retentions: Arc::new(DashMap::with_hasher(RandomState::new())),
+            last_bars: Arc::new(DashMap::with_hasher(RandomState::new())),
+        };
+        if let Ok(Some(list)) = db.query_all_symbol() {
+            for itr in list {
+                db.symbols.insert(itr.security_id.clone(), itr);
+            }
+        }
+        //加载当前持仓
+        const SQL: &str = "SELECT time,body FROM positions;";
+        if let Some(list) = select_positions(&db.inner.lock(), SQL, [])? {
+            for (_, position) in list {
+                db.cache_position(position);
+            }
+        }
+        //按保存的保留策略清理
+        for (period, retention) in load_retentions(&db)? {
+            db.retentions.insert(period, retention);
+            db.purge_bars(None, period)?;
+        }
+        Ok(db)
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    //按保留策略清理k线，security_id为None时清理该周期所有证券
+    fn purge_bars(&self, security_id: Option<&str>, period: Period) -> Result<()> {
+        const DAYS_SQL: &str =
+            "DELETE FROM bars WHERE (?1 IS NULL OR security_id=?1) AND period=?2 AND time<?3;";
+        const COUNT_SQL: &str = r#"DELETE FROM bars WHERE (?1 IS NULL OR security_id=?1) AND period=?2 AND time<=(
+            SELECT b.time FROM bars b WHERE b.security_id=bars.security_id AND b.period=?2
+            ORDER BY b.time DESC LIMIT 1 OFFSET ?3);"#;
+        let p: String = period.into();
+        match self.retention(period) {
+            Retention::Forever => {}
+            Retention::Days(days) => {
+                let time = Utc::now().timestamp() - days as i64 * 86400;
+                self.inner
+                    .lock()
+                    .execute(DAYS_SQL, params![security_id, p, time])?;
+            }
+            Retention::Count(n) => {
+                self.inner
+                    .lock()
+                    .execute(COUNT_SQL, params![security_id, p, n as i64])?;
+            }
+        }
+        Ok(())
+    }
+
+    //缓存按证券整体加载，未加载的证券不写缓存，避免缓存不完整
+    fn cache_order(&self, order: Order) {
+        if let Some(mut orders) = self.orders.get_mut(order.security_id()) {
+            if let Some(exist) = orders.iter_mut().find(|o| o.id() == order.id()) {
+                *exist = order;
+            } else {
+                orders.push(order);
+            }
+        }
+    }
+
+    fn cache_tx(&self, tx: Transaction) {
+        if let Some(trans) = self.transactions.get(&tx.security_id) {
+            let mut txs = trans.entry(tx.order_id).or_insert_with(Vec::new);
+            if let Some(exist) = txs.iter_mut().find(|t| t.id == tx.id) {
+                *exist = tx;
+            } else {
+                txs.push(tx);
+            }
+        }
+    }
+
+    fn cache_position(&self, position: Position) {
+        let mut positions = self
+            .positions
+            .entry(position.security_id.clone())
+            .or_insert_with(Vec::new);
+        if let Some(exist) = positions.iter_mut().find(|p| p.side == position.side) {
+            *exist = position;
+        } else {
+            positions.push(position);
+        }
     }
 }
 
 impl QboxStore for SqliteStore {
+    //所有查询在同一事务中执行
+    fn read_state(&self) -> Result<UnitState> {
+        const SETTINGS_SQL: &str = "SELECT key,value FROM qbox WHERE unit=?;";
+        const SYMBOLS_SQL: &str = "SELECT security_id,exchange,symbol,kind,base_currency,quote_currency,multiplier,state,items FROM symbols;";
+        const ORDERS_SQL: &str = "SELECT body FROM orders ORDER BY time;";
+        const HISTORY_SQL: &str = "SELECT body FROM order_states WHERE order_id=? ORDER BY rowid;";
+        const TXS_SQL: &str = "SELECT body FROM transactions ORDER BY time;";
+        const POSITIONS_SQL: &str = "SELECT time,body FROM positions;";
+        const SNAPSHOTS_SQL: &str = "SELECT time,body FROM position_snapshots ORDER BY rowid;";
+        let mut conn = self.inner.lock();
+        let tx = conn.transaction()?;
+        let mut settings = vec![];
+        {
+            let mut stat = tx.prepare(SETTINGS_SQL)?;
+            let list = stat.query_map(params![self.unit], |row| {
+                let key: String = row.get(0)?;
+                let val: String = row.get(1)?;
+                Ok((key, val))
+            })?;
+            for val in list {
+                settings.push(val?);
+            }
+        }
+        let mut orders = vec![];
+        for order in select_orders(&tx, ORDERS_SQL, [])?.unwrap_or_default() {
+            let mut history =
+                select_orders(&tx, HISTORY_SQL, params![order.id() as i64])?.unwrap_or_default();
+            if history.last() != Some(&order) {
+                history.push(order);
+            }
+            orders.push(history);
+        }
+        let state = UnitState {
+            settings,
+            instruments: select_symbols(&tx, SYMBOLS_SQL, [])?.unwrap_or_default(),
+            orders,
+            transactions: select_txs(&tx, TXS_SQL, [])?.unwrap_or_default(),
+            positions: select_positions(&tx, POSITIONS_SQL, [])?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(_, position)| position)
+                .collect(),
+            position_history: select_positions(&tx, SNAPSHOTS_SQL, [])?.unwrap_or_default(),
+        };
+        tx.commit()?;
+        Ok(state)
+    }
+
     fn set(&self, k: &str, v: &str) -> Result<()> {
         const SQL: &str = r#"INSERT OR REPLACE INTO qbox (unit,key,value) VALUES (?1,?2,?3);"#;
-        self.inner.execute(SQL, params![self.unit, k, v])?;
+        self.inner.lock().execute(SQL, params![self.unit, k, v])?;
         Ok(())
     }
 
     fn remove(&self, k: &str) -> Result<()> {
         const SQL: &str = r#"DELETE FROM qbox WHERE unit=? and key=?;"#;
-        self.inner.execute(SQL, params![self.unit, k])?;
+        self.inner.lock().execute(SQL, params![self.unit, k])?;
         Ok(())
     }
 
     fn get(&self, k: &str) -> Result<Option<String>> {
-        const SQL: &str = "SELECT value FROM qbox WHERE unit=? and key=? LIMIT=1;";
-        let mut stat = self.inner.prepare(SQL)?;
+        const SQL: &str = "SELECT value FROM qbox WHERE unit=? and key=? LIMIT 1;";
+        let conn = self.inner.lock();
+        let mut stat = conn.prepare(SQL)?;
         let mut list = stat.query_map(params![self.unit, k], |row| {
             let val: String = row.get(0)?;
             Ok(val)
@@ -90,7 +223,8 @@ impl QboxStore for SqliteStore {
     fn get_all(&self) -> Result<Option<Vec<(String, String)>>> {
         let mut ret = vec![];
         const SQL: &str = "SELECT key,value FROM qbox WHERE unit=?;";
-        let mut stat = self.inner.prepare(SQL)?;
+        let conn = self.inner.lock();
+        let mut stat = conn.prepare(SQL)?;
         let list = stat.query_map(params![self.unit], |row| {
             let key: String = row.get(0)?;
             let val: String = row.get(1)?;
@@ -109,7 +243,8 @@ impl QboxStore for SqliteStore {
     fn get_prefix(&self, prefix: &str) -> Result<Option<Vec<(String, String)>>> {
         let mut ret = vec![];
         const SQL: &str = "SELECT key,value FROM qbox WHERE unit=? AND key GLOB ?;";
-        let mut stat = self.inner.prepare(SQL)?;
+        let conn = self.inner.lock();
+        let mut stat = conn.prepare(SQL)?;
         let list = stat.query_map(params![self.unit, format!("{}*", prefix)], |row| {
             let key: String = row.get(0)?;
             let val: String = row.get(1)?;
@@ -149,8 +284,8 @@ impl QboxStore for SqliteStore {
         let exchange: &str = symbol.exchange.into();
         let kind: &str = symbol.kind.into();
         let state = format!("{:?}", symbol.state);
-        let items = ron::to_string(&symbol.items)?;
-        self.inner.execute(
+        let items = codec::to_string(&symbol.items)?;
+        self.inner.lock().execute(
             SQL,
             params![
                 symbol.security_id,
@@ -172,7 +307,7 @@ impl QboxStore for SqliteStore {
             return Ok(Some(symbol.value().clone()));
         }
         const SQL:&str = "SELECT security_id,exchange,symbol,kind,base_currency,quote_currency,multiplier,state,items FROM symbols WHERE security_id = ?;";
-        if let Some(list) = select_symbols(&self.inner, SQL, params![security_id])? {
+        if let Some(list) = select_symbols(&self.inner.lock(), SQL, params![security_id])? {
             if let Some(one) = list.first() {
                 return Ok(Some(one.clone()));
             }
@@ -182,7 +317,7 @@ impl QboxStore for SqliteStore {
 
     fn query_symbol_with_prefix(&self, prefix: &str) -> Result<Option<Vec<Instrument>>> {
         const SQL:&str = "SELECT security_id,exchange,symbol,kind,base_currency,quote_currency,multiplier,state,items FROM symbols WHERE security_id GLOB ?;";
-        select_symbols(&self.inner, SQL, params![format!("{}*", prefix)])
+        select_symbols(&self.inner.lock(), SQL, params![format!("{}*", prefix)])
     }
 
     fn query_symbol_with_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<Instrument>>> {
@@ -204,7 +339,140 @@ impl QboxStore for SqliteStore {
 
     fn query_all_symbol(&self) -> Result<Option<Vec<Instrument>>> {
         const SQL:&str = "SELECT security_id,exchange,symbol,kind,base_currency,quote_currency,multiplier,state,items FROM symbols;";
-        select_symbols(&self.inner, SQL, [])
+        select_symbols(&self.inner.lock(), SQL, [])
+    }
+}
+
+impl QuoteStore for SqliteStore {
+    fn update_level1(&self, level1: Level1) -> Result<()> {
+        self.quotes.update_level1(level1)
+    }
+    fn query_one_level1(&self, security_id: &str) -> Result<Option<Level1>> {
+        self.quotes.query_one_level1(security_id)
+    }
+    fn query_all_level1(&self) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_all_level1()
+    }
+    fn query_level1_with_prefix(&self, prefix: &str) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_level1_with_prefix(prefix)
+    }
+    fn query_level1_with_prefixs(&self, prefixs: &[&str]) -> Result<Option<Vec<Level1>>> {
+        self.quotes.query_level1_with_prefixs(prefixs)
+    }
+    fn insert_bar(&self, bar: Bar) -> Result<()> {
+        const SQL: &str = r#"INSERT OR REPLACE INTO bars (security_id,period,time,exchange,open,high,low,close,volume,turnover) VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10);"#;
+        let period: String = bar.period.into();
+        let exchange: &str = bar.exchange.into();
+        self.inner.lock().execute(
+            SQL,
+            params![
+                bar.security_id,
+                period,
+                bar.time,
+                exchange,
+                bar.open,
+                bar.high,
+                bar.low,
+                bar.close,
+                bar.volume,
+                bar.turnover
+            ],
+        )?;
+        //新k线开始时按保留策略清理，未完成k线的更新不触发
+        let key = (bar.security_id.clone(), bar.period);
+        let is_new = match self.last_bars.get(&key) {
+            Some(last) => bar.time > *last.value(),
+            None => true,
+        };
+        if is_new {
+            self.last_bars.insert(key, bar.time);
+            self.purge_bars(Some(&bar.security_id), bar.period)?;
+        }
+        Ok(())
+    }
+    fn query_bar(&self, security_id: &str, period: Period) -> Result<Option<Vec<Bar>>> {
+        const SQL: &str = "SELECT security_id,period,time,exchange,open,high,low,close,volume,turnover FROM bars WHERE security_id=? AND period=? ORDER BY time;";
+        let period: String = period.into();
+        select_bars(&self.inner.lock(), SQL, params![security_id, period])
+    }
+    fn set_retention(&self, period: Period, retention: Retention) -> Result<()> {
+        save_retention(self, period, retention)?;
+        self.retentions.insert(period, retention);
+        self.purge_bars(None, period)
+    }
+    fn retention(&self, period: Period) -> Retention {
+        self.retentions
+            .get(&period)
+            .map(|r| *r.value())
+            .unwrap_or_default()
+    }
+    fn query_bar_with_time(
+        &self,
+        security_id: &str,
+        period: Period,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Bar>>> {
+        const SQL: &str = "SELECT security_id,period,time,exchange,open,high,low,close,volume,turnover FROM bars WHERE security_id=? AND period=? AND time>=? AND time<? ORDER BY time;";
+        let period: String = period.into();
+        select_bars(
+            &self.inner.lock(),
+            SQL,
+            params![security_id, period, begin, end],
+        )
+    }
+    fn query_last_bar(
+        &self,
+        security_id: &str,
+        period: Period,
+        n: usize,
+    ) -> Result<Option<Vec<Bar>>> {
+        const SQL: &str = r#"SELECT * FROM (
+            SELECT security_id,period,time,exchange,open,high,low,close,volume,turnover FROM bars
+            WHERE security_id=? AND period=? ORDER BY time DESC LIMIT ?) ORDER BY time;"#;
+        let period: String = period.into();
+        select_bars(
+            &self.inner.lock(),
+            SQL,
+            params![security_id, period, n as i64],
+        )
+    }
+    fn insert_analytics(&self, analytics: Analytics) -> Result<()> {
+        const SQL: &str =
+            r#"INSERT OR REPLACE INTO analytics (security_id,time,body) VALUES (?1,?2,?3);"#;
+        let body = codec::to_string(&analytics)?;
+        self.inner
+            .lock()
+            .execute(SQL, params![analytics.security_id, analytics.time, body])?;
+        Ok(())
+    }
+    fn query_analytics_with_time(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<Analytics>>> {
+        const SQL: &str =
+            "SELECT body FROM analytics WHERE security_id=? AND time>=? AND time<? ORDER BY time;";
+        select_bodies(&self.inner.lock(), SQL, params![security_id, begin, end])
+    }
+    fn insert_tick2offer(&self, tto: TickToOffer) -> Result<()> {
+        self.quotes.insert_tick2offer(tto)
+    }
+    fn query_tick2offer(&self, security_id: &str) -> Result<Option<Vec<TickToOffer>>> {
+        self.quotes.query_tick2offer(security_id)
+    }
+    fn insert_tick2trade(&self, ttt: TickToTrade) -> Result<()> {
+        self.quotes.insert_tick2trade(ttt)
+    }
+    fn query_tick2trade(&self, security_id: &str) -> Result<Option<Vec<TickToTrade>>> {
+        self.quotes.query_tick2trade(security_id)
+    }
+    fn update_depth(&self, level2: Level2) -> Result<()> {
+        self.quotes.update_depth(level2)
+    }
+    fn query_depth(&self, security_id: &str) -> Result<Option<Level2>> {
+        self.quotes.query_depth(security_id)
     }
 }
 
@@ -213,105 +481,204 @@ impl OrderStore for SqliteStore {
         self.update_order(order)
     }
     fn update_order(&self, order: Order) -> Result<()> {
-        if let Some(mut orders) = self.orders.get_mut(order.security_id()) {
-            orders.value_mut().push(order.clone());
-        } else {
-            let mut orders = vec![];
-            orders.push(order.clone());
-            self.orders.insert(order.security_id().into(), orders);
-        }
+        const SQL: &str = r#"INSERT OR REPLACE INTO orders (id,security_id,exchange,time,state,body,updated_at) VALUES (?1,?2,?3,?4,?5,?6,CURRENT_TIMESTAMP);"#;
+        const HISTORY_SQL: &str = r#"INSERT INTO order_states (order_id,time,state,body,created_at) VALUES (?1,?2,?3,?4,CURRENT_TIMESTAMP);"#;
+        let exchange: &str = order.exchange().into();
+        let state = format!("{:?}", order.state().state);
+        let body = codec::to_string(&order)?;
+        let mut conn = self.inner.lock();
+        let tx = conn.transaction()?;
+        tx.execute(
+            SQL,
+            params![
+                order.id() as i64,
+                order.security_id(),
+                exchange,
+                order.time(),
+                state,
+                body
+            ],
+        )?;
+        tx.execute(
+            HISTORY_SQL,
+            params![order.id() as i64, order.state().last_time, state, body],
+        )?;
+        tx.commit()?;
+        self.cache_order(order);
         Ok(())
     }
     fn remove_order(&self, order_id: u64) -> Result<()> {
-        unimplemented!()
+        if let Some(order) = self.query_one_order(order_id)? {
+            if let Some(mut orders) = self.orders.get_mut(order.security_id()) {
+                orders.retain(|o| o.id() != order_id);
+            }
+        }
+        let mut conn = self.inner.lock();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM orders WHERE id=?;", params![order_id as i64])?;
+        tx.execute(
+            "DELETE FROM order_states WHERE order_id=?;",
+            params![order_id as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
     }
     fn query_one_order(&self, order_id: u64) -> Result<Option<Order>> {
-        unimplemented!()
+        const SQL: &str = "SELECT body FROM orders WHERE id=?;";
+        Ok(
+            select_orders(&self.inner.lock(), SQL, params![order_id as i64])?
+                .and_then(|list| list.into_iter().next()),
+        )
     }
     fn query_order(&self, security_id: &str) -> Result<Option<Vec<Order>>> {
         if let Some(orders) = self.orders.get(security_id) {
-            Ok(Some(orders.value().clone()))
-        } else {
-            Ok(None)
+            return Ok(Some(orders.value().clone()).filter(|v| v.len() > 0));
         }
+        const SQL: &str = "SELECT body FROM orders WHERE security_id=? ORDER BY time;";
+        let orders = select_orders(&self.inner.lock(), SQL, params![security_id])?;
+        self.orders
+            .insert(security_id.into(), orders.clone().unwrap_or_default());
+        Ok(orders)
     }
     fn query_all_order(&self) -> Result<Option<Vec<Order>>> {
-        let data: Vec<Vec<Order>> = self.orders.iter().map(|v| v.value().clone()).collect();
-        if data.len() > 0 {
-            Ok(Some(data.concat()))
-        } else {
-            Ok(None)
-        }
+        const SQL: &str = "SELECT body FROM orders ORDER BY time;";
+        select_orders(&self.inner.lock(), SQL, [])
+    }
+    fn query_order_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Order>>> {
+        const SQL: &str = "SELECT body FROM orders WHERE time>=? AND time<? ORDER BY time;";
+        select_orders(&self.inner.lock(), SQL, params![begin, end])
+    }
+    fn query_order_history(&self, order_id: u64) -> Result<Option<Vec<Order>>> {
+        const SQL: &str = "SELECT body FROM order_states WHERE order_id=? ORDER BY rowid;";
+        select_orders(&self.inner.lock(), SQL, params![order_id as i64])
     }
 
     fn insert_tx(&self, tx: Transaction) -> Result<()> {
-        unimplemented!()
+        const SQL: &str = r#"INSERT INTO transactions (id,order_id,out_id,security_id,exchange,time,body,updated_at) VALUES (?1,?2,?3,?4,?5,?6,?7,CURRENT_TIMESTAMP);"#;
+        let exchange: &str = tx.exchange.into();
+        self.inner.lock().execute(
+            SQL,
+            params![
+                tx.id as i64,
+                tx.order_id as i64,
+                tx.out_id,
+                tx.security_id,
+                exchange,
+                tx.time,
+                codec::to_string(&tx)?
+            ],
+        )?;
+        self.cache_tx(tx);
+        Ok(())
     }
     fn update_tx(&self, tx: Transaction) -> Result<()> {
-        unimplemented!()
+        const SQL: &str = r#"INSERT OR REPLACE INTO transactions (id,order_id,out_id,security_id,exchange,time,body,updated_at) VALUES (?1,?2,?3,?4,?5,?6,?7,CURRENT_TIMESTAMP);"#;
+        let exchange: &str = tx.exchange.into();
+        self.inner.lock().execute(
+            SQL,
+            params![
+                tx.id as i64,
+                tx.order_id as i64,
+                tx.out_id,
+                tx.security_id,
+                exchange,
+                tx.time,
+                codec::to_string(&tx)?
+            ],
+        )?;
+        self.cache_tx(tx);
+        Ok(())
     }
     fn remove_tx(&self, txid: u64) -> Result<()> {
-        unimplemented!()
+        if let Some(tx) = self.query_one_tx(txid)? {
+            if let Some(trans) = self.transactions.get(&tx.security_id) {
+                if let Some(mut txs) = trans.get_mut(&tx.order_id) {
+                    txs.retain(|t| t.id != txid);
+                }
+            }
+        }
+        self.inner
+            .lock()
+            .execute("DELETE FROM transactions WHERE id=?;", params![txid as i64])?;
+        Ok(())
     }
     fn query_one_tx(&self, txid: u64) -> Result<Option<Transaction>> {
-        unimplemented!()
+        const SQL: &str = "SELECT body FROM transactions WHERE id=?;";
+        Ok(select_txs(&self.inner.lock(), SQL, params![txid as i64])?
+            .and_then(|list| list.into_iter().next()))
     }
     fn query_tx_with_order(&self, order_id: u64) -> Result<Option<Vec<Transaction>>> {
-        unimplemented!()
+        const SQL: &str = "SELECT body FROM transactions WHERE order_id=? ORDER BY time;";
+        select_txs(&self.inner.lock(), SQL, params![order_id as i64])
     }
     fn query_tx_with_symbol(&self, security_id: &str) -> Result<Option<Vec<Transaction>>> {
-        if let Some(positions) = self.transactions.get(security_id) {
-            let data: Vec<Vec<Transaction>> = positions
-                .value()
+        if let Some(trans) = self.transactions.get(security_id) {
+            let mut data: Vec<Transaction> = trans
                 .iter()
                 .map(|item| item.value().clone())
+                .flatten()
                 .collect();
-            Ok(Some(data.concat()))
-        } else {
-            Ok(None)
+            data.sort_by_key(|tx| tx.time);
+            return Ok(Some(data).filter(|v| v.len() > 0));
+        }
+        const SQL: &str = "SELECT body FROM transactions WHERE security_id=? ORDER BY time;";
+        let txs = select_txs(&self.inner.lock(), SQL, params![security_id])?;
+        let trans = DashMap::with_hasher(RandomState::new());
+        for tx in txs.iter().flatten() {
+            trans
+                .entry(tx.order_id)
+                .or_insert_with(Vec::new)
+                .push(tx.clone());
         }
+        self.transactions.insert(security_id.into(), trans);
+        Ok(txs)
     }
     fn query_tx_with_symbol_and_order(
         &self,
         security_id: &str,
         order_id: u64,
     ) -> Result<Option<Vec<Transaction>>> {
+        if !self.transactions.contains_key(security_id) {
+            self.query_tx_with_symbol(security_id)?;
+        }
         if let Some(trans) = self.transactions.get(security_id) {
             if let Some(txs) = trans.value().get(&order_id) {
-                Ok(Some(txs.clone()))
-            } else {
-                Ok(None)
+                return Ok(Some(txs.clone()));
             }
-        } else {
-            Ok(None)
         }
+        Ok(None)
     }
     fn query_all_tx(&self) -> Result<Option<Vec<Transaction>>> {
-        let data: Vec<Vec<Vec<Transaction>>> = self
-            .transactions
-            .iter()
-            .map(|v| v.value().iter().map(|item| item.value().clone()).collect())
-            .collect();
-        if data.len() > 0 {
-            Ok(Some(data.concat().concat()))
-        } else {
-            Ok(None)
-        }
+        const SQL: &str = "SELECT body FROM transactions ORDER BY time;";
+        select_txs(&self.inner.lock(), SQL, [])
+    }
+    fn query_tx_with_time(&self, begin: i64, end: i64) -> Result<Option<Vec<Transaction>>> {
+        const SQL: &str = "SELECT body FROM transactions WHERE time>=? AND time<? ORDER BY time;";
+        select_txs(&self.inner.lock(), SQL, params![begin, end])
     }
 
     fn update_position(&self, position: Position) -> Result<()> {
-        if let Some(mut positions) = self.positions.get_mut(&position.security_id) {
-            positions.value_mut().push(position.clone());
-        } else {
-            let mut positions = vec![];
-            positions.push(position.clone());
-            self.positions
-                .insert(position.security_id.clone(), positions);
-        }
+        const SQL: &str = r#"INSERT OR REPLACE INTO positions (security_id,side,exchange,time,body) VALUES (?1,?2,?3,?4,?5);"#;
+        const SNAPSHOT_SQL: &str = r#"INSERT INTO position_snapshots (security_id,side,exchange,time,body) VALUES (?1,?2,?3,?4,?5);"#;
+        let exchange: &str = position.exchange.into();
+        let side = format!("{:?}", position.side);
+        let time = position.time;
+        let body = codec::to_string(&position)?;
+        let mut conn = self.inner.lock();
+        let tx = conn.transaction()?;
+        let args = params![position.security_id, side, exchange, time, body];
+        tx.execute(SQL, args)?;
+        tx.execute(SNAPSHOT_SQL, args)?;
+        tx.commit()?;
+        self.cache_position(position);
         Ok(())
     }
 
     fn remove_position(&self, security_id: &str) -> Result<()> {
+        self.inner.lock().execute(
+            "DELETE FROM positions WHERE security_id=?;",
+            params![security_id],
+        )?;
         self.positions.remove(security_id);
         Ok(())
     }
@@ -334,6 +701,107 @@ impl OrderStore for SqliteStore {
             Ok(None)
         }
     }
+    fn query_position_history(
+        &self,
+        security_id: &str,
+        begin: i64,
+        end: i64,
+    ) -> Result<Option<Vec<(i64, Position)>>> {
+        const SQL: &str = "SELECT time,body FROM position_snapshots WHERE security_id=? AND time>=? AND time<? ORDER BY rowid;";
+        select_positions(&self.inner.lock(), SQL, params![security_id, begin, end])
+    }
+}
+
+pub fn select_bars<P: Params>(db: &Connection, sql: &str, params: P) -> Result<Option<Vec<Bar>>> {
+    let mut ret = vec![];
+    let mut stat = db.prepare(sql)?;
+    //NaN在sqlite中存为NULL
+    let price = |v: Option<f64>| v.unwrap_or(f64::NAN);
+    let list = stat.query_map(params, |row| {
+        let period: String = row.get(1)?;
+        let exchange: String = row.get(3)?;
+        Ok(Bar {
+            security_id: row.get(0)?,
+            exchange: Exchange::from(&exchange),
+            period: Period::from(&period),
+            time: row.get(2)?,
+            open: price(row.get(4)?),
+            high: price(row.get(5)?),
+            low: price(row.get(6)?),
+            close: price(row.get(7)?),
+            volume: price(row.get(8)?),
+            turnover: row.get(9)?,
+        })
+    })?;
+    for bar in list {
+        ret.push(bar?);
+    }
+    if ret.len() > 0 {
+        Ok(Some(ret))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn select_orders<P: Params>(
+    db: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Option<Vec<Order>>> {
+    select_bodies(db, sql, params)
+}
+
+pub fn select_txs<P: Params>(
+    db: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Option<Vec<Transaction>>> {
+    select_bodies(db, sql, params)
+}
+
+pub fn select_positions<P: Params>(
+    db: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Option<Vec<(i64, Position)>>> {
+    let mut ret = vec![];
+    let mut stat = db.prepare(sql)?;
+    let list = stat.query_map(params, |row| {
+        let time: i64 = row.get(0)?;
+        let body: String = row.get(1)?;
+        Ok((time, body))
+    })?;
+    for item in list {
+        let (time, body) = item?;
+        ret.push((time, codec::from_str::<Position>(&body)?));
+    }
+    if ret.len() > 0 {
+        Ok(Some(ret))
+    } else {
+        Ok(None)
+    }
+}
+
+//按body列解码
+fn select_bodies<T: codec::Versioned, P: Params>(
+    db: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Option<Vec<T>>> {
+    let mut ret = vec![];
+    let mut stat = db.prepare(sql)?;
+    let list = stat.query_map(params, |row| {
+        let body: String = row.get(0)?;
+        Ok(body)
+    })?;
+    for body in list {
+        ret.push(codec::from_str::<T>(&body?)?);
+    }
+    if ret.len() > 0 {
+        Ok(Some(ret))
+    } else {
+        Ok(None)
+    }
 }
 
 pub fn select_symbols<P: Params>(
@@ -349,7 +817,7 @@ pub fn select_symbols<P: Params>(
         let exchange: String = row.get(1)?;
         let kind: String = row.get(3)?;
         let state: String = row.get(7)?;
-        let items: Parameter = if let Ok(items) = ron::from_str::<Parameter>(&items) {
+        let items: Parameter = if let Ok(items) = codec::from_str::<Parameter>(&items) {
             items
         } else {
             Parameter::new()
@@ -376,3 +844,282 @@ pub fn select_symbols<P: Params>(
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    //每个测试独立的数据文件
+    fn open(name: &str) -> SqliteStore {
+        SqliteStore::open(unit(name)).unwrap()
+    }
+
+    fn close(store: SqliteStore) {
+        let unit = store.unit().to_string();
+        drop(store);
+        cleanup(&unit);
+    }
+
+    fn count(store: &SqliteStore, period: Period) -> usize {
+        store
+            .query_bar("rb2205", period)
+            .unwrap()
+            .map(|bars| bars.len())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_bars() {
+        let store = open("sqlite-bars");
+        let period = Period::Minute(1);
+        for i in 0..5 {
+            store
+                .insert_bar(bar(period, TIME + i * 60, 4800.0))
+                .unwrap();
+        }
+        //未完成k线覆盖写入
+        let mut partial = bar(period, TIME + 240, 4810.0);
+        partial.volume = 3.0;
+        partial.turnover = Some(14430.0);
+        store.insert_bar(partial).unwrap();
+        let bars = store.query_bar("rb2205", period).unwrap().unwrap();
+        assert_eq!(bars.len(), 5);
+        assert_eq!(bars[4].close, 4810.0);
+        assert_eq!(bars[4].volume, 3.0);
+        assert_eq!(bars[4].turnover, Some(14430.0));
+        assert!(bars[0].turnover.is_none());
+        assert_eq!(count(&store, Period::Minute(5)), 0);
+
+        let times: Vec<i64> = store
+            .query_bar_with_time("rb2205", period, TIME + 60, TIME + 180)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|b| b.time)
+            .collect();
+        assert_eq!(times, vec![TIME + 60, TIME + 120]);
+        let last = store.query_last_bar("rb2205", period, 2).unwrap().unwrap();
+        assert_eq!(last.len(), 2);
+        assert_eq!(last[0].time, TIME + 180);
+        assert_eq!(last[1].close, 4810.0);
+        close(store);
+    }
+
+    #[test]
+    fn test_retention() {
+        let store = open("sqlite-retention");
+        let now = Utc::now().timestamp();
+        let (minute, hour) = (Period::Minute(1), Period::Hour(1));
+        for i in 0..5 {
+            store
+                .insert_bar(bar(minute, TIME + i * 60, 4800.0))
+                .unwrap();
+        }
+        for days in [3, 2, 0] {
+            store
+                .insert_bar(bar(hour, now - days * 86400, 4800.0))
+                .unwrap();
+        }
+
+        store.set_retention(minute, Retention::Count(2)).unwrap();
+        assert_eq!(count(&store, minute), 2);
+        //新k线开始时清理
+        store.insert_bar(bar(minute, TIME + 300, 4800.0)).unwrap();
+        assert_eq!(count(&store, minute), 2);
+        store.set_retention(hour, Retention::Days(1)).unwrap();
+        assert_eq!(count(&store, hour), 1);
+
+        //重新打开时按保存的策略清理
+        let unit = store.unit().to_string();
+        let path = Path::new(&crate::data_path()).join(format!("{}.db", unit));
+        drop(store);
+        let conn = Connection::open(&path).unwrap();
+        for time in [TIME + 360, TIME + 420] {
+            conn.execute(
+                "INSERT INTO bars (security_id,period,time,exchange,close) VALUES ('rb2205','1m',?1,'SHFE',4800.0);",
+                params![time],
+            )
+            .unwrap();
+        }
+        drop(conn);
+        let store = SqliteStore::open(&unit).unwrap();
+        assert_eq!(store.retention(minute), Retention::Count(2));
+        let times: Vec<i64> = store
+            .query_bar("rb2205", minute)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|b| b.time)
+            .collect();
+        assert_eq!(times, vec![TIME + 360, TIME + 420]);
+
+        store.set_retention(minute, Retention::Forever).unwrap();
+        drop(store);
+        let store = SqliteStore::open(&unit).unwrap();
+        assert_eq!(store.retention(minute), Retention::Forever);
+        assert_eq!(store.retention(hour), Retention::Days(1));
+        close(store);
+    }
+
+    #[test]
+    fn test_orders() {
+        let store = open("sqlite-orders");
+        store
+            .insert_order(order(1, TIME, State::Created, TIME))
+            .unwrap();
+        //回报时间可能乱序，状态历史按写入顺序
+        store
+            .update_order(order(1, TIME, State::Accepted, TIME + 5))
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Filled, TIME + 2))
+            .unwrap();
+        store
+            .insert_order(order(2, TIME + 60, State::Created, TIME + 60))
+            .unwrap();
+
+        let one = store.query_one_order(1).unwrap().unwrap();
+        assert_eq!(one.state().state, State::Filled);
+        let states: Vec<State> = store
+            .query_order_history(1)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|o| o.state().state)
+            .collect();
+        assert_eq!(states, vec![State::Created, State::Accepted, State::Filled]);
+        assert_eq!(store.query_order("rb2205").unwrap().unwrap().len(), 2);
+
+        //时间范围左闭右开
+        let orders = store
+            .query_order_with_time(TIME, TIME + 60)
+            .unwrap()
+            .unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].id(), 1);
+        assert!(store.query_order_with_time(0, TIME).unwrap().is_none());
+
+        //删除订单同时删除状态历史
+        store.remove_order(1).unwrap();
+        assert!(store.query_one_order(1).unwrap().is_none());
+        assert!(store.query_order_history(1).unwrap().is_none());
+        assert_eq!(store.query_order("rb2205").unwrap().unwrap().len(), 1);
+        close(store);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let store = open("sqlite-transactions");
+        store.insert_tx(tx(1, 1, "rb2205", TIME)).unwrap();
+        store.insert_tx(tx(2, 1, "rb2205", TIME + 1)).unwrap();
+        store.insert_tx(tx(3, 2, "rb2205", TIME + 60)).unwrap();
+        //主键重复时插入失败，更新覆盖
+        assert!(store.insert_tx(tx(1, 1, "rb2205", TIME)).is_err());
+        let mut updated = tx(2, 1, "rb2205", TIME + 1);
+        updated.price = 4801.0;
+        store.update_tx(updated).unwrap();
+
+        assert_eq!(store.query_one_tx(2).unwrap().unwrap().price, 4801.0);
+        assert_eq!(store.query_tx_with_order(1).unwrap().unwrap().len(), 2);
+        assert_eq!(
+            store.query_tx_with_symbol("rb2205").unwrap().unwrap().len(),
+            3
+        );
+        assert_eq!(
+            store
+                .query_tx_with_symbol_and_order("rb2205", 2)
+                .unwrap()
+                .unwrap()[0]
+                .id,
+            3
+        );
+        let ids: Vec<u64> = store
+            .query_tx_with_time(TIME, TIME + 60)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        store.remove_tx(1).unwrap();
+        assert!(store.query_one_tx(1).unwrap().is_none());
+        assert_eq!(store.query_all_tx().unwrap().unwrap().len(), 2);
+        assert_eq!(store.query_tx_with_order(1).unwrap().unwrap().len(), 1);
+        close(store);
+    }
+
+    #[test]
+    fn test_read_state() {
+        let store = open("sqlite-state");
+        store.set("strategy/grid", "{}").unwrap();
+        store
+            .update_order(order(1, TIME, State::Submitted, TIME))
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Filled, TIME + 1))
+            .unwrap();
+        store.insert_tx(tx(10, 1, "rb2205", TIME + 1)).unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 1, TIME))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 2, TIME + 60))
+            .unwrap();
+
+        let state = store.read_state().unwrap();
+        assert_eq!(state.settings, vec![("strategy/grid".into(), "{}".into())]);
+        assert_eq!(state.orders.len(), 1);
+        let states: Vec<State> = state.orders[0].iter().map(|o| o.state().state).collect();
+        assert_eq!(states, vec![State::Submitted, State::Filled]);
+        assert_eq!(state.transactions.len(), 1);
+        assert_eq!(state.positions.len(), 1);
+        assert_eq!(state.positions[0].quantity, 2);
+        let history: Vec<(i64, i64)> = state
+            .position_history
+            .iter()
+            .map(|(t, p)| (*t, p.quantity))
+            .collect();
+        assert_eq!(history, vec![(TIME, 1), (TIME + 60, 2)]);
+        close(store);
+    }
+
+    #[test]
+    fn test_position_snapshots() {
+        let store = open("sqlite-positions");
+        store
+            .update_position(position("rb2205", Side::Long, 1, TIME))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 3, TIME + 60))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 2, TIME + 120))
+            .unwrap();
+
+        let current = store.query_position("rb2205").unwrap().unwrap();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].quantity, 2);
+
+        //快照按持仓自身的时间记录
+        let history = store
+            .query_position_history("rb2205", TIME, TIME + 120)
+            .unwrap()
+            .unwrap();
+        let history: Vec<(i64, i64)> = history.iter().map(|(t, p)| (*t, p.quantity)).collect();
+        assert_eq!(history, vec![(TIME, 1), (TIME + 60, 3)]);
+
+        //重新打开后加载当前持仓
+        let unit = store.unit().to_string();
+        drop(store);
+        let store = SqliteStore::open(unit).unwrap();
+        assert_eq!(
+            store.query_all_position().unwrap().unwrap()[0].time,
+            TIME + 120
+        );
+        store.remove_position("rb2205").unwrap();
+        assert!(store.query_position("rb2205").unwrap().is_none());
+        close(store);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod limit;
+pub mod quality;
 
 use anyhow::Result;
 
@@ -50,3 +51,12 @@ impl<T, W: Filter<T>, U: Filter<T>> Filter<T> for Chain<W, U> {
         Ok(())
     }
 }
+
+//处理阶段，可丢弃、缓冲或改写事件
+pub trait Stage<T>: Send + Sync {
+    fn name(&self) -> &'static str {
+        "Stage"
+    }
+
+    fn process(&self, event: T) -> Vec<T>;
+}
@@ -0,0 +1,502 @@
+use super::Stage;
+use crate::broker::{Depth, Exchange, Level1, Level2, Value};
+use crate::calendar;
+use crate::core::{self, *};
+use chrono::Utc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const UPPER_LIMIT: &str = "upper_limit";
+const LOWER_LIMIT: &str = "lower_limit";
+//每个证券保留的成交编号个数
+const MAX_TRADE_IDS: usize = 1000;
+//无乱序缓冲时检查停更的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[doc = "行情质量检查选项"]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct QualityOptions {
+    //丢弃重复的快照和成交
+    pub dedup: bool,
+    //乱序缓冲时长，缓冲期内的行情按时间排序后发布，0为不缓冲
+    pub hold: Duration,
+    //相对近期中位价的最大偏离，0为不检查
+    pub max_deviation: f64,
+    //计算中位价的近期价格个数
+    pub window: usize,
+    //连续偏离且相互一致的价格达到此笔数时认为行情确实跳变
+    pub confirm: usize,
+    //交易时段内超过此时长没有更新时告警，0为不检查
+    pub stale_after: Duration,
+}
+
+impl Default for QualityOptions {
+    fn default() -> Self {
+        Self {
+            dedup: true,
+            hold: Duration::from_millis(0),
+            max_deviation: 0.1,
+            window: 20,
+            confirm: 3,
+            stale_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl QualityOptions {
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn with_hold(mut self, hold: Duration) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    pub fn with_max_deviation(mut self, max_deviation: f64) -> Self {
+        self.max_deviation = max_deviation;
+        self
+    }
+
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn with_confirm(mut self, confirm: usize) -> Self {
+        self.confirm = confirm.max(1);
+        self
+    }
+
+    pub fn with_stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+}
+
+#[doc = "行情停更告警"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StaleAlert {
+    pub security_id: String,
+    pub exchange: Exchange,
+    pub last_update: i64, //最后一次收到行情的时间
+    pub silent: i64,      //已停更秒数
+}
+
+//检查的行情类型
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Level1 = 0,
+    Level2 = 1,
+    Trade = 2,
+}
+
+fn kind(ev: &QuoteEvent) -> Option<(&str, Exchange, i64, Kind)> {
+    match ev {
+        QuoteEvent::Level1(v) => Some((&v.security_id, v.exchange, v.time, Kind::Level1)),
+        QuoteEvent::Level2(v) => Some((&v.security_id, v.exchange, v.time, Kind::Level2)),
+        QuoteEvent::TickToTrade(v) => Some((&v.security_id, v.exchange, v.time, Kind::Trade)),
+        _ => None,
+    }
+}
+
+//f64::MAX等占位值改为NAN
+fn clean(v: f64) -> f64 {
+    if v.is_finite() && v.abs() < f64::MAX {
+        v
+    } else {
+        f64::NAN
+    }
+}
+
+fn clean_depth(levels: &mut [Depth]) {
+    for level in levels.iter_mut() {
+        *level = (
+            clean(level.0),
+            clean(level.1),
+            clean(level.2),
+            clean(level.3),
+        );
+    }
+}
+
+fn sanitize(ev: &mut QuoteEvent) {
+    match ev {
+        QuoteEvent::Level1(v) => {
+            for field in [
+                &mut v.open,
+                &mut v.high,
+                &mut v.low,
+                &mut v.close,
+                &mut v.average,
+                &mut v.last,
+                &mut v.last_volume,
+                &mut v.volume,
+                &mut v.turnover,
+            ] {
+                *field = clean(*field);
+            }
+            clean_depth(&mut v.bids);
+            clean_depth(&mut v.asks);
+        }
+        QuoteEvent::Level2(v) => {
+            clean_depth(&mut v.bids);
+            clean_depth(&mut v.asks);
+        }
+        QuoteEvent::TickToTrade(v) => {
+            v.price = clean(v.price);
+            v.quantity = clean(v.quantity);
+        }
+        _ => {}
+    }
+}
+
+fn hash_depth(hasher: &mut DefaultHasher, levels: &[Depth]) {
+    for level in levels {
+        hasher.write_u64(level.0.to_bits());
+        hasher.write_u64(level.1.to_bits());
+    }
+}
+
+fn fingerprint_level1(v: &Level1) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_i64(v.time);
+    for field in [v.last, v.volume, v.turnover] {
+        hasher.write_u64(field.to_bits());
+    }
+    hash_depth(&mut hasher, &v.bids);
+    hash_depth(&mut hasher, &v.asks);
+    hasher.finish()
+}
+
+fn fingerprint_level2(v: &Level2) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_i64(v.time);
+    hash_depth(&mut hasher, &v.bids);
+    hash_depth(&mut hasher, &v.asks);
+    hasher.finish()
+}
+
+fn limit(v: &Level1, key: &str) -> Option<f64> {
+    match v.items.get(key)? {
+        Value::F64(v) if clean(*v) > 0.0 => Some(*v),
+        _ => None,
+    }
+}
+
+fn median(prices: &VecDeque<f64>) -> Option<f64> {
+    if prices.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<f64> = prices.iter().copied().collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    Some(sorted[sorted.len() / 2])
+}
+
+#[derive(Default)]
+struct Track {
+    exchange: Exchange,
+    //各类型最后发布的行情时间
+    last_time: [i64; 3],
+    //快照指纹
+    fingerprints: [u64; 3],
+    trade_ids: VecDeque<String>,
+    //近期价格
+    prices: VecDeque<f64>,
+    //连续偏离的价格
+    rejected: Vec<f64>,
+    //(跌停价,涨停价)
+    limits: Option<(f64, f64)>,
+    //最后收到行情的时间
+    arrived: i64,
+    stale: bool,
+}
+
+#[derive(Default)]
+struct State {
+    tracks: HashMap<String, Track>,
+    //(收到时间,行情)
+    pending: VecDeque<(Instant, QuoteEvent)>,
+}
+
+#[doc = "行情质量检查：去重、乱序重排、剔除异常价格并检测停更"]
+pub struct QualityStage {
+    options: QualityOptions,
+    state: Mutex<State>,
+    running: AtomicBool,
+}
+
+impl QualityStage {
+    pub fn new(options: QualityOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            state: Mutex::new(State::default()),
+            running: AtomicBool::new(false),
+        })
+    }
+
+    pub fn options(&self) -> &QualityOptions {
+        &self.options
+    }
+
+    //发布缓冲期已满的行情，按行情时间排序
+    pub fn flush(&self, now: Instant) -> Vec<QuoteEvent> {
+        let mut state = self.state.lock();
+        let mut ready = vec![];
+        while let Some((arrived, _)) = state.pending.front() {
+            if *arrived + self.options.hold > now {
+                break;
+            }
+            if let Some((_, ev)) = state.pending.pop_front() {
+                ready.push(ev);
+            }
+        }
+        ready.sort_by_key(|ev| kind(ev).map(|(_, _, time, _)| time).unwrap_or_default());
+        ready
+            .into_iter()
+            .filter_map(|ev| self.accept(&mut state, ev))
+            .collect()
+    }
+
+    fn accept(&self, state: &mut State, ev: QuoteEvent) -> Option<QuoteEvent> {
+        let (security_id, exchange, time, kind) = match kind(&ev) {
+            Some(v) => v,
+            None => return Some(ev),
+        };
+        let track = state.tracks.entry(security_id.to_string()).or_default();
+        track.exchange = exchange;
+        track.arrived = Utc::now().timestamp();
+        track.stale = false;
+        //早于已发布的行情
+        if time < track.last_time[kind as usize] {
+            log::debug!("drop out of order quote {} at {}", security_id, time);
+            return None;
+        }
+        if self.options.dedup && self.duplicated(track, &ev) {
+            return None;
+        }
+        let price = match &ev {
+            QuoteEvent::Level1(v) => {
+                if let (Some(lower), Some(upper)) = (limit(v, LOWER_LIMIT), limit(v, UPPER_LIMIT)) {
+                    track.limits = Some((lower, upper));
+                }
+                v.last
+            }
+            QuoteEvent::TickToTrade(v) => v.price,
+            _ => f64::NAN,
+        };
+        if !price.is_nan() && !self.check_price(track, price) {
+            log::warn!("drop outlier quote {} price {}", security_id, price);
+            return None;
+        }
+        track.last_time[kind as usize] = time;
+        Some(ev)
+    }
+
+    fn duplicated(&self, track: &mut Track, ev: &QuoteEvent) -> bool {
+        let (fingerprint, kind) = match ev {
+            QuoteEvent::Level1(v) => (fingerprint_level1(v), Kind::Level1),
+            QuoteEvent::Level2(v) => (fingerprint_level2(v), Kind::Level2),
+            QuoteEvent::TickToTrade(v) => {
+                if v.id.is_empty() {
+                    return false;
+                }
+                if track.trade_ids.contains(&v.id) {
+                    return true;
+                }
+                if track.trade_ids.len() >= MAX_TRADE_IDS {
+                    track.trade_ids.pop_front();
+                }
+                track.trade_ids.push_back(v.id.clone());
+                return false;
+            }
+            _ => return false,
+        };
+        if track.fingerprints[kind as usize] == fingerprint {
+            return true;
+        }
+        track.fingerprints[kind as usize] = fingerprint;
+        false
+    }
+
+    //超出涨跌停或偏离近期中位价的价格不通过，连续偏离的价格相互一致时认为行情跳变
+    fn check_price(&self, track: &mut Track, price: f64) -> bool {
+        if let Some((lower, upper)) = track.limits {
+            if price < lower || price > upper {
+                return false;
+            }
+        }
+        let max = self.options.max_deviation;
+        if max > 0.0 {
+            if let Some(median) = median(&track.prices) {
+                if (price / median - 1.0).abs() > max {
+                    track.rejected.push(price);
+                    let agreed = track.rejected.len() >= self.options.confirm
+                        && track
+                            .rejected
+                            .iter()
+                            .all(|p| (p / price - 1.0).abs() <= max);
+                    if !agreed {
+                        if track.rejected.len() >= self.options.confirm {
+                            track.rejected.remove(0);
+                        }
+                        return false;
+                    }
+                    track.prices.clear();
+                    track.prices.extend(track.rejected.drain(..));
+                    return true;
+                }
+            }
+        }
+        track.rejected.clear();
+        if track.prices.len() >= self.options.window {
+            track.prices.pop_front();
+        }
+        track.prices.push_back(price);
+        true
+    }
+
+    //交易时段内超时没有更新的证券，每次停更只告警一次
+    pub fn check_stale(&self, now: i64) -> Vec<StaleAlert> {
+        let secs = self.options.stale_after.as_secs() as i64;
+        if secs == 0 {
+            return vec![];
+        }
+        let mut state = self.state.lock();
+        state
+            .tracks
+            .iter_mut()
+            .filter(|(_, t)| !t.stale && t.arrived > 0 && now - t.arrived >= secs)
+            .filter(|(security_id, t)| calendar::is_trading_at(t.exchange, security_id, now))
+            .map(|(security_id, t)| {
+                t.stale = true;
+                StaleAlert {
+                    security_id: security_id.clone(),
+                    exchange: t.exchange,
+                    last_update: t.arrived,
+                    silent: now - t.arrived,
+                }
+            })
+            .collect()
+    }
+
+    //接入行情发布，并启动乱序缓冲及停更检查
+    pub fn start(self: &Arc<Self>) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        core::set_quote_stage(Some(self.clone()));
+        let stage = self.clone();
+        let interval = if stage.options.hold.as_millis() > 0 {
+            stage.options.hold.min(CHECK_INTERVAL)
+        } else {
+            CHECK_INTERVAL
+        };
+        let spawned = std::thread::Builder::new()
+            .name("qbox-quote-quality".into())
+            .spawn(move || {
+                while stage.running.load(Ordering::SeqCst) {
+                    std::thread::sleep(interval);
+                    for ev in stage.flush(Instant::now()) {
+                        publish(ev);
+                    }
+                    for alert in stage.check_stale(Utc::now().timestamp()) {
+                        log::warn!("stale quote {} silent {}s", alert.security_id, alert.silent);
+                        publish(QuoteEvent::Stale(alert));
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            log::error!("start quote quality error {}", err);
+        }
+    }
+
+    pub fn stop(&self) {
+        if self.running.swap(false, Ordering::SeqCst) {
+            core::set_quote_stage(None);
+        }
+    }
+}
+
+//绕过质量检查直接发布
+fn publish(ev: QuoteEvent) {
+    if let Err(err) = core::publish(QUOTES_EVENT, Event::QuoteEvent(ev)) {
+        log::error!("publish quote error {}", err);
+    }
+}
+
+impl Stage<QuoteEvent> for QualityStage {
+    fn name(&self) -> &'static str {
+        "QualityStage"
+    }
+
+    fn process(&self, mut ev: QuoteEvent) -> Vec<QuoteEvent> {
+        sanitize(&mut ev);
+        if self.options.hold.as_millis() == 0 || kind(&ev).is_none() {
+            let mut state = self.state.lock();
+            return self.accept(&mut state, ev).into_iter().collect();
+        }
+        let now = Instant::now();
+        self.state.lock().pending.push_back((now, ev));
+        self.flush(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level1(time: i64, last: f64) -> QuoteEvent {
+        let mut level1 = Level1::new();
+        level1.security_id = "rb2205".into();
+        level1.exchange = Exchange::SHFE;
+        level1.time = time;
+        level1.last = last;
+        level1.volume = time as f64;
+        QuoteEvent::Level1(level1)
+    }
+
+    fn last(events: &[QuoteEvent]) -> Vec<f64> {
+        events
+            .iter()
+            .filter_map(|ev| match ev {
+                QuoteEvent::Level1(v) => Some(v.last),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_quality() {
+        let stage = QualityStage::new(QualityOptions::default().with_confirm(2));
+        assert_eq!(last(&stage.process(level1(100, 4800.0))), vec![4800.0]);
+        //重复快照
+        assert!(stage.process(level1(100, 4800.0)).is_empty());
+        //乱序
+        assert!(stage.process(level1(99, 4801.0)).is_empty());
+        //占位值
+        let events = stage.process(level1(101, f64::MAX));
+        assert!(last(&events)[0].is_nan());
+        //异常价格，连续两笔一致时认为跳变
+        assert!(stage.process(level1(102, 6000.0)).is_empty());
+        assert_eq!(last(&stage.process(level1(103, 6001.0))), vec![6001.0]);
+        assert_eq!(last(&stage.process(level1(104, 6002.0))), vec![6002.0]);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let hold = Duration::from_millis(50);
+        let stage = QualityStage::new(QualityOptions::default().with_hold(hold));
+        assert!(stage.process(level1(102, 4802.0)).is_empty());
+        assert!(stage.process(level1(101, 4801.0)).is_empty());
+        let events = stage.flush(Instant::now() + hold);
+        assert_eq!(last(&events), vec![4801.0, 4802.0]);
+    }
+}
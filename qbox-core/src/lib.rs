@@ -3,14 +3,24 @@
 
 mod bus;
 
+pub mod adjust;
 pub mod broker;
+pub mod calendar;
+pub mod codec;
 pub mod comm;
 pub mod core;
+pub mod dataio;
 mod db;
 pub mod filter;
 pub mod indicators;
+pub mod recorder;
+pub mod ring;
 pub mod setting;
+pub mod snapshot;
 pub mod strategy;
+#[cfg(test)]
+mod testing;
+pub mod vendor;
 
 const DATA_PATH: &str = "data";
 const LOG_PATH: &str = "logs";
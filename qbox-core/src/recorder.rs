@@ -0,0 +1,699 @@
+use crate::broker::Exchange;
+use crate::bus::Token;
+use crate::calendar;
+use crate::codec::{self, Format};
+use crate::core::{self, Event, QuoteEvent, BROADCAST, QUOTES_EVENT};
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, Utc};
+use crossbeam::channel::{self, Receiver, Sender};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+//目录结构：<ticks>/<交易所>/<交易日>.dat，同名.idx为块索引
+const TICKS_PATH: &str = "ticks";
+const DATA_EXT: &str = "dat";
+const INDEX_EXT: &str = "idx";
+//每块最多事件数/字节数，块内独立压缩，可按索引直接定位
+const BLOCK_EVENTS: usize = 4096;
+const BLOCK_BYTES: usize = 1 << 20;
+//块缓存超过该秒数未落盘时写入
+const FLUSH_SECS: i64 = 5;
+//定时检查块缓存的间隔，行情停止时缓存也能按时落盘
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[doc = "数据块索引"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlockIndex {
+    pub offset: u64,
+    pub size: u32,
+    pub count: u32,
+    pub begin: i64,
+    pub end: i64,
+    pub security_ids: Vec<String>,
+}
+
+impl BlockIndex {
+    fn matches(&self, begin: i64, end: i64, security_ids: &HashSet<String>) -> bool {
+        self.end >= begin
+            && self.begin < end
+            && (security_ids.is_empty()
+                || self.security_ids.iter().any(|s| security_ids.contains(s)))
+    }
+}
+
+//记录的行情事件，返回(证券代码,交易所,时间)
+fn event_key(ev: &QuoteEvent) -> Option<(&str, Exchange, i64)> {
+    match ev {
+        QuoteEvent::Level1(v) => Some((&v.security_id, v.exchange, v.time)),
+        QuoteEvent::Level2(v) => Some((&v.security_id, v.exchange, v.time)),
+        QuoteEvent::TickToTrade(v) => Some((&v.security_id, v.exchange, v.time)),
+        QuoteEvent::TickToOffer(v) => Some((&v.security_id, v.exchange, v.time)),
+        _ => None,
+    }
+}
+
+fn file_path(dir: &Path, exchange: Exchange, day: NaiveDate, ext: &str) -> PathBuf {
+    let exchange: &str = exchange.into();
+    dir.join(exchange)
+        .join(format!("{}.{}", day.format("%Y%m%d"), ext))
+}
+
+//待写入的数据块，事件已编码，压缩和写文件在写入线程完成
+struct Block {
+    exchange: Exchange,
+    day: NaiveDate,
+    events: Vec<(i64, String, Vec<u8>)>,
+    bytes: usize,
+}
+
+//交易所当前交易日的块缓存
+struct BlockBuffer {
+    day: NaiveDate,
+    events: Vec<(i64, String, Vec<u8>)>,
+    bytes: usize,
+    created: i64,
+}
+
+impl BlockBuffer {
+    fn push(&mut self, security_id: &str, time: i64, body: Vec<u8>, now: i64) {
+        if self.events.is_empty() {
+            self.created = now;
+        }
+        self.bytes += body.len() + 4;
+        self.events.push((time, security_id.into(), body));
+    }
+
+    fn full(&self) -> bool {
+        self.events.len() >= BLOCK_EVENTS || self.bytes >= BLOCK_BYTES
+    }
+
+    fn expired(&self, now: i64) -> bool {
+        !self.events.is_empty() && now - self.created >= FLUSH_SECS
+    }
+
+    fn take(&mut self, exchange: Exchange) -> Option<Block> {
+        if self.events.is_empty() {
+            return None;
+        }
+        let bytes = self.bytes;
+        self.bytes = 0;
+        Some(Block {
+            exchange,
+            day: self.day,
+            events: std::mem::take(&mut self.events),
+            bytes,
+        })
+    }
+}
+
+enum Command {
+    Write(Block),
+    //之前的块写完后回复，带上期间的第一个错误
+    Flush(Sender<Result<()>>),
+}
+
+struct BlockWriter {
+    data: File,
+    index: File,
+}
+
+impl BlockWriter {
+    fn open(dir: &Path, exchange: Exchange, day: NaiveDate) -> Result<Self> {
+        let path = file_path(dir, exchange, day, DATA_EXT);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let open = |path: PathBuf| OpenOptions::new().create(true).append(true).open(path);
+        Ok(Self {
+            data: open(path)?,
+            index: open(file_path(dir, exchange, day, INDEX_EXT))?,
+        })
+    }
+
+    //先写数据块再写索引，索引中的块一定完整，未写索引的残留数据读取时忽略
+    fn write(&mut self, mut block: Block) -> Result<()> {
+        block.events.sort_by_key(|(time, _, _)| *time);
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(block.bytes), Compression::fast());
+        let mut security_ids = vec![];
+        for (_, security_id, body) in block.events.iter() {
+            encoder.write_all(&(body.len() as u32).to_le_bytes())?;
+            encoder.write_all(body)?;
+            if !security_ids.contains(security_id) {
+                security_ids.push(security_id.clone());
+            }
+        }
+        let data = encoder.finish()?;
+        let offset = self.data.seek(SeekFrom::End(0))?;
+        self.data.write_all(&data)?;
+        self.data.flush()?;
+        let entry = BlockIndex {
+            offset,
+            size: data.len() as u32,
+            count: block.events.len() as u32,
+            begin: block.events.first().map(|e| e.0).unwrap_or_default(),
+            end: block.events.last().map(|e| e.0).unwrap_or_default(),
+            security_ids,
+        };
+        let entry = codec::encode(Format::Bincode, &entry)?;
+        self.index.write_all(&(entry.len() as u32).to_le_bytes())?;
+        self.index.write_all(&entry)?;
+        self.index.flush()?;
+        Ok(())
+    }
+}
+
+//写入线程，Recorder释放后通道断开时退出
+fn write_loop(dir: PathBuf, rx: Receiver<Command>) {
+    let mut writers: HashMap<Exchange, (NaiveDate, BlockWriter)> = HashMap::new();
+    let mut error = None;
+    for cmd in rx.iter() {
+        match cmd {
+            Command::Write(block) => {
+                //换日时关闭前一交易日的文件
+                if let Some((day, _)) = writers.get(&block.exchange) {
+                    if *day != block.day {
+                        writers.remove(&block.exchange);
+                    }
+                }
+                if let Entry::Vacant(entry) = writers.entry(block.exchange) {
+                    match BlockWriter::open(&dir, block.exchange, block.day) {
+                        Ok(writer) => {
+                            entry.insert((block.day, writer));
+                        }
+                        Err(err) => {
+                            log::error!("open block file error {}", err);
+                            error.get_or_insert(err);
+                            continue;
+                        }
+                    }
+                }
+                if let Some((_, writer)) = writers.get_mut(&block.exchange) {
+                    if let Err(err) = writer.write(block) {
+                        log::error!("write block error {}", err);
+                        error.get_or_insert(err);
+                    }
+                }
+            }
+            Command::Flush(done) => {
+                done.send(error.take().map_or(Ok(()), Err)).ok();
+            }
+        }
+    }
+}
+
+#[doc = "行情录制，按交易所、交易日分文件压缩保存"]
+pub struct Recorder {
+    buffers: Mutex<HashMap<Exchange, BlockBuffer>>,
+    writer: Sender<Command>,
+    running: AtomicBool,
+    tokens: Mutex<Vec<Token>>,
+}
+
+impl Recorder {
+    pub fn open() -> Result<Arc<Self>> {
+        Self::with_path(Path::new(&crate::data_path()).join(TICKS_PATH))
+    }
+
+    pub fn with_path<P: AsRef<Path>>(dir: P) -> Result<Arc<Self>> {
+        fs::create_dir_all(dir.as_ref())?;
+        let (tx, rx) = channel::unbounded();
+        let dir = dir.as_ref().to_path_buf();
+        std::thread::Builder::new()
+            .name("qbox-recorder-writer".into())
+            .spawn(move || write_loop(dir, rx))?;
+        Ok(Arc::new(Self {
+            buffers: Mutex::new(HashMap::new()),
+            writer: tx,
+            running: AtomicBool::new(false),
+            tokens: Mutex::new(vec![]),
+        }))
+    }
+
+    //订阅行情开始录制，定时及关机时落盘
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let recorder = self.clone();
+        let quotes = core::subscribe(QUOTES_EVENT, move |_, ev| {
+            if let Event::QuoteEvent(ev) = ev.as_ref() {
+                if let Err(err) = recorder.record(ev) {
+                    log::error!("record quote error {}", err);
+                }
+            }
+        })?;
+        let recorder = self.clone();
+        let shutdown = core::subscribe(BROADCAST, move |_, ev| {
+            if let Event::Shutdown = ev.as_ref() {
+                if let Err(err) = recorder.flush() {
+                    log::error!("flush recorder error {}", err);
+                }
+            }
+        })?;
+        self.tokens.lock().extend(vec![quotes, shutdown]);
+        //线程只持有弱引用，Recorder释放后退出
+        let recorder = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("qbox-recorder".into())
+            .spawn(move || loop {
+                std::thread::sleep(FLUSH_INTERVAL);
+                let recorder = match recorder.upgrade() {
+                    Some(recorder) if recorder.running.load(Ordering::SeqCst) => recorder,
+                    _ => break,
+                };
+                if let Err(err) = recorder.flush_expired(Utc::now().timestamp()) {
+                    log::error!("flush recorder error {}", err);
+                }
+            })?;
+        Ok(())
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        for token in self.tokens.lock().drain(..) {
+            core::unsubscribe(&token);
+        }
+        self.flush()
+    }
+
+    //在总线回调中执行，只编码缓存，写满的块交给写入线程
+    pub fn record(&self, ev: &QuoteEvent) -> Result<()> {
+        let (security_id, exchange, time) = match event_key(ev) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let day = calendar::trading_day(exchange, time);
+        let body = codec::encode(Format::Bincode, ev)?;
+        let now = Utc::now().timestamp();
+        let mut buffers = self.buffers.lock();
+        let buffer = buffers.entry(exchange).or_insert_with(|| BlockBuffer {
+            day,
+            events: vec![],
+            bytes: 0,
+            created: now,
+        });
+        //换日时先写出前一交易日的块
+        if buffer.day != day {
+            if let Some(block) = buffer.take(exchange) {
+                self.send(block)?;
+            }
+            buffer.day = day;
+        }
+        buffer.push(security_id, time, body, now);
+        if buffer.full() || buffer.expired(now) {
+            if let Some(block) = buffer.take(exchange) {
+                self.send(block)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, block: Block) -> Result<()> {
+        self.writer
+            .send(Command::Write(block))
+            .map_err(|_| anyhow!("recorder writer closed"))
+    }
+
+    //等待已提交的块写完
+    fn wait(&self) -> Result<()> {
+        let (tx, rx) = channel::bounded(1);
+        self.writer
+            .send(Command::Flush(tx))
+            .map_err(|_| anyhow!("recorder writer closed"))?;
+        rx.recv().map_err(|_| anyhow!("recorder writer closed"))?
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.flush_if(|_| true)
+    }
+
+    //缓存超过FLUSH_SECS秒的块落盘
+    pub fn flush_expired(&self, now: i64) -> Result<()> {
+        self.flush_if(|buffer| buffer.expired(now))
+    }
+
+    fn flush_if<F: Fn(&BlockBuffer) -> bool>(&self, f: F) -> Result<()> {
+        let blocks: Vec<Block> = self
+            .buffers
+            .lock()
+            .iter_mut()
+            .filter(|(_, buffer)| f(buffer))
+            .filter_map(|(exchange, buffer)| buffer.take(*exchange))
+            .collect();
+        for block in blocks {
+            self.send(block)?;
+        }
+        self.wait()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("flush recorder error {}", err);
+        }
+    }
+}
+
+#[doc = "录制行情读取"]
+pub struct TickReader {
+    dir: PathBuf,
+}
+
+impl TickReader {
+    pub fn open() -> Self {
+        Self::with_path(Path::new(&crate::data_path()).join(TICKS_PATH))
+    }
+
+    pub fn with_path<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().into(),
+        }
+    }
+
+    //已录制的交易日
+    pub fn days(&self, exchange: Exchange) -> Result<Vec<NaiveDate>> {
+        let exchange: &str = exchange.into();
+        let dir = self.dir.join(exchange);
+        let mut days = vec![];
+        if !dir.exists() {
+            return Ok(days);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(INDEX_EXT) {
+                continue;
+            }
+            if let Some(day) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| NaiveDate::parse_from_str(s, "%Y%m%d").ok())
+            {
+                days.push(day);
+            }
+        }
+        days.sort();
+        Ok(days)
+    }
+
+    pub fn index(&self, exchange: Exchange, day: NaiveDate) -> Result<Vec<BlockIndex>> {
+        let mut buf = vec![];
+        File::open(file_path(&self.dir, exchange, day, INDEX_EXT))?.read_to_end(&mut buf)?;
+        let mut ret = vec![];
+        let mut pos = 0;
+        while pos + 4 <= buf.len() {
+            let len = u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            let end = pos + 4 + len as usize;
+            if end > buf.len() {
+                //未写完的索引
+                break;
+            }
+            ret.push(codec::decode(Format::Bincode, &buf[pos + 4..end])?);
+            pos = end;
+        }
+        Ok(ret)
+    }
+
+    //按时间顺序读取[begin,end)内的行情，security_ids为空时读取全部证券
+    pub fn read(
+        &self,
+        exchanges: &[Exchange],
+        security_ids: &[&str],
+        begin: i64,
+        end: i64,
+    ) -> Result<TickIter> {
+        let security_ids: HashSet<String> = security_ids.iter().map(|s| s.to_string()).collect();
+        let mut iter = TickIter {
+            files: vec![],
+            sources: vec![],
+            heap: BinaryHeap::new(),
+            security_ids,
+            begin,
+            end,
+        };
+        for exchange in exchanges {
+            let first = calendar::trading_day(*exchange, begin);
+            let last = calendar::trading_day(*exchange, end);
+            for day in self.days(*exchange)? {
+                if day < first || day > last {
+                    continue;
+                }
+                let blocks: Vec<BlockIndex> = self
+                    .index(*exchange, day)?
+                    .into_iter()
+                    .filter(|b| b.matches(begin, end, &iter.security_ids))
+                    .collect();
+                if blocks.is_empty() {
+                    continue;
+                }
+                let file = iter.files.len();
+                iter.files
+                    .push(File::open(file_path(&self.dir, *exchange, day, DATA_EXT))?);
+                //块按索引中的起始时间排队，归并到时才解压
+                for block in blocks {
+                    iter.heap.push(Reverse((block.begin, iter.sources.len())));
+                    iter.sources.push(Source {
+                        file,
+                        block,
+                        events: None,
+                    });
+                }
+            }
+        }
+        Ok(iter)
+    }
+}
+
+//一个数据块，块内已按时间排序，块之间时间可能重叠
+struct Source {
+    file: usize,
+    block: BlockIndex,
+    events: Option<VecDeque<(i64, QuoteEvent)>>,
+}
+
+fn load(data: &mut File, block: &BlockIndex) -> Result<VecDeque<(i64, QuoteEvent)>> {
+    let mut buf = vec![0u8; block.size as usize];
+    data.seek(SeekFrom::Start(block.offset))?;
+    data.read_exact(&mut buf)?;
+    let mut raw = vec![];
+    DeflateDecoder::new(&buf[..]).read_to_end(&mut raw)?;
+    let mut events = VecDeque::with_capacity(block.count as usize);
+    let mut pos = 0;
+    while pos + 4 <= raw.len() {
+        let len = u32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]);
+        let end = pos + 4 + len as usize;
+        if end > raw.len() {
+            return Err(anyhow!("corrupted block at {}", block.offset));
+        }
+        let ev: QuoteEvent = codec::decode(Format::Bincode, &raw[pos + 4..end])?;
+        if let Some((_, _, time)) = event_key(&ev) {
+            events.push_back((time, ev));
+        }
+        pos = end;
+    }
+    Ok(events)
+}
+
+#[doc = "多文件、多数据块按时间归并的行情迭代器"]
+pub struct TickIter {
+    files: Vec<File>,
+    sources: Vec<Source>,
+    //(时间,块序号)，未解压的块用起始时间，时间相同时按块顺序
+    heap: BinaryHeap<Reverse<(i64, usize)>>,
+    security_ids: HashSet<String>,
+    begin: i64,
+    end: i64,
+}
+
+impl TickIter {
+    //将块的下一条符合条件的行情时间放入堆
+    fn advance(&mut self, i: usize) {
+        let security_ids = &self.security_ids;
+        let events = match self.sources[i].events.as_mut() {
+            Some(events) => events,
+            None => return,
+        };
+        while let Some((time, ev)) = events.front() {
+            let keep = *time >= self.begin
+                && *time < self.end
+                && (security_ids.is_empty()
+                    || event_key(ev)
+                        .map(|(s, _, _)| security_ids.contains(s))
+                        .unwrap_or(false));
+            if keep {
+                self.heap.push(Reverse((*time, i)));
+                return;
+            }
+            events.pop_front();
+        }
+    }
+}
+
+impl Iterator for TickIter {
+    type Item = Result<QuoteEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Reverse((_, i)) = self.heap.pop()?;
+            let source = &mut self.sources[i];
+            match source.events.as_mut() {
+                Some(events) => {
+                    let (_, ev) = events.pop_front()?;
+                    self.advance(i);
+                    return Some(Ok(ev));
+                }
+                None => match load(&mut self.files[source.file], &source.block) {
+                    Ok(events) => {
+                        source.events = Some(events);
+                        self.advance(i);
+                    }
+                    Err(err) => return Some(Err(err)),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{Level1, TickToTrade};
+
+    fn tick(security_id: &str, exchange: Exchange, time: i64) -> QuoteEvent {
+        QuoteEvent::TickToTrade(TickToTrade {
+            security_id: security_id.into(),
+            exchange,
+            id: time.to_string(),
+            time,
+            price: 1.0,
+            quantity: 1.0,
+            order_side: None,
+            into_side: None,
+            take_order_id: None,
+            make_order_id: None,
+        })
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = std::env::temp_dir().join(format!("qbox-ticks-{}", std::process::id()));
+        //2022-03-14 10:00:00 +08:00
+        let t0 = 1647223200;
+        {
+            let recorder = Recorder::with_path(&dir).unwrap();
+            for i in 0..10 {
+                recorder
+                    .record(&tick("rb2205", Exchange::SHFE, t0 + i * 2))
+                    .unwrap();
+                recorder
+                    .record(&tick("m2205", Exchange::DCE, t0 + i * 2 + 1))
+                    .unwrap();
+                if i == 4 {
+                    recorder.flush().unwrap();
+                }
+            }
+            let mut level1 = Level1::new();
+            level1.security_id = "rb2205".into();
+            level1.exchange = Exchange::SHFE;
+            level1.time = t0 + 100;
+            recorder.record(&QuoteEvent::Level1(level1)).unwrap();
+        }
+
+        let reader = TickReader::with_path(&dir);
+        assert_eq!(
+            reader
+                .index(Exchange::SHFE, reader.days(Exchange::SHFE).unwrap()[0])
+                .unwrap()
+                .len(),
+            2
+        );
+        let times: Vec<i64> = reader
+            .read(&[Exchange::SHFE, Exchange::DCE], &[], t0, t0 + 3600)
+            .unwrap()
+            .map(|ev| event_key(&ev.unwrap()).unwrap().2)
+            .collect();
+        let mut expect: Vec<i64> = (0..20).map(|i| t0 + i).collect();
+        expect.push(t0 + 100);
+        assert_eq!(times, expect);
+
+        let ids: Vec<String> = reader
+            .read(
+                &[Exchange::SHFE, Exchange::DCE],
+                &["m2205"],
+                t0 + 5,
+                t0 + 10,
+            )
+            .unwrap()
+            .map(|ev| event_key(&ev.unwrap()).unwrap().0.to_string())
+            .collect();
+        assert_eq!(ids, vec!["m2205"; 3]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_flush_expired() {
+        let dir = std::env::temp_dir().join(format!("qbox-ticks-expired-{}", std::process::id()));
+        let t0 = 1647223200;
+        let recorder = Recorder::with_path(&dir).unwrap();
+        let now = Utc::now().timestamp();
+        recorder
+            .record(&tick("rb2205", Exchange::SHFE, t0))
+            .unwrap();
+        let reader = TickReader::with_path(&dir);
+        //未到期不落盘
+        recorder.flush_expired(now).unwrap();
+        assert!(reader.days(Exchange::SHFE).unwrap().is_empty());
+        //没有新行情也按时落盘
+        recorder.flush_expired(now + FLUSH_SECS + 1).unwrap();
+        let day = reader.days(Exchange::SHFE).unwrap()[0];
+        assert_eq!(reader.index(Exchange::SHFE, day).unwrap().len(), 1);
+        recorder.flush_expired(now + FLUSH_SECS * 2).unwrap();
+        assert_eq!(reader.index(Exchange::SHFE, day).unwrap().len(), 1);
+        drop(recorder);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_overlapped_blocks() {
+        let dir = std::env::temp_dir().join(format!("qbox-ticks-overlap-{}", std::process::id()));
+        let t0 = 1647223200;
+        {
+            let recorder = Recorder::with_path(&dir).unwrap();
+            //同一文件中后写入的块时间更早
+            for time in [t0 + 10, t0 + 12, t0 + 14] {
+                recorder
+                    .record(&tick("rb2205", Exchange::SHFE, time))
+                    .unwrap();
+            }
+            recorder.flush().unwrap();
+            for time in [t0 + 1, t0 + 11, t0 + 20] {
+                recorder
+                    .record(&tick("hc2205", Exchange::SHFE, time))
+                    .unwrap();
+            }
+        }
+        let reader = TickReader::with_path(&dir);
+        let times: Vec<i64> = reader
+            .read(&[Exchange::SHFE], &[], t0, t0 + 60)
+            .unwrap()
+            .map(|ev| event_key(&ev.unwrap()).unwrap().2)
+            .collect();
+        assert_eq!(
+            times,
+            vec![t0 + 1, t0 + 10, t0 + 11, t0 + 12, t0 + 14, t0 + 20]
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+}
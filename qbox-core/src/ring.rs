@@ -0,0 +1,152 @@
+use std::collections::vec_deque::{self, VecDeque};
+use std::iter::Map;
+use std::sync::Arc;
+
+#[doc = "定长环形缓冲，写满后覆盖最早的数据，读取只复制引用"]
+#[derive(Debug, Clone)]
+pub struct Ring<T> {
+    items: VecDeque<Arc<T>>,
+    capacity: usize,
+}
+
+pub type Iter<'a, T> = Map<vec_deque::Iter<'a, Arc<T>>, fn(&'a Arc<T>) -> &'a T>;
+
+fn deref<T>(item: &Arc<T>) -> &T {
+    item
+}
+
+impl<T> Ring<T> {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    //调整容量，缩小时丢弃最早的数据
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.items.len() > self.capacity {
+            self.items.pop_front();
+        }
+        self.items.shrink_to_fit();
+        self.items.reserve(self.capacity - self.items.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    //追加到末尾，返回被覆盖的数据
+    pub fn push(&mut self, item: T) -> Option<Arc<T>> {
+        let evicted = if self.items.len() >= self.capacity {
+            self.items.pop_front()
+        } else {
+            None
+        };
+        self.items.push_back(Arc::new(item));
+        evicted
+    }
+
+    //按键有序写入，键相同时覆盖；按序到达时只比较末尾
+    pub fn upsert_by_key<K: Ord, F: Fn(&T) -> K>(&mut self, item: T, f: F) -> Option<Arc<T>> {
+        let key = f(&item);
+        let pos = match self.items.back() {
+            Some(last) if f(last) < key => Err(self.items.len()),
+            Some(last) if f(last) == key => Ok(self.items.len() - 1),
+            _ => self.items.binary_search_by(|v| f(v).cmp(&key)),
+        };
+        match pos {
+            Ok(i) => {
+                self.items[i] = Arc::new(item);
+                None
+            }
+            //比缓冲中所有数据都早且已写满，直接丢弃
+            Err(0) if self.items.len() >= self.capacity => Some(Arc::new(item)),
+            Err(i) => {
+                self.items.insert(i, Arc::new(item));
+                if self.items.len() > self.capacity {
+                    self.items.pop_front()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.items.front().map(deref)
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.items.back().map(deref)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.items.get(index).map(deref)
+    }
+
+    //按时间顺序遍历，不复制数据
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.items.iter().map(deref as fn(&Arc<T>) -> &T)
+    }
+
+    //当前全部数据的快照
+    pub fn snapshot(&self) -> Vec<Arc<T>> {
+        self.items.iter().cloned().collect()
+    }
+
+    //最近n条数据的快照
+    pub fn tail(&self, n: usize) -> Vec<Arc<T>> {
+        let start = self.items.len().saturating_sub(n);
+        self.items.range(start..).cloned().collect()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Ring<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let mut ring = Ring::new(3);
+        for i in 0..5 {
+            ring.push(i);
+        }
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(*ring.tail(2)[0], 3);
+
+        //有序覆盖、插入及丢弃
+        assert!(ring.upsert_by_key(4, |v| *v).is_none());
+        assert_eq!(ring.upsert_by_key(1, |v| *v).as_deref(), Some(&1));
+        assert_eq!(ring.upsert_by_key(5, |v| *v).as_deref(), Some(&2));
+        assert_eq!(ring.upsert_by_key(4, |v| *v), None);
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), vec![3, 4, 5]);
+
+        ring.set_capacity(2);
+        assert_eq!(ring.first(), Some(&4));
+        assert_eq!(ring.len(), 2);
+    }
+}
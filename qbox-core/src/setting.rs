@@ -18,4 +18,4 @@ pub(crate) fn get<T: std::str::FromStr>(key: &str) -> Result<T> {
         Ok(v) => Ok(v),
         Err(_) => Err(anyhow!("parser error")),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,343 @@
+use crate::broker::{Instrument, Order, Position, Transaction};
+use crate::codec::{self, Format, PositionV1, Versioned};
+use crate::db;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[doc = "单元状态快照，策略状态保存在键值设置中"]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub unit: String,
+    pub time: i64,
+    pub settings: Vec<(String, String)>,
+    pub instruments: Vec<Instrument>,
+    //每个订单的状态历史，最后一条为当前状态
+    pub orders: Vec<Vec<Order>>,
+    pub transactions: Vec<Transaction>,
+    pub positions: Vec<Position>,
+    //持仓快照(时间,持仓)，按写入顺序
+    pub position_history: Vec<(i64, Position)>,
+}
+
+//版本2：增加持仓快照历史，持仓增加更新时间
+impl Versioned for Snapshot {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, format: Format, body: &[u8]) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(codec::deserialize::<SnapshotV1>(format, body)?.into()),
+            _ => Err(anyhow!("Snapshot can't upgrade from version {}", version)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    unit: String,
+    time: i64,
+    settings: Vec<(String, String)>,
+    instruments: Vec<Instrument>,
+    orders: Vec<Vec<Order>>,
+    transactions: Vec<Transaction>,
+    positions: Vec<PositionV1>,
+}
+
+impl From<SnapshotV1> for Snapshot {
+    fn from(v1: SnapshotV1) -> Self {
+        Snapshot {
+            unit: v1.unit,
+            time: v1.time,
+            settings: v1.settings,
+            instruments: v1.instruments,
+            orders: v1.orders,
+            transactions: v1.transactions,
+            positions: v1.positions.into_iter().map(Position::from).collect(),
+            position_history: vec![],
+        }
+    }
+}
+
+impl Snapshot {
+    //在同一读事务中读取单元状态，读取期间的交易写入不会混入快照
+    pub fn capture<S: AsRef<str>>(unit: S) -> Result<Self> {
+        let unit = unit.as_ref();
+        let state = db::open(unit)?.read_state()?;
+        Ok(Self {
+            unit: unit.into(),
+            time: Utc::now().timestamp(),
+            settings: state.settings,
+            instruments: state.instruments,
+            orders: state.orders,
+            transactions: state.transactions,
+            positions: state.positions,
+            position_history: state.position_history,
+        })
+    }
+
+    //压缩写入单个文件，先写临时文件再改名
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        {
+            let mut encoder = DeflateEncoder::new(File::create(&tmp)?, Compression::default());
+            encoder.write_all(&codec::encode(Format::Bincode, self)?)?;
+            encoder.finish()?.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut buf = vec![];
+        DeflateDecoder::new(File::open(path)?).read_to_end(&mut buf)?;
+        codec::decode(Format::Bincode, &buf)
+    }
+
+    //恢复到单元，单元必须没有订单、成交、持仓和设置
+    //先写入临时单元，成功后替换目标单元，失败时目标单元仍为空，可以重试
+    pub fn restore<S: AsRef<str>>(&self, unit: S) -> Result<()> {
+        let unit = unit.as_ref();
+        {
+            let store = db::open(unit)?;
+            if store.get_all()?.is_some()
+                || store.query_all_order()?.is_some()
+                || store.query_all_tx()?.is_some()
+                || store.query_all_position()?.is_some()
+            {
+                return Err(anyhow!("unit {} is not empty", unit));
+            }
+        }
+        let file = format!("{}.restore", unit);
+        db::remove_file(&file)?;
+        if let Err(err) = db::open_file(unit, &file).and_then(|store| self.write(store.as_ref())) {
+            db::remove_file(&file).ok();
+            return Err(err);
+        }
+        db::replace(&file, unit)
+    }
+
+    fn write(&self, store: &dyn db::Store) -> Result<()> {
+        for (k, v) in self.settings.iter() {
+            store.set(k, v)?;
+        }
+        for instrument in self.instruments.iter() {
+            store.update_symbol(instrument.clone())?;
+        }
+        //按历史顺序重放，保留订单状态历史
+        for history in self.orders.iter() {
+            for order in history {
+                store.update_order(order.clone())?;
+            }
+        }
+        for tx in self.transactions.iter() {
+            store.insert_tx(tx.clone())?;
+        }
+        //按顺序重放持仓快照，每次写入持仓都会记录快照
+        let mut replayed: HashMap<(String, String), Vec<u8>> = HashMap::new();
+        for (_, position) in self.position_history.iter() {
+            store.update_position(position.clone())?;
+            replayed.insert(
+                position_key(position),
+                codec::encode(Format::Bincode, position)?,
+            );
+        }
+        //已平仓的证券移除持仓，与最后快照不同的持仓再写入一次
+        let current: HashMap<(String, String), &Position> = self
+            .positions
+            .iter()
+            .map(|position| (position_key(position), position))
+            .collect();
+        let mut removed: Vec<&String> = replayed
+            .keys()
+            .filter(|key| !current.contains_key(key))
+            .map(|(security_id, _)| security_id)
+            .collect();
+        removed.sort();
+        removed.dedup();
+        for security_id in removed {
+            store.remove_position(security_id)?;
+        }
+        for position in self.positions.iter() {
+            let body = codec::encode(Format::Bincode, position)?;
+            let exists = store
+                .query_position(&position.security_id)?
+                .unwrap_or_default()
+                .iter()
+                .any(|p| p.side == position.side);
+            if !exists || replayed.get(&position_key(position)) != Some(&body) {
+                store.update_position(position.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn position_key(position: &Position) -> (String, String) {
+    (position.security_id.clone(), format!("{:?}", position.side))
+}
+
+pub fn snapshot<S: AsRef<str>, P: AsRef<Path>>(unit: S, path: P) -> Result<Snapshot> {
+    let snapshot = Snapshot::capture(unit)?;
+    snapshot.save(path)?;
+    Ok(snapshot)
+}
+
+pub fn restore<S: AsRef<str>, P: AsRef<Path>>(unit: S, path: P) -> Result<Snapshot> {
+    let snapshot = Snapshot::load(path)?;
+    snapshot.restore(unit)?;
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{Exchange, Side, State};
+    use crate::testing::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("qbox-snapshot-{}.snap", std::process::id()));
+        let snapshot = Snapshot {
+            unit: "unit".into(),
+            time: 1647223200,
+            settings: vec![("strategy/grid".into(), "{}".into())],
+            instruments: vec![Instrument::new()],
+            ..Default::default()
+        };
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.unit, "unit");
+        assert_eq!(loaded.settings, snapshot.settings);
+        assert_eq!(loaded.instruments.len(), 1);
+        fs::remove_file(&path).ok();
+    }
+
+    //(证券,方向,数量,时间)
+    fn positions(list: &[Position]) -> Vec<(String, Side, i64, i64)> {
+        let mut ret: Vec<_> = list
+            .iter()
+            .map(|p| (p.security_id.clone(), p.side, p.quantity, p.time))
+            .collect();
+        ret.sort_by(|a, b| (&a.0, a.3).cmp(&(&b.0, b.3)));
+        ret
+    }
+
+    fn history(list: &[(i64, Position)]) -> Vec<(i64, String, i64)> {
+        list.iter()
+            .map(|(t, p)| (*t, p.security_id.clone(), p.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_capture_and_restore() {
+        let source = unit("snapshot-source");
+        let target = unit("snapshot-target");
+        let path = std::env::temp_dir().join(format!("{}.snap", source));
+        let store = db::open(&source).unwrap();
+        store.set("strategy/grid", "{}").unwrap();
+        store
+            .update_symbol(
+                Instrument::new()
+                    .with_exchange(Exchange::SHFE)
+                    .with_secrity_id("rb2205"),
+            )
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Submitted, TIME))
+            .unwrap();
+        store
+            .update_order(order(1, TIME, State::Filled, TIME + 1))
+            .unwrap();
+        store
+            .insert_tx(Transaction {
+                id: 10,
+                order_id: 1,
+                out_id: "T10".into(),
+                exchange: Exchange::SHFE,
+                security_id: "rb2205".into(),
+                time: TIME + 1,
+                side: Side::Buy,
+                into_side: Side::Taker,
+                price: 4800.0,
+                quantity: 2.0,
+                ask_order_id: None,
+                bid_order_id: None,
+            })
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 1, TIME))
+            .unwrap();
+        store
+            .update_position(position("ag2206", Side::Short, 3, TIME + 30))
+            .unwrap();
+        store
+            .update_position(position("rb2205", Side::Long, 2, TIME + 60))
+            .unwrap();
+        //平仓后只保留快照
+        store.remove_position("ag2206").unwrap();
+
+        let snapshot = snapshot(&source, &path).unwrap();
+        assert_eq!(snapshot.orders.len(), 1);
+        assert_eq!(snapshot.orders[0].len(), 2);
+        assert_eq!(snapshot.transactions.len(), 1);
+        assert_eq!(snapshot.positions.len(), 1);
+        assert_eq!(snapshot.position_history.len(), 3);
+
+        //恢复到新的数据文件
+        let restored = restore(&target, &path).unwrap();
+        let state = db::open(&target).unwrap().read_state().unwrap();
+        assert_eq!(state.settings, snapshot.settings);
+        assert_eq!(state.instruments, snapshot.instruments);
+        assert_eq!(state.orders, snapshot.orders);
+        let ids: Vec<u64> = state.transactions.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![10]);
+        assert_eq!(positions(&state.positions), positions(&restored.positions));
+        assert_eq!(
+            history(&state.position_history),
+            history(&snapshot.position_history)
+        );
+        assert_eq!(
+            history(&state.position_history),
+            vec![
+                (TIME, "rb2205".into(), 1),
+                (TIME + 30, "ag2206".into(), 3),
+                (TIME + 60, "rb2205".into(), 2),
+            ]
+        );
+
+        //目标单元非空时拒绝恢复
+        assert!(restored.restore(&target).is_err());
+
+        cleanup(&source);
+        cleanup(&target);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_restore_failure() {
+        let target = unit("snapshot-failure");
+        //成交编号重复，写入到一半失败
+        let mut snapshot = Snapshot {
+            unit: "source".into(),
+            settings: vec![("strategy/grid".into(), "{}".into())],
+            transactions: vec![tx(1, 1, "rb2205", TIME), tx(1, 1, "rb2205", TIME)],
+            ..Default::default()
+        };
+        assert!(snapshot.restore(&target).is_err());
+        //目标单元仍为空，可以重试
+        assert!(db::open(&target).unwrap().get_all().unwrap().is_none());
+        snapshot.transactions.pop();
+        snapshot.restore(&target).unwrap();
+        let state = db::open(&target).unwrap().read_state().unwrap();
+        assert_eq!(state.settings, snapshot.settings);
+        assert_eq!(state.transactions.len(), 1);
+        cleanup(&target);
+    }
+}
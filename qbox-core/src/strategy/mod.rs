@@ -1,6 +1,8 @@
 pub mod executor;
 use crate::broker::Parameter;
+use crate::codec;
 use crate::core::events::QuoteEvent;
+use crate::db;
 use anyhow::Result;
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
@@ -31,6 +33,23 @@ pub fn unregister_driver(name: &str) -> Result<()> {
     Ok(())
 }
 
+//策略状态保存在单元键值设置中，随快照备份
+const STATE_PREFIX: &str = "strategy/";
+
+pub fn save_state<S: AsRef<str>>(unit: S, name: &str, state: &Parameter) -> Result<()> {
+    db::open(unit)?.set(
+        &format!("{}{}", STATE_PREFIX, name),
+        &codec::to_string(state)?,
+    )
+}
+
+pub fn load_state<S: AsRef<str>>(unit: S, name: &str) -> Result<Option<Parameter>> {
+    match db::open(unit)?.get(&format!("{}{}", STATE_PREFIX, name))? {
+        Some(state) => Ok(Some(codec::from_str(&state)?)),
+        None => Ok(None),
+    }
+}
+
 struct Factory;
 
 impl Factory {
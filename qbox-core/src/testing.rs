@@ -0,0 +1,97 @@
+//测试公用的单元与数据
+use crate::broker::*;
+use crate::db;
+use std::path::Path;
+
+//2022-03-14 15:00:00 +08:00
+pub const TIME: i64 = 1647241200;
+
+//每个测试独立的单元，打开前清理上次残留的数据文件
+pub fn unit(name: &str) -> String {
+    let unit = format!("{}-{}", name, std::process::id());
+    cleanup(&unit);
+    unit
+}
+
+pub fn cleanup(unit: &str) {
+    db::close(unit);
+    for ext in &["db", "persy"] {
+        std::fs::remove_file(Path::new(&crate::data_path()).join(format!("{}.{}", unit, ext))).ok();
+    }
+}
+
+pub fn order(id: u64, time: i64, state: State, last_time: i64) -> Order {
+    Order::Limit {
+        id,
+        security_id: "rb2205".into(),
+        exchange: Exchange::SHFE,
+        time,
+        side: Side::Buy,
+        offset: Side::Open,
+        price: 4800.0,
+        quantity: 2.0,
+        lever: 1,
+        pov: OrderLife::GTC,
+        remark: "".into(),
+        state: OrderState {
+            filled_quantity: 0.0,
+            filled_amount: 0.0,
+            avg_price: 0.0,
+            last_time,
+            state,
+        },
+    }
+}
+
+pub fn tx(id: u64, order_id: u64, security_id: &str, time: i64) -> Transaction {
+    Transaction {
+        id,
+        order_id,
+        out_id: format!("T{}", id),
+        exchange: Exchange::SHFE,
+        security_id: security_id.into(),
+        time,
+        side: Side::Buy,
+        into_side: Side::Taker,
+        price: 4800.0,
+        quantity: 1.0,
+        ask_order_id: None,
+        bid_order_id: None,
+    }
+}
+
+pub fn position(security_id: &str, side: Side, quantity: i64, time: i64) -> Position {
+    Position {
+        exchange: Exchange::SHFE,
+        security_id: security_id.into(),
+        side,
+        offset: Side::Open,
+        margin_level: 1,
+        quantity,
+        frozen: 0.0,
+        last: 4800.0,
+        average: 4800.0,
+        settlement: 4800.0,
+        cost: 4800.0 * quantity as f64,
+        margin: 0.0,
+        realized_pnl: 0.0,
+        unrealized_pnl: 0.0,
+        position_pnl: 0.0,
+        time,
+    }
+}
+
+pub fn bar(period: Period, time: i64, close: f64) -> Bar {
+    Bar {
+        security_id: "rb2205".into(),
+        exchange: Exchange::SHFE,
+        period,
+        time,
+        open: close,
+        high: close,
+        low: close,
+        close,
+        volume: 1.0,
+        turnover: None,
+    }
+}
@@ -0,0 +1,376 @@
+use crate::broker::{Bar, Exchange, Period};
+use crate::calendar;
+use crate::dataio::{self, CsvOptions, TimeFormat};
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, TimeZone};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//通达信日线、分钟线记录长度
+const TDX_RECORD: usize = 32;
+
+#[doc = "通达信数据文件，如vipdoc/sh/lday/sh600000.day、vipdoc/ds/fzline/30#RBL8.lc5"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TdxFile {
+    pub path: PathBuf,
+    pub security_id: String,
+    pub exchange: Exchange,
+    pub period: Period,
+}
+
+impl TdxFile {
+    //按文件名识别证券代码、交易所和周期
+    pub fn parse<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let period = match path.extension().and_then(|e| e.to_str()) {
+            Some("day") => Period::Day(1),
+            Some("lc1") => Period::Minute(1),
+            Some("lc5") => Period::Minute(5),
+            _ => return Err(anyhow!("unsupported tdx file {}", path.display())),
+        };
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("invalid tdx file {}", path.display()))?;
+        let (exchange, security_id) = match stem.split_once('#') {
+            //扩展市场，市场代码#合约代码
+            Some((market, code)) => {
+                let exchange = match market {
+                    "28" => Exchange::DZCE,
+                    "29" => Exchange::DCE,
+                    "30" => Exchange::SHFE,
+                    "47" => Exchange::CFFEX,
+                    _ => return Err(anyhow!("unknown tdx market {}", market)),
+                };
+                //上期所、大商所合约代码为小写
+                let code = match exchange {
+                    Exchange::SHFE | Exchange::DCE => code.to_lowercase(),
+                    _ => code.to_uppercase(),
+                };
+                (exchange, code)
+            }
+            None if stem.len() > 2 && stem.is_char_boundary(2) => {
+                let (market, code) = stem.split_at(2);
+                let exchange = match market.to_lowercase().as_str() {
+                    "sh" => Exchange::SSE,
+                    "sz" => Exchange::SZE,
+                    _ => return Err(anyhow!("unknown tdx market {}", market)),
+                };
+                (exchange, code.to_string())
+            }
+            None => return Err(anyhow!("invalid tdx file {}", path.display())),
+        };
+        Ok(Self {
+            path: path.into(),
+            security_id,
+            exchange,
+            period,
+        })
+    }
+
+    //沪深市场的日线价格为整数，其余为浮点数
+    fn is_stock(&self) -> bool {
+        self.exchange == Exchange::SSE || self.exchange == Exchange::SZE
+    }
+
+    //沪深日线价格倍数，基金、债券为1000，股票为100
+    fn price_scale(&self) -> f64 {
+        let code = self.security_id.as_str();
+        let fund = match self.exchange {
+            Exchange::SSE => code.starts_with('5') || code.starts_with("11"),
+            Exchange::SZE => {
+                code.starts_with("15") || code.starts_with("16") || code.starts_with("12")
+            }
+            _ => false,
+        };
+        if fund {
+            1000.0
+        } else {
+            100.0
+        }
+    }
+
+    pub fn read(&self) -> Result<Vec<Bar>> {
+        let buf = fs::read(&self.path)?;
+        let offset = calendar::timezone(self.exchange);
+        let mut bars = Vec::with_capacity(buf.len() / TDX_RECORD);
+        for rec in buf.chunks_exact(TDX_RECORD) {
+            let u32_at =
+                |i: usize| u32::from_le_bytes([rec[i], rec[i + 1], rec[i + 2], rec[i + 3]]);
+            let f32_at = |i: usize| f32::from_bits(u32_at(i)) as f64;
+            let bar = match self.period {
+                Period::Day(_) => {
+                    let date = u32_at(0);
+                    let date = match NaiveDate::from_ymd_opt(
+                        (date / 10000) as i32,
+                        date / 100 % 100,
+                        date % 100,
+                    ) {
+                        Some(date) => date,
+                        None => continue,
+                    };
+                    let time = offset
+                        .from_local_datetime(&date.and_hms(0, 0, 0))
+                        .single()
+                        .map(|dt| dt.timestamp())
+                        .unwrap_or_default();
+                    if self.is_stock() {
+                        let scale = self.price_scale();
+                        let price = |i: usize| u32_at(i) as f64 / scale;
+                        self.bar(
+                            time,
+                            [price(4), price(8), price(12), price(16)],
+                            u32_at(24) as f64,
+                            Some(f32_at(20)),
+                        )
+                    } else {
+                        self.bar(
+                            time,
+                            [f32_at(4), f32_at(8), f32_at(12), f32_at(16)],
+                            u32_at(24) as f64,
+                            None,
+                        )
+                    }
+                }
+                _ => {
+                    //日期为(年-2004)*2048+月*100+日，时间为当日分钟数，标记的是k线结束时间
+                    let date = u16::from_le_bytes([rec[0], rec[1]]) as u32;
+                    let minutes = u16::from_le_bytes([rec[2], rec[3]]) as u32;
+                    let date = match NaiveDate::from_ymd_opt(
+                        (date / 2048 + 2004) as i32,
+                        date % 2048 / 100,
+                        date % 2048 % 100,
+                    ) {
+                        Some(date) => date,
+                        None => continue,
+                    };
+                    let end = offset
+                        .from_local_datetime(&date.and_hms(minutes / 60 % 24, minutes % 60, 0))
+                        .single()
+                        .map(|dt| dt.timestamp())
+                        .unwrap_or_default();
+                    let time = end - self.period.seconds().unwrap_or_default();
+                    let turnover = if self.is_stock() {
+                        Some(f32_at(20))
+                    } else {
+                        None
+                    };
+                    self.bar(
+                        time,
+                        [f32_at(4), f32_at(8), f32_at(12), f32_at(16)],
+                        u32_at(24) as f64,
+                        turnover,
+                    )
+                }
+            };
+            bars.push(bar);
+        }
+        Ok(bars)
+    }
+
+    fn bar(&self, time: i64, ohlc: [f64; 4], volume: f64, turnover: Option<f64>) -> Bar {
+        Bar {
+            security_id: self.security_id.clone(),
+            exchange: self.exchange,
+            period: self.period,
+            time,
+            open: ohlc[0],
+            high: ohlc[1],
+            low: ohlc[2],
+            close: ohlc[3],
+            volume,
+            turnover,
+        }
+    }
+}
+
+pub fn read_tdx<P: AsRef<Path>>(path: P) -> Result<Vec<Bar>> {
+    TdxFile::parse(path)?.read()
+}
+
+//递归查找目录下可识别的通达信数据文件
+pub fn scan_tdx<P: AsRef<Path>>(dir: P) -> Result<Vec<TdxFile>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(scan_tdx(&path)?);
+        } else if let Ok(file) = TdxFile::parse(&path) {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+//导入通达信文件或目录（如vipdoc）到单元的k线存储，返回写入条数
+pub fn import_tdx<S, P>(unit: S, path: P) -> Result<usize>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let files = if path.is_dir() {
+        scan_tdx(path)?
+    } else {
+        vec![TdxFile::parse(path)?]
+    };
+    let mut count = 0;
+    for file in files {
+        count += dataio::load_bars(unit.as_ref(), file.read()?)?;
+    }
+    Ok(count)
+}
+
+//分钟线以结束时间标记，转为开始时间
+fn shift_to_begin(mut bars: Vec<Bar>) -> Vec<Bar> {
+    for bar in bars.iter_mut() {
+        if is_intraday(bar.period) {
+            bar.time -= bar.period.seconds().unwrap_or_default();
+        }
+    }
+    bars
+}
+
+fn is_intraday(period: Period) -> bool {
+    matches!(
+        period,
+        Period::Second(_) | Period::Minute(_) | Period::Hour(_)
+    )
+}
+
+//文华财经导出数据，列为 时间,开盘价,最高价,最低价,收盘价,成交量,持仓量
+pub fn wenhua_options(security_id: &str, exchange: Exchange, period: Period) -> CsvOptions {
+    let pattern = if is_intraday(period) {
+        "%Y/%m/%d %H:%M"
+    } else {
+        "%Y/%m/%d"
+    };
+    CsvOptions::new()
+        .with_security_id(security_id)
+        .with_exchange(exchange)
+        .with_period(period)
+        .with_column("date", "时间")
+        .with_column("open", "开盘价")
+        .with_column("high", "最高价")
+        .with_column("low", "最低价")
+        .with_column("close", "收盘价")
+        .with_column("volume", "成交量")
+        .with_time_format(TimeFormat::Pattern(pattern.into()))
+}
+
+pub fn read_wenhua<P: AsRef<Path>>(
+    path: P,
+    security_id: &str,
+    exchange: Exchange,
+    period: Period,
+) -> Result<Vec<Bar>> {
+    let opts = wenhua_options(security_id, exchange, period);
+    Ok(shift_to_begin(dataio::read_bars(path, &opts)?))
+}
+
+//交易开拓者(TB)导出数据，列为 Date,Time,Open,High,Low,Close,Vol,OpenInt
+pub fn tb_options(security_id: &str, exchange: Exchange, period: Period) -> CsvOptions {
+    let opts = CsvOptions::new()
+        .with_security_id(security_id)
+        .with_exchange(exchange)
+        .with_period(period)
+        .with_column("date", "Date")
+        .with_column("open", "Open")
+        .with_column("high", "High")
+        .with_column("low", "Low")
+        .with_column("close", "Close")
+        .with_column("volume", "Vol");
+    if is_intraday(period) {
+        opts.with_column("time", "Time")
+            .with_time_format(TimeFormat::Pattern("%Y/%m/%d %H:%M".into()))
+    } else {
+        //日线忽略时间列
+        opts.with_column("time", "")
+            .with_time_format(TimeFormat::Pattern("%Y/%m/%d".into()))
+    }
+}
+
+pub fn read_tb<P: AsRef<Path>>(
+    path: P,
+    security_id: &str,
+    exchange: Exchange,
+    period: Period,
+) -> Result<Vec<Bar>> {
+    let opts = tb_options(security_id, exchange, period);
+    Ok(shift_to_begin(dataio::read_bars(path, &opts)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: [u32; 8]) -> Vec<u8> {
+        fields.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_tdx() {
+        let dir = std::env::temp_dir().join(format!("qbox-tdx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let day = dir.join("sh600000.day");
+        let mut buf = record([20220314, 850, 870, 840, 860, 0f32.to_bits(), 1000, 0]);
+        buf.extend(record([
+            20220315,
+            860,
+            880,
+            850,
+            870,
+            0f32.to_bits(),
+            2000,
+            0,
+        ]));
+        fs::write(&day, buf).unwrap();
+        let bars = read_tdx(&day).unwrap();
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].security_id, "600000");
+        assert_eq!(bars[0].exchange, Exchange::SSE);
+        assert_eq!(bars[0].period, Period::Day(1));
+        //2022-03-14 00:00:00 +08:00
+        assert_eq!(bars[0].time, 1647187200);
+        assert_eq!(bars[1].close, 8.7);
+
+        //2022-03-14 09:05
+        let lc5 = dir.join("30#RBL8.lc5");
+        let date = (2022 - 2004) * 2048 + 314;
+        let mut rec = vec![];
+        rec.extend((date as u16).to_le_bytes());
+        rec.extend((9 * 60 + 5u16).to_le_bytes());
+        for v in [4900f32, 4910.0, 4890.0, 4905.0, 0.0] {
+            rec.extend(v.to_le_bytes());
+        }
+        rec.extend(120u32.to_le_bytes());
+        rec.extend(0u32.to_le_bytes());
+        fs::write(&lc5, rec).unwrap();
+        let bars = read_tdx(&lc5).unwrap();
+        assert_eq!(bars[0].security_id, "rbl8");
+        assert_eq!(bars[0].exchange, Exchange::SHFE);
+        assert_eq!(bars[0].period, Period::Minute(5));
+        assert_eq!(bars[0].time, 1647187200 + 9 * 3600);
+        assert_eq!(bars[0].close, 4905.0);
+
+        assert_eq!(scan_tdx(&dir).unwrap().len(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_wenhua() {
+        let dir = std::env::temp_dir().join(format!("qbox-wenhua-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rb.csv");
+        fs::write(
+            &path,
+            "时间,开盘价,最高价,最低价,收盘价,成交量,持仓量\n2022/03/14 09:01,4900,4910,4890,4905,120,3000\n",
+        )
+        .unwrap();
+        let bars = read_wenhua(&path, "rb2205", Exchange::SHFE, Period::Minute(1)).unwrap();
+        assert_eq!(bars[0].time, 1647187200 + 9 * 3600);
+        assert_eq!(bars[0].volume, 120.0);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -10,6 +10,7 @@ use druid::text::{Attribute, RichText, TextStorage};
 use druid::{
     Color, Data, Env, FontFamily, FontStyle, FontWeight, Lens, Point, Rect, Size, WindowId,
 };
+use qbox_core::adjust::{self, AdjustMode};
 use qbox_core::broker::Bar;
 use std::path::Path;
 use std::sync::Arc;
@@ -41,6 +42,8 @@ pub fn get_primary_work_rect() -> Option<Rect> {
 pub struct State {
     pub period: i32,
     pub data: Arc<Vec<Bar>>,
+    pub bars: Arc<Vec<Bar>>, //未复权k线
+    pub adjust: i32,         //复权：0不复权，1前复权，2后复权
     pub symbol: Arc<Vec<String>>,
     pub radio: MyRadio,
     pub windid: Option<Arc<WindowId>>,
@@ -94,6 +97,8 @@ impl State {
             name2: NameText::new(text2),
             period: Default::default(),
             data: Default::default(),
+            bars: Default::default(),
+            adjust: Default::default(),
             symbol: Default::default(),
             radio: Default::default(),
             windid: None,
@@ -889,15 +894,27 @@ impl State {
     pub fn flush_symbol(&mut self, symbol: &str) {
         if let Ok(file) = std::fs::File::open(format!("{}.csv", symbol)) {
             let mut rdr = csv::Reader::from_reader(file);
-            let data = Arc::make_mut(&mut self.data);
-            data.clear();
+            let bars = Arc::make_mut(&mut self.bars);
+            bars.clear();
             for result in rdr.deserialize() {
                 if let Ok(bar) = result {
-                    data.push(bar);
+                    bars.push(bar);
                 }
             }
+            self.set_adjust(self.adjust);
         }
     }
+
+    //切换复权方式，按未复权k线重新计算
+    pub fn set_adjust(&mut self, adjust: i32) {
+        self.adjust = adjust;
+        let mode = match adjust {
+            1 => AdjustMode::Forward,
+            2 => AdjustMode::Backward,
+            _ => AdjustMode::None,
+        };
+        self.data = Arc::new(adjust::adjust(&self.bars, mode));
+    }
 }
 
 #[derive(Data, Lens, Clone)]
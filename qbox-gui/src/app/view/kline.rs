@@ -36,6 +36,27 @@ impl KLine {
                             .expand_height()
                             .on_click(|_ctx, state: &mut State, env| {}),
                     )
+                    .with_spacer(1.0)
+                    .with_child(
+                        Button::new("不复权")
+                            .fix_width(60.)
+                            .expand_height()
+                            .on_click(|_ctx, state: &mut State, _env| state.set_adjust(0)),
+                    )
+                    .with_spacer(1.0)
+                    .with_child(
+                        Button::new("前复权")
+                            .fix_width(60.)
+                            .expand_height()
+                            .on_click(|_ctx, state: &mut State, _env| state.set_adjust(1)),
+                    )
+                    .with_spacer(1.0)
+                    .with_child(
+                        Button::new("后复权")
+                            .fix_width(60.)
+                            .expand_height()
+                            .on_click(|_ctx, state: &mut State, _env| state.set_adjust(2)),
+                    )
                     .fix_height(30.)
                     .align_left()
                     .padding(5.0),